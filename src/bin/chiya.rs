@@ -1,6 +1,21 @@
-use std::io::Read;
+use std::io::{Read, Write};
 
-use chiya::codegen::{self, emitter::Emitter, llvm::LLVM};
+use chiya::codegen::{
+    self,
+    brainfuck::Brainfuck,
+    c::{self, C},
+    elf::Elf,
+    emitter::Emitter,
+    javascript::JavaScript,
+    llvm::LLVM,
+    options,
+    rust::Rust,
+    wasm::{binary, Wasm},
+    x86_64::X86_64,
+};
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use chiya::interpreter::jit::Jit;
+use chiya::interpreter::{bytecode::Program, Compiler, Interpreter, InterpreterError};
 use chiya::parser;
 use chiya::token;
 
@@ -9,23 +24,248 @@ fn main() -> Result<(), failure::Error> {
 
     let debug = args.iter().any(|a| *a == "--debug");
     let bf = args.iter().any(|a| *a == "--bf");
+    let jit = args.iter().any(|a| *a == "--jit");
+
+    // `chiya run <path>` interprets the program at <path>, leaving stdin to its input, while
+    // `chiya compile` is the same as `chiya`.
+    let run = match args.get(1).map(|a| a.as_str()) {
+        Some("run") => Some(
+            args.get(2)
+                .ok_or_else(|| failure::format_err!("run requires a path"))?,
+        ),
+        _ => None,
+    };
+
+    // Bytecode carries the options it was compiled with, except for the limits.
+    if let Some(path) = run.filter(|p| p.ends_with(".chb")) {
+        let mut program = Program::decode(&std::fs::read(path)?)?;
+        for name in LIMITS {
+            if let Some(value) = option_value(&args, name) {
+                program.options.set(name, value)?;
+            }
+        }
+        program.options.check()?;
+        program.verify()?;
+        let status = if jit {
+            run_jit(&program)?
+        } else {
+            interpret(&program.options, |input, output| program.run(input, output))?
+        };
+        std::process::exit(status);
+    }
 
     let mut src = String::new();
-    std::io::stdin().read_to_string(&mut src)?;
+    match run {
+        Some(path) => src = std::fs::read_to_string(path)?,
+        None => {
+            std::io::stdin().read_to_string(&mut src)?;
+        }
+    }
 
-    let e = LLVM::new();
+    let mut options = options::Options::new();
+    options.apply_pragmas(&src)?;
+    for name in options::Options::NAMES {
+        if options::Options::FLAGS.contains(name) {
+            if args.iter().any(|a| *a == format!("--{}", name)) {
+                options.set(name, "true")?;
+            }
+        } else if let Some(value) = option_value(&args, name) {
+            options.set(name, value)?;
+        }
+    }
 
-    if bf {
-        compile_bf(e, &src)?;
-    } else {
-        compile(e, &src, debug)?;
+    if args.iter().any(|a| *a == "-g") {
+        options.set("debug-info", "true")?;
+    }
+
+    options.check()?;
+
+    if run.is_some() {
+        let mut compiler = Compiler::new();
+        generate(&mut compiler, &src, bf, debug)?;
+        let code = compiler.code();
+        let status = if jit {
+            run_jit(&Program::new(&code, &options))?
+        } else {
+            interpret(&options, |input, output| {
+                Interpreter::with_options(&options).run(&code, input, output)
+            })?
+        };
+        std::process::exit(status);
+    }
+
+    if let Some(path) = option_value(&args, "c-header") {
+        let header = c::header(&options)
+            .ok_or_else(|| failure::format_err!("--c-header requires --function"))?;
+        std::fs::write(path, header)?;
+    }
+
+    let output = args
+        .iter()
+        .position(|a| *a == "-o")
+        .and_then(|i| args.get(i + 1));
+
+    let llvm = matches!(option_value(&args, "emit"), Some("llvm") | None);
+    // Bytecode keeps no limits, but compiling it with --fuel or --max-tape leaves it unfused so
+    // that it can be run with them.
+    let bytecode = option_value(&args, "emit") == Some("bytecode");
+    let limited = ((options.fuel.is_some() || options.max_tape.is_some()) && !bytecode)
+        || options.max_output.is_some();
+    if !llvm && limited {
+        failure::bail!(
+            "--fuel, --max-tape and --max-output are only supported by run and LLVM IR, and --fuel and --max-tape by bytecode"
+        );
+    }
+
+    let code = match option_value(&args, "emit") {
+        Some("exe") => {
+            if options.function.is_some() || options.debug_info {
+                failure::bail!("--emit exe does not support --function or debug-info");
+            }
+            let path = output.ok_or_else(|| failure::format_err!("--emit exe requires -o"))?;
+            let mut e = Elf::with_options(&options);
+            generate(&mut e, &src, bf, debug)?;
+            std::fs::write(path, e.executable())?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+            }
+            return Ok(());
+        }
+        Some("wasm") => {
+            if options.function.is_some() || options.debug_info {
+                failure::bail!("--emit wasm does not support --function or debug-info");
+            }
+            let path = output.ok_or_else(|| failure::format_err!("--emit wasm requires -o"))?;
+            let mut e = Wasm::with_options(&options);
+            e.check_tape()?;
+            let wat = generate(&mut e, &src, bf, debug)?;
+            let module = binary::parse(&wat)?;
+            module.validate()?;
+            std::fs::write(path, module.encode())?;
+            return Ok(());
+        }
+        Some("bytecode") => {
+            if options.function.is_some() || options.debug_info {
+                failure::bail!("--emit bytecode does not support --function or debug-info");
+            }
+            let path = output.ok_or_else(|| failure::format_err!("--emit bytecode requires -o"))?;
+            let mut compiler = Compiler::new();
+            generate(&mut compiler, &src, bf, debug)?;
+            std::fs::write(path, Program::new(&compiler.code(), &options).encode())?;
+            return Ok(());
+        }
+        Some("wat") => {
+            if options.function.is_some() || options.debug_info {
+                failure::bail!("--emit wat does not support --function or debug-info");
+            }
+            let mut e = Wasm::with_options(&options);
+            e.check_tape()?;
+            generate(&mut e, &src, bf, debug)?
+        }
+        Some("asm") => generate(&mut X86_64::with_options(&options), &src, bf, debug)?,
+        Some("c") => generate(&mut C::with_options(&options), &src, bf, debug)?,
+        Some("js") => {
+            if options.function.is_some() || options.debug_info {
+                failure::bail!("--emit js does not support --function or debug-info");
+            }
+            generate(&mut JavaScript::with_options(&options), &src, bf, debug)?
+        }
+        Some("bf") => {
+            if options.function.is_some() || options.debug_info {
+                failure::bail!("--emit bf does not support --function or debug-info");
+            }
+            let mut e = Brainfuck::with_options(&options);
+            let code = generate(&mut e, &src, bf, debug)?;
+            e.check()?;
+            code
+        }
+        Some("rust") => {
+            if options.debug_info {
+                failure::bail!("--emit rust does not support debug-info");
+            }
+            generate(&mut Rust::with_options(&options), &src, bf, debug)?
+        }
+        Some("llvm") | None => generate(&mut LLVM::with_options(&options), &src, bf, debug)?,
+        Some(emit) => failure::bail!("unknown --emit value: {}", emit),
+    };
+
+    match output {
+        Some(path) => std::fs::write(path, code + "\n")?,
+        None => println!("{}", code),
     }
 
     Ok(())
 }
 
-fn compile<E: Emitter>(mut e: E, src: &str, debug: bool) -> Result<(), failure::Error> {
-    let tokens = token::tokenize(&src)?;
+// Runs a program with `run` on the standard I/O and returns its exit status, which is 1 on an
+// error.
+fn interpret(
+    options: &options::Options,
+    run: impl FnOnce(&mut dyn Read, &mut dyn Write) -> Result<i32, InterpreterError>,
+) -> Result<i32, failure::Error> {
+    let stdout = std::io::stdout();
+    let mut output: Box<dyn Write> = if options.buffered_io {
+        Box::new(std::io::BufWriter::new(stdout.lock()))
+    } else {
+        Box::new(stdout.lock())
+    };
+    let status = run(&mut std::io::stdin().lock(), &mut output);
+    output.flush()?;
+    match status {
+        Ok(status) => Ok(status),
+        Err(InterpreterError::Io(e)) => Err(e.into()),
+        Err(e) => {
+            eprintln!("chiya: {}", e);
+            if let Some(snapshot) = e.snapshot() {
+                eprintln!("chiya: {}", snapshot);
+            }
+            Ok(1)
+        }
+    }
+}
+
+// Runs `program` compiled to machine code.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_jit(program: &Program) -> Result<i32, failure::Error> {
+    let jit = Jit::new(program)?;
+    interpret(&program.options, |input, output| jit.run(input, output))
+}
+
+// Runs `program` with the bytecode VM where there is no JIT.
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn run_jit(program: &Program) -> Result<i32, failure::Error> {
+    interpret(&program.options, |input, output| program.run(input, output))
+}
+
+// The options limiting untrusted programs.
+const LIMITS: [&str; 3] = ["fuel", "max-tape", "max-output"];
+
+// Returns the argument following `--<name>`.
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| *a == format!("--{}", name))
+        .and_then(|i| args.get(i + 1))
+        .map(|a| a.as_str())
+}
+
+// Generates the code for `src`, which is Brainfuck if `bf`.
+fn generate<E: Emitter>(
+    e: &mut E,
+    src: &str,
+    bf: bool,
+    debug: bool,
+) -> Result<String, failure::Error> {
+    if bf {
+        Ok(codegen::gen_bf(e, src))
+    } else {
+        compile(e, src, debug)
+    }
+}
+
+fn compile<E: Emitter>(e: &mut E, src: &str, debug: bool) -> Result<String, failure::Error> {
+    let (tokens, locations) = token::tokenize_with_locations(src)?;
     if debug {
         eprintln!("tokens: {:?}", tokens);
     }
@@ -35,29 +275,5 @@ fn compile<E: Emitter>(mut e: E, src: &str, debug: bool) -> Result<(), failure::
         eprintln!("syntax tree:\n{:#?}", tree.1);
     }
 
-    let code = codegen::gen(&mut e, &tree.1)?;
-    println!("{}", code);
-
-    Ok(())
-}
-
-fn compile_bf<E: Emitter>(mut e: E, src: &str) -> Result<(), failure::Error> {
-    println!("{}", e.emit_header());
-    for c in src.chars() {
-        let l = match c {
-            '>' => e.emit_move_ptr(1),
-            '<' => e.emit_move_ptr(-1),
-            '+' => e.emit_add(1),
-            '-' => e.emit_add(-1),
-            '.' => e.emit_call_putchar(),
-            ',' => e.emit_call_getchar(),
-            '[' => e.emit_loop_begin(),
-            ']' => e.emit_loop_end(),
-            _ => continue,
-        };
-        println!("{}", l);
-    }
-    println!("{}", e.emit_footer());
-
-    Ok(())
+    Ok(codegen::gen_with_locations(e, &tree.1, &locations)?)
 }
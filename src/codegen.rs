@@ -1,7 +1,16 @@
+pub mod brainfuck;
+pub mod c;
+pub mod elf;
 pub mod emitter;
+pub mod javascript;
 pub mod llvm;
+pub mod options;
+pub mod rust;
+pub mod wasm;
+pub mod x86_64;
 
-use crate::parser::{Block, Expression, Lhs, Program, Rhs, Statement, Statements};
+use crate::parser::{Argument, Block, Expression, Lhs, Program, Rhs, Statement, Statements};
+use crate::token::Location;
 
 #[derive(Debug, PartialEq, failure::Fail)]
 pub enum CodegenError {
@@ -16,41 +25,110 @@ pub enum CodegenError {
 }
 
 pub fn gen<E: emitter::Emitter>(emitter: &mut E, tree: &Program) -> Result<String, CodegenError> {
+    gen_with_locations(emitter, tree, &[])
+}
+
+// Same as `gen`, but also tells the emitter where each statement is. `locations` are the
+// locations of the tokens `tree` was parsed from.
+pub fn gen_with_locations<E: emitter::Emitter>(
+    emitter: &mut E,
+    tree: &Program,
+    locations: &[Location],
+) -> Result<String, CodegenError> {
     match tree {
         Program::Statements(ss) => {
             let header = emitter.emit_header();
-            let body = statements(emitter, ss)?;
+            let body = statements(emitter, ss, locations)?;
             let footer = emitter.emit_footer();
             Ok(format!("{}{}{}", header, body, footer))
         }
     }
 }
 
+// Generates the code for the Brainfuck program `src`, telling the emitter where each command is.
+// Pragma lines are skipped, so that they may contain commands.
+pub fn gen_bf<E: emitter::Emitter>(emitter: &mut E, src: &str) -> String {
+    let mut lines = vec![emitter.emit_header()];
+    let mut location = Location { line: 1, column: 1 };
+    for line in src.split_inclusive('\n') {
+        if options::pragma(line).is_some() {
+            location = location.advance(line);
+            continue;
+        }
+
+        for c in line.chars() {
+            emitter.set_location(location);
+            location = location.advance(c.encode_utf8(&mut [0; 4]));
+
+            let l = match c {
+                '>' => emitter.emit_move_ptr(1),
+                '<' => emitter.emit_move_ptr(-1),
+                '+' => emitter.emit_add(1),
+                '-' => emitter.emit_add(-1),
+                '.' => emitter.emit_call_putchar(),
+                ',' => emitter.emit_call_getchar(),
+                '[' => emitter.emit_loop_begin(),
+                ']' => emitter.emit_loop_end(),
+                _ => continue,
+            };
+            lines.push(l);
+        }
+    }
+    lines.push(emitter.emit_footer());
+
+    lines.join("\n")
+}
+
+// Skips the locations of the first `n` tokens.
+fn skip(locations: &[Location], n: usize) -> &[Location] {
+    locations.get(n..).unwrap_or(&[])
+}
+
+fn locate<E: emitter::Emitter>(emitter: &mut E, locations: &[Location]) {
+    if let Some(l) = locations.first() {
+        emitter.set_location(*l);
+    }
+}
+
 fn statements<E: emitter::Emitter>(
     emitter: &mut E,
     tree: &Statements,
+    locations: &[Location],
 ) -> Result<String, CodegenError> {
     match tree {
-        Statements::Statement(s) => statement(emitter, s),
-        Statements::Statements(ss, s) => statements(emitter, ss)
-            .and_then(|s0| statement(emitter, s).map(|s1| format!("{}{}", s0, s1))),
+        Statements::Statement(s) => statement(emitter, s, locations),
+        Statements::Statements(ss, s) => statements(emitter, ss, locations).and_then(|s0| {
+            statement(emitter, s, skip(locations, ss.token_count()))
+                .map(|s1| format!("{}{}", s0, s1))
+        }),
     }
 }
 
 fn statement<E: emitter::Emitter>(
     emitter: &mut E,
     tree: &Statement,
+    locations: &[Location],
 ) -> Result<String, CodegenError> {
     match tree {
-        Statement::Expression(e) => expression(emitter, e),
-        Statement::Block(b) => block(emitter, b),
-        Statement::While(e, b) => while_s(emitter, e, b),
+        Statement::Expression(e) => {
+            locate(emitter, locations);
+            expression(emitter, e)
+        }
+        Statement::Block(b) => block(emitter, b, locations),
+        Statement::While(e, b) => {
+            locate(emitter, locations);
+            while_s(emitter, e, b, skip(locations, 1 + e.token_count()))
+        }
     }
 }
 
-fn block<E: emitter::Emitter>(emitter: &mut E, tree: &Block) -> Result<String, CodegenError> {
+fn block<E: emitter::Emitter>(
+    emitter: &mut E,
+    tree: &Block,
+    locations: &[Location],
+) -> Result<String, CodegenError> {
     match tree {
-        Block::Statements(ss) => statements(emitter, ss),
+        Block::Statements(ss) => statements(emitter, ss, skip(locations, 1)),
     }
 }
 
@@ -64,6 +142,7 @@ fn expression<E: emitter::Emitter>(
         Expression::AssignAdd(Lhs::Dereference(p), Rhs::Number(n)) => add(emitter, p, *n),
         Expression::AssignSub(Lhs::Dereference(p), Rhs::Number(n)) => add(emitter, p, -*n),
         Expression::FunctionCall(Lhs::Pointer(p)) => function_call(emitter, p),
+        Expression::FunctionCallWith(Lhs::Pointer(p), a) => function_call_with(emitter, p, a),
         _ => Err(CodegenError::NotImplemented),
     }
 }
@@ -72,6 +151,7 @@ fn while_s<E: emitter::Emitter>(
     emitter: &mut E,
     cond: &Expression,
     body: &Block,
+    locations: &[Location],
 ) -> Result<String, CodegenError> {
     match cond {
         Expression::Lhs(Lhs::Dereference(ptr)) => {
@@ -81,7 +161,7 @@ fn while_s<E: emitter::Emitter>(
                 })
             } else {
                 let header = emitter.emit_loop_begin();
-                let body = block(emitter, body)?;
+                let body = block(emitter, body, locations)?;
                 let footer = emitter.emit_loop_end();
                 Ok(format!("{}{}{}", header, body, footer))
             }
@@ -122,8 +202,165 @@ fn function_call<E: emitter::Emitter>(
     match funcname {
         "getchar" => Ok(emitter.emit_call_getchar()),
         "putchar" => Ok(emitter.emit_call_putchar()),
+        "getint" => Ok(emitter.emit_call_getint()),
+        "putint" => Ok(emitter.emit_call_putint()),
         _ => Err(CodegenError::InvalidFunctionName {
             name: funcname.to_string(),
         }),
     }
 }
+
+fn function_call_with<E: emitter::Emitter>(
+    emitter: &mut E,
+    funcname: &str,
+    argument: &Argument,
+) -> Result<String, CodegenError> {
+    match (funcname, argument) {
+        ("exit", Argument::Rhs(Rhs::Number(n))) => Ok(emitter.emit_exit(Some(*n))),
+        ("exit", Argument::Lhs(Lhs::Dereference(p))) => {
+            if p != "ptr" {
+                Err(CodegenError::InvalidVariableName {
+                    name: p.to_string(),
+                })
+            } else {
+                Ok(emitter.emit_exit(None))
+            }
+        }
+        ("exit", _) => Err(CodegenError::NotImplemented),
+        _ => Err(CodegenError::InvalidFunctionName {
+            name: funcname.to_string(),
+        }),
+    }
+}
+
+// Compiles `src` with `emitter`.
+#[cfg(test)]
+pub(crate) fn compile<E: emitter::Emitter>(emitter: &mut E, src: &str) -> String {
+    let (tokens, locations) = crate::token::tokenize_with_locations(src).unwrap();
+    let tree = crate::parser::program(&tokens).unwrap();
+    gen_with_locations(emitter, &tree.1, &locations).unwrap()
+}
+
+// Whether `tool` can be run, e.g. to skip the tests that need it.
+#[cfg(test)]
+pub(crate) fn has_tool(tool: &str) -> bool {
+    std::process::Command::new(tool)
+        .arg("--version")
+        .output()
+        .is_ok()
+}
+
+// Runs a step building a program and checks that it succeeds.
+#[cfg(test)]
+pub(crate) fn build_step(command: &mut std::process::Command) {
+    let status = command.status().unwrap();
+    assert!(status.success(), "{:?}", command);
+}
+
+// Builds a program with `build` in a new temporary directory and runs the command it returns on
+// `input`. Returns the output, the error output and the exit status, or None if `build` cannot
+// build the program here.
+#[cfg(test)]
+pub(crate) fn run_built(
+    name: &str,
+    input: &str,
+    build: impl FnOnce(&std::path::Path) -> Option<std::process::Command>,
+) -> Option<(String, String, i32)> {
+    use std::io::Write;
+    use std::process::Stdio;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "chiya-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir(&dir).unwrap();
+    let output = build(&dir).map(|mut command| {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    });
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    output.map(|output| {
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+            output.status.code().unwrap_or(-1),
+        )
+    })
+}
+
+// Runs the programs in 'testdata', whose options are given by pragmas, on their '.in' files with
+// `run`, and compares the outputs with the '.out' files. The LLVM builds are checked against the
+// same files, so every backend matches them. `run` returns the output, or None if the program
+// cannot run here.
+#[cfg(test)]
+pub(crate) fn golden(mut run: impl FnMut(&str, &options::Options, &str) -> Option<String>) {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "chiya"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let src = std::fs::read_to_string(&path).unwrap();
+        let input = std::fs::read_to_string(path.with_extension("in")).unwrap_or_default();
+        let expected = std::fs::read_to_string(path.with_extension("out")).unwrap();
+        let mut options = options::Options::new();
+        options.apply_pragmas(&src).unwrap();
+        if let Some(output) = run(&src, &options, &input) {
+            assert_eq!(output, expected, "{}", path.display());
+        }
+    }
+}
+
+#[test]
+fn test_function_call() {
+    let mut e = llvm::LLVM::new();
+    assert!(function_call(&mut e, "putint")
+        .unwrap()
+        .contains("@printf("));
+    assert!(function_call(&mut e, "getint").unwrap().contains("@scanf("));
+    assert_eq!(
+        function_call(&mut e, "putstr"),
+        Err(CodegenError::InvalidFunctionName {
+            name: "putstr".to_owned()
+        })
+    );
+
+    let exit = |a| function_call_with(&mut llvm::LLVM::new(), "exit", &a);
+    assert!(exit(Argument::Rhs(Rhs::Number(3)))
+        .unwrap()
+        .contains("br label %exit"));
+    assert!(exit(Argument::Lhs(Lhs::Dereference("ptr".to_owned()))).is_ok());
+    assert_eq!(
+        exit(Argument::Lhs(Lhs::Dereference("hoge".to_owned()))),
+        Err(CodegenError::InvalidVariableName {
+            name: "hoge".to_owned()
+        })
+    );
+    assert_eq!(
+        function_call_with(&mut e, "putchar", &Argument::Rhs(Rhs::Number(3))),
+        Err(CodegenError::InvalidFunctionName {
+            name: "putchar".to_owned()
+        })
+    );
+}
@@ -0,0 +1,533 @@
+use crate::codegen::emitter;
+use crate::codegen::options::{CellWidth, Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::token::Location;
+
+#[derive(Debug, PartialEq, failure::Fail)]
+pub enum BrainfuckError {
+    #[fail(
+        display = "{}:{}: {} cannot be lowered to Brainfuck",
+        line, column, what
+    )]
+    Unsupported {
+        what: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+// The scratch cells following each chiya cell, as offsets from it.
+const NEG: usize = 1; // putint: 1 if the value is negative
+const MAGNITUDE: usize = 2; // putint: the absolute value
+const STARTED: usize = 3; // putint: 1 once a nonzero digit has been printed
+const UP: usize = 4; // putint: the value counting up to zero, followed by two zero cells
+const DOWN: usize = 7; // putint: the value counting down to zero, followed by two zero cells
+const RUNNING: usize = 10; // putint: 1 until UP or DOWN reaches zero
+const CARRY: usize = 4; // putint: a copy of a digit, followed by two zero cells and a temporary
+const DIGITS: usize = 8; // putint: the decimal digits, least significant first
+
+// Addends up to this are written out, and larger ones are multiplied from a counter in the next
+// cell, which is itself set the same way.
+const UNROLLED: i64 = 32;
+const FACTOR: i64 = 16;
+
+// Emits Brainfuck, which behaves the same when compiled back with `--bf`. Each chiya cell is
+// followed by scratch cells used to lower what Brainfuck has no command for, so a chiya cell `i` is
+// the Brainfuck cell `i * stride`. The options are carried over by a pragma in a comment loop at
+// the start of the program, which is skipped since the first cell is zero.
+#[derive(Default)]
+pub struct Brainfuck {
+    options: Options,
+    location: Location,
+    depth: usize,
+    // The first construct that cannot be lowered.
+    error: Option<BrainfuckError>,
+}
+
+impl Brainfuck {
+    pub fn new() -> Brainfuck {
+        Default::default()
+    }
+
+    pub fn with_options(options: &Options) -> Brainfuck {
+        Brainfuck {
+            options: options.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Returns the error about the first construct that could not be lowered, if any.
+    pub fn check(&mut self) -> Result<(), BrainfuckError> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+
+    fn unsupported(&mut self, what: &str) -> String {
+        if self.error.is_none() {
+            self.error = Some(BrainfuckError::Unsupported {
+                what: what.to_string(),
+                line: self.location.line,
+                column: self.location.column,
+            });
+        }
+        "".to_owned()
+    }
+
+    // The number of Brainfuck cells per chiya cell.
+    fn stride(&self) -> usize {
+        stride(self.options.cell_width)
+    }
+
+    // Formats `code` as a line of the current loop body.
+    fn line(&self, code: &str) -> String {
+        format!("\n{}{}", "  ".repeat(self.depth), code)
+    }
+
+    // The pragma settings of the options that affect the behavior of the program.
+    fn settings(&self) -> Vec<String> {
+        let options = &self.options;
+        let default = Options::new();
+        let mut settings = vec![];
+        if options.eof != default.eof {
+            let eof = match options.eof {
+                Eof::Zero => "0",
+                Eof::MinusOne => "-1",
+                Eof::Unchanged => "unchanged",
+            };
+            settings.push(format!("eof={}", eof));
+        }
+        if options.cell_width != default.cell_width {
+            settings.push(format!("cell-width={}", options.cell_width.bits()));
+        }
+        if options.overflow != default.overflow {
+            let overflow = match options.overflow {
+                Overflow::Wrap => "wrap",
+                Overflow::Saturate => "saturate",
+                Overflow::Trap => "trap",
+            };
+            settings.push(format!("overflow={}", overflow));
+        }
+        settings.push(format!("tape-size={}", options.tape_size.0 * self.stride()));
+        if options.tape_mode != default.tape_mode {
+            let mode = match options.tape_mode {
+                TapeMode::Fixed => "fixed",
+                TapeMode::Grow => "grow",
+                TapeMode::Wrap => "wrap",
+            };
+            settings.push(format!("tape-mode={}", mode));
+        }
+        for (name, value) in [
+            ("bidirectional", options.bidirectional),
+            ("bounds-check", options.bounds_check),
+            ("buffered-io", options.buffered_io),
+        ] {
+            if value {
+                settings.push(format!("{}=true", name));
+            }
+        }
+        if options.exit_status == ExitStatus::Cell {
+            settings.push("exit-status=cell".to_owned());
+        }
+        settings
+    }
+}
+
+// The number of decimal digits of the largest absolute value of a cell of `width`.
+fn digits(width: CellWidth) -> usize {
+    (1u128 << (width.bits() - 1)).to_string().len()
+}
+
+// The number of Brainfuck cells per chiya cell of `width`, which has room for the digits.
+fn stride(width: CellWidth) -> usize {
+    DIGITS + digits(width)
+}
+
+// Brainfuck code working on a chiya cell and its scratch cells. The scratch cells are zero
+// before and after each lowered construct.
+struct Code {
+    code: String,
+    // The offset of the pointer from the chiya cell.
+    at: usize,
+}
+
+impl Code {
+    fn new() -> Code {
+        Code {
+            code: String::new(),
+            at: 0,
+        }
+    }
+
+    // Returns the code, which leaves the pointer at the chiya cell.
+    fn finish(mut self) -> String {
+        self.go(0);
+        self.code
+    }
+
+    fn go(&mut self, cell: usize) {
+        if cell > self.at {
+            self.code += &">".repeat(cell - self.at);
+        } else {
+            self.code += &"<".repeat(self.at - cell);
+        }
+        self.at = cell;
+    }
+
+    fn add(&mut self, cell: usize, n: i64) {
+        self.go(cell);
+        let c = if n < 0 { "-" } else { "+" };
+        self.code += &c.repeat(n.unsigned_abs() as usize);
+    }
+
+    // Adds `n` to `cell`, multiplying large ones from a counter in the next cell. Adding one at a
+    // time saturates or traps the same as adding at once, so this works for every overflow mode.
+    fn add_large(&mut self, cell: usize, n: i64) {
+        if n.abs() <= UNROLLED {
+            return self.add(cell, n);
+        }
+
+        self.add_large(cell + 1, n.abs() / FACTOR);
+        self.while_nonzero(cell + 1, |c| {
+            c.add(cell, n.signum() * FACTOR);
+            c.add(cell + 1, -1);
+        });
+        self.add(cell, n % FACTOR);
+    }
+
+    fn output(&mut self, cell: usize) {
+        self.go(cell);
+        self.code += ".";
+    }
+
+    // Clears `cell`, counting down.
+    fn clear(&mut self, cell: usize) {
+        self.go(cell);
+        self.code += "[-]";
+    }
+
+    // Clears `cell`, counting up.
+    fn clear_up(&mut self, cell: usize) {
+        self.go(cell);
+        self.code += "[+]";
+    }
+
+    fn while_nonzero(&mut self, cell: usize, body: impl FnOnce(&mut Code)) {
+        self.go(cell);
+        self.code += "[";
+        body(self);
+        self.go(cell);
+        self.code += "]";
+    }
+
+    // Runs `body` if `cell` is zero, keeping it. The two cells following `cell` must be zero.
+    fn if_zero(&mut self, cell: usize, body: impl FnOnce(&mut Code)) {
+        // The pointer ends up on the first following cell, which is one, only if `cell` is zero.
+        self.go(cell + 1);
+        self.code += "+<[>-]>[-<";
+        self.at = cell;
+        body(self);
+        self.go(cell + 2);
+        self.code += "]<<";
+        self.at = cell;
+    }
+
+    // Moves `src` to each of `dsts`, counting down.
+    fn move_to(&mut self, src: usize, dsts: &[usize]) {
+        self.while_nonzero(src, |c| {
+            c.add(src, -1);
+            for d in dsts {
+                c.add(*d, 1);
+            }
+        });
+    }
+
+    fn copy(&mut self, src: usize, dst: usize, tmp: usize) {
+        self.move_to(src, &[dst, tmp]);
+        self.move_to(tmp, &[src]);
+    }
+
+    // Prints the chiya cell in decimal. Cells must wrap around, and finding out the sign of a
+    // negative value takes as many steps as counting it down to zero.
+    fn putint(&mut self, digits: usize) {
+        // Counts a copy of the value up and another down until either reaches zero, which tells
+        // its sign and absolute value.
+        self.move_to(0, &[UP, DOWN, UP + 1]);
+        self.move_to(UP + 1, &[0]);
+        self.add(RUNNING, 1);
+        self.if_zero(DOWN, |c| c.clear(RUNNING));
+        self.while_nonzero(RUNNING, |c| {
+            c.add(UP, 1);
+            c.add(DOWN, -1);
+            c.add(MAGNITUDE, 1);
+            c.if_zero(UP, |c| {
+                c.add(NEG, 1);
+                c.clear(RUNNING);
+            });
+            c.if_zero(DOWN, |c| c.clear(RUNNING));
+        });
+
+        // The other copy is left at twice the value, which has the same sign.
+        self.clear(UP);
+        self.clear_up(DOWN);
+        self.while_nonzero(NEG, |c| {
+            c.add(UP, '-' as i64);
+            c.output(UP);
+            c.add(UP, -('-' as i64));
+            c.add(NEG, -1);
+        });
+
+        // Counts the absolute value into the digits.
+        self.while_nonzero(MAGNITUDE, |c| {
+            c.add(MAGNITUDE, -1);
+            c.increment(0, digits);
+        });
+
+        // Prints the digits, skipping the leading zeros.
+        for i in (1..digits).rev() {
+            self.copy(DIGITS + i, CARRY, CARRY + 3);
+            self.while_nonzero(CARRY, |c| {
+                c.clear(STARTED);
+                c.add(STARTED, 1);
+                c.clear(CARRY);
+            });
+            self.copy(STARTED, CARRY, CARRY + 3);
+            self.while_nonzero(CARRY, |c| {
+                c.add(DIGITS + i, '0' as i64);
+                c.output(DIGITS + i);
+                c.add(DIGITS + i, -('0' as i64));
+                c.add(CARRY, -1);
+            });
+            self.clear(DIGITS + i);
+        }
+        self.add(DIGITS, '0' as i64);
+        self.output(DIGITS);
+        self.clear(DIGITS);
+        self.clear(STARTED);
+    }
+
+    // Increments the decimal digits from the `i`th, carrying into the next one.
+    fn increment(&mut self, i: usize, digits: usize) {
+        self.add(DIGITS + i, 1);
+        if i + 1 == digits {
+            return;
+        }
+
+        self.copy(DIGITS + i, CARRY, CARRY + 3);
+        self.add(CARRY, -10);
+        self.if_zero(CARRY, |c| {
+            c.add(DIGITS + i, -10);
+            c.increment(i + 1, digits);
+        });
+        self.add(CARRY, 10);
+        self.clear(CARRY);
+    }
+}
+
+impl emitter::Emitter for Brainfuck {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        let n = offset.unsigned_abs() as usize * self.stride();
+        let c = if offset < 0 { "<" } else { ">" };
+        self.line(&c.repeat(n))
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        let width = self.options.cell_width;
+        let n = match self.options.overflow {
+            Overflow::Wrap => width.wrap(n as i64),
+            // Anything beyond ±2^bits overflows regardless of the value of the cell.
+            _ => width.split_addend(n as i64).iter().sum(),
+        };
+
+        let mut c = Code::new();
+        c.add_large(0, n);
+        self.line(&c.finish())
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        self.line(".")
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        self.line(",")
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        if self.options.overflow != Overflow::Wrap {
+            return self.unsupported("putint() without overflow=wrap");
+        }
+        // Finding out the sign and the digits takes as many steps as the value.
+        if self.options.cell_width.bits() > 16 {
+            return self.unsupported("putint() with cells wider than 16 bits");
+        }
+
+        let mut c = Code::new();
+        c.putint(digits(self.options.cell_width));
+        self.line(&c.finish())
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        // A Brainfuck program cannot put back the character ending the number.
+        self.unsupported("getint()")
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let s = self.line("[");
+        self.depth += 1;
+        s
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        self.depth -= 1;
+        self.line("]")
+    }
+
+    fn emit_exit(&mut self, _status: Option<i32>) -> String {
+        self.unsupported("exit()")
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        let mut header = format!("[\n// chiya: {}\n]", self.settings().join(" "));
+
+        // The pointer starts in the middle of the tape, which may be between two chiya cells.
+        if self.options.bidirectional {
+            let size = self.options.tape_size.0;
+            let stride = self.stride();
+            let misaligned = size * stride / 2 - size / 2 * stride;
+            if misaligned > 0 {
+                header += &format!("\n{}", "<".repeat(misaligned));
+            }
+        }
+        header
+    }
+
+    fn emit_footer(&mut self) -> String {
+        "\n".to_owned()
+    }
+}
+
+// Compiles the Brainfuck program `code` back with the options of its pragma, as `--bf` does, and
+// runs it on `input`. Returns the output, or None if there is no LLVM.
+#[cfg(test)]
+fn run(code: &str, input: &str) -> Option<String> {
+    let mut options = Options::new();
+    options.apply_pragmas(code).unwrap();
+    let (output, error, status) =
+        crate::codegen::llvm::run_with(&options, "", input, |e| crate::codegen::gen_bf(e, code))?;
+    assert_eq!((error.as_str(), status), ("", 0));
+    Some(output)
+}
+
+#[cfg(test)]
+fn lower(src: &str, options: &Options) -> Result<String, BrainfuckError> {
+    let (tokens, locations) = crate::token::tokenize_with_locations(src).unwrap();
+    let tree = crate::parser::program(&tokens).unwrap().1;
+    let mut e = Brainfuck::with_options(options);
+    let code = crate::codegen::gen_with_locations(&mut e, &tree, &locations).unwrap();
+    e.check().map(|_| code)
+}
+
+#[test]
+fn test_emit() {
+    use crate::codegen::emitter::Emitter;
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let mut e = Brainfuck::with_options(&options);
+    assert_eq!(
+        e.emit_header(),
+        "[\n// chiya: cell-width=8 tape-size=330000\n]"
+    );
+    assert_eq!(e.emit_move_ptr(1), format!("\n{}", ">".repeat(11)));
+    assert_eq!(e.emit_add(-3), "\n---");
+    assert_eq!(
+        e.emit_add(300),
+        format!("\n>++[<{}>-]<{}", "+".repeat(16), "+".repeat(12))
+    );
+    assert_eq!(e.emit_loop_begin(), "\n[");
+    assert_eq!(e.emit_call_putchar(), "\n  .");
+    assert_eq!(e.emit_loop_end(), "\n]");
+    assert_eq!(e.check(), Ok(()));
+
+    options.cell_width = CellWidth::W32;
+    options.overflow = Overflow::Saturate;
+    options.eof = Eof::Zero;
+    options.tape_size.0 = 3;
+    options.bidirectional = true;
+    let mut e = Brainfuck::with_options(&options);
+    assert_eq!(
+        e.emit_header(),
+        "[\n// chiya: eof=0 overflow=saturate tape-size=54 bidirectional=true\n]\n<<<<<<<<<"
+    );
+    assert_eq!(
+        e.emit_add(-100),
+        format!("\n>++++++[<{}>-]<----", "-".repeat(16))
+    );
+
+    e.set_location(Location { line: 3, column: 5 });
+    assert_eq!(e.emit_call_putint(), "");
+    e.set_location(Location { line: 4, column: 1 });
+    assert_eq!(e.emit_exit(None), "");
+    assert_eq!(
+        e.check(),
+        Err(BrainfuckError::Unsupported {
+            what: "putint() without overflow=wrap".to_string(),
+            line: 3,
+            column: 5,
+        })
+    );
+}
+
+#[test]
+fn test_putint() {
+    for (width, values) in [
+        (
+            CellWidth::W8,
+            vec![0, 1, 9, 10, 99, 100, 127, -1, -10, -128],
+        ),
+        (CellWidth::W16, vec![-1, -300, 1000, 32767, -32768]),
+    ] {
+        let mut options = Options::new();
+        options.cell_width = width;
+        for n in values {
+            let src = format!("*ptr += {}; putint(); ptr += 1; *ptr += 7; putint();", n);
+            let code = lower(&src, &options).unwrap();
+            if let Some(output) = run(&code, "") {
+                assert_eq!(output, format!("{}7", n));
+            }
+        }
+    }
+
+    // Wider cells would take up to 2^63 steps.
+    for width in [CellWidth::W32, CellWidth::W64] {
+        let mut options = Options::new();
+        options.cell_width = width;
+        assert_eq!(
+            lower("*ptr -= 1;\nputint();\n", &options),
+            Err(BrainfuckError::Unsupported {
+                what: "putint() with cells wider than 16 bits".to_string(),
+                line: 2,
+                column: 1,
+            })
+        );
+    }
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        let code = match lower(src, options) {
+            Ok(code) => code,
+            Err(BrainfuckError::Unsupported { .. }) => return None,
+        };
+
+        // The options are carried over by the pragma.
+        let mut lowered = Options::new();
+        lowered.apply_pragmas(&code).unwrap();
+        assert_eq!(lowered.cell_width, options.cell_width);
+        assert_eq!(lowered.tape_mode, options.tape_mode);
+
+        run(&code, input)
+    });
+}
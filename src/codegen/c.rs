@@ -0,0 +1,634 @@
+use crate::codegen::emitter;
+use crate::codegen::options::{Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::token::Location;
+
+// Returns the C header declaring the function emitted with the `function` option.
+pub fn header(options: &Options) -> Option<String> {
+    let name = options.function.as_ref()?;
+    let guard = format!("CHIYA_{}_H", name.to_uppercase());
+    Some(format!(
+        r#"// Generated by chiya.
+#ifndef {0}
+#define {0}
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {{
+#endif
+
+#ifndef CHIYA_CALLBACKS
+#define CHIYA_CALLBACKS
+// The I/O of a chiya program. `data` is passed to each of the functions.
+struct chiya_callbacks {{
+  void *data;
+  // Returns the next byte of the input, or -1 at its end.
+  int32_t (*getchar)(void *data);
+  void (*putchar)(void *data, int32_t c);
+  // Stores the next integer of the input to `*value` and returns 1, or returns anything else at
+  // its end.
+  int32_t (*getint)(void *data, int64_t *value);
+  void (*putint)(void *data, int64_t value);
+}};
+#endif
+
+// Runs the program on `tape` of `len` zero-initialized cells, starting at {3}. Returns the exit
+// status of the program, or 1 if it fails, e.g. when the pointer leaves the tape or a circular
+// tape is empty.
+int32_t {1}(int{2}_t *tape, int64_t len, const struct chiya_callbacks *callbacks);
+
+#ifdef __cplusplus
+}}
+#endif
+
+#endif
+"#,
+        guard,
+        name,
+        options.cell_width.bits(),
+        if options.bidirectional {
+            "`tape[len / 2]`"
+        } else {
+            "`tape[0]`"
+        }
+    ))
+}
+
+// Escapes `s` for a C string literal.
+fn escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '"' | '\\' => format!("\\{}", c),
+            c if c.is_control() => format!("\\{:03o}", c as u32 & 0xff),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+// Emits C99. The tape is a heap array of `len` cells `tape`, and the pointer is the index `ptr`.
+#[derive(Default)]
+pub struct C {
+    options: Options,
+    location: Location,
+    // The locations of the enclosing loops.
+    loop_stack: Vec<Location>,
+    // True if `exit()` has been emitted, which jumps to the 'end' label.
+    exits: bool,
+}
+
+impl C {
+    pub fn new() -> C {
+        Default::default()
+    }
+
+    pub fn with_options(options: &Options) -> C {
+        C {
+            options: options.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Formats `lines` as statements of the current block, each preceded by a '#line' directive
+    // pointing to the current location if debug info is enabled.
+    fn lines(&self, lines: &[String]) -> String {
+        let indent = "    ".repeat(self.loop_stack.len() + 1);
+        lines
+            .iter()
+            .map(|l| {
+                let directive = if self.options.debug_info {
+                    format!(
+                        "\n#line {} \"{}\"",
+                        self.location.line.max(1),
+                        escape(self.options.source.as_deref().unwrap_or("<stdin>"))
+                    )
+                } else {
+                    "".to_owned()
+                };
+                format!("{}\n{}{}", directive, indent, l)
+            })
+            .collect()
+    }
+
+    // The statements checking that `ptr` is inside the tape, if requested.
+    fn check(&self) -> Vec<String> {
+        if self.options.bounds_check && self.options.tape_mode == TapeMode::Fixed {
+            let check = format!(
+                "chiya_check(ptr, len, {}, {})",
+                self.location.line, self.location.column
+            );
+            vec![match self.options.function {
+                // A function returns the error to its caller.
+                Some(_) => format!("if (!{}) return 1;", check),
+                None => check + ";",
+            }]
+        } else {
+            vec![]
+        }
+    }
+
+    // The arguments of the input helpers.
+    fn input_args(&self) -> String {
+        let mut args = vec![];
+        if self.options.function.is_some() {
+            args.push("callbacks");
+        }
+        if self.options.eof == Eof::Unchanged {
+            args.push("tape[ptr]");
+        }
+        args.join(", ")
+    }
+
+    // Defines the helper functions used by the emitted statements.
+    fn helpers(&self) -> String {
+        let width = self.options.cell_width.bits();
+        let add = match self.options.overflow {
+            Overflow::Wrap => format!(
+                r#"typedef uint{0}_t ucell;
+
+static inline cell chiya_add(cell a, cell b) {{
+    return (cell)((ucell)a + (ucell)b);
+}}"#,
+                width
+            ),
+            overflow => {
+                let (max, min) = match overflow {
+                    Overflow::Trap => ("abort();", "abort();"),
+                    _ => ("return CELL_MAX;", "return CELL_MIN;"),
+                };
+                format!(
+                    r#"#define CELL_MIN INT{0}_MIN
+#define CELL_MAX INT{0}_MAX
+
+static inline cell chiya_add(cell a, cell b) {{
+    if (b > 0 && a > CELL_MAX - b) {{
+        {1}
+    }}
+    if (b < 0 && a < CELL_MIN - b) {{
+        {2}
+    }}
+    return (cell)(a + b);
+}}"#,
+                    width, max, min
+                )
+            }
+        };
+
+        let eof = match self.options.eof {
+            Eof::MinusOne => "-1",
+            Eof::Zero => "0",
+            Eof::Unchanged => "old",
+        };
+        let old = if self.options.eof == Eof::Unchanged {
+            "cell old"
+        } else {
+            ""
+        };
+        let input = match self.options.function {
+            Some(_) => format!(
+                r#"static inline cell chiya_getchar(const struct chiya_callbacks *callbacks{0}) {{
+    int32_t c = callbacks->getchar(callbacks->data);
+    return c == -1 ? {1} : (cell)c;
+}}
+
+static inline cell chiya_getint(const struct chiya_callbacks *callbacks{0}) {{
+    int64_t value;
+    return callbacks->getint(callbacks->data, &value) == 1 ? (cell)value : {1};
+}}"#,
+                if old.is_empty() {
+                    "".to_owned()
+                } else {
+                    format!(", {}", old)
+                },
+                eof
+            ),
+            None => {
+                // The output so far, e.g. a prompt, must be visible before waiting for the input.
+                let flush = if self.options.buffered_io {
+                    "\n    fflush(stdout);"
+                } else {
+                    ""
+                };
+                format!(
+                    r#"static inline cell chiya_getchar({0}) {{{1}
+    int c = getchar();
+    return c == EOF ? {2} : (cell)c;
+}}
+
+static inline cell chiya_getint({0}) {{
+    long long value;{1}
+    return scanf("%lld", &value) == 1 ? (cell)value : {2};
+}}"#,
+                    if old.is_empty() { "void" } else { old },
+                    flush,
+                    eof
+                )
+            }
+        };
+
+        let check = match (self.options.bounds_check, &self.options.function) {
+            (false, _) => "",
+            (true, Some(_)) => {
+                r#"
+
+static inline int chiya_check(int64_t index, int64_t len, int line, int column) {
+    if (index < 0 || index >= len) {
+        fprintf(stderr, "chiya: %d:%d: pointer out of bounds (%lld)\n", line, column, (long long)index);
+        return 0;
+    }
+    return 1;
+}"#
+            }
+            (true, None) => {
+                r#"
+
+static inline void chiya_check(int64_t index, int64_t len, int line, int column) {
+    if (index < 0 || index >= len) {
+        fprintf(stderr, "chiya: %d:%d: pointer out of bounds (%lld)\n", line, column, (long long)index);
+        exit(1);
+    }
+}"#
+            }
+        };
+        let grow = if self.options.tape_mode == TapeMode::Grow {
+            r#"
+
+// Grows the tape so that it covers `index`, which may be negative, and returns the index of the
+// same cell in the new tape.
+static inline int64_t chiya_grow(cell **tape, int64_t *len, int64_t index) {
+    if (index >= 0 && index < *len) {
+        return index;
+    }
+
+    if (index >= 0) {
+        int64_t new_len = index + 1 > *len * 2 ? index + 1 : *len * 2;
+        cell *grown = realloc(*tape, new_len * sizeof(cell));
+        if (!grown) {
+            abort();
+        }
+        memset(grown + *len, 0, (new_len - *len) * sizeof(cell));
+        *tape = grown;
+        *len = new_len;
+        return index;
+    }
+
+    int64_t shift = -index > *len ? -index : *len;
+    cell *grown = calloc(*len + shift, sizeof(cell));
+    if (!grown) {
+        abort();
+    }
+    memcpy(grown + shift, *tape, *len * sizeof(cell));
+    free(*tape);
+    *tape = grown;
+    *len += shift;
+    return index + shift;
+}"#
+        } else {
+            ""
+        };
+        let buffer = if self.options.buffered_io {
+            "\n\nstatic char chiya_out[4096];"
+        } else {
+            ""
+        };
+
+        format!("{}\n\n{}{}{}{}", add, input, check, grow, buffer)
+    }
+}
+
+impl emitter::Emitter for C {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        let offset = offset as i64;
+        let moved = if offset < 0 {
+            format!("ptr - {}", -offset)
+        } else {
+            format!("ptr + {}", offset)
+        };
+        let line = match self.options.tape_mode {
+            TapeMode::Fixed if offset < 0 => format!("ptr -= {};", -offset),
+            TapeMode::Fixed => format!("ptr += {};", offset),
+            TapeMode::Grow => format!("ptr = chiya_grow(&tape, &len, {});", moved),
+            // The size of a tape given to a function is only known at run time.
+            TapeMode::Wrap if self.options.function.is_some() => {
+                format!("ptr = (({}) % len + len) % len;", moved)
+            }
+            TapeMode::Wrap => match offset.rem_euclid(self.options.tape_size.0 as i64) {
+                0 => return "".to_owned(),
+                offset => format!("ptr = (ptr + {}) % len;", offset),
+            },
+        };
+        self.lines(&[line])
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        let width = self.options.cell_width;
+        let addends = match self.options.overflow {
+            Overflow::Wrap => vec![width.wrap(n as i64)],
+            _ => width.split_addend(n as i64),
+        };
+        let value = addends.iter().fold("tape[ptr]".to_owned(), |v, n| {
+            format!("chiya_add({}, {})", v, n)
+        });
+
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = {};", value));
+        self.lines(&lines)
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(match self.options.function {
+            Some(_) => "callbacks->putchar(callbacks->data, (int32_t)tape[ptr]);".to_owned(),
+            None => "putchar((int)tape[ptr]);".to_owned(),
+        });
+        self.lines(&lines)
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = chiya_getchar({});", self.input_args()));
+        self.lines(&lines)
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(match self.options.function {
+            Some(_) => "callbacks->putint(callbacks->data, (int64_t)tape[ptr]);".to_owned(),
+            None => r#"printf("%lld", (long long)tape[ptr]);"#.to_owned(),
+        });
+        self.lines(&lines)
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = chiya_getint({});", self.input_args()));
+        self.lines(&lines)
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push("while (tape[ptr]) {".to_owned());
+        let s = self.lines(&lines);
+        self.loop_stack.push(self.location);
+        s
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        // The code closing the loop belongs to the loop statement.
+        match self.loop_stack.last() {
+            Some(location) => self.location = *location,
+            None => return "".to_owned(),
+        }
+        let s = self.lines(&self.check());
+        self.loop_stack.pop();
+        s + &self.lines(&["}".to_owned()])
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        self.exits = true;
+        let mut lines = vec![];
+        match status {
+            Some(n) => lines.push(format!("status = {};", n)),
+            None => {
+                lines.extend(self.check());
+                lines.push("status = (int)tape[ptr];".to_owned());
+            }
+        }
+        lines.push("goto end;".to_owned());
+        self.lines(&lines)
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        let (top, function) = match &self.options.function {
+            Some(name) => (
+                header(&self.options).unwrap_or_default() + "\n",
+                format!(
+                    "int32_t {}(cell *tape, int64_t len, const struct chiya_callbacks *callbacks) {{\n    int32_t status = 0;\n    (void)len;\n    (void)callbacks;{}",
+                    name,
+                    if self.options.tape_mode == TapeMode::Wrap {
+                        // An empty circular tape has no cell to wrap around to.
+                        "\n    if (len == 0) {\n        return 1;\n    }"
+                    } else {
+                        ""
+                    }
+                ),
+            ),
+            None => (
+                "// Generated by chiya.\n".to_owned(),
+                format!(
+                    r#"int main(void) {{
+    int64_t len = {};
+    cell *tape = calloc(len, sizeof(cell));
+    int status = 0;{}"#,
+                    self.options.tape_size.0,
+                    if self.options.buffered_io {
+                        "\n    setvbuf(stdout, chiya_out, _IOFBF, sizeof chiya_out);"
+                    } else {
+                        ""
+                    }
+                ),
+            ),
+        };
+
+        format!(
+            r#"{0}#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>{1}
+
+typedef int{2}_t cell;
+
+{3}
+
+{4}
+    int64_t ptr = {5};
+"#,
+            top,
+            if self.options.tape_mode == TapeMode::Grow {
+                "\n#include <string.h>"
+            } else {
+                ""
+            },
+            self.options.cell_width.bits(),
+            self.helpers(),
+            function,
+            if self.options.bidirectional {
+                "len / 2"
+            } else {
+                "0"
+            }
+        )
+    }
+
+    fn emit_footer(&mut self) -> String {
+        let end = if self.exits { "\n\nend:" } else { "\n" };
+        let status = match self.options.exit_status {
+            ExitStatus::Zero => "".to_owned(),
+            ExitStatus::Cell => {
+                let mut lines = self.check();
+                lines.push("status = (int)tape[ptr];".to_owned());
+                self.lines(&lines)
+            }
+        };
+        match self.options.function {
+            // The tape belongs to the caller.
+            Some(_) => format!("{}{}\n    return status;\n}}\n", status, end),
+            None => format!(
+                "{}{}\n    free(tape);\n    return status;\n}}\n",
+                status, end
+            ),
+        }
+    }
+}
+
+// Compiles `src` to C, builds it with the system `cc` together with the C source `host`, which can
+// include the header of `function`, and runs it on `input`. Returns the output, the error output
+// and the exit status, or None if there is no `cc`.
+#[cfg(test)]
+fn run(src: &str, options: &Options, host: &str, input: &str) -> Option<(String, String, i32)> {
+    use crate::codegen::{build_step, has_tool};
+    use std::process::Command;
+
+    let code = crate::codegen::compile(&mut C::with_options(options), src);
+    crate::codegen::run_built("c", input, |dir| {
+        if !has_tool("cc") {
+            return None;
+        }
+        let (c, exe) = (dir.join("main.c"), dir.join("main"));
+        std::fs::write(&c, code).unwrap();
+        let mut cc = Command::new("cc");
+        cc.args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-o"])
+            .arg(&exe)
+            .arg(&c);
+        if let (Some(name), Some(header)) = (&options.function, header(options)) {
+            let host_c = dir.join("host.c");
+            std::fs::write(dir.join(format!("{}.h", name)), header).unwrap();
+            std::fs::write(&host_c, host).unwrap();
+            cc.arg(&host_c);
+        }
+        build_step(&mut cc);
+        Some(Command::new(exe))
+    })
+}
+
+#[test]
+fn test_emit() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::CellWidth;
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let mut e = C::with_options(&options);
+    assert_eq!(
+        e.emit_add(300),
+        "\n    tape[ptr] = chiya_add(tape[ptr], 44);"
+    );
+    assert_eq!(e.emit_move_ptr(-2), "\n    ptr -= 2;");
+    assert_eq!(e.emit_loop_begin(), "\n    while (tape[ptr]) {");
+    assert_eq!(e.emit_call_putchar(), "\n        putchar((int)tape[ptr]);");
+    assert_eq!(e.emit_loop_end(), "\n    }");
+
+    options.overflow = Overflow::Saturate;
+    let mut e = C::with_options(&options);
+    assert!(e.emit_header().contains("#define CELL_MAX INT8_MAX"));
+    assert_eq!(
+        e.emit_add(200),
+        "\n    tape[ptr] = chiya_add(chiya_add(tape[ptr], 127), 73);"
+    );
+}
+
+#[test]
+fn test_run() {
+    use crate::codegen::options::{CellWidth, TapeSize};
+
+    let src = "getint();\n*ptr += 5;\nputint();\nptr += 1;\n*ptr += 10;\nputchar();\n";
+    let output = match run(src, &Options::new(), "", " 37 x") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("42\n".to_owned(), "".to_owned(), 0));
+
+    let src = "*ptr -= 1;\nputint();\n*ptr += 100;\n*ptr += 100;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    assert_eq!(run(src, &options, "", "").unwrap().0, "-1-57");
+    options.overflow = Overflow::Saturate;
+    assert_eq!(run(src, &options, "", "").unwrap().0, "-1127");
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nptr -= 1;\nputint();\nptr += 2;\nputint();\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    options.tape_size = TapeSize(1);
+    assert_eq!(run(src, &options, "", "").unwrap().0, "03");
+
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(2);
+    assert_eq!(run(src, &options, "", "").unwrap().0, "33");
+
+    options.tape_mode = TapeMode::Fixed;
+    options.tape_size = TapeSize(30000);
+    options.bounds_check = true;
+    assert_eq!(
+        run(src, &options, "", "").unwrap(),
+        (
+            "".to_owned(),
+            "chiya: 9:1: pointer out of bounds (-1)\n".to_owned(),
+            1
+        )
+    );
+
+    let src = "*ptr += 7;\nwhile *ptr {\n  exit(*ptr);\n}\nexit(3);\n";
+    assert_eq!(run(src, &Options::new(), "", "").unwrap().2, 7);
+}
+
+#[test]
+fn test_function() {
+    let host = r#"#include <stdio.h>
+#include "run.h"
+
+static void put(void *data, int32_t c) {
+  (void)data;
+  putchar(c);
+}
+
+int main(void) {
+  int32_t tape[4] = {0};
+  struct chiya_callbacks callbacks = {0};
+  callbacks.putchar = put;
+  int32_t status = run(tape, 4, &callbacks);
+  tape[0] = 0;
+  printf(" %d %d", status, run(tape, 0, &callbacks));
+  return 0;
+}
+"#;
+    let mut options = Options::new();
+    options.function = Some("run".to_owned());
+    let src = "*ptr += 65;\nputchar();\nexit(*ptr);\n";
+    let output = match run(src, &options, host, "") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("AA 65 65".to_owned(), "".to_owned(), 0));
+
+    // Errors are returned to the caller instead of exiting.
+    options.bounds_check = true;
+    let src = "*ptr += 1;\nptr -= 1;\n*ptr += 1;\n";
+    assert_eq!(
+        run(src, &options, host, "").unwrap(),
+        (
+            " 1 1".to_owned(),
+            "chiya: 3:1: pointer out of bounds (-1)\nchiya: 1:1: pointer out of bounds (0)\n"
+                .to_owned(),
+            0
+        )
+    );
+
+    // An empty circular tape has no cell to wrap around to.
+    options.bounds_check = false;
+    options.tape_mode = TapeMode::Wrap;
+    options.exit_status = ExitStatus::Cell;
+    let src = "ptr -= 1;\n*ptr += 2;\n";
+    assert_eq!(run(src, &options, host, "").unwrap().0, " 2 1");
+}
@@ -0,0 +1,861 @@
+use std::collections::VecDeque;
+
+use crate::codegen::emitter;
+use crate::codegen::options::{Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::codegen::x86_64::assembler::*;
+use crate::codegen::x86_64::grow_cells;
+use crate::token::Location;
+
+// The address the executable is loaded at, and the size of the ELF header and the program
+// headers preceding the code.
+const BASE: u64 = 0x400000;
+const HEADERS: u64 = 64 + 56 * 3;
+const PAGE: u64 = 0x1000;
+
+// The size of the input and output buffers.
+const BUFFER: u64 = 4096;
+
+// The labels of the runtime routines and the data. The routines may clobber the caller-saved
+// registers only.
+#[derive(Debug)]
+struct Runtime {
+    peek: Label,
+    getchar: Label,
+    getint: Label,
+    putchar: Label,
+    puts: Label,
+    putint: Label,
+    flush: Label,
+    exit: Label,
+    write: Label,
+    format_int: Label,
+    out_of_bounds: Label,
+    out_of_bounds_end: Label,
+    tape_limit: Label,
+    trap: Label,
+    input: Label,
+    output: Label,
+    tape: Label,
+}
+
+// Writes a static x86-64 Linux executable directly, with the same registers as `X86_64`: the
+// tape is at 'r12' with 'r14' cells, and the pointer is the index 'rbx'. The position and the
+// length of the buffered input are 'r13' and 'r15', and the length of the buffered output is
+// 'rbp'.
+//
+// The emit methods append to the machine code and return nothing. `executable` returns the
+// resulting ELF file.
+#[derive(Debug)]
+pub struct Elf {
+    options: Options,
+    location: Location,
+    code: Code,
+    loop_stack: VecDeque<(Label, Label, Location)>,
+    // The error messages of the bounds checks and their locations.
+    checks: Vec<(Location, Label)>,
+    // The same for the checks of a growing tape.
+    limits: Vec<(Location, Label)>,
+    runtime: Runtime,
+}
+
+impl Elf {
+    pub fn new() -> Elf {
+        Default::default()
+    }
+
+    pub fn with_options(options: &Options) -> Elf {
+        let mut code = Code::default();
+        let mut label = || code.label();
+        let runtime = Runtime {
+            peek: label(),
+            getchar: label(),
+            getint: label(),
+            putchar: label(),
+            puts: label(),
+            putint: label(),
+            flush: label(),
+            exit: label(),
+            write: label(),
+            format_int: label(),
+            out_of_bounds: label(),
+            out_of_bounds_end: label(),
+            tape_limit: label(),
+            trap: label(),
+            input: label(),
+            output: label(),
+            tape: label(),
+        };
+        let mut e = Elf {
+            options: options.clone(),
+            location: Default::default(),
+            code,
+            loop_stack: VecDeque::new(),
+            checks: Vec::new(),
+            limits: Vec::new(),
+            runtime,
+        };
+        e.prologue();
+        e
+    }
+
+    // Returns the executable. `emit_footer` must have been called.
+    pub fn executable(&self) -> Vec<u8> {
+        let mut code = self.code.clone();
+        let address = BASE + HEADERS;
+        let end = address + code.bytes.len() as u64;
+
+        // The data follows the code in a zero-filled segment.
+        let data = end.next_multiple_of(PAGE);
+        let mut size = 0;
+        let mut place = |l: Label, bytes: u64| {
+            code.place(l, data + size);
+            size += bytes.next_multiple_of(16);
+        };
+        place(self.runtime.input, BUFFER);
+        place(self.runtime.output, BUFFER);
+        if self.options.tape_mode != TapeMode::Grow {
+            place(
+                self.runtime.tape,
+                self.options.tape_size.0 as u64 * self.options.cell_width.bytes() as u64,
+            );
+        }
+        code.link(address);
+
+        let mut elf = Vec::new();
+        let mut put = |n: u64, size: u32| elf.extend_from_slice(&n.to_le_bytes()[..size as usize]);
+        // The ELF header: 64-bit, little endian, executable, x86-64.
+        put(0x00010102464c457f, 8);
+        put(0, 8);
+        put(2, 2);
+        put(0x3e, 2);
+        put(1, 4);
+        put(address, 8);
+        put(64, 8);
+        put(0, 8);
+        put(0, 4);
+        put(64, 2);
+        put(56, 2);
+        put(3, 2);
+        put(64, 2);
+        put(0, 2);
+        put(0, 2);
+        // The program headers: the code (R+X), the data (R+W), and a non-executable stack.
+        for (kind, flags, offset, address, file_size, memory_size) in [
+            (1, 5, 0, BASE, end - BASE, end - BASE),
+            (1, 6, 0, data, 0, size),
+            (0x6474e551, 6, 0, 0, 0, 0),
+        ] {
+            put(kind, 4);
+            put(flags, 4);
+            put(offset, 8);
+            put(address, 8);
+            put(address, 8);
+            put(file_size, 8);
+            put(memory_size, 8);
+            put(if kind == 1 { PAGE } else { 16 }, 8);
+        }
+
+        elf.extend_from_slice(&code.bytes);
+        elf
+    }
+
+    // The operand of the current cell.
+    fn cell(&self) -> Operand {
+        Operand::Mem(Mem {
+            base: R12,
+            index: Some((RBX, self.options.cell_width.bytes())),
+            disp: 0,
+            label: None,
+        })
+    }
+
+    // Emits the 8-bit form `op8` of an instruction on a cell, or `op` for the wider ones.
+    fn cell_insn(&mut self, op8: u8, op: u8, reg: u8, rm: Operand) {
+        let size = self.options.cell_width.bytes();
+        self.code
+            .insn(size, &[if size == 1 { op8 } else { op }], reg, rm);
+    }
+
+    // Sign-extends the current cell into `dst`, as a 64-bit value if `wide`.
+    fn load_cell(&mut self, dst: u8, wide: bool) {
+        let (size, cell) = (if wide { 8 } else { 4 }, self.cell());
+        let op: &[u8] = match (self.options.cell_width.bytes(), wide) {
+            (1, _) => &[0x0f, 0xbe],
+            (2, _) => &[0x0f, 0xbf],
+            (4, true) => &[0x63],
+            _ => &[0x8b],
+        };
+        self.code.insn(size, op, dst, cell);
+    }
+
+    // Checks that the pointer is inside the tape, if requested.
+    fn check(&mut self) {
+        if !self.options.bounds_check || self.options.tape_mode != TapeMode::Fixed {
+            return;
+        }
+
+        let message = match self.checks.iter().find(|(l, _)| *l == self.location) {
+            Some((_, message)) => *message,
+            None => {
+                let message = self.code.label();
+                self.checks.push((self.location, message));
+                message
+            }
+        };
+        self.code.mov_address(RDI, message);
+        self.code.alu(CMP, RBX, R14);
+        self.code.jcc(AE, self.runtime.out_of_bounds);
+    }
+
+    // Checks that the pointer is inside the address range of a growing tape.
+    fn limit(&mut self) {
+        let message = match self.limits.iter().find(|(l, _)| *l == self.location) {
+            Some((_, message)) => *message,
+            None => {
+                let message = self.code.label();
+                self.limits.push((self.location, message));
+                message
+            }
+        };
+        self.code.mov_address(RDI, message);
+        self.code.alu(CMP, RBX, R14);
+        self.code.jcc(AE, self.runtime.tape_limit);
+    }
+
+    // Replaces the input in 'rax' with the value for the end of input if the condition `cc`
+    // holds, and stores it to the cell.
+    fn store_input(&mut self, cc: u8) {
+        match self.options.eof {
+            Eof::MinusOne => self.code.mov_imm(RCX, -1),
+            // Not 'xor', which would clobber the flags.
+            Eof::Zero => self.code.mov_imm(RCX, 0),
+            Eof::Unchanged => self.load_cell(RCX, true),
+        }
+        self.code.cmov(cc, RAX, RCX);
+        let cell = self.cell();
+        self.cell_insn(0x88, 0x89, RAX, cell);
+    }
+
+    fn prologue(&mut self) {
+        let cells = match self.options.tape_mode {
+            TapeMode::Grow => {
+                // mmap(NULL, <bytes>, PROT_READ | PROT_WRITE,
+                //      MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0)
+                let cells = grow_cells(&self.options);
+                let bytes = cells.saturating_mul(self.options.cell_width.bytes() as u64);
+                let c = &mut self.code;
+                c.mov_imm(RAX, 9);
+                c.xor32(RDI);
+                c.mov_imm(RSI, bytes as i64);
+                c.mov_imm(RDX, 3);
+                c.mov_imm(R10, 0x4022);
+                c.mov_imm(R8, -1);
+                c.xor32(R9);
+                c.syscall();
+                c.test(RAX);
+                c.jcc(S, self.runtime.trap);
+                c.mov(R12, RAX);
+                cells
+            }
+            _ => {
+                self.code.mov_address(R12, self.runtime.tape);
+                self.options.tape_size.0 as u64
+            }
+        };
+        let start = if self.options.bidirectional || self.options.tape_mode == TapeMode::Grow {
+            cells / 2
+        } else {
+            0
+        };
+        let c = &mut self.code;
+        c.mov_imm(R14, cells as i64);
+        c.mov_imm(RBX, start as i64);
+        c.xor32(R13);
+        c.xor32(R15);
+        c.xor32(RBP);
+    }
+
+    fn emit_runtime(&mut self) {
+        let buffered = self.options.buffered_io;
+        let r = &self.runtime;
+        let c = &mut self.code;
+
+        // Returns the next byte of the input in 'rax' without consuming it, or -1 at its end.
+        let (have, eof) = (c.label(), c.label());
+        c.bind(r.peek);
+        c.mov(RAX, R13);
+        c.alu(CMP, R13, R15);
+        c.jcc(B, have);
+        if buffered {
+            // The output so far, e.g. a prompt, must be visible before waiting for the input.
+            c.call(r.flush);
+        }
+        c.xor32(RAX);
+        c.xor32(RDI);
+        c.mov_address(RSI, r.input);
+        c.mov_imm(RDX, BUFFER as i64);
+        c.syscall();
+        c.test(RAX);
+        c.jcc(LE, eof);
+        c.mov(R15, RAX);
+        c.xor32(R13);
+        c.xor32(RAX);
+        c.bind(have);
+        let input = Mem {
+            label: Some(r.input),
+            ..Mem::new(RAX, 0)
+        };
+        c.insn(4, &[0x0f, 0xb6], RAX, Operand::Mem(input));
+        c.ret();
+        c.bind(eof);
+        c.mov_imm(RAX, -1);
+        c.ret();
+
+        // Returns the next byte of the input in 'rax', or -1 at its end.
+        let done = c.label();
+        c.bind(r.getchar);
+        c.call(r.peek);
+        c.test(RAX);
+        c.jcc(S, done);
+        c.inc(R13);
+        c.bind(done);
+        c.ret();
+
+        // Reads a decimal number like scanf("%lld"), saturating at the limits of 64 bits.
+        // Returns it in 'rax', and 1 in 'rdx' if there was one.
+        let (skip, sign, plus, consume, digits, negative, saturate, end) = (
+            c.label(),
+            c.label(),
+            c.label(),
+            c.label(),
+            c.label(),
+            c.label(),
+            c.label(),
+            c.label(),
+        );
+        c.bind(r.getint);
+        c.call(r.peek);
+        c.alu_imm(CMP, RAX, b' ' as i32);
+        c.jcc(E, skip);
+        c.alu_imm(CMP, RAX, b'\t' as i32);
+        c.jcc(L, sign);
+        c.alu_imm(CMP, RAX, b'\r' as i32);
+        c.jcc(G, sign);
+        c.bind(skip);
+        c.inc(R13);
+        c.jmp(r.getint);
+        c.bind(sign);
+        c.xor32(R8);
+        c.xor32(R9);
+        c.xor32(R10);
+        c.alu_imm(CMP, RAX, b'-' as i32);
+        c.jcc(NE, plus);
+        c.inc(R9);
+        c.jmp(consume);
+        c.bind(plus);
+        c.alu_imm(CMP, RAX, b'+' as i32);
+        c.jcc(NE, digits);
+        c.bind(consume);
+        c.inc(R13);
+        c.bind(digits);
+        c.call(r.peek);
+        c.alu_imm(SUB, RAX, b'0' as i32);
+        c.alu_imm(CMP, RAX, 9);
+        c.jcc(A, end);
+        c.inc(R10);
+        c.inc(R13);
+        // The number is accumulated with its sign, so that it can reach either limit.
+        // imul r8, r8, 10
+        c.insn(8, &[0x6b], R8, Operand::Reg(R8));
+        c.imm(10, 1);
+        c.jcc(O, saturate);
+        c.test(R9);
+        c.jcc(NE, negative);
+        c.alu(ADD, R8, RAX);
+        c.jcc(NO, digits);
+        c.jmp(saturate);
+        c.bind(negative);
+        c.alu(SUB, R8, RAX);
+        c.jcc(NO, digits);
+        // The maximum, plus 1 for a negative number, which wraps around to the minimum.
+        c.bind(saturate);
+        c.mov_imm(R8, i64::MAX);
+        c.alu(ADD, R8, R9);
+        c.jmp(digits);
+        c.bind(end);
+        c.mov(RAX, R8);
+        c.xor32(RDX);
+        c.test(R10);
+        // setnz dl
+        c.insn(1, &[0x0f, 0x90 | NE], 0, Operand::Reg(RDX));
+        c.ret();
+
+        if buffered {
+            // Appends the byte 'dil' to the output buffer, and writes it when it fills.
+            c.bind(r.putchar);
+            let output = Mem {
+                label: Some(r.output),
+                ..Mem::new(RBP, 0)
+            };
+            c.insn(1, &[0x88], RDI, Operand::Mem(output));
+            c.inc(RBP);
+            c.alu_imm(CMP, RBP, BUFFER as i32);
+            c.jcc(E, r.flush);
+            c.ret();
+
+            // Appends 'rdx' bytes at 'rsi' to the output buffer.
+            let done = c.label();
+            c.bind(r.puts);
+            c.test(RDX);
+            c.jcc(E, done);
+            c.push(RSI);
+            c.push(RDX);
+            c.insn(4, &[0x0f, 0xb6], RDI, Operand::Mem(Mem::new(RSI, 0)));
+            c.call(r.putchar);
+            c.pop(RDX);
+            c.pop(RSI);
+            c.inc(RSI);
+            c.dec(RDX);
+            c.jmp(r.puts);
+            c.bind(done);
+            c.ret();
+
+            // Writes the output buffer.
+            c.bind(r.flush);
+            c.mov_imm(RDI, 1);
+            c.mov_address(RSI, r.output);
+            c.mov(RDX, RBP);
+            c.xor32(RBP);
+            c.jmp(r.write);
+        } else {
+            // Writes the byte 'dil' to the output.
+            c.bind(r.putchar);
+            c.push(RDI);
+            c.mov(RSI, RSP);
+            c.mov_imm(RDX, 1);
+            c.mov_imm(RDI, 1);
+            c.call(r.write);
+            c.pop(RDI);
+            c.ret();
+
+            // Writes 'rdx' bytes at 'rsi' to the output.
+            c.bind(r.puts);
+            c.mov_imm(RDI, 1);
+            c.jmp(r.write);
+
+            c.bind(r.flush);
+            c.ret();
+        }
+
+        // Writes 'rdi' to the output as a signed decimal number.
+        c.bind(r.putint);
+        c.alu_imm(SUB, RSP, 24);
+        c.mov(RAX, RDI);
+        c.lea(RDI, Mem::new(RSP, 24));
+        c.call(r.format_int);
+        c.call(r.puts);
+        c.alu_imm(ADD, RSP, 24);
+        c.ret();
+
+        // Exits with the status 'edi'.
+        c.bind(r.exit);
+        c.push(RDI);
+        c.call(r.flush);
+        c.pop(RDI);
+        c.mov_imm(RAX, 231);
+        c.syscall();
+
+        // Writes 'rdx' bytes at 'rsi' to the file descriptor 'edi'.
+        let done = c.label();
+        c.bind(r.write);
+        c.test(RDX);
+        c.jcc(LE, done);
+        c.mov_imm(RAX, 1);
+        c.syscall();
+        c.test(RAX);
+        c.jcc(LE, done);
+        c.alu(ADD, RSI, RAX);
+        c.alu(SUB, RDX, RAX);
+        c.jmp(r.write);
+        c.bind(done);
+        c.ret();
+
+        // Formats 'rax' as a signed decimal number ending at 'rdi'. Returns its start in 'rsi'
+        // and its length in 'rdx'.
+        let (digit, done) = (c.label(), c.label());
+        c.bind(r.format_int);
+        c.mov(RSI, RDI);
+        c.mov(R8, RAX);
+        c.test(RAX);
+        c.jcc(NS, digit);
+        c.neg(RAX);
+        c.bind(digit);
+        c.mov_imm(RCX, 10);
+        let next = c.label();
+        c.bind(next);
+        c.xor32(RDX);
+        // div rcx
+        c.insn(8, &[0xf7], 6, Operand::Reg(RCX));
+        // add dl, '0'
+        c.insn(1, &[0x80], 0, Operand::Reg(RDX));
+        c.imm(b'0' as i64, 1);
+        c.dec(RSI);
+        c.insn(1, &[0x88], RDX, Operand::Mem(Mem::new(RSI, 0)));
+        c.test(RAX);
+        c.jcc(NE, next);
+        c.test(R8);
+        c.jcc(NS, done);
+        c.dec(RSI);
+        c.insn(1, &[0xc6], 0, Operand::Mem(Mem::new(RSI, 0)));
+        c.imm(b'-' as i64, 1);
+        c.bind(done);
+        c.mov(RDX, RDI);
+        c.alu(SUB, RDX, RSI);
+        c.ret();
+
+        // Reports that the pointer is outside the tape at the statement described by the string
+        // 'rdi', and exits with the status 1.
+        let (next, found) = (c.label(), c.label());
+        c.bind(r.out_of_bounds);
+        c.mov(RSI, RDI);
+        c.xor32(RDX);
+        c.bind(next);
+        let byte = Mem {
+            index: Some((RDX, 1)),
+            ..Mem::new(RSI, 0)
+        };
+        c.insn(1, &[0x80], 7, Operand::Mem(byte));
+        c.imm(0, 1);
+        c.jcc(E, found);
+        c.inc(RDX);
+        c.jmp(next);
+        c.bind(found);
+        c.mov_imm(RDI, 2);
+        c.call(r.write);
+        c.alu_imm(SUB, RSP, 24);
+        c.mov(RAX, RBX);
+        c.lea(RDI, Mem::new(RSP, 24));
+        c.call(r.format_int);
+        c.mov_imm(RDI, 2);
+        c.call(r.write);
+        c.alu_imm(ADD, RSP, 24);
+        c.mov_address(RSI, r.out_of_bounds_end);
+        c.mov_imm(RDX, 2);
+        c.mov_imm(RDI, 2);
+        c.call(r.write);
+        c.mov_imm(RDI, 1);
+        c.jmp(r.exit);
+
+        // Reports that the pointer left the address range of a growing tape with the string
+        // 'rdi', and exits with the status 1.
+        let (next, found) = (c.label(), c.label());
+        c.bind(r.tape_limit);
+        c.mov(RSI, RDI);
+        c.xor32(RDX);
+        c.bind(next);
+        let byte = Mem {
+            index: Some((RDX, 1)),
+            ..Mem::new(RSI, 0)
+        };
+        c.insn(1, &[0x80], 7, Operand::Mem(byte));
+        c.imm(0, 1);
+        c.jcc(E, found);
+        c.inc(RDX);
+        c.jmp(next);
+        c.bind(found);
+        c.mov_imm(RDI, 2);
+        c.call(r.write);
+        c.mov_imm(RDI, 1);
+        c.jmp(r.exit);
+
+        // ud2
+        c.bind(r.trap);
+        c.emit(&[0x0f, 0x0b]);
+
+        c.bind(r.out_of_bounds_end);
+        c.emit(b")\n");
+        for (location, message) in &self.checks {
+            c.bind(*message);
+            c.emit(
+                format!(
+                    "chiya: {}:{}: pointer out of bounds (\0",
+                    location.line, location.column
+                )
+                .as_bytes(),
+            );
+        }
+        for (location, message) in &self.limits {
+            c.bind(*message);
+            c.emit(
+                format!(
+                    "chiya: {}:{}: tape limit of {} cells exceeded\n\0",
+                    location.line,
+                    location.column,
+                    grow_cells(&self.options)
+                )
+                .as_bytes(),
+            );
+        }
+    }
+}
+
+impl Default for Elf {
+    fn default() -> Self {
+        Elf::with_options(&Options::new())
+    }
+}
+
+impl emitter::Emitter for Elf {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        match self.options.tape_mode {
+            TapeMode::Wrap => {
+                let size = self.options.tape_size.0 as i64;
+                let c = &mut self.code;
+                c.mov_imm(RAX, (offset as i64).rem_euclid(size));
+                c.alu(ADD, RBX, RAX);
+                c.mov(RAX, RBX);
+                c.alu(SUB, RAX, R14);
+                c.alu(CMP, RBX, R14);
+                c.cmov(AE, RBX, RAX);
+            }
+            TapeMode::Grow if offset != 0 => {
+                self.code.alu_imm(ADD, RBX, offset);
+                self.limit();
+            }
+            _ if offset != 0 => self.code.alu_imm(ADD, RBX, offset),
+            _ => (),
+        }
+        "".to_owned()
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        self.check();
+        let width = self.options.cell_width;
+        let (size, cell) = (width.bytes(), self.cell());
+        match self.options.overflow {
+            Overflow::Wrap => {
+                self.cell_insn(0x80, 0x81, 0, cell);
+                self.code.imm(width.wrap(n as i64), size.min(4));
+            }
+            Overflow::Trap => {
+                for a in width.split_addend(n as i64) {
+                    self.cell_insn(0x80, 0x81, 0, cell);
+                    self.code.imm(a, size.min(4));
+                    self.code.jcc(O, self.runtime.trap);
+                }
+            }
+            Overflow::Saturate => {
+                self.cell_insn(0x8a, 0x8b, RAX, cell);
+                for a in width.split_addend(n as i64) {
+                    let ok = self.code.label();
+                    self.cell_insn(0x80, 0x81, 0, Operand::Reg(RAX));
+                    self.code.imm(a, size.min(4));
+                    self.code.jcc(NO, ok);
+                    let limit = if a > 0 { width.max() } else { width.min() };
+                    match size {
+                        8 => self.code.mov_imm(RAX, limit),
+                        2 => self.code.emit(&[0x66, 0xb8]),
+                        _ => self.code.emit(&[if size == 1 { 0xb0 } else { 0xb8 }]),
+                    }
+                    if size != 8 {
+                        self.code.imm(limit, size);
+                    }
+                    self.code.bind(ok);
+                }
+                self.cell_insn(0x88, 0x89, RAX, cell);
+            }
+        }
+        "".to_owned()
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        self.check();
+        let cell = self.cell();
+        self.code.insn(4, &[0x0f, 0xb6], RDI, cell);
+        self.code.call(self.runtime.putchar);
+        "".to_owned()
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        self.check();
+        self.code.call(self.runtime.getchar);
+        self.code.alu_imm(CMP, RAX, -1);
+        self.store_input(E);
+        "".to_owned()
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        self.check();
+        self.load_cell(RDI, true);
+        self.code.call(self.runtime.putint);
+        "".to_owned()
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        self.check();
+        self.code.call(self.runtime.getint);
+        self.code.alu_imm(CMP, RDX, 1);
+        self.store_input(NE);
+        "".to_owned()
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let (cond, end) = (self.code.label(), self.code.label());
+        self.loop_stack.push_back((cond, end, self.location));
+        self.code.bind(cond);
+        self.check();
+        let cell = self.cell();
+        self.cell_insn(0x80, 0x83, 7, cell);
+        self.code.imm(0, 1);
+        self.code.jcc(E, end);
+        "".to_owned()
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        if let Some((cond, end, location)) = self.loop_stack.pop_back() {
+            self.location = location;
+            self.code.jmp(cond);
+            self.code.bind(end);
+        }
+        "".to_owned()
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        match status {
+            Some(n) => self.code.mov_imm(RDI, n as u32 as i64),
+            None => {
+                self.check();
+                self.load_cell(RDI, false);
+            }
+        }
+        self.code.jmp(self.runtime.exit);
+        "".to_owned()
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        // The prologue is emitted on construction.
+        "".to_owned()
+    }
+
+    fn emit_footer(&mut self) -> String {
+        match self.options.exit_status {
+            ExitStatus::Zero => self.code.xor32(RDI),
+            ExitStatus::Cell => {
+                self.check();
+                self.load_cell(RDI, false);
+            }
+        }
+        self.code.jmp(self.runtime.exit);
+        self.emit_runtime();
+        "".to_owned()
+    }
+}
+
+// Compiles `src` to an executable and runs it on `input`. Returns the output and the exit
+// status, or None if it can't run here.
+#[cfg(test)]
+fn run(src: &str, options: &Options, input: &str) -> Option<(String, String, i32)> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut e = Elf::with_options(options);
+    crate::codegen::compile(&mut e, src);
+    crate::codegen::run_built("elf", input, |dir| {
+        if !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            return None;
+        }
+        let exe = dir.join("main");
+        std::fs::write(&exe, e.executable()).unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        Some(std::process::Command::new(exe))
+    })
+}
+
+#[test]
+fn test_run() {
+    use crate::codegen::options::{CellWidth, TapeSize};
+
+    let src = "getint();\n*ptr += 5;\nputint();\nptr += 1;\n*ptr += 10;\nputchar();\ngetint();\nputint();\n";
+    let output = match run(src, &Options::new(), " -37 x") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("-32\n-1".to_owned(), "".to_owned(), 0));
+
+    let src = "*ptr -= 1;\nputint();\n*ptr += 100;\n*ptr += 100;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1-57");
+    options.overflow = Overflow::Saturate;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1127");
+    options.cell_width = CellWidth::W64;
+    options.buffered_io = true;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1199");
+    options.cell_width = CellWidth::W16;
+    options.overflow = Overflow::Trap;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1199");
+
+    let src = "getchar();\nputint();\ngetchar();\nputint();\n";
+    let mut options = Options::new();
+    options.eof = Eof::Zero;
+    assert_eq!(run(src, &options, " ").unwrap().0, "320");
+    options.eof = Eof::Unchanged;
+    assert_eq!(run(src, &options, " ").unwrap().0, "3232");
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nptr -= 1;\nputint();\nptr += 2;\nputint();\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    assert_eq!(run(src, &options, "").unwrap().0, "03");
+
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(2);
+    assert_eq!(run(src, &options, "").unwrap().0, "33");
+
+    options.tape_mode = TapeMode::Fixed;
+    options.tape_size = TapeSize(30000);
+    options.bounds_check = true;
+    assert_eq!(
+        run(src, &options, "").unwrap(),
+        (
+            "".to_owned(),
+            "chiya: 9:1: pointer out of bounds (-1)\n".to_owned(),
+            1
+        )
+    );
+
+    let src = "*ptr += 7;\nwhile *ptr {\n  exit(*ptr);\n}\nexit(3);\n";
+    assert_eq!(run(src, &Options::new(), "").unwrap().2, 7);
+
+    // Numbers out of range saturate like with scanf.
+    let src = "getint();\nputint();\nptr += 1;\n*ptr += 32;\nputchar();\nptr += 1;\ngetint();\nputint();\n";
+    let input = "99999999999999999999 -99999999999999999999";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W64;
+    assert_eq!(
+        run(src, &options, input).unwrap().0,
+        "9223372036854775807 -9223372036854775808"
+    );
+
+    // A growing tape fails when it leaves the address range reserved for it.
+    let src = "ptr -= 2000000000;\nptr -= 2000000000;\nptr -= 2000000000;\nptr -= 2000000000;\nptr -= 2000000000;\n*ptr += 1;\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    assert_eq!(
+        run(src, &options, "").unwrap(),
+        (
+            "".to_owned(),
+            "chiya: 5:1: tape limit of 17179869184 cells exceeded\n".to_owned(),
+            1
+        )
+    );
+
+    let src = "ptr -= 1;\n*ptr += 7;\nputint();\nptr += 1;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(3000000000);
+    assert_eq!(run(src, &options, "").unwrap().0, "70");
+}
@@ -1,13 +1,23 @@
+use crate::token::Location;
+
 pub trait Emitter {
     fn emit_move_ptr(&mut self, offset: i32) -> String;
     fn emit_add(&mut self, n: i32) -> String;
 
     fn emit_call_putchar(&mut self) -> String;
     fn emit_call_getchar(&mut self) -> String;
+    fn emit_call_putint(&mut self) -> String;
+    fn emit_call_getint(&mut self) -> String;
 
     fn emit_loop_begin(&mut self) -> String;
     fn emit_loop_end(&mut self) -> String;
 
+    // Exits the program with `status`, or the value of the current cell if it is None.
+    fn emit_exit(&mut self, status: Option<i32>) -> String;
+
+    // Called before emitting the code for the statement at `location`.
+    fn set_location(&mut self, location: Location);
+
     fn emit_header(&self) -> String;
-    fn emit_footer(&self) -> String;
+    fn emit_footer(&mut self) -> String;
 }
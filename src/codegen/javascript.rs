@@ -0,0 +1,445 @@
+use crate::codegen::emitter;
+use crate::codegen::options::{CellWidth, Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::token::Location;
+
+// Emits an ES module exporting `run(input)`, which runs the program on the UTF-8 bytes of the
+// string `input` and returns its output as a string of one character per byte, like latin1. The
+// tape is a typed array `tape` of the cell width, and the pointer is the index `ptr`. 64-bit cells
+// are BigInts.
+#[derive(Default)]
+pub struct JavaScript {
+    options: Options,
+    location: Location,
+    // The locations of the enclosing loops.
+    loop_stack: Vec<Location>,
+}
+
+impl JavaScript {
+    pub fn new() -> JavaScript {
+        Default::default()
+    }
+
+    pub fn with_options(options: &Options) -> JavaScript {
+        JavaScript {
+            options: options.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Formats `lines` as statements of the current block.
+    fn lines(&self, lines: &[String]) -> String {
+        let indent = "  ".repeat(self.loop_stack.len() + 1);
+        lines.iter().map(|l| format!("\n{}{}", indent, l)).collect()
+    }
+
+    // The literal of the cell value `n`.
+    fn literal(&self, n: i64) -> String {
+        match self.options.cell_width {
+            CellWidth::W64 => format!("{}n", n),
+            _ => n.to_string(),
+        }
+    }
+
+    // The statements checking that `ptr` is inside the tape, if requested.
+    fn check(&self) -> Vec<String> {
+        if self.options.bounds_check && self.options.tape_mode == TapeMode::Fixed {
+            vec![format!(
+                "check({}, {});",
+                self.location.line, self.location.column
+            )]
+        } else {
+            vec![]
+        }
+    }
+
+    // The argument of the input helpers.
+    fn input_arg(&self) -> &str {
+        if self.options.eof == Eof::Unchanged {
+            "tape[ptr]"
+        } else {
+            ""
+        }
+    }
+
+    // Defines the helper functions used by the emitted statements.
+    fn helpers(&self) -> String {
+        let width = self.options.cell_width;
+        let big = width == CellWidth::W64;
+        let mut helpers = vec![];
+
+        if self.options.overflow != Overflow::Wrap {
+            let overflow = match self.options.overflow {
+                Overflow::Trap => r#"throw new RangeError("chiya: overflow");"#.to_owned(),
+                _ => "return sum > MAX ? MAX : MIN;".to_owned(),
+            };
+            helpers.push(format!(
+                r#"const MIN = {0};
+  const MAX = {1};
+
+  function add(a, b) {{
+    const sum = a + b;
+    if (sum > MAX || sum < MIN) {{
+      {2}
+    }}
+    return sum;
+  }}"#,
+                self.literal(width.min()),
+                self.literal(width.max()),
+                overflow
+            ));
+        }
+
+        let eof = match self.options.eof {
+            Eof::MinusOne => self.literal(-1),
+            Eof::Zero => self.literal(0),
+            Eof::Unchanged => "old".to_owned(),
+        };
+        let old = if self.options.eof == Eof::Unchanged {
+            "old"
+        } else {
+            ""
+        };
+        helpers.push(format!(
+            r#"// Returns the next byte of the input, or {1} at its end.
+  function getchar({0}) {{
+    return pos < input.length ? {2} : {1};
+  }}
+
+  // Reads a decimal integer like scanf("%lld"), or returns {1} if there is none.
+  function getint({0}) {{
+    while ([9, 10, 11, 12, 13, 32].includes(input[pos])) {{
+      pos++;
+    }}
+    let negative = false;
+    if (input[pos] === 43 || input[pos] === 45) {{
+      negative = input[pos++] === 45;
+    }}
+    let digits = 0;
+    let value = 0n;
+    while (input[pos] >= 48 && input[pos] <= 57) {{
+      value = value * 10n + BigInt(input[pos++] - 48);
+      digits++;
+    }}
+    if (digits === 0) {{
+      return {1};
+    }}
+    // Numbers out of range saturate like with scanf.
+    const max = 9223372036854775807n;
+    value = negative ? -value : value;
+    value = value > max ? max : value < -max - 1n ? -max - 1n : value;
+    return {3};
+  }}
+
+  function putchar(c) {{
+    output.push({4});
+  }}
+
+  function putint(value) {{
+    for (const c of String(value)) {{
+      output.push(c.charCodeAt(0));
+    }}
+  }}"#,
+            old,
+            eof,
+            if big {
+                "BigInt(input[pos++])"
+            } else {
+                "input[pos++]"
+            },
+            if big {
+                "value".to_owned()
+            } else {
+                format!("Number(BigInt.asIntN({}, value))", width.bits())
+            },
+            if big {
+                "Number(BigInt.asUintN(8, c))"
+            } else {
+                "c & 255"
+            }
+        ));
+
+        if self.options.bounds_check {
+            helpers.push(
+                r#"function check(line, column) {
+    if (ptr < 0 || ptr >= tape.length) {
+      throw new RangeError(`chiya: ${line}:${column}: pointer out of bounds (${ptr})`);
+    }
+  }"#
+                .to_owned(),
+            );
+        }
+        if self.options.tape_mode == TapeMode::Grow {
+            helpers.push(format!(
+                r#"// Grows the tape so that it covers `index`, which may be negative, and returns the index of
+  // the same cell in the new tape.
+  function grow(index) {{
+    if (index >= 0 && index < tape.length) {{
+      return index;
+    }}
+    const shift = index < 0 ? Math.max(-index, tape.length) : 0;
+    const length = index < 0 ? tape.length + shift : Math.max(index + 1, tape.length * 2);
+    const grown = new {}(length);
+    grown.set(tape, shift);
+    tape = grown;
+    return index + shift;
+  }}"#,
+                self.array()
+            ));
+        }
+        helpers.push(
+            r#"function finish() {
+    let s = "";
+    for (let i = 0; i < output.length; i += 4096) {
+      s += String.fromCharCode(...output.slice(i, i + 4096));
+    }
+    return s;
+  }
+
+  // Sets the exit status of the process running the program, if there is one, and finishes.
+  function exit(status) {
+    if (typeof process !== "undefined") {
+      process.exitCode = status;
+    }
+    return finish();
+  }"#
+            .to_owned(),
+        );
+
+        helpers.join("\n\n  ")
+    }
+
+    // The typed array of the cells.
+    fn array(&self) -> String {
+        match self.options.cell_width {
+            CellWidth::W64 => "BigInt64Array".to_owned(),
+            width => format!("Int{}Array", width.bits()),
+        }
+    }
+}
+
+impl emitter::Emitter for JavaScript {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        let offset = offset as i64;
+        let moved = if offset < 0 {
+            format!("ptr - {}", -offset)
+        } else {
+            format!("ptr + {}", offset)
+        };
+        let line = match self.options.tape_mode {
+            TapeMode::Fixed if offset < 0 => format!("ptr -= {};", -offset),
+            TapeMode::Fixed => format!("ptr += {};", offset),
+            TapeMode::Grow => format!("ptr = grow({});", moved),
+            TapeMode::Wrap => match offset.rem_euclid(self.options.tape_size.0 as i64) {
+                0 => return "".to_owned(),
+                offset => format!("ptr = (ptr + {}) % tape.length;", offset),
+            },
+        };
+        self.lines(&[line])
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        let mut lines = self.check();
+        lines.push(match self.options.overflow {
+            Overflow::Wrap => match self.options.cell_width.wrap(n as i64) {
+                n if n < 0 => format!("tape[ptr] -= {};", self.literal(-(n as i128) as i64)),
+                n => format!("tape[ptr] += {};", self.literal(n)),
+            },
+            _ => format!("tape[ptr] = add(tape[ptr], {});", self.literal(n as i64)),
+        });
+        self.lines(&lines)
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push("putchar(tape[ptr]);".to_owned());
+        self.lines(&lines)
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = getchar({});", self.input_arg()));
+        self.lines(&lines)
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push("putint(tape[ptr]);".to_owned());
+        self.lines(&lines)
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = getint({});", self.input_arg()));
+        self.lines(&lines)
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push("while (tape[ptr]) {".to_owned());
+        let s = self.lines(&lines);
+        self.loop_stack.push(self.location);
+        s
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        // The code closing the loop belongs to the loop statement.
+        match self.loop_stack.last() {
+            Some(location) => self.location = *location,
+            None => return "".to_owned(),
+        }
+        let s = self.lines(&self.check());
+        self.loop_stack.pop();
+        s + &self.lines(&["}".to_owned()])
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        let mut lines = vec![];
+        match status {
+            Some(n) => lines.push(format!("return exit({});", n)),
+            None => {
+                lines.extend(self.check());
+                lines.push("return exit(Number(tape[ptr]));".to_owned());
+            }
+        }
+        self.lines(&lines)
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        format!(
+            r#"// Generated by chiya.
+
+export function run(text) {{
+  const input = new TextEncoder().encode(text);
+  const output = [];
+  let pos = 0;
+  let tape = new {}({});
+  let ptr = {};
+
+  {}
+"#,
+            self.array(),
+            self.options.tape_size.0,
+            if self.options.bidirectional {
+                self.options.tape_size.0 / 2
+            } else {
+                0
+            },
+            self.helpers()
+        )
+    }
+
+    fn emit_footer(&mut self) -> String {
+        let end = match self.options.exit_status {
+            ExitStatus::Zero => self.lines(&["return finish();".to_owned()]),
+            ExitStatus::Cell => self.emit_exit(None),
+        };
+        format!("{}\n}}\n", end)
+    }
+}
+
+// Compiles `src` to JavaScript and runs it with Node.js on `input`. Returns the output, the error
+// output and the exit status, or None if there is no `node`.
+#[cfg(test)]
+fn run(src: &str, options: &Options, input: &str) -> Option<(String, String, i32)> {
+    let code = crate::codegen::compile(&mut JavaScript::with_options(options), src);
+    crate::codegen::run_built("js", input, |dir| {
+        if !crate::codegen::has_tool("node") {
+            return None;
+        }
+        let js = dir.join("main.mjs");
+        std::fs::write(&js, code).unwrap();
+        let mut command = std::process::Command::new("node");
+        command.arg("--input-type=module").arg("-e").arg(format!(
+            "import {{ readFileSync }} from 'node:fs';\n\
+             import {{ run }} from {:?};\n\
+             process.stdout.write(Buffer.from(run(readFileSync(0, 'utf8')), 'latin1'));",
+            js.to_str().unwrap()
+        ));
+        Some(command)
+    })
+}
+
+#[test]
+fn test_emit() {
+    use crate::codegen::emitter::Emitter;
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let mut e = JavaScript::with_options(&options);
+    assert!(e.emit_header().contains("let tape = new Int8Array(30000);"));
+    assert_eq!(e.emit_add(300), "\n  tape[ptr] += 44;");
+    assert_eq!(e.emit_add(-1), "\n  tape[ptr] -= 1;");
+    assert_eq!(e.emit_move_ptr(-2), "\n  ptr -= 2;");
+    assert_eq!(e.emit_loop_begin(), "\n  while (tape[ptr]) {");
+    assert_eq!(e.emit_call_putchar(), "\n    putchar(tape[ptr]);");
+    assert_eq!(e.emit_loop_end(), "\n  }");
+
+    options.cell_width = CellWidth::W64;
+    options.overflow = Overflow::Saturate;
+    options.eof = Eof::Unchanged;
+    let mut e = JavaScript::with_options(&options);
+    assert!(e
+        .emit_header()
+        .contains("const MAX = 9223372036854775807n;"));
+    assert_eq!(e.emit_add(200), "\n  tape[ptr] = add(tape[ptr], 200n);");
+    assert_eq!(e.emit_call_getchar(), "\n  tape[ptr] = getchar(tape[ptr]);");
+
+    // The same program gives the same module.
+    assert_eq!(
+        e.emit_header(),
+        JavaScript::with_options(&options).emit_header()
+    );
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        let (output, error, status) = run(src, options, input)?;
+        assert_eq!((error.as_str(), status), ("", 0));
+        Some(output)
+    });
+}
+
+#[test]
+fn test_bytes() {
+    // The bytes are not decoded as UTF-8, so they are written as they are.
+    let src = "*ptr += 195;\nputchar();\n*ptr -= 26;\nputchar();\n";
+    if let Some(output) = run(src, &Options::new(), "") {
+        assert_eq!(output, ("\u{e9}".to_owned(), "".to_owned(), 0));
+    }
+}
+
+#[test]
+fn test_getint() {
+    // Numbers out of range saturate like with scanf, and then wrap to the cell width.
+    let src = "getint();\nputint();\nptr += 1;\n*ptr += 32;\nputchar();\nptr += 1;\ngetint();\nputint();\n";
+    let input = "99999999999999999999 -99999999999999999999";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W64;
+    let output = match run(src, &options, input) {
+        Some((output, _, _)) => output,
+        None => return,
+    };
+    assert_eq!(output, "9223372036854775807 -9223372036854775808");
+    options.cell_width = CellWidth::W32;
+    assert_eq!(run(src, &options, input).unwrap().0, "-1 0");
+}
+
+#[test]
+fn test_exit() {
+    let src = "*ptr += 7;\nwhile *ptr {\n  putchar();\n  exit(*ptr);\n}\nexit(3);\n";
+    let output = match run(src, &Options::new(), "") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("\u{7}".to_owned(), "".to_owned(), 7));
+    assert_eq!(run("exit(3);\n", &Options::new(), "").unwrap().2, 3);
+
+    let mut options = Options::new();
+    options.exit_status = ExitStatus::Cell;
+    assert_eq!(run("*ptr += 4;\n", &options, "").unwrap().2, 4);
+}
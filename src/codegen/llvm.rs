@@ -1,162 +1,2290 @@
+use std::cmp::Ordering;
 use std::collections::VecDeque;
 
-use crate::codegen::emitter;
+use crate::codegen::options::{Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::codegen::{c, emitter};
+use crate::token::Location;
+
+// The pointer types, which are typed pointers before LLVM 15 and 'ptr' since.
+#[derive(Clone, Copy)]
+struct Pointers {
+    typed: bool,
+}
+
+impl Pointers {
+    // A pointer to `pointee`, e.g. 'i8*' or 'ptr'.
+    fn to(self, pointee: &str) -> String {
+        if self.typed {
+            format!("{}*", pointee)
+        } else {
+            "ptr".to_owned()
+        }
+    }
+
+    // The suffix of the memory intrinsics taking 'i8' pointers.
+    fn intrinsic(self) -> &'static str {
+        if self.typed {
+            "p0i8"
+        } else {
+            "p0"
+        }
+    }
+}
+
+// The data layout of the well-known targets.
+fn datalayout(triple: &str, version: u32) -> Option<String> {
+    let darwin = triple.contains("apple") || triple.contains("darwin");
+    match triple.split('-').next() {
+        Some("x86_64") => Some(format!(
+            "e-m:{}-p270:32:32-p271:32:32-p272:64:64-i64:64{}-f80:128-n8:16:32:64-S128",
+            if darwin { "o" } else { "e" },
+            if version >= 18 { "-i128:128" } else { "" }
+        )),
+        Some("aarch64") | Some("arm64") if darwin => {
+            Some("e-m:o-i64:64-i128:128-n32:64-S128".to_owned())
+        }
+        Some("aarch64") => Some("e-m:e-i8:8:32-i16:16:32-i64:64-i128:128-n32:64-S128".to_owned()),
+        _ => None,
+    }
+}
+
+// The metadata nodes of debug info: `main`, the variables for the pointer and the cell, the
+// location of the code outside statements, and the first of the locations of statements.
+const DEBUG_SUBPROGRAM: usize = 4;
+const DEBUG_PTR: usize = 10;
+const DEBUG_CELL: usize = 11;
+const DEBUG_SCOPE: usize = 12;
+const DEBUG_LOCATIONS: usize = 13;
+
+// Appends '!dbg !<n>' to every instruction in `ir`.
+fn attach_dbg(ir: &str, n: usize) -> String {
+    ir.split('\n')
+        .map(|line| {
+            if line.starts_with("  ") && !line.starts_with("  ;") {
+                format!("{}, !dbg !{}", line, n)
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Tells the debugger that the pointer is `ptr` and the cell is `cell`, or is in memory at `ptr` if
+// `cell` is None.
+fn debug_values(p: Pointers, t: &str, ptr: &str, cell: Option<&str>) -> String {
+    let cell = match cell {
+        Some(v) => format!(
+            "metadata {} {}, metadata !{}, metadata !DIExpression()",
+            t, v, DEBUG_CELL
+        ),
+        None => format!(
+            "metadata {} {}, metadata !{}, metadata !DIExpression(DW_OP_deref)",
+            p.to(t),
+            ptr,
+            DEBUG_CELL
+        ),
+    };
+    format!(
+        r#"
+  call void @llvm.dbg.value(metadata {0} {1}, metadata !{2}, metadata !DIExpression())
+  call void @llvm.dbg.value({3})"#,
+        p.to(t),
+        ptr,
+        DEBUG_PTR,
+        cell
+    )
+}
+
+// Escapes `s` for a metadata string.
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'"' | b'\\' | 0..=0x1f | 0x7f..=0xff => format!("\\{:02X}", b),
+            _ => (b as char).to_string(),
+        })
+        .collect()
+}
+
+// void @chiya_out_of_bounds(i64 line, i64 column, i64 index)
+// Reports that the statement at `line`:`column` accessed the cell `index`, then exits.
+fn chiya_out_of_bounds(p: Pointers, function: bool) -> String {
+    let (attributes, end) = report_end(function);
+    format!(
+        r#"
+
+define internal void @chiya_out_of_bounds(i64 %line, i64 %column, i64 %index){attributes} {{
+  %1 = call i32 (i32, {i8p}, ...) @dprintf(i32 2, {i8p} getelementptr inbounds ([48 x i8], {fmt} @.fmt_out_of_bounds, i64 0, i64 0), i64 %line, i64 %column, i64 %index){end}
+}}
+
+@.fmt_out_of_bounds = private unnamed_addr constant [48 x i8] c"chiya: %lld:%lld: pointer out of bounds (%lld)\0A\00""#,
+        i8p = p.to("i8"),
+        fmt = p.to("[48 x i8]"),
+        attributes = attributes,
+        end = end
+    )
+}
+
+// void @chiya_limit(i8* message, i64 line, i64 column)
+// Reports that the statement at `line`:`column` hit the limit described by `message`, then exits.
+fn chiya_limit(p: Pointers, function: bool) -> String {
+    let (attributes, end) = report_end(function);
+    format!(
+        r#"
+
+define internal void @chiya_limit({i8p} %message, i64 %line, i64 %column){attributes} {{
+  %1 = call i32 (i32, {i8p}, ...) @dprintf(i32 2, {i8p} getelementptr inbounds ([22 x i8], {fmt} @.fmt_limit, i64 0, i64 0), i64 %line, i64 %column, {i8p} %message){end}
+}}
+
+@.fmt_limit = private unnamed_addr constant [22 x i8] c"chiya: %lld:%lld: %s\0A\00""#,
+        i8p = p.to("i8"),
+        fmt = p.to("[22 x i8]"),
+        attributes = attributes,
+        end = end
+    )
+}
+
+// The attributes and the last instructions of an error reporting routine, which exits, or returns
+// in a function so that the function can return the status 1 to its caller.
+fn report_end(function: bool) -> (&'static str, &'static str) {
+    if function {
+        ("", "\n  ret void")
+    } else {
+        (" noreturn", "\n  call void @exit(i32 1)\n  unreachable")
+    }
+}
+
+// The libc functions used by `chiya_out_of_bounds` and `chiya_limit`.
+fn report(p: Pointers, function: bool) -> String {
+    format!(
+        r#"
+
+declare i32 @dprintf(i32, {i8p}, ...){exit}"#,
+        i8p = p.to("i8"),
+        exit = if function {
+            ""
+        } else {
+            "\ndeclare void @exit(i32)"
+        }
+    )
+}
+
+// i64 @chiya_int_length(i64 value)
+// Returns the number of characters `putint()` writes for `value`.
+const INT_LENGTH: &str = r#"
+
+define internal i64 @chiya_int_length(i64 %value) {
+entry:
+  %negative = icmp slt i64 %value, 0
+  %sign = zext i1 %negative to i64
+  br label %digit
+
+digit:
+  %n = phi i64 [ %value, %entry ], [ %next, %digit ]
+  %length = phi i64 [ %sign, %entry ], [ %more, %digit ]
+  %next = sdiv i64 %n, 10
+  %more = add i64 %length, 1
+  %done = icmp eq i64 %next, 0
+  br i1 %done, label %end, label %digit
+
+end:
+  ret i64 %more
+}"#;
+
+// i64 @chiya_grow(i8** heap, i64* len, i64 index, i64 size, i64 max)
+// Grows the tape `*heap` of `*len` cells of `size` bytes so that it covers `index`, which may be
+// negative, and returns the index of the same cell in the new tape. The tape doubles, but not
+// beyond `max` cells, and -1 is returned if `index` cannot be covered within them.
+fn chiya_grow(p: Pointers) -> String {
+    format!(
+        r#"
+
+define internal i64 @chiya_grow({i8pp} %heap, {i64p} %len, i64 %index, i64 %size, i64 %max) {{
+  %old = load {i8p}, {i8pp} %heap, align 8
+  %n = load i64, {i64p} %len, align 8
+  %old_bytes = mul i64 %n, %size
+  %negative = icmp slt i64 %index, 0
+  br i1 %negative, label %front, label %back
+
+back:
+  %too_far = icmp sge i64 %index, %max
+  br i1 %too_far, label %limit, label %back_grow
+back_grow:
+  %double = shl i64 %n, 1
+  %need = add i64 %index, 1
+  %need_more = icmp ugt i64 %need, %double
+  %wanted_len = select i1 %need_more, i64 %need, i64 %double
+  %too_long = icmp ugt i64 %wanted_len, %max
+  %back_len = select i1 %too_long, i64 %max, i64 %wanted_len
+  %back_bytes = mul i64 %back_len, %size
+  %back_heap = call {i8p} @realloc({i8p} %old, i64 %back_bytes)
+  %back_failed = icmp eq {i8p} %back_heap, null
+  br i1 %back_failed, label %oom, label %back_ok
+back_ok:
+  %tail = getelementptr i8, {i8p} %back_heap, i64 %old_bytes
+  %tail_bytes = sub i64 %back_bytes, %old_bytes
+  call void @llvm.memset.{p0}.i64({i8p} %tail, i8 0, i64 %tail_bytes, i1 false)
+  store {i8p} %back_heap, {i8pp} %heap, align 8
+  store i64 %back_len, {i64p} %len, align 8
+  ret i64 %index
+
+front:
+  %want = sub i64 0, %index
+  %room = sub i64 %max, %n
+  %no_room = icmp sgt i64 %want, %room
+  br i1 %no_room, label %limit, label %front_grow
+front_grow:
+  %want_more = icmp ugt i64 %want, %n
+  %wanted_shift = select i1 %want_more, i64 %want, i64 %n
+  %too_wide = icmp ugt i64 %wanted_shift, %room
+  %shift = select i1 %too_wide, i64 %room, i64 %wanted_shift
+  %front_len = add i64 %n, %shift
+  %front_heap = call {i8p} @calloc(i64 %front_len, i64 %size)
+  %front_failed = icmp eq {i8p} %front_heap, null
+  br i1 %front_failed, label %oom, label %front_ok
+front_ok:
+  %shift_bytes = mul i64 %shift, %size
+  %dest = getelementptr i8, {i8p} %front_heap, i64 %shift_bytes
+  call void @llvm.memcpy.{p0}.{p0}.i64({i8p} %dest, {i8p} %old, i64 %old_bytes, i1 false)
+  call void @free({i8p} %old)
+  store {i8p} %front_heap, {i8pp} %heap, align 8
+  store i64 %front_len, {i64p} %len, align 8
+  %front_index = add i64 %index, %shift
+  ret i64 %front_index
+
+limit:
+  ret i64 -1
+
+oom:
+  call void @abort()
+  unreachable
+}}
+
+declare {i8p} @realloc({i8p}, i64)
+declare void @abort()
+declare void @llvm.memset.{p0}.i64({i8p}, i8, i64, i1)
+declare void @llvm.memcpy.{p0}.{p0}.i64({i8p}, {i8p}, i64, i1)"#,
+        i8p = p.to("i8"),
+        i8pp = p.to(&p.to("i8")),
+        i64p = p.to("i64"),
+        p0 = p.intrinsic()
+    )
+}
+
+// The runtime of `buffered_io`, replacing the libc I/O:
+// i32 @chiya_putchar(i32 c), i32 @chiya_getchar(), void @chiya_putint(i64 value),
+// i32 @chiya_getint(i64* value) (returns 1 on success like scanf), and void @chiya_flush(), which
+// writes the buffered output and is registered with atexit.
+fn buffered_io(p: Pointers) -> String {
+    format!(
+        r#"
+
+@chiya_out = internal global [4096 x i8] zeroinitializer
+@chiya_out_len = internal global i64 0
+@chiya_in = internal global [4096 x i8] zeroinitializer
+@chiya_in_pos = internal global i64 0
+@chiya_in_len = internal global i64 0
+
+define internal void @chiya_flush() {{
+entry:
+  %len = load i64, {i64p} @chiya_out_len, align 8
+  br label %loop
+loop:
+  %done = phi i64 [ 0, %entry ], [ %next, %write ]
+  %rest = sub i64 %len, %done
+  %empty = icmp sle i64 %rest, 0
+  br i1 %empty, label %end, label %write
+write:
+  %p = getelementptr inbounds [4096 x i8], {buffer} @chiya_out, i64 0, i64 %done
+  %n = call i64 @write(i32 1, {i8p} %p, i64 %rest)
+  %failed = icmp sle i64 %n, 0
+  %next = add i64 %done, %n
+  br i1 %failed, label %end, label %loop
+end:
+  store i64 0, {i64p} @chiya_out_len, align 8
+  ret void
+}}
+
+define internal i32 @chiya_putchar(i32 %c) {{
+entry:
+  %len = load i64, {i64p} @chiya_out_len, align 8
+  %full = icmp eq i64 %len, 4096
+  br i1 %full, label %flush, label %put
+flush:
+  call void @chiya_flush()
+  br label %put
+put:
+  %i = phi i64 [ %len, %entry ], [ 0, %flush ]
+  %p = getelementptr inbounds [4096 x i8], {buffer} @chiya_out, i64 0, i64 %i
+  %b = trunc i32 %c to i8
+  store i8 %b, {i8p} %p, align 1
+  %next = add i64 %i, 1
+  store i64 %next, {i64p} @chiya_out_len, align 8
+  ret i32 %c
+}}
+
+; Returns the next byte of the input without consuming it, or -1 at its end.
+define internal i32 @chiya_peek() {{
+entry:
+  %pos = load i64, {i64p} @chiya_in_pos, align 8
+  %len = load i64, {i64p} @chiya_in_len, align 8
+  %empty = icmp eq i64 %pos, %len
+  br i1 %empty, label %fill, label %read
+fill:
+  ; The output so far, e.g. a prompt, must be visible before waiting for the input.
+  call void @chiya_flush()
+  %n = call i64 @read(i32 0, {i8p} getelementptr inbounds ([4096 x i8], {buffer} @chiya_in, i64 0, i64 0), i64 4096)
+  %eof = icmp sle i64 %n, 0
+  br i1 %eof, label %end, label %filled
+filled:
+  store i64 0, {i64p} @chiya_in_pos, align 8
+  store i64 %n, {i64p} @chiya_in_len, align 8
+  br label %read
+read:
+  %i = phi i64 [ %pos, %entry ], [ 0, %filled ]
+  %p = getelementptr inbounds [4096 x i8], {buffer} @chiya_in, i64 0, i64 %i
+  %b = load i8, {i8p} %p, align 1
+  %c = zext i8 %b to i32
+  ret i32 %c
+end:
+  ret i32 -1
+}}
+
+define internal i32 @chiya_getchar() {{
+entry:
+  %c = call i32 @chiya_peek()
+  %eof = icmp eq i32 %c, -1
+  br i1 %eof, label %end, label %next
+next:
+  %pos = load i64, {i64p} @chiya_in_pos, align 8
+  %pos_next = add i64 %pos, 1
+  store i64 %pos_next, {i64p} @chiya_in_pos, align 8
+  br label %end
+end:
+  ret i32 %c
+}}
+
+define internal void @chiya_putint(i64 %value) {{
+entry:
+  %buf = alloca [21 x i8], align 1
+  %s = getelementptr inbounds [21 x i8], {digits} %buf, i64 0, i64 0
+  %n = call i32 ({i8p}, i64, {i8p}, ...) @snprintf({i8p} %s, i64 21, {i8p} getelementptr inbounds ([5 x i8], {fmt_int} @.fmt_int, i64 0, i64 0), i64 %value)
+  %len = sext i32 %n to i64
+  br label %loop
+loop:
+  %i = phi i64 [ 0, %entry ], [ %next, %put ]
+  %end = icmp sge i64 %i, %len
+  br i1 %end, label %done, label %put
+put:
+  %p = getelementptr inbounds [21 x i8], {digits} %buf, i64 0, i64 %i
+  %b = load i8, {i8p} %p, align 1
+  %c = zext i8 %b to i32
+  %put_c = call i32 @chiya_putchar(i32 %c)
+  %next = add i64 %i, 1
+  br label %loop
+done:
+  ret void
+}}
+
+; Reads an integer like scanf("%lld").
+define internal i32 @chiya_getint({i64p} %value) {{
+entry:
+  br label %space
+space:
+  %c = call i32 @chiya_peek()
+  %is_space = icmp eq i32 %c, 32
+  %c_control = sub i32 %c, 9
+  %is_control = icmp ult i32 %c_control, 5
+  %skip = or i1 %is_space, %is_control
+  br i1 %skip, label %next_space, label %sign
+next_space:
+  %space_c = call i32 @chiya_getchar()
+  br label %space
+sign:
+  %minus = icmp eq i32 %c, 45
+  %plus = icmp eq i32 %c, 43
+  %has_sign = or i1 %minus, %plus
+  br i1 %has_sign, label %next_sign, label %first
+next_sign:
+  %sign_c = call i32 @chiya_getchar()
+  br label %first
+first:
+  %d = call i32 @chiya_peek()
+  %d_digit = sub i32 %d, 48
+  %is_digit = icmp ult i32 %d_digit, 10
+  br i1 %is_digit, label %digits, label %fail
+digits:
+  %acc = phi i64 [ 0, %first ], [ %acc_next, %digit ]
+  %e = call i32 @chiya_peek()
+  %e_digit = sub i32 %e, 48
+  %more = icmp ult i32 %e_digit, 10
+  br i1 %more, label %digit, label %done
+digit:
+  ; The value is accumulated with its sign in i128, which cannot overflow, and saturates like scanf.
+  %digit_c = call i32 @chiya_getchar()
+  %acc_wide = sext i64 %acc to i128
+  %acc_10 = mul i128 %acc_wide, 10
+  %e_128 = zext i32 %e_digit to i128
+  %e_negated = sub i128 0, %e_128
+  %addend = select i1 %minus, i128 %e_negated, i128 %e_128
+  %sum = add i128 %acc_10, %addend
+  %too_large = icmp sgt i128 %sum, 9223372036854775807
+  %too_small = icmp slt i128 %sum, -9223372036854775808
+  %below_max = select i1 %too_large, i128 9223372036854775807, i128 %sum
+  %clamped = select i1 %too_small, i128 -9223372036854775808, i128 %below_max
+  %acc_next = trunc i128 %clamped to i64
+  br label %digits
+done:
+  store i64 %acc, {i64p} %value, align 8
+  ret i32 1
+fail:
+  %eof = icmp eq i32 %d, -1
+  %r = select i1 %eof, i32 -1, i32 0
+  ret i32 %r
+}}
+
+declare i64 @write(i32, {i8p}, i64)
+declare i64 @read(i32, {i8p}, i64)
+declare i32 @atexit({callback})
+declare i32 @snprintf({i8p}, i64, {i8p}, ...)"#,
+        i8p = p.to("i8"),
+        i64p = p.to("i64"),
+        buffer = p.to("[4096 x i8]"),
+        digits = p.to("[21 x i8]"),
+        fmt_int = p.to("[5 x i8]"),
+        callback = p.to("void ()")
+    )
+}
+
+// The fields of '%chiya_callbacks' and their types. 'data' is passed to each of the functions.
+fn callbacks(p: Pointers) -> [(&'static str, String); 5] {
+    let data = p.to("i8");
+    [
+        ("data", data.clone()),
+        ("getchar", p.to(&format!("i32 ({})", data))),
+        ("putchar", p.to(&format!("void ({}, i32)", data))),
+        ("getint", p.to(&format!("i32 ({}, {})", data, p.to("i64")))),
+        ("putint", p.to(&format!("void ({}, i64)", data))),
+    ]
+}
 
 pub struct LLVM {
     variable_idx: u32,
     label_idx: u32,
-    loop_stack: VecDeque<u32>,
+    loop_stack: VecDeque<(u32, Location)>,
+    options: Options,
+    location: Location,
+    // The label of the current basic block.
+    block: String,
+    // With `ssa`, the value of the pointer, whether it has been checked to be inside the tape,
+    // and the value of the cell it points to, if known, with whether it is yet to be stored.
+    ptr: String,
+    checked: bool,
+    cell_value: Option<(String, bool)>,
+    // With `debug_info`, the locations referred to by '!dbg' attachments, and with `ssa` also the
+    // values of the pointer and the cell last reported to the debugger.
+    debug_locations: Vec<Location>,
+    tracked: (String, Option<String>),
+    // The exit statuses and the blocks branching to 'exit', where the program ends.
+    exits: Vec<(String, String)>,
 }
 
 impl LLVM {
     pub fn new() -> LLVM {
         Default::default()
     }
-}
 
-impl Default for LLVM {
-    fn default() -> Self {
-        LLVM {
-            variable_idx: 1,
-            label_idx: 1,
-            loop_stack: VecDeque::new(),
+    pub fn with_options(options: &Options) -> LLVM {
+        let mut e = LLVM {
+            options: options.clone(),
+            ..Default::default()
+        };
+        if e.checks_empty_tape() {
+            // The entry block returns 1 for an empty tape.
+            e.exits.push(("1".to_owned(), "0".to_owned()));
+            e.block = "start".to_owned();
         }
+        e
     }
-}
 
-impl emitter::Emitter for LLVM {
-    fn emit_move_ptr(&mut self, offset: i32) -> String {
+    // True if the function fails on an empty tape given by its caller, which a circular tape
+    // cannot wrap around.
+    fn checks_empty_tape(&self) -> bool {
+        self.options.function.is_some() && self.options.tape_mode == TapeMode::Wrap
+    }
+
+    // Returns the C header declaring the function emitted with the `function` option.
+    pub fn c_header(&self) -> Option<String> {
+        c::header(&self.options)
+    }
+
+    // The type of a cell, e.g. 'i32'.
+    fn cell(&self) -> String {
+        format!("i{}", self.options.cell_width.bits())
+    }
+
+    fn align(&self) -> u32 {
+        self.options.cell_width.bytes()
+    }
+
+    fn pointers(&self) -> Pointers {
+        Pointers {
+            typed: self.options.llvm_version.typed_pointers(),
+        }
+    }
+
+    // A pointer to `pointee`, e.g. 'i8*' or 'ptr'.
+    fn ptr_to(&self, pointee: &str) -> String {
+        self.pointers().to(pointee)
+    }
+
+    // The type of a pointer to a cell, e.g. 'i32*' or 'ptr'.
+    fn cell_ptr_type(&self) -> String {
+        self.ptr_to(&self.cell())
+    }
+
+    // The pointer to '%lld' for printf and scanf.
+    fn fmt_int(&self) -> String {
+        format!(
+            "{} getelementptr inbounds ([5 x i8], {} @.fmt_int, i64 0, i64 0)",
+            self.ptr_to("i8"),
+            self.ptr_to("[5 x i8]")
+        )
+    }
+
+    // Attaches the current location to the instructions in `ir` if debug info is enabled.
+    fn finish(&mut self, ir: String) -> String {
+        if !self.options.debug_info {
+            return ir;
+        }
+
+        let ir = ir + &self.track();
+        if self.debug_locations.last() != Some(&self.location) {
+            self.debug_locations.push(self.location);
+        }
+        let n = DEBUG_LOCATIONS + self.debug_locations.len() - 1;
+        attach_dbg(&ir, n)
+    }
+
+    // With `ssa`, tells the debugger about the new values of the pointer and the cell.
+    fn track(&mut self) -> String {
+        if !self.options.ssa {
+            return "".to_owned();
+        }
+
+        let tracked = (
+            self.ptr.clone(),
+            self.cell_value.as_ref().map(|(v, _)| v.clone()),
+        );
+        if tracked == self.tracked {
+            return "".to_owned();
+        }
+        self.tracked = tracked;
+        debug_values(
+            self.pointers(),
+            &self.cell(),
+            &self.tracked.0,
+            self.tracked.1.as_deref(),
+        )
+    }
+
+    fn next_variable(&mut self) -> u32 {
+        self.variable_idx += 1;
+        self.variable_idx - 1
+    }
+
+    fn next_label(&mut self) -> u32 {
+        self.label_idx += 1;
+        self.label_idx - 1
+    }
+
+    fn var(&mut self) -> String {
+        format!("%{}", self.next_variable())
+    }
+
+    // Starts the basic block `name`.
+    fn label(&mut self, name: String) -> String {
+        let s = format!("\n{}:", name);
+        self.block = name;
+        s
+    }
+
+    // Converts the cell value `value` from/to an 'i32' (or 'i64') value used by the C library.
+    fn cast(&mut self, value: &str, from: &str, to: &str) -> (String, String) {
+        let bits = |t: &str| t[1..].parse::<u32>().unwrap();
+        let op = match bits(from).cmp(&bits(to)) {
+            Ordering::Equal => return ("".to_owned(), value.to_owned()),
+            Ordering::Greater => "trunc",
+            Ordering::Less => "sext",
+        };
+        let v = self.var();
+        (
+            format!("\n  {0} = {1} {2} {3} to {4}", v, op, from, value, to),
+            v,
+        )
+    }
+
+    // Returns the current value of the pointer.
+    fn get_ptr(&mut self) -> (String, String) {
+        if self.options.ssa {
+            return ("".to_owned(), self.ptr.clone());
+        }
+
+        let ptr = self.var();
+        (
+            format!(
+                "\n  {0} = load {1}, {2} %ptr, align 8",
+                ptr,
+                self.cell_ptr_type(),
+                self.ptr_to(&self.cell_ptr_type())
+            ),
+            ptr,
+        )
+    }
+
+    // Sets the pointer to `ptr`.
+    fn set_ptr(&mut self, ptr: String) -> String {
+        if self.options.ssa {
+            self.ptr = ptr;
+            self.checked = false;
+            self.cell_value = None;
+            return "".to_owned();
+        }
+
+        format!(
+            "\n  store {0} {1}, {2} %ptr, align 8",
+            self.cell_ptr_type(),
+            ptr,
+            self.ptr_to(&self.cell_ptr_type())
+        )
+    }
+
+    // Ends the block `block` after an error has been reported. A function returns 1 to its caller,
+    // and a program has already exited.
+    fn fail(&mut self, block: String) -> &'static str {
+        if self.options.function.is_none() {
+            return "\n  unreachable";
+        }
+        self.exits.push(("1".to_owned(), block));
+        "\n  br label %exit"
+    }
+
+    // Returns the pointer to the current cell, checking that it is inside the tape if requested.
+    fn cell_ptr(&mut self) -> (String, String) {
+        if self.options.ssa && self.checked {
+            return ("".to_owned(), self.ptr.clone());
+        }
+        self.checked = true;
+
+        let (s, ptr) = self.get_ptr();
+        if !self.options.bounds_check || self.options.tape_mode != TapeMode::Fixed {
+            return (s, ptr);
+        }
+
+        let (offset, index, cond, l) = (
+            self.variable_idx + 1,
+            self.variable_idx + 3,
+            self.variable_idx + 2,
+            self.next_label(),
+        );
+        let fail = self.fail(format!("bounds{}", l));
         let s = format!(
-            r#"
-  ; emit_move_ptr({2})
-  %{0} = load i32*, i32** %ptr, align 8
-  %{1} = getelementptr inbounds i32, i32* %{0}, i32 {2}
-  store i32* %{1}, i32** %ptr, align 8"#,
+            r#"{0}
+  %{1} = ptrtoint {9} {2} to i64
+  %{3} = sub i64 %{1}, %heap_int
+  %{4} = icmp uge i64 %{3}, {10}
+  br i1 %{4}, label %bounds{5}, label %bounds{5}_ok
+bounds{5}:
+  %{6} = sdiv exact i64 %{3}, {11}
+  call void @chiya_out_of_bounds(i64 {7}, i64 {8}, i64 %{6}){12}"#,
+            s,
             self.variable_idx,
+            ptr,
+            offset,
+            cond,
+            l,
+            index,
+            self.location.line,
+            self.location.column,
+            self.cell_ptr_type(),
+            match self.options.function {
+                Some(_) => "%tape_bytes".to_owned(),
+                None => (self.options.tape_size.0 as u64 * self.align() as u64).to_string(),
+            },
+            self.align(),
+            fail
+        );
+        self.variable_idx += 4;
+
+        (s + &self.label(format!("bounds{}_ok", l)), ptr)
+    }
+
+    // Returns the value of the cell `ptr` points to.
+    fn load_value(&mut self, ptr: &str) -> (String, String) {
+        if let Some((v, _)) = &self.cell_value {
+            return ("".to_owned(), v.clone());
+        }
+
+        let v = self.var();
+        let s = format!(
+            "\n  {0} = load {1}, {2} {3}, align {4}",
+            v,
+            self.cell(),
+            self.cell_ptr_type(),
+            ptr,
+            self.align()
+        );
+        if self.options.ssa {
+            self.cell_value = Some((v.clone(), false));
+        }
+        (s, v)
+    }
+
+    // Returns the pointer to and the value of the current cell.
+    fn load_cell(&mut self) -> (String, String, String) {
+        let (s, ptr) = self.cell_ptr();
+        let (l, v) = self.load_value(&ptr);
+        (s + &l, ptr, v)
+    }
+
+    // Stores `value` to the cell `ptr` points to.
+    fn store_cell(&mut self, ptr: &str, value: String) -> String {
+        if self.options.ssa {
+            self.cell_value = Some((value, true));
+            return "".to_owned();
+        }
+
+        format!(
+            "\n  store {0} {1}, {2} {3}, align {4}",
+            self.cell(),
+            value,
+            self.cell_ptr_type(),
+            ptr,
+            self.align()
+        )
+    }
+
+    // With `ssa`, stores the value of the current cell if it has been changed.
+    fn flush(&mut self) -> String {
+        match self.cell_value.take() {
+            Some((v, true)) => {
+                let s = format!(
+                    "\n  store {0} {1}, {2} {3}, align {4}",
+                    self.cell(),
+                    v,
+                    self.cell_ptr_type(),
+                    self.ptr,
+                    self.align()
+                );
+                self.cell_value = Some((v, false));
+                s
+            }
+            v => {
+                self.cell_value = v;
+                "".to_owned()
+            }
+        }
+    }
+
+    // Stores `value`, the 'i32' or 'i64' result of an input operation, to the cell `ptr` points
+    // to, or applies the configured EOF behavior if `eof` is true.
+    fn store_input(&mut self, ptr: &str, value: &str, value_type: &str, eof: &str) -> String {
+        let t = self.cell();
+        let (s, value) = self.cast(value, value_type, &t);
+        let (load, on_eof) = match self.options.eof {
+            Eof::MinusOne => ("".to_owned(), "-1".to_owned()),
+            Eof::Zero => ("".to_owned(), "0".to_owned()),
+            Eof::Unchanged => self.load_value(ptr),
+        };
+        let v = self.var();
+        let store = self.store_cell(ptr, v.clone());
+
+        format!(
+            r#"{0}{1}
+  {2} = select i1 {3}, {4} {5}, {4} {6}{7}"#,
+            s, load, v, eof, t, on_eof, value, store
+        )
+    }
+
+    // Computes the pointer moved by `offset` modulo the tape size.
+    fn move_ptr_wrap(&mut self, offset: i32) -> (String, String) {
+        let size = self.options.tape_size.0 as i64;
+        let library = self.options.function.is_some();
+        let offset = if library {
+            offset as i64
+        } else {
+            (offset as i64).rem_euclid(size)
+        };
+        let (s, ptr) = self.get_ptr();
+        if offset == 0 {
+            return (s, ptr);
+        }
+
+        // The index is in [0, size), so `index + offset` is in [0, 2 * size) unless the size is
+        // unknown.
+        let (t, index, moved) = (self.cell(), self.variable_idx + 2, self.variable_idx + 3);
+        let s = format!(
+            r#"{0}
+  %{1} = ptrtoint {2} {3} to i64
+  %{4} = sub i64 %{1}, %heap_int
+  %{5} = lshr exact i64 %{4}, {6}
+  %{7} = add i64 %{5}, {8}"#,
+            s,
+            self.variable_idx,
+            self.cell_ptr_type(),
+            ptr,
             self.variable_idx + 1,
+            index,
+            self.align().trailing_zeros(),
+            moved,
             offset
         );
-        self.variable_idx += 2;
+        self.variable_idx += 4;
 
-        s
+        let (wrap, wrapped) = if library {
+            // The size of the tape is only known at run time.
+            let (r, c, d, v) = (
+                self.next_variable(),
+                self.next_variable(),
+                self.next_variable(),
+                self.next_variable(),
+            );
+            (
+                format!(
+                    r#"
+  %{0} = srem i64 %{4}, %tape_len
+  %{1} = icmp slt i64 %{0}, 0
+  %{2} = add i64 %{0}, %tape_len
+  %{3} = select i1 %{1}, i64 %{2}, i64 %{0}"#,
+                    r, c, d, v, moved
+                ),
+                v,
+            )
+        } else if size & (size - 1) == 0 {
+            let v = self.next_variable();
+            (
+                format!("\n  %{0} = and i64 %{1}, {2}", v, moved, size - 1),
+                v,
+            )
+        } else {
+            let (c, d, v) = (
+                self.next_variable(),
+                self.next_variable(),
+                self.next_variable(),
+            );
+            (
+                format!(
+                    r#"
+  %{0} = icmp uge i64 %{3}, {4}
+  %{1} = sub i64 %{3}, {4}
+  %{2} = select i1 %{0}, i64 %{1}, i64 %{3}"#,
+                    c, d, v, moved, size
+                ),
+                v,
+            )
+        };
+        let ptr = self.var();
+        (
+            format!(
+                r#"{0}{1}
+  {2} = getelementptr inbounds {3}, {4} %heap_cell, i64 %{5}"#,
+                s,
+                wrap,
+                ptr,
+                t,
+                self.cell_ptr_type(),
+                wrapped
+            ),
+            ptr,
+        )
     }
 
-    fn emit_add(&mut self, n: i32) -> String {
+    // Reallocates the tape if `ptr`, the new value of the pointer, is out of it, and returns the
+    // pointer to the same cell.
+    fn grow(&mut self, ptr: &str) -> (String, String) {
+        let (l, block) = (self.next_label(), self.block.clone());
         let s = format!(
             r#"
-  ; emit_add({3})
-  %{0} = load i32*, i32** %ptr, align 8
-  %{1} = load i32, i32* %{0}, align 4
-  %{2} = add nsw i32 %{1}, {3}
-  store i32 %{2}, i32* %{0}, align 4"#,
+  %{0} = load {9}, {14} %heap, align 8
+  %{1} = ptrtoint {9} {8} to i64
+  %{2} = ptrtoint {9} %{0} to i64
+  %{3} = sub i64 %{1}, %{2}
+  %{4} = sdiv exact i64 %{3}, {10}
+  %{5} = load i64, {16} %len, align 8
+  %{6} = icmp uge i64 %{4}, %{5}
+  br i1 %{6}, label %grow{7}, label %grow{7}_end
+grow{7}:
+  %{11} = bitcast {14} %heap to {15}
+  %{12} = call i64 @chiya_grow({15} %{11}, {16} %len, i64 %{4}, i64 {10}, i64 {13})"#,
             self.variable_idx,
             self.variable_idx + 1,
             self.variable_idx + 2,
-            n
+            self.variable_idx + 3,
+            self.variable_idx + 4,
+            self.variable_idx + 5,
+            self.variable_idx + 6,
+            l,
+            ptr,
+            self.cell_ptr_type(),
+            self.align(),
+            self.variable_idx + 7,
+            self.variable_idx + 8,
+            self.options
+                .max_tape
+                .map_or(i64::MAX, |max| max.min(i64::MAX as usize) as i64),
+            self.ptr_to(&self.cell_ptr_type()),
+            self.ptr_to(&self.ptr_to("i8")),
+            self.ptr_to("i64"),
         );
-        self.variable_idx += 3;
+        let index = self.variable_idx + 8;
+        self.variable_idx += 9;
+        self.block = format!("grow{}", l);
 
-        s
+        let s = match self.options.max_tape {
+            Some(_) => {
+                let cond = self.var();
+                let s = format!("{0}\n  {1} = icmp slt i64 %{2}, 0", s, cond, index);
+                s + &self.limit(&cond, "tape_limit")
+            }
+            None => s,
+        };
+        let (heap, grown, from) = (self.var(), self.var(), self.block.clone());
+        let s = format!(
+            r#"{0}
+  {1} = load {6}, {7} %heap, align 8
+  {2} = getelementptr {3}, {6} {1}, i64 %{4}
+  br label %grow{5}_end"#,
+            s,
+            heap,
+            grown,
+            self.cell(),
+            index,
+            l,
+            self.cell_ptr_type(),
+            self.ptr_to(&self.cell_ptr_type())
+        ) + &self.label(format!("grow{}_end", l));
+        let v = self.var();
+        (
+            format!(
+                "{0}\n  {1} = phi {2} [ {3}, %{4} ], [ {5}, %{6} ]",
+                s,
+                v,
+                self.cell_ptr_type(),
+                ptr,
+                block,
+                grown,
+                from
+            ),
+            v,
+        )
     }
 
-    fn emit_call_putchar(&mut self) -> String {
+    // Adds `n` to the cell value `value`, following the configured overflow policy.
+    fn add(&mut self, value: String, n: i32) -> (String, String) {
+        let t = self.cell();
+        let width = self.options.cell_width;
+        match self.options.overflow {
+            Overflow::Wrap => {
+                let v = self.var();
+                (
+                    format!(
+                        "\n  {0} = add {1} {2}, {3}",
+                        v,
+                        t,
+                        value,
+                        width.wrap(n as i64)
+                    ),
+                    v,
+                )
+            }
+
+            Overflow::Saturate => width.split_addend(n as i64).into_iter().fold(
+                ("".to_owned(), value),
+                |(s, value), n| {
+                    let v = self.var();
+                    (
+                        format!(
+                            "{0}\n  {1} = call {2} @llvm.sadd.sat.{2}({2} {3}, {2} {4})",
+                            s, v, t, value, n
+                        ),
+                        v,
+                    )
+                },
+            ),
+
+            Overflow::Trap => width.split_addend(n as i64).into_iter().fold(
+                ("".to_owned(), value),
+                |(s, value), n| {
+                    let (r, v, o, l) = (
+                        self.next_variable(),
+                        self.var(),
+                        self.next_variable(),
+                        self.next_label(),
+                    );
+                    let s = format!(
+                        r#"{0}
+  %{1} = call {{{2}, i1}} @llvm.sadd.with.overflow.{2}({2} {3}, {2} {4})
+  {5} = extractvalue {{{2}, i1}} %{1}, 0
+  %{6} = extractvalue {{{2}, i1}} %{1}, 1
+  br i1 %{6}, label %overflow, label %add{7}_ok"#,
+                        s, r, t, value, n, v, o, l
+                    );
+                    (s + &self.label(format!("add{}_ok", l)), v)
+                },
+            ),
+        }
+    }
+
+    // The names and the texts of the messages of the limits in the options.
+    fn limits(&self) -> Vec<(&'static str, String)> {
+        let options = &self.options;
+        let mut limits = vec![];
+        if options.fuel.is_some() {
+            limits.push(("out_of_fuel", "out of fuel".to_owned()));
+        }
+        if let Some(max) = options.max_tape {
+            limits.push((
+                "tape_limit",
+                format!("tape limit of {} cells exceeded", max),
+            ));
+        }
+        if let Some(max) = options.max_output {
+            limits.push((
+                "output_limit",
+                format!("output limit of {} bytes exceeded", max),
+            ));
+        }
+        limits
+    }
+
+    // Reports the limit `name` at the current location and fails if `cond` is true.
+    fn limit(&mut self, cond: &str, name: &str) -> String {
+        let len = self
+            .limits()
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map_or(0, |(_, message)| message.len() + 1);
+        let l = self.next_label();
+        let fail = self.fail(format!("limit{}", l));
         let s = format!(
             r#"
-  ; emit_call_putchar()
-  %{0} = load i32*, i32** %ptr, align 8
-  %{1} = load i32, i32* %{0}, align 4
-  %{2} = call i32 @putchar(i32 %{1})"#,
-            self.variable_idx,
-            self.variable_idx + 1,
-            self.variable_idx + 2,
+  br i1 {0}, label %limit{1}, label %limit{1}_ok
+limit{1}:
+  call void @chiya_limit({6} getelementptr inbounds ([{2} x i8], {7} @.{3}, i64 0, i64 0), i64 {4}, i64 {5}){8}"#,
+            cond,
+            l,
+            len,
+            name,
+            self.location.line,
+            self.location.column,
+            self.ptr_to("i8"),
+            self.ptr_to(&format!("[{} x i8]", len)),
+            fail
         );
-        self.variable_idx += 3;
+        s + &self.label(format!("limit{}_ok", l))
+    }
 
-        s
+    // With `fuel`, uses up one unit of it for an operation, or exits if there is none left.
+    fn burn(&mut self) -> String {
+        if self.options.fuel.is_none() {
+            return "".to_owned();
+        }
+
+        let (fuel, empty) = (self.var(), self.var());
+        let s = format!(
+            r#"
+  {0} = load i64, {2} %fuel, align 8
+  {1} = icmp eq i64 {0}, 0"#,
+            fuel,
+            empty,
+            self.ptr_to("i64")
+        ) + &self.limit(&empty, "out_of_fuel");
+        let left = self.var();
+        format!(
+            r#"{0}
+  {1} = sub i64 {2}, 1
+  store i64 {1}, {3} %fuel, align 8"#,
+            s,
+            left,
+            fuel,
+            self.ptr_to("i64")
+        )
     }
 
-    fn emit_call_getchar(&mut self) -> String {
+    // With `max_output`, counts the `n` bytes about to be written, or exits if they would exceed
+    // the limit.
+    fn count_output(&mut self, n: &str) -> String {
+        let max = match self.options.max_output {
+            Some(max) => max,
+            None => return "".to_owned(),
+        };
+
+        let (written, total, over) = (self.var(), self.var(), self.var());
         let s = format!(
             r#"
-  ; emit_call_getchar()
-  %{0} = load i32*, i32** %ptr, align 8
-  %{1} = call i32 @getchar()
-  store i32 %{1}, i32* %{0}, align 4"#,
-            self.variable_idx,
-            self.variable_idx + 1,
+  {0} = load i64, {5} %written, align 8
+  {1} = add i64 {0}, {3}
+  {2} = icmp ugt i64 {1}, {4}"#,
+            written,
+            total,
+            over,
+            n,
+            max,
+            self.ptr_to("i64")
+        ) + &self.limit(&over, "output_limit");
+        format!(
+            "{0}\n  store i64 {1}, {2} %written, align 8",
+            s,
+            total,
+            self.ptr_to("i64")
+        )
+    }
+}
+
+impl Default for LLVM {
+    fn default() -> Self {
+        LLVM {
+            variable_idx: 1,
+            label_idx: 1,
+            loop_stack: VecDeque::new(),
+            options: Options::new(),
+            location: Default::default(),
+            block: "0".to_owned(),
+            ptr: "%ptr_init".to_owned(),
+            checked: false,
+            cell_value: None,
+            debug_locations: Vec::new(),
+            tracked: ("%ptr_init".to_owned(), None),
+            exits: Vec::new(),
+        }
+    }
+}
+
+// The IR of the statements, which `finish` attaches the debug locations to.
+impl LLVM {
+    fn ir_move_ptr(&mut self, offset: i32) -> String {
+        let s = format!("\n  ; emit_move_ptr({})", offset) + &self.burn() + &self.flush();
+        let (m, ptr) = match self.options.tape_mode {
+            TapeMode::Wrap => self.move_ptr_wrap(offset),
+            mode => {
+                let (s, ptr) = self.get_ptr();
+                let moved = self.var();
+                let s = format!(
+                    "{0}\n  {1} = getelementptr {2}{3}, {4} {5}, i32 {6}",
+                    s,
+                    moved,
+                    // The pointer may leave the tape until it grows or is checked, which
+                    // 'inbounds' would make undefined.
+                    if mode == TapeMode::Grow || self.options.bounds_check {
+                        ""
+                    } else {
+                        "inbounds "
+                    },
+                    self.cell(),
+                    self.cell_ptr_type(),
+                    ptr,
+                    offset
+                );
+                if mode == TapeMode::Grow {
+                    let (g, ptr) = self.grow(&moved);
+                    (s + &g, ptr)
+                } else {
+                    (s, moved)
+                }
+            }
+        };
+
+        s + &m + &self.set_ptr(ptr)
+    }
+
+    fn ir_add(&mut self, n: i32) -> String {
+        let fuel = self.burn();
+        let (s, ptr, value) = self.load_cell();
+        let (add, result) = self.add(value, n);
+        let store = self.store_cell(&ptr, result);
+        format!("\n  ; emit_add({0}){1}{2}{3}{4}", n, fuel, s, add, store)
+    }
+
+    fn ir_call_putchar(&mut self) -> String {
+        let t = self.cell();
+        let fuel = self.burn();
+        let (s, _, value) = self.load_cell();
+        let (cast, value) = self.cast(&value, &t, "i32");
+        let count = self.count_output("1");
+        let call = match self.options.function {
+            Some(_) => format!(
+                "call void %cb_putchar({} %cb_data, i32 {})",
+                self.ptr_to("i8"),
+                value
+            ),
+            None => format!(
+                "{} = call i32 {}(i32 {})",
+                self.var(),
+                if self.options.buffered_io {
+                    "@chiya_putchar"
+                } else {
+                    "@putchar"
+                },
+                value
+            ),
+        };
+        format!(
+            "\n  ; emit_call_putchar(){0}{1}{2}{3}\n  {4}",
+            fuel, s, cast, count, call
+        )
+    }
+
+    fn ir_call_getchar(&mut self) -> String {
+        let fuel = self.burn();
+        let (s, ptr) = self.cell_ptr();
+        let (value, eof) = (self.var(), self.var());
+        let s = format!(
+            r#"
+  ; emit_call_getchar(){4}{0}
+  {1} = call i32 {3}
+  {2} = icmp eq i32 {1}, -1"#,
+            s,
+            value,
+            eof,
+            match self.options.function {
+                Some(_) => format!("%cb_getchar({} %cb_data)", self.ptr_to("i8")),
+                None if self.options.buffered_io => "@chiya_getchar()".to_owned(),
+                None => "@getchar()".to_owned(),
+            },
+            fuel
         );
-        self.variable_idx += 2;
 
-        s
+        s + &self.store_input(&ptr, &value, "i32", &eof)
     }
 
-    fn emit_loop_begin(&mut self) -> String {
+    fn ir_call_putint(&mut self) -> String {
+        let t = self.cell();
+        let fuel = self.burn();
+        let (s, _, value) = self.load_cell();
+        let (cast, value) = self.cast(&value, &t, "i64");
+        let count = match self.options.max_output {
+            Some(_) => {
+                let length = self.var();
+                format!(
+                    "\n  {0} = call i64 @chiya_int_length(i64 {1})",
+                    length, value
+                ) + &self.count_output(&length)
+            }
+            None => "".to_owned(),
+        };
+        let call = match self.options.function {
+            Some(_) => format!(
+                "call void %cb_putint({} %cb_data, i64 {})",
+                self.ptr_to("i8"),
+                value
+            ),
+            None if self.options.buffered_io => format!("call void @chiya_putint(i64 {})", value),
+            None => format!(
+                "{} = call i32 ({}, ...) @printf({}, i64 {})",
+                self.var(),
+                self.ptr_to("i8"),
+                self.fmt_int(),
+                value
+            ),
+        };
+        format!(
+            "\n  ; emit_call_putint(){0}{1}{2}{3}\n  {4}",
+            fuel, s, cast, count, call
+        )
+    }
+
+    fn ir_call_getint(&mut self) -> String {
+        let fuel = self.burn();
+        let (s, ptr) = self.cell_ptr();
+        let (r, value, eof) = (self.var(), self.var(), self.var());
         let s = format!(
             r#"
-  ; emit_loop_begin()
-  br label %loop{3}_cond
-loop{3}_cond:
-  %{0} = load i32*, i32** %ptr, align 8
-  %{1} = load i32, i32* %{0}, align 4
-  %{2} = icmp ne i32 %{1}, 0
-  br i1 %{2}, label %loop{3}_body, label %loop{3}_end
-loop{3}_body:"#,
-            self.variable_idx,
-            self.variable_idx + 1,
-            self.variable_idx + 2,
-            self.label_idx
+  ; emit_call_getint(){5}{0}
+  {1} = call i32 {4}
+  {2} = load i64, {6} %int, align 8
+  {3} = icmp ne i32 {1}, 1"#,
+            s,
+            r,
+            value,
+            eof,
+            match self.options.function {
+                Some(_) => format!(
+                    "%cb_getint({} %cb_data, {} %int)",
+                    self.ptr_to("i8"),
+                    self.ptr_to("i64")
+                ),
+                None if self.options.buffered_io => {
+                    format!("@chiya_getint({} %int)", self.ptr_to("i64"))
+                }
+                None => format!(
+                    "({}, ...) @scanf({}, {} %int)",
+                    self.ptr_to("i8"),
+                    self.fmt_int(),
+                    self.ptr_to("i64")
+                ),
+            },
+            fuel,
+            self.ptr_to("i64")
         );
-        self.loop_stack.push_back(self.label_idx);
-        self.variable_idx += 3;
-        self.label_idx += 1;
 
+        s + &self.store_input(&ptr, &value, "i64", &eof)
+    }
+
+    fn ir_loop_begin(&mut self) -> String {
+        let l = self.next_label();
+        self.loop_stack.push_back((l, self.location));
+        // The fuel is used up when entering the loop, and at its end for each iteration.
+        let fuel = self.burn();
+
+        if !self.options.ssa {
+            let s = format!(
+                "\n  ; emit_loop_begin(){}\n  br label %loop{}_cond",
+                fuel, l
+            ) + &self.label(format!("loop{}_cond", l));
+            let (c, _, value) = self.load_cell();
+            let cond = self.var();
+            return format!(
+                r#"{0}{1}
+  {2} = icmp ne {3} {4}, 0
+  br i1 {2}, label %loop{5}_body, label %loop{5}_end{6}"#,
+                s,
+                c,
+                cond,
+                self.cell(),
+                value,
+                l,
+                self.label(format!("loop{}_body", l))
+            );
+        }
+
+        // The pointer and the cell at the loop header are merged from the ones before the loop
+        // and the ones at the end of its body.
+        let s = self.flush();
+        let (c, _, value) = self.load_cell();
+        let (ptr, block, cond) = (self.ptr.clone(), self.block.clone(), self.var());
+        let s = format!(
+            r#"
+  ; emit_loop_begin(){9}{0}{1}
+  br label %loop{2}_cond{3}
+  %loop{2}_ptr = phi {10} [ {5}, %{6} ], [ %loop{2}_ptr_back, %loop{2}_latch ]
+  %loop{2}_cell = phi {4} [ {7}, %{6} ], [ %loop{2}_cell_back, %loop{2}_latch ]
+  {8} = icmp ne {4} %loop{2}_cell, 0
+  br i1 {8}, label %loop{2}_body, label %loop{2}_end"#,
+            s,
+            c,
+            l,
+            self.label(format!("loop{}_cond", l)),
+            self.cell(),
+            ptr,
+            block,
+            value,
+            cond,
+            fuel,
+            self.cell_ptr_type()
+        );
+        self.ptr = format!("%loop{}_ptr", l);
+        self.checked = true;
+        self.cell_value = Some((format!("%loop{}_cell", l), false));
+
+        s + &self.label(format!("loop{}_body", l))
+    }
+
+    fn ir_loop_end(&mut self) -> String {
+        let l = match self.loop_stack.pop_back() {
+            Some((l, _)) => l,
+            None => return "".to_owned(),
+        };
+        let fuel = self.burn();
+
+        if !self.options.ssa {
+            return format!("\n  ; emit_loop_end(){}\n  br label %loop{}_cond", fuel, l)
+                + &self.label(format!("loop{}_end", l));
+        }
+
+        let s = self.flush();
+        let (c, _, value) = self.load_cell();
+
+        let s = format!(
+            r#"
+  ; emit_loop_end(){7}{0}{1}
+  br label %loop{2}_latch{3}
+  %loop{2}_ptr_back = getelementptr {4}, {8} {5}, i64 0
+  %loop{2}_cell_back = add {4} {6}, 0
+  br label %loop{2}_cond"#,
+            s,
+            c,
+            l,
+            self.label(format!("loop{}_latch", l)),
+            self.cell(),
+            self.ptr,
+            value,
+            fuel,
+            self.cell_ptr_type()
+        );
+        self.ptr = format!("%loop{}_ptr", l);
+        self.checked = true;
+        self.cell_value = Some(("0".to_owned(), false));
+
+        s + &self.label(format!("loop{}_end", l))
+    }
+
+    fn ir_header(&self) -> String {
+        let target = match &self.options.target {
+            Some(triple) => {
+                let layout = datalayout(triple, self.options.llvm_version.0)
+                    .map(|l| format!("target datalayout = \"{}\"\n", l))
+                    .unwrap_or_default();
+                format!("{}target triple = \"{}\"\n\n", layout, triple)
+            }
+            None => "".to_owned(),
+        };
+
+        let (t, tp) = (self.cell(), self.cell_ptr_type());
+        let dbg = if self.options.debug_info {
+            format!(" !dbg !{}", DEBUG_SUBPROGRAM)
+        } else {
+            "".to_owned()
+        };
+        let (entry, len) = match &self.options.function {
+            Some(name) => {
+                let callbacks = callbacks(self.pointers());
+                (
+                    format!(
+                        r#"%chiya_callbacks = type {{ {6} }}
+
+; emit_header()
+define i32 @{1}({7} %tape, i64 %tape_len, {8} %callbacks){2} {{
+  %heap_cell = getelementptr inbounds {0}, {7} %tape, i64 0
+  %ptr_index = {3}
+  %ptr_init = getelementptr inbounds {0}, {7} %heap_cell, i64 %ptr_index
+  %heap_int = ptrtoint {7} %heap_cell to i64
+  %tape_bytes = mul i64 %tape_len, {4}{5}"#,
+                        t,
+                        name,
+                        dbg,
+                        if self.options.bidirectional {
+                            "lshr i64 %tape_len, 1"
+                        } else {
+                            "add i64 0, 0"
+                        },
+                        self.align(),
+                        callbacks
+                            .iter()
+                            .enumerate()
+                            .map(|(i, (name, ty))| format!(
+                                r#"
+  %cb_{0}_ptr = getelementptr inbounds %chiya_callbacks, {3} %callbacks, i32 0, i32 {1}
+  %cb_{0} = load {2}, {4} %cb_{0}_ptr, align 8"#,
+                                name,
+                                i,
+                                ty,
+                                self.ptr_to("%chiya_callbacks"),
+                                self.ptr_to(ty)
+                            ))
+                            .collect::<String>(),
+                        callbacks
+                            .iter()
+                            .map(|(_, ty)| ty.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        tp,
+                        self.ptr_to("%chiya_callbacks")
+                    ),
+                    "%tape_len".to_owned(),
+                )
+            }
+            None => (
+                format!(
+                    r#"; emit_header()
+define i32 @main(){0} {{
+  %heap_i8 = call {5} @calloc(i64 {1}, i64 {2})
+  %heap_cell = bitcast {5} %heap_i8 to {6}
+  %ptr_init = getelementptr inbounds {3}, {6} %heap_cell, i64 {4}
+  %heap_int = ptrtoint {6} %heap_cell to i64"#,
+                    dbg,
+                    self.options.tape_size.0,
+                    self.align(),
+                    t,
+                    if self.options.bidirectional {
+                        self.options.tape_size.0 / 2
+                    } else {
+                        0
+                    },
+                    self.ptr_to("i8"),
+                    tp
+                ),
+                self.options.tape_size.0.to_string(),
+            ),
+        };
+
+        target
+            + &entry
+            + &format!(
+                r#"
+  %heap = alloca {0}, align 8
+  %ptr = alloca {0}, align 8
+  %len = alloca i64, align 8
+  %int = alloca i64, align 8
+  store {0} %heap_cell, {5} %heap, align 8
+  store {0} %ptr_init, {5} %ptr, align 8
+  store i64 {1}, {6} %len, align 8{4}{2}{3}{7}"#,
+                tp,
+                len,
+                if self.options.buffered_io {
+                    format!(
+                        "\n  %atexit = call i32 @atexit({} @chiya_flush)",
+                        self.ptr_to("void ()")
+                    )
+                } else {
+                    "".to_owned()
+                },
+                self.debug_variables(),
+                self.counters(),
+                self.ptr_to(&tp),
+                self.ptr_to("i64"),
+                if self.checks_empty_tape() {
+                    // Moving on an empty circular tape would divide by zero.
+                    "\n  %empty = icmp eq i64 %tape_len, 0\n  br i1 %empty, label %exit, label %start\nstart:"
+                } else {
+                    ""
+                }
+            )
+    }
+
+    // With limits, the fuel left and the number of bytes written.
+    fn counters(&self) -> String {
+        let mut s = "".to_owned();
+        if let Some(fuel) = self.options.fuel {
+            s += &format!(
+                "\n  %fuel = alloca i64, align 8\n  store i64 {}, {} %fuel, align 8",
+                fuel as i64,
+                self.ptr_to("i64")
+            );
+        }
+        if self.options.max_output.is_some() {
+            s += &format!(
+                "\n  %written = alloca i64, align 8\n  store i64 0, {} %written, align 8",
+                self.ptr_to("i64")
+            );
+        }
         s
     }
 
-    fn emit_loop_end(&mut self) -> String {
-        self.loop_stack
-            .pop_back()
-            .map(|n| {
+    // Declares the debugger-visible variables for the pointer and the cell.
+    fn debug_variables(&self) -> String {
+        if !self.options.debug_info {
+            return "".to_owned();
+        }
+        if self.options.ssa {
+            return debug_values(self.pointers(), &self.cell(), "%ptr_init", None);
+        }
+
+        format!(
+            r#"
+  call void @llvm.dbg.declare(metadata {0} %ptr, metadata !{1}, metadata !DIExpression())
+  call void @llvm.dbg.declare(metadata {0} %ptr, metadata !{2}, metadata !DIExpression(DW_OP_deref))"#,
+            self.ptr_to(&self.cell_ptr_type()),
+            DEBUG_PTR,
+            DEBUG_CELL
+        )
+    }
+
+    // The metadata nodes referred to by the debug info, numbered as `DEBUG_*`.
+    fn debug_metadata(&self) -> String {
+        let source = self.options.source.as_deref().unwrap_or("<stdin>");
+        let path = std::path::Path::new(source);
+        let filename = path
+            .file_name()
+            .map(|f| f.to_string_lossy())
+            .unwrap_or_else(|| source.into());
+        let directory = match path.parent().map(|p| p.to_string_lossy()) {
+            Some(p) if !p.is_empty() => p,
+            _ => ".".into(),
+        };
+
+        let locations = self
+            .debug_locations
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
                 format!(
-                    r#"
-  ; emit_loop_end()
-  br label %loop{0}_cond
-loop{0}_end:"#,
-                    n
+                    "\n!{} = !DILocation(line: {}, column: {}, scope: !{})",
+                    DEBUG_LOCATIONS + i,
+                    l.line,
+                    l.column,
+                    DEBUG_SUBPROGRAM
                 )
             })
-            .unwrap_or_else(|| "".to_owned())
-    }
+            .collect::<String>();
 
-    fn emit_header(&self) -> String {
-        r#"; emit_header()
-define i32 @main() {
-  %heap_i8 = call i8* @calloc(i64 30000, i64 4)
-  %heap_i32 = bitcast i8* %heap_i8 to i32*
-  %heap = alloca i32*, align 8
-  %ptr = alloca i32*, align 8
-  store i32* %heap_i32, i32** %heap, align 8
-  store i32* %heap_i32, i32** %ptr, align 8"#
-            .to_owned()
-    }
-
-    fn emit_footer(&self) -> String {
         format!(
             r#"
-  ; emit_footer()
-  %{0} = load i32*, i32** %heap, align 8
 
-  %{1} = bitcast i32* %{0} to i8*
-  call void @free(i8* %{1})
-  ret i32 0
-}}
+declare void @llvm.dbg.declare(metadata, metadata, metadata)
+declare void @llvm.dbg.value(metadata, metadata, metadata)
 
-declare i8* @calloc(i64, i64)
-declare void @free(i8*)
+!llvm.dbg.cu = !{{!0}}
+!llvm.module.flags = !{{!1, !2}}
+!0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !3, producer: "chiya", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug)
+!1 = !{{i32 7, !"Dwarf Version", i32 4}}
+!2 = !{{i32 2, !"Debug Info Version", i32 3}}
+!3 = !DIFile(filename: "{0}", directory: "{1}")
+!4 = distinct !DISubprogram(name: "{4}", scope: !3, file: !3, line: 1, type: !5, scopeLine: 1, spFlags: DISPFlagDefinition, unit: !0)
+!5 = !DISubroutineType(types: !6)
+!6 = !{{!7}}
+!7 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!8 = !DIBasicType(name: "cell", size: {2}, encoding: DW_ATE_signed)
+!9 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !8, size: 64)
+!10 = !DILocalVariable(name: "ptr", scope: !4, file: !3, line: 1, type: !9)
+!11 = !DILocalVariable(name: "cell", scope: !4, file: !3, line: 1, type: !8)
+!12 = !DILocation(line: 1, column: 1, scope: !4){3}"#,
+            escape(&filename),
+            escape(&directory),
+            self.options.cell_width.bits(),
+            locations,
+            self.options.function.as_deref().unwrap_or("main")
+        )
+    }
+
+    // Branches to 'exit' with `status`, or the value of the current cell if it is None.
+    fn exit(&mut self, status: Option<i32>) -> String {
+        let t = self.cell();
+        // With `ssa`, the cell must reach the tape, which the caller of `function` reads.
+        let s = self.flush();
+        let (s, status) = match status {
+            Some(n) => (s, n.to_string()),
+            None => {
+                let (c, _, value) = self.load_cell();
+                let (cast, value) = self.cast(&value, &t, "i32");
+                (s + &c + &cast, value)
+            }
+        };
+        self.exits.push((status, self.block.clone()));
+
+        s + "\n  br label %exit"
+    }
+
+    fn ir_exit(&mut self, status: Option<i32>) -> String {
+        let s = format!(
+            "\n  ; emit_exit({})",
+            status.map_or("*ptr".to_owned(), |n| n.to_string())
+        ) + &self.burn()
+            + &self.exit(status);
+
+        // The following statements are unreachable.
+        let l = self.next_label();
+        s + &self.label(format!("exit{}_after", l))
+    }
+
+    // The status of the program from the blocks branching to 'exit'.
+    fn exit_status(&self) -> String {
+        format!(
+            "%exit_status = phi i32 {}",
+            self.exits
+                .iter()
+                .map(|(status, block)| format!("[ {}, %{} ]", status, block))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+
+    fn ir_footer(&mut self) -> String {
+        let t = self.cell();
+        // Every way to end the program frees the tape (and flushes the output) here.
+        let s = self.exit(match self.options.exit_status {
+            ExitStatus::Zero => Some(0),
+            ExitStatus::Cell => None,
+        }) + &self.label("exit".to_owned());
+        let overflow = match self.options.overflow {
+            Overflow::Wrap => "".to_owned(),
+            Overflow::Saturate => format!(
+                "\ndeclare {0} @llvm.sadd.sat.{0}({0}, {0})",
+                t
+            ),
+            Overflow::Trap => format!(
+                "\ndeclare {{{0}, i1}} @llvm.sadd.with.overflow.{0}({0}, {0})\ndeclare void @llvm.trap()",
+                t
+            ),
+        };
+        let (p, function) = (self.pointers(), self.options.function.is_some());
+        let grow = match self.options.tape_mode {
+            TapeMode::Grow => chiya_grow(p),
+            _ => "".to_owned(),
+        };
+        let bounds = if self.options.bounds_check {
+            chiya_out_of_bounds(p, function)
+        } else {
+            "".to_owned()
+        };
+        let limits = self.limits();
+        let limit = if limits.is_empty() {
+            "".to_owned()
+        } else {
+            chiya_limit(p, function)
+                + &limits
+                    .iter()
+                    .map(|(name, message)| {
+                        format!(
+                            "\n@.{0} = private unnamed_addr constant [{1} x i8] c\"{2}\\00\"",
+                            name,
+                            message.len() + 1,
+                            message
+                        )
+                    })
+                    .collect::<String>()
+        };
+        let report = if self.options.bounds_check || !limits.is_empty() {
+            report(p, function)
+        } else {
+            "".to_owned()
+        };
+        let int_length = match self.options.max_output {
+            Some(_) => INT_LENGTH,
+            None => "",
+        };
+        let buffered_io = if self.options.buffered_io {
+            buffered_io(p)
+        } else {
+            "".to_owned()
+        };
+        let trap = match self.options.overflow {
+            Overflow::Trap => "\n\noverflow:\n  call void @llvm.trap()\n  unreachable".to_owned(),
+            _ => "".to_owned(),
+        };
+
+        let (end, libc) = match self.options.function {
+            // The tape and the I/O belong to the caller.
+            Some(_) => (
+                format!("  {}\n  ret i32 %exit_status", self.exit_status()),
+                "".to_owned(),
+            ),
+            None => {
+                let (heap, heap_i8) = (self.var(), self.var());
+                (
+                    format!(
+                        r#"  {0}
+  {1} = load {3}, {4} %heap, align 8
+  {2} = bitcast {3} {1} to {5}
+  call void @free({5} {2})
+  ret i32 %exit_status"#,
+                        self.exit_status(),
+                        heap,
+                        heap_i8,
+                        self.cell_ptr_type(),
+                        self.ptr_to(&self.cell_ptr_type()),
+                        p.to("i8")
+                    ),
+                    format!(
+                        r#"
+declare {0} @calloc(i64, i64)
+declare void @free({0})
 declare i32 @getchar()
-declare i32 @putchar(i32)"#,
-            self.variable_idx,
-            self.variable_idx + 1
+declare i32 @putchar(i32)
+declare i32 @printf({0}, ...)
+declare i32 @scanf({0}, ...)
+
+@.fmt_int = private unnamed_addr constant [5 x i8] c"%lld\00""#,
+                        p.to("i8")
+                    ),
+                )
+            }
+        };
+
+        format!(
+            r#"
+  ; emit_footer(){0}
+{1}{2}
+}}
+{3}{4}{5}{6}{7}{8}{9}{10}"#,
+            s, end, trap, libc, overflow, grow, bounds, limit, report, int_length, buffered_io
         )
     }
 }
+
+impl emitter::Emitter for LLVM {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        let s = self.ir_move_ptr(offset);
+        self.finish(s)
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        let s = self.ir_add(n);
+        self.finish(s)
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        let s = self.ir_call_putchar();
+        self.finish(s)
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        let s = self.ir_call_getchar();
+        self.finish(s)
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        let s = self.ir_call_putint();
+        self.finish(s)
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        let s = self.ir_call_getint();
+        self.finish(s)
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let s = self.ir_loop_begin();
+        self.finish(s)
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        // The code closing the loop belongs to the loop statement.
+        if let Some((_, location)) = self.loop_stack.back() {
+            self.location = *location;
+        }
+        let s = self.ir_loop_end();
+        self.finish(s)
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        let s = self.ir_exit(status);
+        self.finish(s)
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        let s = self.ir_header();
+        if self.options.debug_info {
+            return attach_dbg(&s, DEBUG_SCOPE);
+        }
+        s
+    }
+
+    fn emit_footer(&mut self) -> String {
+        let s = self.ir_footer();
+        if self.options.debug_info {
+            // Only the end of `main` gets locations.
+            let (main, rest) = s.split_at(s.find("\n}\n").unwrap_or(s.len()));
+            return attach_dbg(main, DEBUG_SCOPE) + rest + &self.debug_metadata();
+        }
+        s
+    }
+}
+
+// Compiles `src` to IR for the installed LLVM, builds it with `llc` and `cc` together with the C
+// source `host`, which can include the header of `function`, and runs it on `input`. Returns the
+// output, the error output and the exit status, or None if there is no `llc` or `cc`.
+#[cfg(test)]
+fn run(src: &str, options: &Options, host: &str, input: &str) -> Option<(String, String, i32)> {
+    run_with(options, host, input, |e| crate::codegen::compile(e, src))
+}
+
+// Same as `run`, but generates the IR with `gen` from an emitter for the installed LLVM.
+#[cfg(test)]
+pub(super) fn run_with(
+    options: &Options,
+    host: &str,
+    input: &str,
+    gen: impl FnOnce(&mut LLVM) -> String,
+) -> Option<(String, String, i32)> {
+    use crate::codegen::options::LlvmVersion;
+    use crate::codegen::{build_step, has_tool};
+    use std::process::Command;
+
+    crate::codegen::run_built("llvm", input, |dir| {
+        if !has_tool("llc") || !has_tool("cc") {
+            return None;
+        }
+        // e.g. "  LLVM version 14.0.6"
+        let version = Command::new("llc")
+            .arg("--version")
+            .output()
+            .unwrap()
+            .stdout;
+        let version = String::from_utf8(version).unwrap();
+        let mut options = options.clone();
+        options.llvm_version = LlvmVersion(
+            version
+                .split("LLVM version ")
+                .nth(1)?
+                .split('.')
+                .next()?
+                .parse()
+                .ok()?,
+        );
+
+        let (ll, o, exe) = (dir.join("main.ll"), dir.join("main.o"), dir.join("main"));
+        let code = gen(&mut LLVM::with_options(&options));
+        std::fs::write(&ll, code).unwrap();
+        build_step(
+            Command::new("llc")
+                .args(["-filetype=obj", "-relocation-model=pic", "-o"])
+                .arg(&o)
+                .arg(&ll),
+        );
+        let mut cc = Command::new("cc");
+        cc.arg("-o").arg(&exe).arg(&o);
+        if let (Some(name), Some(header)) = (&options.function, c::header(&options)) {
+            let c = dir.join("host.c");
+            std::fs::write(dir.join(format!("{}.h", name)), header).unwrap();
+            std::fs::write(&c, host).unwrap();
+            cc.arg(&c);
+        }
+        build_step(&mut cc);
+        Some(Command::new(exe))
+    })
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        let (output, error, status) = run(src, options, "", input)?;
+        assert_eq!((error.as_str(), status), ("", 0));
+        Some(output)
+    });
+}
+
+#[test]
+fn test_emit_add() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::CellWidth;
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let mut e = LLVM::with_options(&options);
+    assert!(e.emit_add(300).contains("add i8 %2, 44"));
+
+    options.overflow = Overflow::Saturate;
+    let mut e = LLVM::with_options(&options);
+    let s = e.emit_add(200);
+    assert!(s.contains("@llvm.sadd.sat.i8(i8 %2, i8 127)"));
+    assert!(s.contains("@llvm.sadd.sat.i8(i8 %3, i8 73)"));
+
+    options.overflow = Overflow::Trap;
+    let mut e = LLVM::with_options(&options);
+    assert!(e
+        .emit_add(1)
+        .contains("br i1 %5, label %overflow, label %add1_ok"));
+    assert!(e.emit_footer().contains("call void @llvm.trap()"));
+}
+
+#[test]
+fn test_bounds_check() {
+    use crate::codegen::emitter::Emitter;
+
+    let mut e = LLVM::new();
+    assert!(!e.emit_call_putchar().contains("@chiya_out_of_bounds"));
+    assert!(e.emit_move_ptr(-1).contains("getelementptr inbounds i32"));
+    assert!(!e.emit_footer().contains("@chiya_out_of_bounds"));
+
+    let mut options = Options::new();
+    options.bounds_check = true;
+    let mut e = LLVM::with_options(&options);
+    e.set_location(Location { line: 2, column: 5 });
+    assert!(e
+        .emit_call_putchar()
+        .contains("call void @chiya_out_of_bounds(i64 2, i64 5, i64 %5)"));
+    // The pointer is checked after it has left the tape.
+    assert!(e.emit_move_ptr(-1).contains("getelementptr i32"));
+    assert!(e.emit_footer().contains("@chiya_out_of_bounds"));
+}
+
+#[test]
+fn test_ssa() {
+    use crate::codegen::emitter::Emitter;
+
+    let mut options = Options::new();
+    options.ssa = true;
+    let mut e = LLVM::with_options(&options);
+    let s = e.emit_add(1) + &e.emit_add(2) + &e.emit_move_ptr(1);
+    assert_eq!(s.matches("load ").count(), 1);
+    assert_eq!(s.matches("store ").count(), 1);
+    assert!(!s.contains("%ptr,"));
+
+    let s = e.emit_loop_begin();
+    assert!(s.contains("%loop1_ptr = phi ptr [ %4, %0 ], [ %loop1_ptr_back, %loop1_latch ]"));
+    assert!(e.emit_loop_end().contains("br label %loop1_latch"));
+    assert!(!e.emit_call_putchar().contains("load "));
+}
+
+#[test]
+fn test_debug_info() {
+    use crate::codegen::emitter::Emitter;
+
+    let mut options = Options::new();
+    options.debug_info = true;
+    options.source = Some("dir/a.chiya".to_owned());
+    let mut e = LLVM::with_options(&options);
+    assert!(e.emit_header().contains("define i32 @main() !dbg !4 {"));
+    e.set_location(Location { line: 3, column: 2 });
+    assert!(e
+        .emit_add(1)
+        .ends_with("store i32 %3, ptr %1, align 4, !dbg !13"));
+    e.set_location(Location { line: 4, column: 2 });
+    assert!(e.emit_call_putchar().ends_with("!dbg !14"));
+
+    let s = e.emit_footer();
+    assert!(s.contains("ret i32 %exit_status, !dbg !12"));
+    assert!(s.contains(r#"!DIFile(filename: "a.chiya", directory: "dir")"#));
+    assert!(s.contains("!14 = !DILocation(line: 4, column: 2, scope: !4)"));
+}
+
+#[test]
+fn test_function() {
+    use crate::codegen::emitter::Emitter;
+
+    assert_eq!(LLVM::new().c_header(), None);
+
+    let mut options = Options::new();
+    options.function = Some("run".to_owned());
+    options.cell_width = crate::codegen::options::CellWidth::W8;
+    let mut e = LLVM::with_options(&options);
+    assert!(e
+        .emit_header()
+        .contains("define i32 @run(ptr %tape, i64 %tape_len, ptr %callbacks) {"));
+    assert!(e
+        .emit_call_putchar()
+        .contains("call void %cb_putchar(ptr %cb_data, i32 %3)"));
+    let s = e.emit_footer();
+    assert!(s.contains("ret i32 %exit_status"));
+    assert!(!s.contains("@free"));
+    assert!(e.c_header().unwrap().contains(
+        "int32_t run(int8_t *tape, int64_t len, const struct chiya_callbacks *callbacks);"
+    ));
+}
+
+#[test]
+fn test_function_ssa() {
+    let host = r#"#include <stdio.h>
+#include "run.h"
+
+int main(void) {
+  int32_t tape[4] = {0};
+  struct chiya_callbacks callbacks = {0};
+  run(tape, 4, &callbacks);
+  printf("%d %d", tape[0], tape[1]);
+  return 0;
+}
+"#;
+    let mut options = Options::new();
+    options.function = Some("run".to_owned());
+    options.ssa = true;
+    let src = "*ptr += 5;\nptr += 1;\n*ptr += 7;\n";
+    let output = match run(src, &options, host, "") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("5 7".to_owned(), "".to_owned(), 0));
+
+    let src = "*ptr += 5;\nptr += 1;\n*ptr += 7;\nexit(0);\n*ptr += 1;\n";
+    assert_eq!(run(src, &options, host, "").unwrap().0, "5 7");
+}
+
+#[test]
+fn test_function_exit() {
+    let host = r#"#include <stdio.h>
+#include "run.h"
+
+int main(void) {
+  int32_t tape[4] = {0};
+  struct chiya_callbacks callbacks = {0};
+  printf("%d", run(tape, 4, &callbacks));
+  return 0;
+}
+"#;
+    let mut options = Options::new();
+    options.function = Some("run".to_owned());
+    let src = "*ptr += 7;\nwhile *ptr {\n  exit(*ptr);\n}\nexit(3);\n";
+    let output = match run(src, &options, host, "") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("7".to_owned(), "".to_owned(), 0));
+    options.ssa = true;
+    assert_eq!(run(src, &options, host, "").unwrap().0, "7");
+
+    options.exit_status = ExitStatus::Cell;
+    assert_eq!(run("*ptr += 4;\n", &options, host, "").unwrap().0, "4");
+}
+
+#[test]
+fn test_function_failure() {
+    let host = r#"#include <stdio.h>
+#include "run.h"
+
+int main(void) {
+  int32_t tape[4] = {0};
+  struct chiya_callbacks callbacks = {0};
+  int32_t status = run(tape, 4, &callbacks);
+  printf("%d %d", status, run(tape, 0, &callbacks));
+  return 0;
+}
+"#;
+    // Errors are returned to the caller instead of exiting.
+    let mut options = Options::new();
+    options.function = Some("run".to_owned());
+    options.bounds_check = true;
+    let src = "*ptr += 1;\nptr -= 1;\n*ptr += 1;\n";
+    let output = match run(src, &options, host, "") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(
+        output,
+        (
+            "1 1".to_owned(),
+            "chiya: 3:1: pointer out of bounds (-1)\nchiya: 1:1: pointer out of bounds (0)\n"
+                .to_owned(),
+            0
+        )
+    );
+
+    // An empty circular tape has no cell to wrap around to.
+    options.bounds_check = false;
+    options.tape_mode = TapeMode::Wrap;
+    let src = "ptr -= 1;\n*ptr += 1;\n";
+    assert_eq!(
+        run(src, &options, host, "").unwrap(),
+        ("0 1".to_owned(), "".to_owned(), 0)
+    );
+}
+
+#[test]
+fn test_buffered_io() {
+    use crate::codegen::emitter::Emitter;
+
+    let mut options = Options::new();
+    options.buffered_io = true;
+    let mut e = LLVM::with_options(&options);
+    assert!(e
+        .emit_header()
+        .contains("call i32 @atexit(ptr @chiya_flush)"));
+    assert!(e
+        .emit_call_putchar()
+        .contains("call i32 @chiya_putchar(i32 %2)"));
+    assert!(e.emit_call_getchar().contains("call i32 @chiya_getchar()"));
+    assert!(e
+        .emit_call_putint()
+        .contains("call void @chiya_putint(i64 %"));
+    assert!(e
+        .emit_call_getint()
+        .contains("call i32 @chiya_getint(ptr %int)"));
+    assert!(e
+        .emit_footer()
+        .contains("call i64 @write(i32 1, ptr %p, i64 %rest)"));
+}
+
+#[test]
+fn test_buffered_getint() {
+    use crate::codegen::options::CellWidth;
+
+    // Numbers out of range saturate like with scanf.
+    let src = "getint();\nputint();\nptr += 1;\n*ptr += 32;\nputchar();\nptr += 1;\ngetint();\nputint();\n";
+    let input = "99999999999999999999 -99999999999999999999";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W64;
+    for buffered_io in [false, true] {
+        options.buffered_io = buffered_io;
+        if let Some((output, _, _)) = run(src, &options, "", input) {
+            assert_eq!(output, "9223372036854775807 -9223372036854775808");
+        }
+    }
+}
+
+#[test]
+fn test_move_ptr_wrap() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::TapeSize;
+
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(8);
+    let mut e = LLVM::with_options(&options);
+    assert!(e
+        .emit_move_ptr(-1)
+        .contains("add i64 %4, 7\n  %6 = and i64 %5, 7"));
+    assert!(!e.emit_move_ptr(16).contains("getelementptr"));
+
+    options.tape_size = TapeSize(10);
+    let mut e = LLVM::with_options(&options);
+    assert!(e.emit_move_ptr(-1).contains("icmp uge i64 %5, 10"));
+}
+
+#[test]
+fn test_pointers() {
+    let typed = Pointers { typed: true };
+    assert_eq!(typed.to(&typed.to("i32")), "i32**");
+    assert_eq!(typed.to("i32 (i8*)"), "i32 (i8*)*");
+    assert_eq!(typed.intrinsic(), "p0i8");
+
+    let opaque = Pointers { typed: false };
+    assert_eq!(opaque.to(&opaque.to("i32")), "ptr");
+    assert_eq!(opaque.intrinsic(), "p0");
+    assert!(!chiya_grow(opaque).contains('*'));
+    assert!(!buffered_io(opaque).contains('*'));
+    assert!(callbacks(opaque).iter().all(|(_, ty)| ty == "ptr"));
+}
+
+#[test]
+fn test_target() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::LlvmVersion;
+
+    assert_eq!(datalayout("riscv64-unknown-linux-gnu", 17), None);
+    assert!(datalayout("x86_64-pc-linux-gnu", 18)
+        .unwrap()
+        .contains("-i128:128-"));
+
+    let mut options = Options::new();
+    assert!(!LLVM::with_options(&options)
+        .emit_header()
+        .contains("target"));
+    assert!(LLVM::with_options(&options)
+        .emit_header()
+        .contains("alloca ptr"));
+
+    options.target = Some("x86_64-apple-darwin".to_owned());
+    options.llvm_version = LlvmVersion(14);
+    let header = LLVM::with_options(&options).emit_header();
+    assert!(header.starts_with("target datalayout = \"e-m:o-"));
+    assert!(header.contains("target triple = \"x86_64-apple-darwin\"\n"));
+    assert!(header.contains("alloca i32*"));
+}
+
+#[test]
+fn test_limits() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::TapeSize;
+
+    let mut e = LLVM::new();
+    assert!(!e.emit_add(1).contains("%fuel"));
+    assert!(!e.emit_footer().contains("@chiya_limit"));
+
+    let mut options = Options::new();
+    options.fuel = Some(1000);
+    options.max_output = Some(10);
+    options.max_tape = Some(100);
+    options.tape_mode = TapeMode::Grow;
+    options.tape_size = TapeSize(10);
+    let mut e = LLVM::with_options(&options);
+    let s = e.emit_header();
+    assert!(s.contains("store i64 1000, ptr %fuel, align 8"));
+    assert!(s.contains("store i64 0, ptr %written, align 8"));
+    e.set_location(Location { line: 2, column: 3 });
+    let s = e.emit_add(1);
+    assert!(s.contains("%1 = load i64, ptr %fuel, align 8\n  %2 = icmp eq i64 %1, 0"));
+    assert!(s.contains("call void @chiya_limit(ptr getelementptr inbounds ([12 x i8], ptr @.out_of_fuel, i64 0, i64 0), i64 2, i64 3)"));
+    assert!(e
+        .emit_move_ptr(1)
+        .contains("call i64 @chiya_grow(ptr %19, ptr %len, i64 %16, i64 4, i64 100)"));
+    assert!(e
+        .emit_call_putint()
+        .contains("call i64 @chiya_int_length(i64 %"));
+    assert!(e.emit_call_putchar().contains("icmp ugt i64 %"));
+    let s = e.emit_footer();
+    assert!(s.contains(r#"@.tape_limit = private unnamed_addr constant [33 x i8] c"tape limit of 100 cells exceeded\00""#));
+    assert_eq!(s.matches("declare void @exit(i32)").count(), 1);
+}
@@ -0,0 +1,515 @@
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, failure::Fail)]
+pub enum OptionsError {
+    #[fail(display = "unknown option: '{}'", name)]
+    UnknownOption { name: String },
+
+    #[fail(display = "invalid value for option '{}': '{}'", name, value)]
+    InvalidValue { name: String, value: String },
+
+    #[fail(display = "invalid pragma: '{}'", pragma)]
+    InvalidPragma { pragma: String },
+
+    #[fail(display = "option '{}' cannot be used with '{}'", name, other)]
+    Conflict { name: String, other: String },
+}
+
+// What `getchar()` (and `getint()`) stores to *ptr when the input is exhausted.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Eof {
+    Zero, // '0'
+    #[default]
+    MinusOne, // '-1'
+    Unchanged, // 'unchanged'
+}
+
+impl FromStr for Eof {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Eof::Zero),
+            "-1" => Ok(Eof::MinusOne),
+            "unchanged" => Ok(Eof::Unchanged),
+            _ => Err(()),
+        }
+    }
+}
+
+// The exit status of a program reaching its end.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    #[default]
+    Zero, // '0'
+    Cell, // 'cell', the value of the current cell
+}
+
+impl FromStr for ExitStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(ExitStatus::Zero),
+            "cell" => Ok(ExitStatus::Cell),
+            _ => Err(()),
+        }
+    }
+}
+
+// The width of a cell. Cells are signed integers of this width.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum CellWidth {
+    W8,  // '8'
+    W16, // '16'
+    #[default]
+    W32, // '32'
+    W64, // '64'
+}
+
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::W8 => 8,
+            CellWidth::W16 => 16,
+            CellWidth::W32 => 32,
+            CellWidth::W64 => 64,
+        }
+    }
+
+    pub fn bytes(self) -> u32 {
+        self.bits() / 8
+    }
+
+    pub fn min(self) -> i64 {
+        i64::MIN >> (64 - self.bits())
+    }
+
+    pub fn max(self) -> i64 {
+        i64::MAX >> (64 - self.bits())
+    }
+
+    // Wraps `n` around into the range of the cell.
+    pub fn wrap(self, n: i64) -> i64 {
+        (n << (64 - self.bits())) >> (64 - self.bits())
+    }
+
+    // Splits `n` into addends that fit in a cell, such that adding them one by one with a
+    // saturating (or trapping) addition has the same effect as adding `n` at once.
+    pub fn split_addend(self, n: i64) -> Vec<i64> {
+        if self.min() <= n && n <= self.max() {
+            return vec![n];
+        }
+
+        // Anything beyond ±2^bits overflows regardless of the value of the cell.
+        let range = 1i128 << self.bits();
+        let mut n = (n as i128).max(-range).min(range);
+        let mut addends = Vec::new();
+        while n != 0 {
+            let a = n.max(self.min() as i128).min(self.max() as i128);
+            addends.push(a as i64);
+            n -= a;
+        }
+        addends
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::W8),
+            "16" => Ok(CellWidth::W16),
+            "32" => Ok(CellWidth::W32),
+            "64" => Ok(CellWidth::W64),
+            _ => Err(()),
+        }
+    }
+}
+
+// What happens when an addition to a cell overflows.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    #[default]
+    Wrap, // 'wrap'
+    Saturate, // 'saturate'
+    Trap,     // 'trap'
+}
+
+impl FromStr for Overflow {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(Overflow::Wrap),
+            "saturate" => Ok(Overflow::Saturate),
+            "trap" => Ok(Overflow::Trap),
+            _ => Err(()),
+        }
+    }
+}
+
+// What happens when the pointer leaves the tape.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TapeMode {
+    #[default]
+    Fixed, // 'fixed', undefined behavior
+    Grow, // 'grow', the tape is reallocated to cover the new position
+    Wrap, // 'wrap', the position is taken modulo the tape size
+}
+
+impl FromStr for TapeMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(TapeMode::Fixed),
+            "grow" => Ok(TapeMode::Grow),
+            "wrap" => Ok(TapeMode::Wrap),
+            _ => Err(()),
+        }
+    }
+}
+
+// The number of cells, which must not be zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TapeSize(pub usize);
+
+impl Default for TapeSize {
+    fn default() -> Self {
+        TapeSize(30000)
+    }
+}
+
+impl FromStr for TapeSize {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(0) | Err(_) => Err(()),
+            Ok(n) => Ok(TapeSize(n)),
+        }
+    }
+}
+
+// The major version of LLVM the IR is written for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LlvmVersion(pub u32);
+
+impl LlvmVersion {
+    // Typed pointers were replaced by opaque pointers in LLVM 15.
+    pub fn typed_pointers(self) -> bool {
+        self.0 < 15
+    }
+}
+
+impl Default for LlvmVersion {
+    fn default() -> Self {
+        LlvmVersion(17)
+    }
+}
+
+impl FromStr for LlvmVersion {
+    type Err = ();
+
+    // Accepts either a major version or a full one, e.g. '14' or '14.0.6'.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split('.')
+            .next()
+            .and_then(|s| s.parse().ok())
+            .map(LlvmVersion)
+            .ok_or(())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Options {
+    pub eof: Eof,
+    pub cell_width: CellWidth,
+    pub overflow: Overflow,
+    pub tape_size: TapeSize,
+    pub tape_mode: TapeMode,
+    // If true, the pointer starts in the middle of the tape instead of its beginning.
+    pub bidirectional: bool,
+    // If true, every access to a cell checks that the pointer is inside the tape.
+    pub bounds_check: bool,
+    // The target triple, e.g. 'x86_64-pc-linux-gnu'.
+    pub target: Option<String>,
+    pub llvm_version: LlvmVersion,
+    // If true, the LLVM backend keeps the pointer and the current cell in SSA values instead of
+    // reloading them from memory for each operation.
+    pub ssa: bool,
+    // If true, the LLVM backend emits DWARF debug info referring to `source`.
+    pub debug_info: bool,
+    pub source: Option<String>,
+    // If set, the LLVM backend emits a function of this name running the program on a tape and
+    // I/O callbacks given by the caller, instead of `main`.
+    pub function: Option<String>,
+    // If true, the output is written with write(2) from a buffer flushed when it fills, before
+    // reading input and at exit, and the input is read with read(2) into a buffer.
+    pub buffered_io: bool,
+    pub exit_status: ExitStatus,
+    // Limits for running untrusted programs: the number of instructions run, the number of cells
+    // a growing tape may reach, and the number of bytes written.
+    pub fuel: Option<u64>,
+    pub max_tape: Option<usize>,
+    pub max_output: Option<u64>,
+}
+
+impl Options {
+    // The names accepted by `Options::set`.
+    pub const NAMES: &'static [&'static str] = &[
+        "eof",
+        "cell-width",
+        "overflow",
+        "tape-size",
+        "tape-mode",
+        "bidirectional",
+        "bounds-check",
+        "target",
+        "llvm-version",
+        "ssa",
+        "debug-info",
+        "source",
+        "function",
+        "buffered-io",
+        "exit-status",
+        "fuel",
+        "max-tape",
+        "max-output",
+    ];
+
+    // The names of boolean options, which are given as flags on the command line.
+    pub const FLAGS: &'static [&'static str] = &[
+        "bidirectional",
+        "bounds-check",
+        "ssa",
+        "debug-info",
+        "buffered-io",
+    ];
+
+    pub fn new() -> Options {
+        Default::default()
+    }
+
+    // Sets the option `name` from its textual representation. The names are shared by the
+    // command line (`--eof -1`) and the source pragmas (`// chiya: eof=-1`).
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), OptionsError> {
+        fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, OptionsError> {
+            value.parse().map_err(|_| OptionsError::InvalidValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+        }
+
+        match name {
+            "eof" => self.eof = parse(name, value)?,
+            "cell-width" => self.cell_width = parse(name, value)?,
+            "overflow" => self.overflow = parse(name, value)?,
+            "tape-size" => self.tape_size = parse(name, value)?,
+            "tape-mode" => self.tape_mode = parse(name, value)?,
+            "bidirectional" => self.bidirectional = parse(name, value)?,
+            "bounds-check" => self.bounds_check = parse(name, value)?,
+            "target" => self.target = Some(value.to_string()),
+            "llvm-version" => self.llvm_version = parse(name, value)?,
+            "ssa" => self.ssa = parse(name, value)?,
+            "debug-info" => self.debug_info = parse(name, value)?,
+            "source" => self.source = Some(value.to_string()),
+            "function" => {
+                let mut chars = value.chars();
+                let identifier = chars
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !identifier {
+                    return Err(OptionsError::InvalidValue {
+                        name: name.to_string(),
+                        value: value.to_string(),
+                    });
+                }
+                self.function = Some(value.to_string())
+            }
+            "buffered-io" => self.buffered_io = parse(name, value)?,
+            "exit-status" => self.exit_status = parse(name, value)?,
+            "fuel" => self.fuel = Some(parse(name, value)?),
+            "max-tape" => self.max_tape = Some(parse(name, value)?),
+            "max-output" => self.max_output = Some(parse(name, value)?),
+            _ => {
+                return Err(OptionsError::UnknownOption {
+                    name: name.to_string(),
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks that the options can be used together.
+    pub fn check(&self) -> Result<(), OptionsError> {
+        // The tape given to a function is owned by the caller, so it cannot be reallocated.
+        if self.function.is_some() && self.tape_mode == TapeMode::Grow {
+            return Err(OptionsError::Conflict {
+                name: "function".to_string(),
+                other: "tape-mode=grow".to_string(),
+            });
+        }
+        // The I/O of a function is done by the callbacks.
+        if self.function.is_some() && self.buffered_io {
+            return Err(OptionsError::Conflict {
+                name: "function".to_string(),
+                other: "buffered-io".to_string(),
+            });
+        }
+        // Only a growing tape can be limited below its initial size.
+        if self.max_tape.is_some_and(|max| self.tape_size.0 > max) {
+            return Err(OptionsError::Conflict {
+                name: "max-tape".to_string(),
+                other: format!("tape-size={}", self.tape_size.0),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Applies every `// chiya: name=value ...` line found in `src`.
+    pub fn apply_pragmas(&mut self, src: &str) -> Result<(), OptionsError> {
+        for pragma in src.lines().filter_map(pragma) {
+            for setting in pragma.split_whitespace() {
+                let mut kv = setting.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(name), Some(value)) => self.set(name, value)?,
+                    _ => {
+                        return Err(OptionsError::InvalidPragma {
+                            pragma: setting.to_string(),
+                        })
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Returns the settings part of `line` if it is a pragma line.
+pub fn pragma(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with("//") && line[2..].trim_start().starts_with("chiya:") {
+        line[2..].trim_start().get("chiya:".len()..)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_set() {
+    let mut o = Options::new();
+    assert_eq!(o.eof, Eof::MinusOne);
+    assert_eq!(o.set("eof", "0"), Ok(()));
+    assert_eq!(o.eof, Eof::Zero);
+    assert_eq!(o.set("eof", "unchanged"), Ok(()));
+    assert_eq!(o.eof, Eof::Unchanged);
+    assert_eq!(
+        o.set("eof", "1"),
+        Err(OptionsError::InvalidValue {
+            name: "eof".to_owned(),
+            value: "1".to_owned()
+        })
+    );
+    assert_eq!(o.set("cell-width", "8"), Ok(()));
+    assert_eq!(o.cell_width, CellWidth::W8);
+    assert_eq!(o.set("overflow", "trap"), Ok(()));
+    assert_eq!(o.overflow, Overflow::Trap);
+    assert!(o.set("cell-width", "7").is_err());
+    assert_eq!(o.set("tape-size", "100"), Ok(()));
+    assert_eq!(o.tape_size, TapeSize(100));
+    assert!(o.set("tape-size", "0").is_err());
+    assert_eq!(o.set("tape-mode", "grow"), Ok(()));
+    assert_eq!(o.tape_mode, TapeMode::Grow);
+    assert_eq!(o.set("tape-mode", "wrap"), Ok(()));
+    assert_eq!(o.tape_mode, TapeMode::Wrap);
+    assert_eq!(o.set("bidirectional", "true"), Ok(()));
+    assert!(o.bidirectional);
+    assert_eq!(o.set("llvm-version", "14.0.6"), Ok(()));
+    assert_eq!(o.llvm_version, LlvmVersion(14));
+    assert!(o.llvm_version.typed_pointers());
+    assert_eq!(o.set("exit-status", "cell"), Ok(()));
+    assert_eq!(o.exit_status, ExitStatus::Cell);
+    assert!(o.set("exit-status", "1").is_err());
+    assert_eq!(o.set("exit-status", "0"), Ok(()));
+    assert_eq!(o.set("fuel", "1000"), Ok(()));
+    assert_eq!(o.fuel, Some(1000));
+    assert!(o.set("fuel", "-1").is_err());
+    assert_eq!(o.set("max-output", "10"), Ok(()));
+    assert_eq!(o.max_output, Some(10));
+    assert_eq!(o.set("max-tape", "50"), Ok(()));
+    assert_eq!(
+        o.check(),
+        Err(OptionsError::Conflict {
+            name: "max-tape".to_owned(),
+            other: "tape-size=100".to_owned()
+        })
+    );
+    assert_eq!(o.set("max-tape", "100"), Ok(()));
+    assert_eq!(o.set("function", "run_1"), Ok(()));
+    assert_eq!(o.function, Some("run_1".to_owned()));
+    assert!(o.set("function", "1run").is_err());
+    assert!(o.set("function", "").is_err());
+    assert_eq!(o.check(), Ok(()));
+    assert_eq!(o.set("tape-mode", "grow"), Ok(()));
+    assert_eq!(
+        o.check(),
+        Err(OptionsError::Conflict {
+            name: "function".to_owned(),
+            other: "tape-mode=grow".to_owned()
+        })
+    );
+    assert_eq!(
+        o.set("hoge", "1"),
+        Err(OptionsError::UnknownOption {
+            name: "hoge".to_owned()
+        })
+    );
+}
+
+#[test]
+fn test_cell_width() {
+    assert_eq!(CellWidth::W8.min(), -128);
+    assert_eq!(CellWidth::W8.max(), 127);
+    assert_eq!(CellWidth::W64.min(), i64::MIN);
+    assert_eq!(CellWidth::W64.max(), i64::MAX);
+
+    assert_eq!(CellWidth::W8.wrap(255), -1);
+    assert_eq!(CellWidth::W8.wrap(256), 0);
+    assert_eq!(CellWidth::W8.wrap(-129), 127);
+    assert_eq!(CellWidth::W16.wrap(65537), 1);
+
+    assert_eq!(CellWidth::W8.split_addend(100), vec![100]);
+    assert_eq!(CellWidth::W8.split_addend(200), vec![127, 73]);
+    assert_eq!(CellWidth::W8.split_addend(-200), vec![-128, -72]);
+    assert_eq!(CellWidth::W8.split_addend(100_000), vec![127, 127, 2]);
+    assert_eq!(CellWidth::W8.split_addend(-100_000), vec![-128, -128]);
+    assert_eq!(CellWidth::W32.split_addend(-100_000), vec![-100_000]);
+}
+
+#[test]
+fn test_apply_pragmas() {
+    assert_eq!(pragma("// chiya: eof=0"), Some(" eof=0"));
+    assert_eq!(pragma("  //chiya:eof=0"), Some("eof=0"));
+    assert_eq!(pragma("// comment"), None);
+    assert_eq!(pragma("ptr += 1; // chiya: eof=0"), None);
+
+    let mut o = Options::new();
+    assert_eq!(
+        o.apply_pragmas("// hoge\n// chiya: eof=0\nputchar();\n"),
+        Ok(())
+    );
+    assert_eq!(o.eof, Eof::Zero);
+    assert_eq!(
+        o.apply_pragmas("// chiya: eof"),
+        Err(OptionsError::InvalidPragma {
+            pragma: "eof".to_owned()
+        })
+    );
+}
@@ -0,0 +1,492 @@
+use crate::codegen::emitter;
+use crate::codegen::options::{Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::token::Location;
+
+// Emits a Rust source file with `pub fn run(input: &mut impl Read, output: &mut impl Write)`, which
+// runs the program and returns its exit status, and a `main` running it on the standard I/O. With
+// `function`, the function is named after it and there is no `main`. The tape is a `Vec` of cells
+// `tape`, and the pointer is the index `ptr`.
+#[derive(Default)]
+pub struct Rust {
+    options: Options,
+    location: Location,
+    // The locations of the enclosing loops.
+    loop_stack: Vec<Location>,
+}
+
+impl Rust {
+    pub fn new() -> Rust {
+        Default::default()
+    }
+
+    pub fn with_options(options: &Options) -> Rust {
+        Rust {
+            options: options.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Formats `lines` as statements of the current block.
+    fn lines(&self, lines: &[String]) -> String {
+        let indent = "    ".repeat(self.loop_stack.len() + 1);
+        lines.iter().map(|l| format!("\n{}{}", indent, l)).collect()
+    }
+
+    // The statements checking that `ptr` is inside the tape, if requested.
+    fn check(&self) -> Vec<String> {
+        if self.options.bounds_check && self.options.tape_mode == TapeMode::Fixed {
+            vec![format!(
+                "check(ptr, tape.len(), {}, {})?;",
+                self.location.line, self.location.column
+            )]
+        } else {
+            vec![]
+        }
+    }
+
+    // The arguments of the input helpers.
+    fn input_args(&self) -> &str {
+        if self.options.eof == Eof::Unchanged {
+            "&mut input, output, tape[ptr]"
+        } else {
+            "&mut input, output"
+        }
+    }
+
+    // Defines the helper functions used by the emitted statements.
+    fn helpers(&self) -> String {
+        let mut helpers = vec![];
+
+        if self.options.overflow == Overflow::Trap {
+            helpers.push(
+                r#"fn add(a: Cell, b: Cell, line: u32, column: u32) -> io::Result<Cell> {
+    a.checked_add(b).ok_or_else(|| {
+        let message = format!("chiya: {}:{}: overflow", line, column);
+        io::Error::new(io::ErrorKind::Other, message)
+    })
+}"#
+                .to_owned(),
+            );
+        }
+
+        let eof = match self.options.eof {
+            Eof::MinusOne => "-1",
+            Eof::Zero => "0",
+            Eof::Unchanged => "old",
+        };
+        let old = if self.options.eof == Eof::Unchanged {
+            ", old: Cell"
+        } else {
+            ""
+        };
+        helpers.push(format!(
+            r#"// The input, whose next byte can be looked at without reading it like with scanf.
+struct Input<R> {{
+    inner: R,
+    peeked: Option<Option<u8>>,
+}}
+
+impl<R: Read> Input<R> {{
+    fn peek(&mut self) -> io::Result<Option<u8>> {{
+        while self.peeked.is_none() {{
+            let mut byte = [0];
+            match self.inner.read(&mut byte) {{
+                Ok(0) => self.peeked = Some(None),
+                Ok(_) => self.peeked = Some(Some(byte[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {{}}
+                Err(e) => return Err(e),
+            }}
+        }}
+        Ok(self.peeked.unwrap())
+    }}
+
+    fn next(&mut self) -> io::Result<Option<u8>> {{
+        let byte = self.peek()?;
+        self.peeked = None;
+        Ok(byte)
+    }}
+}}
+
+// The output so far, e.g. a prompt, must be visible before waiting for the input.
+fn getchar(input: &mut Input<impl Read>, output: &mut impl Write{0}) -> io::Result<Cell> {{
+    output.flush()?;
+    Ok(match input.next()? {{
+        Some(c) => c as Cell,
+        None => {1},
+    }})
+}}
+
+// Reads a decimal integer like scanf("%lld"), which saturates, or returns {1} if there is none.
+fn getint(input: &mut Input<impl Read>, output: &mut impl Write{0}) -> io::Result<Cell> {{
+    output.flush()?;
+    while let Some(b'\t' | b'\n' | 0x0b | 0x0c | b'\r' | b' ') = input.peek()? {{
+        input.next()?;
+    }}
+    let negative = match input.peek()? {{
+        Some(c @ (b'+' | b'-')) => {{
+            input.next()?;
+            c == b'-'
+        }}
+        _ => false,
+    }};
+    let mut value: Option<i64> = None;
+    while let Some(c @ b'0'..=b'9') = input.peek()? {{
+        input.next()?;
+        let digit = (c - b'0') as i64;
+        let value = value.get_or_insert(0);
+        *value = if negative {{
+            value.saturating_mul(10).saturating_sub(digit)
+        }} else {{
+            value.saturating_mul(10).saturating_add(digit)
+        }};
+    }}
+    Ok(match value {{
+        Some(value) => value as Cell,
+        None => {1},
+    }})
+}}"#,
+            old, eof
+        ));
+
+        if self.options.bounds_check {
+            helpers.push(
+                r#"fn check(ptr: usize, len: usize, line: u32, column: u32) -> io::Result<()> {
+    if ptr >= len {
+        let message = format!(
+            "chiya: {}:{}: pointer out of bounds ({})",
+            line, column, ptr as isize
+        );
+        return Err(io::Error::new(io::ErrorKind::Other, message));
+    }
+    Ok(())
+}"#
+                .to_owned(),
+            );
+        }
+        if self.options.tape_mode == TapeMode::Grow {
+            helpers.push(
+                r#"// Grows the tape so that it covers `index`, which may be negative, and returns the index of the
+// same cell in the new tape.
+fn grow(tape: &mut Vec<Cell>, index: isize) -> usize {
+    let len = tape.len() as isize;
+    if index >= len {
+        tape.resize((index + 1).max(len * 2) as usize, 0);
+    }
+    if index >= 0 {
+        return index as usize;
+    }
+
+    let shift = (-index).max(len);
+    tape.splice(0..0, std::iter::repeat(0).take(shift as usize));
+    (index + shift) as usize
+}"#
+                .to_owned(),
+            );
+        }
+
+        helpers.join("\n\n")
+    }
+}
+
+impl emitter::Emitter for Rust {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        let line = match self.options.tape_mode {
+            // The pointer is checked after it leaves the tape.
+            TapeMode::Fixed if self.options.bounds_check && offset < 0 => {
+                format!("ptr = ptr.wrapping_sub({});", -(offset as i64))
+            }
+            TapeMode::Fixed if self.options.bounds_check => {
+                format!("ptr = ptr.wrapping_add({});", offset)
+            }
+            TapeMode::Fixed if offset < 0 => format!("ptr -= {};", -(offset as i64)),
+            TapeMode::Fixed => format!("ptr += {};", offset),
+            TapeMode::Grow if offset < 0 => {
+                format!(
+                    "ptr = grow(&mut tape, ptr as isize - {});",
+                    -(offset as i64)
+                )
+            }
+            TapeMode::Grow => format!("ptr = grow(&mut tape, ptr as isize + {});", offset),
+            TapeMode::Wrap => match (offset as i64).rem_euclid(self.options.tape_size.0 as i64) {
+                0 => return "".to_owned(),
+                offset => format!("ptr = (ptr + {}) % tape.len();", offset),
+            },
+        };
+        self.lines(&[line])
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        let width = self.options.cell_width;
+        let value = match self.options.overflow {
+            Overflow::Wrap => format!("tape[ptr].wrapping_add({})", width.wrap(n as i64)),
+            Overflow::Saturate => width
+                .split_addend(n as i64)
+                .iter()
+                .fold("tape[ptr]".to_owned(), |v, n| {
+                    format!("{}.saturating_add({})", v, n)
+                }),
+            Overflow::Trap => {
+                let location = self.location;
+                width
+                    .split_addend(n as i64)
+                    .iter()
+                    .fold("tape[ptr]".to_owned(), |v, n| {
+                        format!("add({}, {}, {}, {})?", v, n, location.line, location.column)
+                    })
+            }
+        };
+
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = {};", value));
+        self.lines(&lines)
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push("output.write_all(&[tape[ptr] as u8])?;".to_owned());
+        self.lines(&lines)
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = getchar({})?;", self.input_args()));
+        self.lines(&lines)
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(r#"write!(output, "{}", tape[ptr])?;"#.to_owned());
+        self.lines(&lines)
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push(format!("tape[ptr] = getint({})?;", self.input_args()));
+        self.lines(&lines)
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let mut lines = self.check();
+        lines.push("while tape[ptr] != 0 {".to_owned());
+        let s = self.lines(&lines);
+        self.loop_stack.push(self.location);
+        s
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        // The code closing the loop belongs to the loop statement.
+        match self.loop_stack.last() {
+            Some(location) => self.location = *location,
+            None => return "".to_owned(),
+        }
+        let s = self.lines(&self.check());
+        self.loop_stack.pop();
+        s + &self.lines(&["}".to_owned()])
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        let mut lines = vec![];
+        match status {
+            Some(n) => lines.push(format!("return Ok({});", n)),
+            None => {
+                lines.extend(self.check());
+                lines.push("return Ok(tape[ptr] as i32);".to_owned());
+            }
+        }
+        self.lines(&lines)
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        format!(
+            r#"// Generated by chiya.
+
+// Not every program uses every helper, or reads and writes the tape.
+#![allow(dead_code, unreachable_code, unused_mut, unused_variables)]
+
+use std::io::{{self, Read, Write}};
+
+type Cell = i{0};
+
+{1}
+
+pub fn {2}(input: &mut impl Read, output: &mut impl Write) -> io::Result<i32> {{
+    let mut input = Input {{
+        inner: input,
+        peeked: None,
+    }};
+    let mut tape: Vec<Cell> = vec![0; {3}];
+    let mut ptr: usize = {4};
+"#,
+            self.options.cell_width.bits(),
+            self.helpers(),
+            self.options.function.as_deref().unwrap_or("run"),
+            self.options.tape_size.0,
+            if self.options.bidirectional {
+                self.options.tape_size.0 / 2
+            } else {
+                0
+            }
+        )
+    }
+
+    fn emit_footer(&mut self) -> String {
+        let status = match self.options.exit_status {
+            ExitStatus::Zero => self.lines(&["Ok(0)".to_owned()]),
+            ExitStatus::Cell => {
+                let mut lines = self.check();
+                lines.push("Ok(tape[ptr] as i32)".to_owned());
+                self.lines(&lines)
+            }
+        };
+        if self.options.function.is_some() {
+            return format!("{}\n}}\n", status);
+        }
+
+        let output = if self.options.buffered_io {
+            "io::BufWriter::new(stdout.lock())"
+        } else {
+            "stdout.lock()"
+        };
+        format!(
+            r#"{}
+}}
+
+fn main() {{
+    let stdout = io::stdout();
+    let mut output = {};
+    let result = run(&mut io::stdin().lock(), &mut output);
+    let status = match result.and_then(|status| output.flush().map(|_| status)) {{
+        Ok(status) => status,
+        Err(e) => {{
+            eprintln!("{{}}", e);
+            1
+        }}
+    }};
+    drop(output);
+    std::process::exit(status);
+}}
+"#,
+            status, output
+        )
+    }
+}
+
+// Compiles `src` to Rust, builds it with `rustc` and runs it on `input`. Returns the output, the
+// error output and the exit status, or None if there is no `rustc`.
+#[cfg(test)]
+fn run(src: &str, options: &Options, input: &str) -> Option<(String, String, i32)> {
+    use crate::codegen::{build_step, has_tool};
+    use std::process::Command;
+
+    let code = crate::codegen::compile(&mut Rust::with_options(options), src);
+    crate::codegen::run_built("rust", input, |dir| {
+        if !has_tool("rustc") {
+            return None;
+        }
+        let (rs, exe) = (dir.join("main.rs"), dir.join("main"));
+        std::fs::write(&rs, code).unwrap();
+        build_step(
+            Command::new("rustc")
+                .args(["--edition", "2021", "-D", "warnings", "-o"])
+                .arg(&exe)
+                .arg(&rs),
+        );
+        Some(Command::new(exe))
+    })
+}
+
+#[test]
+fn test_emit() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::CellWidth;
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let mut e = Rust::with_options(&options);
+    assert!(e.emit_header().contains("type Cell = i8;"));
+    assert_eq!(
+        e.emit_add(300),
+        "\n    tape[ptr] = tape[ptr].wrapping_add(44);"
+    );
+    assert_eq!(e.emit_move_ptr(-2), "\n    ptr -= 2;");
+    assert_eq!(e.emit_loop_begin(), "\n    while tape[ptr] != 0 {");
+    assert_eq!(
+        e.emit_call_putchar(),
+        "\n        output.write_all(&[tape[ptr] as u8])?;"
+    );
+    assert_eq!(e.emit_loop_end(), "\n    }");
+
+    options.overflow = Overflow::Saturate;
+    let mut e = Rust::with_options(&options);
+    assert_eq!(
+        e.emit_add(200),
+        "\n    tape[ptr] = tape[ptr].saturating_add(127).saturating_add(73);"
+    );
+
+    options.function = Some("hello".to_string());
+    let mut e = Rust::with_options(&options);
+    assert!(e
+        .emit_header()
+        .contains("pub fn hello(input: &mut impl Read"));
+    assert!(!e.emit_footer().contains("fn main()"));
+}
+
+#[test]
+fn test_run() {
+    use crate::codegen::options::{CellWidth, TapeSize};
+
+    let src = "getint();\n*ptr += 5;\nputint();\nptr += 1;\n*ptr += 10;\nputchar();\ngetchar();\nputchar();\n";
+    let output = match run(src, &Options::new(), " -37x") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("-32\nx".to_owned(), "".to_owned(), 0));
+
+    let src = "*ptr -= 1;\nputint();\n*ptr += 100;\n*ptr += 100;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1-57");
+    options.overflow = Overflow::Saturate;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1127");
+    options.overflow = Overflow::Trap;
+    assert_eq!(
+        run(src, &options, "").unwrap(),
+        ("-1".to_owned(), "chiya: 4:1: overflow\n".to_owned(), 1)
+    );
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nptr -= 1;\nputint();\nptr += 2;\nputint();\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    options.tape_size = TapeSize(1);
+    assert_eq!(run(src, &options, "").unwrap().0, "03");
+
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(2);
+    assert_eq!(run(src, &options, "").unwrap().0, "33");
+
+    options.tape_mode = TapeMode::Fixed;
+    options.tape_size = TapeSize(30000);
+    options.bounds_check = true;
+    assert_eq!(
+        run(src, &options, "").unwrap(),
+        (
+            "".to_owned(),
+            "chiya: 9:1: pointer out of bounds (-1)\n".to_owned(),
+            1
+        )
+    );
+
+    let src = "*ptr += 7;\nwhile *ptr {\n  exit(*ptr);\n}\nexit(3);\n";
+    assert_eq!(run(src, &Options::new(), "").unwrap().2, 7);
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        run(src, options, input).map(|(output, _, _)| output)
+    });
+}
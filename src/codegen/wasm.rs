@@ -0,0 +1,985 @@
+pub mod binary;
+
+use std::collections::VecDeque;
+
+use crate::codegen::emitter;
+use crate::codegen::options::{Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::token::Location;
+
+#[derive(Debug, PartialEq, failure::Fail)]
+pub enum WatError {
+    #[fail(
+        display = "a tape of {} cells does not fit in the 32-bit memory of WebAssembly",
+        size
+    )]
+    TapeTooLarge { size: usize },
+}
+
+// The layout of the linear memory: the scratch space for the WASI calls at 0, the buffer the
+// numbers are formatted into (ending at `NUMBER`), the strings of the error messages, the input
+// and output buffers, and the tape.
+const NUMBER: u32 = 48;
+const STRINGS: u32 = 64;
+const INPUT: u32 = 128;
+const OUTPUT: u32 = INPUT + BUFFER;
+const TAPE: u32 = OUTPUT + BUFFER;
+const BUFFER: u32 = 4096;
+const PAGE: u64 = 65536;
+
+// The strings at `STRINGS` and their offsets.
+const PREFIX: (u32, &str) = (0, "chiya: ");
+const MESSAGE: (u32, &str) = (16, ": pointer out of bounds (");
+const SUFFIX: (u32, &str) = (48, ")\\0a");
+const COLON: (u32, &str) = (50, ":");
+
+// The functions used by the program. `{W}` is the size of a cell.
+const RUNTIME: &str = r#"
+  ;; Writes `len` bytes at `addr` to the file descriptor `fd`.
+  (func $write (param $fd i32) (param $addr i32) (param $len i32)
+    block $done
+      loop $again
+        local.get $len
+        i32.eqz
+        br_if $done
+        i32.const 0
+        local.get $addr
+        i32.store
+        i32.const 4
+        local.get $len
+        i32.store
+        local.get $fd
+        i32.const 0
+        i32.const 1
+        i32.const 8
+        call $fd_write
+        br_if $done
+        local.get $addr
+        i32.const 8
+        i32.load
+        i32.add
+        local.set $addr
+        local.get $len
+        i32.const 8
+        i32.load
+        i32.sub
+        local.set $len
+        br $again
+      end
+    end
+  )
+
+  ;; Formats `v` as a signed decimal number ending at {NUMBER}, and returns its start.
+  (func $format (param $v i64) (result i32)
+    (local $addr i32) (local $n i64)
+    i32.const {NUMBER}
+    local.set $addr
+    i64.const 0
+    local.get $v
+    i64.sub
+    local.get $v
+    local.get $v
+    i64.const 0
+    i64.lt_s
+    select
+    local.set $n
+    loop $digit
+      local.get $addr
+      i32.const 1
+      i32.sub
+      local.tee $addr
+      local.get $n
+      i64.const 10
+      i64.rem_u
+      i32.wrap_i64
+      i32.const 48
+      i32.add
+      i32.store8
+      local.get $n
+      i64.const 10
+      i64.div_u
+      local.tee $n
+      i64.const 0
+      i64.ne
+      br_if $digit
+    end
+    local.get $v
+    i64.const 0
+    i64.lt_s
+    if
+      local.get $addr
+      i32.const 1
+      i32.sub
+      local.tee $addr
+      i32.const 45
+      i32.store8
+    end
+    local.get $addr
+  )
+
+  ;; Writes `v` to the file descriptor `fd` as a signed decimal number.
+  (func $write_int (param $fd i32) (param $v i64)
+    (local $addr i32)
+    local.get $fd
+    local.get $v
+    call $format
+    local.tee $addr
+    i32.const {NUMBER}
+    local.get $addr
+    i32.sub
+    call $write
+  )
+
+  ;; Returns the next byte of the input without consuming it, or -1 at its end.
+  (func $peek (result i32)
+    global.get $in_pos
+    global.get $in_len
+    i32.ge_u
+    if
+      call $flush
+      i32.const 0
+      i32.const {INPUT}
+      i32.store
+      i32.const 4
+      i32.const {BUFFER}
+      i32.store
+      i32.const 0
+      i32.const 0
+      i32.const 1
+      i32.const 8
+      call $fd_read
+      if
+        i32.const -1
+        return
+      end
+      i32.const 8
+      i32.load
+      global.set $in_len
+      i32.const 0
+      global.set $in_pos
+      global.get $in_len
+      i32.eqz
+      if
+        i32.const -1
+        return
+      end
+    end
+    global.get $in_pos
+    i32.load8_u offset={INPUT}
+  )
+
+  ;; Consumes the byte returned by `peek`.
+  (func $next
+    global.get $in_pos
+    i32.const 1
+    i32.add
+    global.set $in_pos
+  )
+
+  ;; Returns the next byte of the input, or -1 at its end.
+  (func $getchar (result i32)
+    (local $c i32)
+    call $peek
+    local.tee $c
+    i32.const 0
+    i32.ge_s
+    if
+      call $next
+    end
+    local.get $c
+  )
+
+  ;; Reads a decimal number like scanf("%lld"). Sets `int_ok` to 1 if there was one.
+  (func $getint (result i64)
+    (local $c i32) (local $negative i32) (local $digits i32) (local $n i64) (local $limit i64)
+    block $start
+      loop $skip
+        call $peek
+        local.tee $c
+        i32.const 32
+        i32.eq
+        local.get $c
+        i32.const 9
+        i32.sub
+        i32.const 5
+        i32.lt_u
+        i32.or
+        i32.eqz
+        br_if $start
+        call $next
+        br $skip
+      end
+    end
+    local.get $c
+    i32.const 45
+    i32.eq
+    local.tee $negative
+    local.get $c
+    i32.const 43
+    i32.eq
+    i32.or
+    if
+      call $next
+    end
+    ;; The magnitude saturates at the limit of its sign like with scanf.
+    i64.const 9223372036854775807
+    local.get $negative
+    i64.extend_i32_u
+    i64.add
+    local.set $limit
+    block $done
+      loop $digit
+        call $peek
+        i32.const 48
+        i32.sub
+        local.tee $c
+        i32.const 10
+        i32.ge_u
+        br_if $done
+        local.get $n
+        i64.const 10
+        i64.mul
+        local.get $c
+        i64.extend_i32_u
+        i64.add
+        local.get $limit
+        local.get $n
+        local.get $limit
+        local.get $c
+        i64.extend_i32_u
+        i64.sub
+        i64.const 10
+        i64.div_u
+        i64.le_u
+        select
+        local.set $n
+        i32.const 1
+        local.set $digits
+        call $next
+        br $digit
+      end
+    end
+    local.get $digits
+    global.set $int_ok
+    i64.const 0
+    local.get $n
+    i64.sub
+    local.get $n
+    local.get $negative
+    select
+  )
+
+  ;; Reports that the pointer is outside the tape at the statement at `line`:`column`, and exits
+  ;; with the status 1.
+  (func $out_of_bounds (param $line i32) (param $column i32) (param $index i32)
+    i32.const 2
+    i32.const {PREFIX}
+    i32.const {PREFIX_LEN}
+    call $write
+    i32.const 2
+    local.get $line
+    i64.extend_i32_s
+    call $write_int
+    i32.const 2
+    i32.const {COLON}
+    i32.const 1
+    call $write
+    i32.const 2
+    local.get $column
+    i64.extend_i32_s
+    call $write_int
+    i32.const 2
+    i32.const {MESSAGE}
+    i32.const {MESSAGE_LEN}
+    call $write
+    i32.const 2
+    local.get $index
+    i64.extend_i32_s
+    call $write_int
+    i32.const 2
+    i32.const {SUFFIX}
+    i32.const 2
+    call $write
+    i32.const 1
+    call $exit
+  )
+
+  ;; Exits with `status`.
+  (func $exit (param $status i32)
+    call $flush
+    local.get $status
+    call $proc_exit
+    unreachable
+  )
+"#;
+
+const UNBUFFERED: &str = r#"
+  (func $putchar (param $c i32)
+    i32.const 12
+    local.get $c
+    i32.store8
+    i32.const 1
+    i32.const 12
+    i32.const 1
+    call $write
+  )
+
+  (func $putint (param $v i64)
+    i32.const 1
+    local.get $v
+    call $write_int
+  )
+
+  (func $flush)
+"#;
+
+const BUFFERED: &str = r#"
+  ;; Appends the byte `c` to the output buffer, and writes it when it fills.
+  (func $putchar (param $c i32)
+    global.get $out_len
+    local.get $c
+    i32.store8 offset={OUTPUT}
+    global.get $out_len
+    i32.const 1
+    i32.add
+    global.set $out_len
+    global.get $out_len
+    i32.const {BUFFER}
+    i32.eq
+    if
+      call $flush
+    end
+  )
+
+  (func $putint (param $v i64)
+    (local $addr i32)
+    local.get $v
+    call $format
+    local.set $addr
+    block $done
+      loop $next
+        local.get $addr
+        i32.const {NUMBER}
+        i32.eq
+        br_if $done
+        local.get $addr
+        i32.load8_u
+        call $putchar
+        local.get $addr
+        i32.const 1
+        i32.add
+        local.set $addr
+        br $next
+      end
+    end
+  )
+
+  ;; Writes the output buffer.
+  (func $flush
+    i32.const 1
+    i32.const {OUTPUT}
+    global.get $out_len
+    call $write
+    i32.const 0
+    global.set $out_len
+  )
+"#;
+
+const GROW: &str = r#"
+  ;; Grows the memory so that the tape can have `cells` cells.
+  (func $reserve (param $cells i32)
+    (local $pages i32)
+    local.get $cells
+    i32.const {W}
+    i32.mul
+    i32.const {TAPE}
+    i32.add
+    i32.const 65535
+    i32.add
+    i32.const 16
+    i32.shr_u
+    memory.size
+    i32.sub
+    local.tee $pages
+    i32.const 0
+    i32.gt_s
+    if
+      local.get $pages
+      memory.grow
+      i32.const -1
+      i32.eq
+      if
+        unreachable
+      end
+    end
+  )
+
+  ;; Grows the tape so that it covers `index`, which may be negative, and returns the index of the
+  ;; same cell in the new tape.
+  (func $grow (param $index i32) (result i32)
+    (local $shift i32) (local $new i32)
+    local.get $index
+    i32.const 0
+    i32.ge_s
+    if
+      local.get $index
+      global.get $tape_len
+      i32.lt_s
+      if
+        local.get $index
+        return
+      end
+      local.get $index
+      i32.const 1
+      i32.add
+      local.tee $new
+      global.get $tape_len
+      i32.const 2
+      i32.mul
+      local.tee $shift
+      local.get $new
+      local.get $shift
+      i32.gt_s
+      select
+      local.tee $new
+      call $reserve
+      local.get $new
+      global.set $tape_len
+      local.get $index
+      return
+    end
+    i32.const 0
+    local.get $index
+    i32.sub
+    local.tee $shift
+    global.get $tape_len
+    local.get $shift
+    global.get $tape_len
+    i32.gt_s
+    select
+    local.tee $shift
+    global.get $tape_len
+    i32.add
+    local.tee $new
+    call $reserve
+    local.get $shift
+    i32.const {W}
+    i32.mul
+    i32.const {TAPE}
+    i32.add
+    i32.const {TAPE}
+    global.get $tape_len
+    i32.const {W}
+    i32.mul
+    memory.copy
+    i32.const {TAPE}
+    i32.const 0
+    local.get $shift
+    i32.const {W}
+    i32.mul
+    memory.fill
+    local.get $new
+    global.set $tape_len
+    local.get $index
+    local.get $shift
+    i32.add
+  )
+"#;
+
+// Emits a WebAssembly text module for WASI. The tape is in the linear memory at `TAPE`, the
+// pointer is the cell index `$ptr`, and cells are handled as 'i64' values.
+pub struct Wasm {
+    label_idx: u32,
+    loop_stack: VecDeque<(u32, Location)>,
+    options: Options,
+    location: Location,
+}
+
+impl Wasm {
+    pub fn new() -> Wasm {
+        Default::default()
+    }
+
+    pub fn with_options(options: &Options) -> Wasm {
+        Wasm {
+            options: options.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Checks that the tape fits in the linear memory, which has 32-bit addresses, and that its
+    // cells can be indexed with 'i32' values.
+    pub fn check_tape(&self) -> Result<(), WatError> {
+        let size = self.options.tape_size.0;
+        let bytes = size as u64 * self.options.cell_width.bytes() as u64;
+        if size > i32::MAX as usize || TAPE as u64 + bytes > 1 << 32 {
+            return Err(WatError::TapeTooLarge { size });
+        }
+        Ok(())
+    }
+
+    fn next_label(&mut self) -> u32 {
+        self.label_idx += 1;
+        self.label_idx - 1
+    }
+
+    // Indents `instructions`, one per line, for the current block.
+    fn lines(&self, instructions: &[String]) -> String {
+        let indent = "  ".repeat(2 + 2 * self.loop_stack.len());
+        instructions
+            .iter()
+            .map(|i| format!("\n{}{}", indent, i))
+            .collect()
+    }
+
+    // Pushes the address of the current cell, relative to `TAPE`.
+    fn address(&self) -> Vec<String> {
+        match self.options.cell_width.bytes() {
+            1 => vec!["local.get $ptr".to_owned()],
+            bytes => vec![
+                "local.get $ptr".to_owned(),
+                format!("i32.const {}", bytes),
+                "i32.mul".to_owned(),
+            ],
+        }
+    }
+
+    // Loads the current cell as an 'i64'.
+    fn load(&self) -> Vec<String> {
+        let mut i = self.address();
+        i.push(match self.options.cell_width.bits() {
+            64 => format!("i64.load offset={}", TAPE),
+            bits => format!("i64.load{}_s offset={}", bits, TAPE),
+        });
+        i
+    }
+
+    // Stores the 'i64' on the stack to the address below it.
+    fn store(&self) -> String {
+        match self.options.cell_width.bits() {
+            64 => format!("i64.store offset={}", TAPE),
+            bits => format!("i64.store{} offset={}", bits, TAPE),
+        }
+    }
+
+    // Checks that the pointer is inside the tape, if requested.
+    fn check(&self) -> Vec<String> {
+        if !self.options.bounds_check || self.options.tape_mode != TapeMode::Fixed {
+            return vec![];
+        }
+        vec![
+            "local.get $ptr".to_owned(),
+            format!("i32.const {}", self.options.tape_size.0),
+            "i32.ge_u".to_owned(),
+            "if".to_owned(),
+            format!("  i32.const {}", self.location.line),
+            format!("  i32.const {}", self.location.column),
+            "  local.get $ptr".to_owned(),
+            "  call $out_of_bounds".to_owned(),
+            "end".to_owned(),
+        ]
+    }
+
+    // The value stored at the end of input.
+    fn eof(&self) -> Vec<String> {
+        match self.options.eof {
+            Eof::MinusOne => vec!["i64.const -1".to_owned()],
+            Eof::Zero => vec!["i64.const 0".to_owned()],
+            Eof::Unchanged => self.load(),
+        }
+    }
+
+    fn runtime(&self) -> String {
+        let width = self.options.cell_width.bytes();
+        let mut runtime = RUNTIME.to_owned();
+        runtime += if self.options.buffered_io {
+            BUFFERED
+        } else {
+            UNBUFFERED
+        };
+        if self.options.tape_mode == TapeMode::Grow {
+            runtime += GROW;
+        }
+        [
+            ("{NUMBER}", NUMBER),
+            ("{INPUT}", INPUT),
+            ("{OUTPUT}", OUTPUT),
+            ("{BUFFER}", BUFFER),
+            ("{TAPE}", TAPE),
+            ("{W}", width),
+            ("{PREFIX}", STRINGS + PREFIX.0),
+            ("{PREFIX_LEN}", PREFIX.1.len() as u32),
+            ("{MESSAGE}", STRINGS + MESSAGE.0),
+            ("{MESSAGE_LEN}", MESSAGE.1.len() as u32),
+            ("{SUFFIX}", STRINGS + SUFFIX.0),
+            ("{COLON}", STRINGS + COLON.0),
+        ]
+        .iter()
+        .fold(runtime, |s, (name, value)| {
+            s.replace(name, &value.to_string())
+        })
+    }
+}
+
+impl Default for Wasm {
+    fn default() -> Self {
+        Wasm {
+            label_idx: 1,
+            loop_stack: VecDeque::new(),
+            options: Options::new(),
+            location: Default::default(),
+        }
+    }
+}
+
+impl emitter::Emitter for Wasm {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        let i = match self.options.tape_mode {
+            TapeMode::Fixed => vec![
+                "local.get $ptr".to_owned(),
+                format!("i32.const {}", offset),
+                "i32.add".to_owned(),
+                "local.set $ptr".to_owned(),
+            ],
+            TapeMode::Grow => vec![
+                "local.get $ptr".to_owned(),
+                format!("i32.const {}", offset),
+                "i32.add".to_owned(),
+                "call $grow".to_owned(),
+                "local.set $ptr".to_owned(),
+            ],
+            TapeMode::Wrap => {
+                let size = self.options.tape_size.0;
+                match (offset as i64).rem_euclid(size as i64) {
+                    0 => vec![],
+                    offset => vec![
+                        "local.get $ptr".to_owned(),
+                        format!("i32.const {}", offset),
+                        "i32.add".to_owned(),
+                        "local.tee $ptr".to_owned(),
+                        format!("i32.const {}", size),
+                        "i32.sub".to_owned(),
+                        "local.get $ptr".to_owned(),
+                        "local.get $ptr".to_owned(),
+                        format!("i32.const {}", size),
+                        "i32.ge_u".to_owned(),
+                        "select".to_owned(),
+                        "local.set $ptr".to_owned(),
+                    ],
+                }
+            }
+        };
+        self.lines(&[vec![format!(";; emit_move_ptr({})", offset)], i].concat())
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        let width = self.options.cell_width;
+        let n = n as i64;
+        let mut i = vec![format!(";; emit_add({})", n)];
+        i.extend(self.check());
+        i.extend(self.address());
+        i.extend(self.load());
+        match self.options.overflow {
+            Overflow::Wrap => {
+                i.push(format!("i64.const {}", width.wrap(n)));
+                i.push("i64.add".to_owned());
+            }
+            overflow => {
+                // The sum of the cell and `n` overflows if the cell is beyond `limit`, and fits
+                // otherwise.
+                let (limit, overflows, fits, saturated) = if n > 0 {
+                    (width.max() - n, "i64.gt_s", "i64.le_s", width.max())
+                } else {
+                    (width.min() - n, "i64.lt_s", "i64.ge_s", width.min())
+                };
+                i.push("local.tee $v".to_owned());
+                if overflow == Overflow::Trap {
+                    i.extend([
+                        format!("i64.const {}", limit),
+                        overflows.to_owned(),
+                        "if".to_owned(),
+                        "  unreachable".to_owned(),
+                        "end".to_owned(),
+                        "local.get $v".to_owned(),
+                        format!("i64.const {}", n),
+                        "i64.add".to_owned(),
+                    ]);
+                } else {
+                    i.extend([
+                        format!("i64.const {}", n),
+                        "i64.add".to_owned(),
+                        format!("i64.const {}", saturated),
+                        "local.get $v".to_owned(),
+                        format!("i64.const {}", limit),
+                        fits.to_owned(),
+                        "select".to_owned(),
+                    ]);
+                }
+            }
+        }
+        i.push(self.store());
+        self.lines(&i)
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        let mut i = vec![";; emit_call_putchar()".to_owned()];
+        i.extend(self.check());
+        i.extend(self.address());
+        i.push(format!("i32.load8_u offset={}", TAPE));
+        i.push("call $putchar".to_owned());
+        self.lines(&i)
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        let mut i = vec![";; emit_call_getchar()".to_owned()];
+        i.extend(self.check());
+        i.extend(self.address());
+        i.push("call $getchar".to_owned());
+        i.push("i64.extend_i32_s".to_owned());
+        if self.options.eof != Eof::MinusOne {
+            i.push("local.tee $v".to_owned());
+            i.extend(self.eof());
+            i.extend([
+                "local.get $v".to_owned(),
+                "i64.const -1".to_owned(),
+                "i64.ne".to_owned(),
+                "select".to_owned(),
+            ]);
+        }
+        i.push(self.store());
+        self.lines(&i)
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        let mut i = vec![";; emit_call_putint()".to_owned()];
+        i.extend(self.check());
+        i.extend(self.load());
+        i.push("call $putint".to_owned());
+        self.lines(&i)
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        let mut i = vec![";; emit_call_getint()".to_owned()];
+        i.extend(self.check());
+        i.extend(self.address());
+        i.push("call $getint".to_owned());
+        i.extend(self.eof());
+        i.push("global.get $int_ok".to_owned());
+        i.push("select".to_owned());
+        i.push(self.store());
+        self.lines(&i)
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let l = self.next_label();
+        let s = self.lines(&[
+            ";; emit_loop_begin()".to_owned(),
+            format!("block $loop{}_end", l),
+            format!("  loop $loop{}_cond", l),
+        ]);
+        self.loop_stack.push_back((l, self.location));
+
+        let mut i = self.check();
+        i.extend(self.load());
+        i.push("i64.eqz".to_owned());
+        i.push(format!("br_if $loop{}_end", l));
+        s + &self.lines(&i)
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        let l = match self.loop_stack.back() {
+            Some((l, location)) => {
+                // The code closing the loop belongs to the loop statement.
+                self.location = *location;
+                *l
+            }
+            None => return "".to_owned(),
+        };
+        let s = self.lines(&[
+            ";; emit_loop_end()".to_owned(),
+            format!("br $loop{}_cond", l),
+        ]);
+        self.loop_stack.pop_back();
+        s + &self.lines(&["  end".to_owned(), "end".to_owned()])
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        let mut i = vec![format!(
+            ";; emit_exit({})",
+            status.map_or("*ptr".to_owned(), |n| n.to_string())
+        )];
+        match status {
+            Some(n) => i.push(format!("i32.const {}", n)),
+            None => {
+                i.extend(self.check());
+                i.extend(self.load());
+                i.push("i32.wrap_i64".to_owned());
+            }
+        }
+        i.push("call $exit".to_owned());
+        self.lines(&i)
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        let start = if self.options.bidirectional {
+            self.options.tape_size.0 / 2
+        } else {
+            0
+        };
+        format!(
+            r#";; emit_header()
+(module
+  (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+
+  (func $main (export "_start")
+    (local $ptr i32) (local $v i64)
+    i32.const {}
+    local.set $ptr"#,
+            start
+        )
+    }
+
+    fn emit_footer(&mut self) -> String {
+        let mut i = vec![";; emit_footer()".to_owned()];
+        match self.options.exit_status {
+            ExitStatus::Zero => i.push("i32.const 0".to_owned()),
+            ExitStatus::Cell => {
+                i.extend(self.check());
+                i.extend(self.load());
+                i.push("i32.wrap_i64".to_owned());
+            }
+        }
+        i.push("call $exit".to_owned());
+
+        let bytes = self.options.tape_size.0 as u64 * self.options.cell_width.bytes() as u64;
+        let pages = (TAPE as u64 + bytes).div_ceil(PAGE);
+        let strings: String = [PREFIX, MESSAGE, SUFFIX, COLON]
+            .iter()
+            .map(|(offset, s)| format!("\n  (data (i32.const {}) \"{}\")", STRINGS + offset, s))
+            .collect();
+        format!(
+            r#"{0}
+  )
+{1}
+  (memory (export "memory") {2}){3}
+
+  (global $in_pos (mut i32) (i32.const 0))
+  (global $in_len (mut i32) (i32.const 0))
+  (global $out_len (mut i32) (i32.const 0))
+  (global $int_ok (mut i32) (i32.const 0))
+  (global $tape_len (mut i32) (i32.const {4}))
+)
+"#,
+            self.lines(&i),
+            self.runtime(),
+            pages,
+            strings,
+            self.options.tape_size.0
+        )
+    }
+}
+
+#[test]
+fn test_emit() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::{CellWidth, TapeSize};
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let mut e = Wasm::with_options(&options);
+    assert!(e.emit_add(300).ends_with(
+        "i64.load8_s offset=8320\n    i64.const 44\n    i64.add\n    i64.store8 offset=8320"
+    ));
+    assert!(e
+        .emit_move_ptr(-2)
+        .ends_with("local.get $ptr\n    i32.const -2\n    i32.add\n    local.set $ptr"));
+    assert!(e
+        .emit_loop_begin()
+        .contains("block $loop1_end\n      loop $loop1_cond"));
+    assert!(e
+        .emit_loop_begin()
+        .ends_with("i64.eqz\n            br_if $loop2_end"));
+    assert!(e
+        .emit_loop_end()
+        .ends_with("br $loop2_cond\n          end\n        end"));
+    assert!(e
+        .emit_loop_end()
+        .ends_with("br $loop1_cond\n      end\n    end"));
+
+    options.overflow = Overflow::Saturate;
+    let mut e = Wasm::with_options(&options);
+    assert!(e.emit_add(-1).contains(
+        "i64.const -128\n    local.get $v\n    i64.const -127\n    i64.ge_s\n    select"
+    ));
+
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(10);
+    let mut e = Wasm::with_options(&options);
+    assert_eq!(e.check_tape(), Ok(()));
+    assert!(e.emit_move_ptr(-3).contains("i32.const 7\n    i32.add"));
+    assert!(e.emit_footer().contains("(memory (export \"memory\") 1)"));
+    // The tape must fit in the 4 GiB of linear memory.
+    options.tape_size = TapeSize(1 << 30);
+    assert_eq!(
+        Wasm::with_options(&options).check_tape(),
+        Err(WatError::TapeTooLarge { size: 1 << 30 })
+    );
+    options.cell_width = CellWidth::W8;
+    options.tape_size = TapeSize(3000000000);
+    assert!(Wasm::with_options(&options).check_tape().is_err());
+
+    let mut options = Options::new();
+    options.bounds_check = true;
+    let mut e = Wasm::with_options(&options);
+    e.set_location(Location { line: 2, column: 5 });
+    assert!(e
+        .emit_call_putchar()
+        .contains("if\n      i32.const 2\n      i32.const 5\n      local.get $ptr\n      call $out_of_bounds\n    end"));
+    let footer = e.emit_footer();
+    assert!(footer.contains("(data (i32.const 80) \": pointer out of bounds (\")"));
+    assert_eq!(
+        footer.matches('(').count() + e.emit_header().matches('(').count(),
+        footer.matches(')').count() + e.emit_header().matches(')').count()
+    );
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        let (output, error, status) = binary::run(src, options, input)?;
+        assert_eq!((error.as_str(), status), ("", 0));
+        Some(output)
+    });
+}
+
+#[test]
+fn test_saturate() {
+    use crate::codegen::options::CellWidth;
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    options.overflow = Overflow::Saturate;
+    // The sum is kept while it fits, and saturates at either end otherwise.
+    for (src, expected) in [
+        ("*ptr += 100;\n*ptr += 20;\nputint();\n", "120"),
+        ("*ptr += 100;\n*ptr += 100;\nputint();\n", "127"),
+        ("*ptr -= 100;\n*ptr -= 20;\nputint();\n", "-120"),
+        ("*ptr -= 100;\n*ptr -= 100;\nputint();\n", "-128"),
+        (
+            "*ptr += 127;\n*ptr -= 100;\n*ptr -= 100;\nputint();\n",
+            "-73",
+        ),
+    ] {
+        let output = match binary::run(src, &options, "") {
+            Some(output) => output,
+            None => return,
+        };
+        assert_eq!(output, (expected.to_owned(), "".to_owned(), 0), "{}", src);
+    }
+}
@@ -0,0 +1,1693 @@
+// The binary format of WebAssembly. `parse` reads the text that `Wasm` emits, `Module::encode`
+// writes it as a '.wasm' module, and `Module::decode` and `Module::validate` read one back and
+// type check it.
+
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, failure::Fail)]
+pub enum WasmError {
+    #[fail(display = "unexpected token in WebAssembly text: '{}'", token)]
+    UnexpectedToken { token: String },
+
+    #[fail(display = "unknown instruction: '{}'", name)]
+    UnknownInstruction { name: String },
+
+    #[fail(display = "unknown identifier: '{}'", name)]
+    UnknownIdentifier { name: String },
+
+    #[fail(display = "malformed module at offset {}", offset)]
+    Malformed { offset: usize },
+
+    #[fail(display = "invalid module: {}", reason)]
+    Invalid { reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+use ValType::{I32, I64};
+
+impl ValType {
+    fn code(self) -> u8 {
+        match self {
+            I32 => 0x7f,
+            I64 => 0x7e,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Plain(u8),            // an instruction without immediates
+    Block(u8),            // 'block', 'loop' or 'if' with the empty block type
+    Br(u8, u32),          // 'br' or 'br_if' and the depth of the label
+    Call(u32),            // the function index
+    Variable(u8, u32),    // 'local.*' or 'global.*' and the index
+    Memory(u8, u32, u32), // a load or a store, the alignment and the offset
+    MemoryIndex(u8),      // 'memory.size' or 'memory.grow'
+    Bulk(u32),            // 'memory.copy' or 'memory.fill'
+    I32Const(i32),
+    I64Const(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub ty: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub ty: u32,
+    pub locals: Vec<ValType>,
+    pub body: Vec<Instruction>, // without the final 'end'
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub ty: ValType,
+    pub mutable: bool,
+    pub init: Instruction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportKind {
+    Function, // 0x00
+    Memory,   // 0x02
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+    pub index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Data {
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    pub functions: Vec<Function>,
+    pub memory: Option<u32>, // the minimum number of pages
+    pub globals: Vec<Global>,
+    pub exports: Vec<Export>,
+    pub data: Vec<Data>,
+}
+
+const PAGE: u64 = 65536;
+
+const UNREACHABLE: u8 = 0x00;
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const END: u8 = 0x0b;
+const BR: u8 = 0x0c;
+const BR_IF: u8 = 0x0d;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const SELECT: u8 = 0x1b;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const GLOBAL_GET: u8 = 0x23;
+const GLOBAL_SET: u8 = 0x24;
+const MEMORY_SIZE: u8 = 0x3f;
+const MEMORY_GROW: u8 = 0x40;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const BULK: u8 = 0xfc;
+const MEMORY_COPY: u32 = 10;
+const MEMORY_FILL: u32 = 11;
+
+// The instructions of `Instruction::Plain` other than the control ones: the name, the opcode, the
+// types of the operands and the type of the result.
+const NUMERIC: &[(&str, u8, &[ValType], Option<ValType>)] = &[
+    ("i32.eqz", 0x45, &[I32], Some(I32)),
+    ("i32.eq", 0x46, &[I32, I32], Some(I32)),
+    ("i32.ne", 0x47, &[I32, I32], Some(I32)),
+    ("i32.lt_s", 0x48, &[I32, I32], Some(I32)),
+    ("i32.lt_u", 0x49, &[I32, I32], Some(I32)),
+    ("i32.gt_s", 0x4a, &[I32, I32], Some(I32)),
+    ("i32.gt_u", 0x4b, &[I32, I32], Some(I32)),
+    ("i32.le_s", 0x4c, &[I32, I32], Some(I32)),
+    ("i32.le_u", 0x4d, &[I32, I32], Some(I32)),
+    ("i32.ge_s", 0x4e, &[I32, I32], Some(I32)),
+    ("i32.ge_u", 0x4f, &[I32, I32], Some(I32)),
+    ("i64.eqz", 0x50, &[I64], Some(I32)),
+    ("i64.eq", 0x51, &[I64, I64], Some(I32)),
+    ("i64.ne", 0x52, &[I64, I64], Some(I32)),
+    ("i64.lt_s", 0x53, &[I64, I64], Some(I32)),
+    ("i64.lt_u", 0x54, &[I64, I64], Some(I32)),
+    ("i64.gt_s", 0x55, &[I64, I64], Some(I32)),
+    ("i64.gt_u", 0x56, &[I64, I64], Some(I32)),
+    ("i64.le_s", 0x57, &[I64, I64], Some(I32)),
+    ("i64.le_u", 0x58, &[I64, I64], Some(I32)),
+    ("i64.ge_s", 0x59, &[I64, I64], Some(I32)),
+    ("i64.ge_u", 0x5a, &[I64, I64], Some(I32)),
+    ("i32.add", 0x6a, &[I32, I32], Some(I32)),
+    ("i32.sub", 0x6b, &[I32, I32], Some(I32)),
+    ("i32.mul", 0x6c, &[I32, I32], Some(I32)),
+    ("i32.div_s", 0x6d, &[I32, I32], Some(I32)),
+    ("i32.div_u", 0x6e, &[I32, I32], Some(I32)),
+    ("i32.rem_s", 0x6f, &[I32, I32], Some(I32)),
+    ("i32.rem_u", 0x70, &[I32, I32], Some(I32)),
+    ("i32.and", 0x71, &[I32, I32], Some(I32)),
+    ("i32.or", 0x72, &[I32, I32], Some(I32)),
+    ("i32.xor", 0x73, &[I32, I32], Some(I32)),
+    ("i32.shl", 0x74, &[I32, I32], Some(I32)),
+    ("i32.shr_s", 0x75, &[I32, I32], Some(I32)),
+    ("i32.shr_u", 0x76, &[I32, I32], Some(I32)),
+    ("i64.add", 0x7c, &[I64, I64], Some(I64)),
+    ("i64.sub", 0x7d, &[I64, I64], Some(I64)),
+    ("i64.mul", 0x7e, &[I64, I64], Some(I64)),
+    ("i64.div_s", 0x7f, &[I64, I64], Some(I64)),
+    ("i64.div_u", 0x80, &[I64, I64], Some(I64)),
+    ("i64.rem_s", 0x81, &[I64, I64], Some(I64)),
+    ("i64.rem_u", 0x82, &[I64, I64], Some(I64)),
+    ("i64.and", 0x83, &[I64, I64], Some(I64)),
+    ("i64.or", 0x84, &[I64, I64], Some(I64)),
+    ("i64.xor", 0x85, &[I64, I64], Some(I64)),
+    ("i64.shl", 0x86, &[I64, I64], Some(I64)),
+    ("i64.shr_s", 0x87, &[I64, I64], Some(I64)),
+    ("i64.shr_u", 0x88, &[I64, I64], Some(I64)),
+    ("i32.wrap_i64", 0xa7, &[I64], Some(I32)),
+    ("i64.extend_i32_s", 0xac, &[I32], Some(I64)),
+    ("i64.extend_i32_u", 0xad, &[I32], Some(I64)),
+];
+
+// The loads and stores: the name, the opcode, the type of the value and the natural alignment.
+const MEMORY: &[(&str, u8, ValType, u32)] = &[
+    ("i32.load", 0x28, I32, 2),
+    ("i64.load", 0x29, I64, 3),
+    ("i32.load8_s", 0x2c, I32, 0),
+    ("i32.load8_u", 0x2d, I32, 0),
+    ("i32.load16_s", 0x2e, I32, 1),
+    ("i32.load16_u", 0x2f, I32, 1),
+    ("i64.load8_s", 0x30, I64, 0),
+    ("i64.load8_u", 0x31, I64, 0),
+    ("i64.load16_s", 0x32, I64, 1),
+    ("i64.load16_u", 0x33, I64, 1),
+    ("i64.load32_s", 0x34, I64, 2),
+    ("i64.load32_u", 0x35, I64, 2),
+    ("i32.store", 0x36, I32, 2),
+    ("i64.store", 0x37, I64, 3),
+    ("i32.store8", 0x3a, I32, 0),
+    ("i32.store16", 0x3b, I32, 1),
+    ("i64.store8", 0x3c, I64, 0),
+    ("i64.store16", 0x3d, I64, 1),
+    ("i64.store32", 0x3e, I64, 2),
+];
+
+fn is_store(opcode: u8) -> bool {
+    opcode >= 0x36
+}
+
+//
+// The text format
+//
+
+#[derive(Debug, Clone, PartialEq)]
+enum Sexp {
+    Atom(String),
+    Str(Vec<u8>),
+    List(Vec<Sexp>),
+}
+
+fn unexpected<T>(token: impl std::fmt::Debug) -> Result<T, WasmError> {
+    Err(WasmError::UnexpectedToken {
+        token: format!("{:?}", token),
+    })
+}
+
+// Reads the S-expressions of `s`, skipping whitespace and line comments.
+fn sexps(s: &str) -> Result<Vec<Sexp>, WasmError> {
+    let mut stack = vec![vec![]];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            ';' if chars.peek() == Some(&';') => while chars.next_if(|c| *c != '\n').is_some() {},
+            '(' => stack.push(vec![]),
+            ')' => {
+                let list = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(l) => l.push(Sexp::List(list)),
+                    None => return unexpected(")"),
+                }
+            }
+            '"' => {
+                let mut bytes = vec![];
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            let escape: String = chars.by_ref().take(2).collect();
+                            match u8::from_str_radix(&escape, 16) {
+                                Ok(b) if escape.len() == 2 => bytes.push(b),
+                                _ => return unexpected(format!("\\{}", escape)),
+                            }
+                        }
+                        Some(c) => bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                        None => return unexpected("\""),
+                    }
+                }
+                stack.last_mut().unwrap().push(Sexp::Str(bytes));
+            }
+            c => {
+                let mut atom = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"()\";".contains(*c)) {
+                    atom.push(c);
+                }
+                stack.last_mut().unwrap().push(Sexp::Atom(atom));
+            }
+        }
+    }
+    match stack.pop() {
+        Some(l) if stack.is_empty() => Ok(l),
+        _ => unexpected("("),
+    }
+}
+
+fn atom(s: Option<&Sexp>) -> Result<&str, WasmError> {
+    match s {
+        Some(Sexp::Atom(a)) => Ok(a),
+        s => unexpected(s),
+    }
+}
+
+fn string(s: Option<&Sexp>) -> Result<String, WasmError> {
+    match s {
+        Some(Sexp::Str(b)) => String::from_utf8(b.clone()).or_else(|_| unexpected(b)),
+        s => unexpected(s),
+    }
+}
+
+fn number<T: std::str::FromStr>(s: &str) -> Result<T, WasmError> {
+    s.parse().or_else(|_| unexpected(s))
+}
+
+fn val_type(s: &str) -> Result<ValType, WasmError> {
+    match s {
+        "i32" => Ok(I32),
+        "i64" => Ok(I64),
+        s => unexpected(s),
+    }
+}
+
+// Returns the head of the list `s`.
+fn head(s: &Sexp) -> Option<&str> {
+    match s {
+        Sexp::List(l) => match l.first() {
+            Some(Sexp::Atom(a)) => Some(a),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Reads `(param ...)`, `(result ...)` and `(local ...)` lists, and returns the names and the types
+// of the params and the locals, and the types of the results.
+#[allow(clippy::type_complexity)]
+fn signature(
+    fields: &[Sexp],
+) -> Result<
+    (
+        Vec<(Option<String>, ValType)>,
+        Vec<ValType>,
+        Vec<(Option<String>, ValType)>,
+    ),
+    WasmError,
+> {
+    let (mut params, mut results, mut locals) = (vec![], vec![], vec![]);
+    for field in fields {
+        let list = match field {
+            Sexp::List(l) => l,
+            s => return unexpected(s),
+        };
+        let kind = atom(list.first())?;
+        let names: Vec<&str> = list[1..]
+            .iter()
+            .map(|s| atom(Some(s)))
+            .collect::<Result<_, _>>()?;
+        // `(param $name type)` names a single one, `(param type type ...)` doesn't name any.
+        let typed = match names.first() {
+            Some(name) if name.starts_with('$') => {
+                if names.len() != 2 {
+                    return unexpected(field);
+                }
+                vec![(Some(name.to_string()), val_type(names[1])?)]
+            }
+            _ => names
+                .iter()
+                .map(|t| Ok((None, val_type(t)?)))
+                .collect::<Result<_, WasmError>>()?,
+        };
+        match kind {
+            "param" => params.extend(typed),
+            "result" if typed.iter().all(|(name, _)| name.is_none()) => {
+                results.extend(typed.into_iter().map(|(_, t)| t))
+            }
+            "local" => locals.extend(typed),
+            _ => return unexpected(field),
+        }
+    }
+    Ok((params, results, locals))
+}
+
+impl Module {
+    fn type_index(&mut self, ty: FuncType) -> u32 {
+        match self.types.iter().position(|t| *t == ty) {
+            Some(i) => i as u32,
+            None => {
+                self.types.push(ty);
+                self.types.len() as u32 - 1
+            }
+        }
+    }
+}
+
+// Parses the WebAssembly text `wat`. Only the parts of the format used by `Wasm` are supported:
+// functions, their imports and exports, one memory, data segments and globals, and the flat
+// (non-folded) form of the instructions.
+pub fn parse(wat: &str) -> Result<Module, WasmError> {
+    let sexps = sexps(wat)?;
+    let fields = match sexps.as_slice() {
+        [Sexp::List(l)] if atom(l.first())? == "module" => &l[1..],
+        _ => return unexpected("module"),
+    };
+
+    // The functions and the globals can be used before their definitions.
+    let mut functions = HashMap::new();
+    let mut globals = HashMap::new();
+    for field in fields {
+        let list = match field {
+            Sexp::List(l) => l,
+            s => return unexpected(s),
+        };
+        match atom(list.first())? {
+            "import" => match list.get(3) {
+                Some(Sexp::List(f)) if atom(f.first())? == "func" => {
+                    functions.insert(atom(f.get(1))?.to_owned(), functions.len() as u32);
+                }
+                s => return unexpected(s),
+            },
+            "func" => {
+                functions.insert(atom(list.get(1))?.to_owned(), functions.len() as u32);
+            }
+            "global" => {
+                globals.insert(atom(list.get(1))?.to_owned(), globals.len() as u32);
+            }
+            _ => {}
+        }
+    }
+
+    let mut module = Module::default();
+    for field in fields {
+        let list = match field {
+            Sexp::List(l) => l,
+            _ => unreachable!(),
+        };
+        match atom(list.first())? {
+            "import" => {
+                let f = match &list[3] {
+                    Sexp::List(f) => f,
+                    _ => unreachable!(),
+                };
+                let (params, results, locals) = signature(&f[2..])?;
+                if !locals.is_empty() || !module.functions.is_empty() {
+                    return unexpected(field);
+                }
+                let ty = module.type_index(FuncType {
+                    params: params.into_iter().map(|(_, t)| t).collect(),
+                    results,
+                });
+                module.imports.push(Import {
+                    module: string(list.get(1))?,
+                    name: string(list.get(2))?,
+                    ty,
+                });
+            }
+            "func" => {
+                let index = functions[atom(list.get(1))?];
+                let mut rest = &list[2..];
+                while let Some(Sexp::List(l)) = rest.first() {
+                    if head(&rest[0]) != Some("export") {
+                        break;
+                    }
+                    module.exports.push(Export {
+                        name: string(l.get(1))?,
+                        kind: ExportKind::Function,
+                        index,
+                    });
+                    rest = &rest[1..];
+                }
+                let n = rest
+                    .iter()
+                    .take_while(|s| matches!(s, Sexp::List(_)))
+                    .count();
+                let (params, results, locals) = signature(&rest[..n])?;
+                let ty = module.type_index(FuncType {
+                    params: params.iter().map(|(_, t)| *t).collect(),
+                    results,
+                });
+                let names: Vec<Option<String>> = params
+                    .iter()
+                    .chain(locals.iter())
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                let body = instructions(&rest[n..], &names, &functions, &globals)?;
+                module.functions.push(Function {
+                    ty,
+                    locals: locals.into_iter().map(|(_, t)| t).collect(),
+                    body,
+                });
+            }
+            "memory" => {
+                let mut rest = &list[1..];
+                if let Some(Sexp::List(l)) = rest.first() {
+                    if head(&rest[0]) != Some("export") {
+                        return unexpected(field);
+                    }
+                    module.exports.push(Export {
+                        name: string(l.get(1))?,
+                        kind: ExportKind::Memory,
+                        index: 0,
+                    });
+                    rest = &rest[1..];
+                }
+                if module.memory.is_some() || rest.len() != 1 {
+                    return unexpected(field);
+                }
+                module.memory = Some(number(atom(rest.first())?)?);
+            }
+            "data" => {
+                let offset = match list.get(1) {
+                    Some(Sexp::List(l)) if atom(l.first())? == "i32.const" => {
+                        number(atom(l.get(1))?)?
+                    }
+                    s => return unexpected(s),
+                };
+                let bytes = match list.get(2) {
+                    Some(Sexp::Str(b)) if list.len() == 3 => b.clone(),
+                    s => return unexpected(s),
+                };
+                module.data.push(Data { offset, bytes });
+            }
+            "global" => {
+                let (ty, mutable) = match list.get(2) {
+                    Some(Sexp::List(l)) if atom(l.first())? == "mut" => {
+                        (val_type(atom(l.get(1))?)?, true)
+                    }
+                    s => (val_type(atom(s)?)?, false),
+                };
+                let init = match (list.get(3), ty) {
+                    (Some(Sexp::List(l)), I32) if atom(l.first())? == "i32.const" => {
+                        Instruction::I32Const(number(atom(l.get(1))?)?)
+                    }
+                    (Some(Sexp::List(l)), I64) if atom(l.first())? == "i64.const" => {
+                        Instruction::I64Const(number(atom(l.get(1))?)?)
+                    }
+                    (s, _) => return unexpected(s),
+                };
+                module.globals.push(Global { ty, mutable, init });
+            }
+            _ => return unexpected(field),
+        }
+    }
+    Ok(module)
+}
+
+// Returns the index named by `s`, which is either a `$name` in `names` or a number.
+fn index(s: &str, names: &HashMap<String, u32>) -> Result<u32, WasmError> {
+    if s.starts_with('$') {
+        names
+            .get(s)
+            .copied()
+            .ok_or_else(|| WasmError::UnknownIdentifier { name: s.to_owned() })
+    } else {
+        number(s)
+    }
+}
+
+// Parses the flat instructions of a function body. `locals` are the names of its params and
+// locals.
+fn instructions(
+    body: &[Sexp],
+    locals: &[Option<String>],
+    functions: &HashMap<String, u32>,
+    globals: &HashMap<String, u32>,
+) -> Result<Vec<Instruction>, WasmError> {
+    let locals: HashMap<String, u32> = locals
+        .iter()
+        .enumerate()
+        .filter_map(|(i, name)| name.clone().map(|n| (n, i as u32)))
+        .collect();
+    let mut atoms = body.iter().map(|s| atom(Some(s))).peekable();
+    let mut labels: Vec<Option<&str>> = vec![];
+    let mut instructions = vec![];
+    while let Some(name) = atoms.next() {
+        let name = name?;
+        let i = match name {
+            "block" | "loop" | "if" => {
+                let label = match atoms.peek() {
+                    Some(Ok(a)) if a.starts_with('$') => Some(atoms.next().unwrap()?),
+                    _ => None,
+                };
+                labels.push(label);
+                Instruction::Block(match name {
+                    "block" => BLOCK,
+                    "loop" => LOOP,
+                    _ => IF,
+                })
+            }
+            "end" => {
+                if labels.pop().is_none() {
+                    return unexpected(name);
+                }
+                Instruction::Plain(END)
+            }
+            "br" | "br_if" => {
+                let label = atoms.next().unwrap_or_else(|| unexpected(name))?;
+                let depth = match labels.iter().rev().position(|l| *l == Some(label)) {
+                    Some(depth) => depth as u32,
+                    None if label.starts_with('$') => {
+                        return Err(WasmError::UnknownIdentifier {
+                            name: label.to_owned(),
+                        })
+                    }
+                    None => number(label)?,
+                };
+                Instruction::Br(if name == "br" { BR } else { BR_IF }, depth)
+            }
+            "call" => Instruction::Call(index(
+                atoms.next().unwrap_or_else(|| unexpected(name))?,
+                functions,
+            )?),
+            "local.get" => Instruction::Variable(
+                LOCAL_GET,
+                index(atoms.next().unwrap_or_else(|| unexpected(name))?, &locals)?,
+            ),
+            "local.set" => Instruction::Variable(
+                LOCAL_SET,
+                index(atoms.next().unwrap_or_else(|| unexpected(name))?, &locals)?,
+            ),
+            "local.tee" => Instruction::Variable(
+                LOCAL_TEE,
+                index(atoms.next().unwrap_or_else(|| unexpected(name))?, &locals)?,
+            ),
+            "global.get" => Instruction::Variable(
+                GLOBAL_GET,
+                index(atoms.next().unwrap_or_else(|| unexpected(name))?, globals)?,
+            ),
+            "global.set" => Instruction::Variable(
+                GLOBAL_SET,
+                index(atoms.next().unwrap_or_else(|| unexpected(name))?, globals)?,
+            ),
+            "i32.const" => {
+                Instruction::I32Const(number(atoms.next().unwrap_or_else(|| unexpected(name))?)?)
+            }
+            "i64.const" => {
+                Instruction::I64Const(number(atoms.next().unwrap_or_else(|| unexpected(name))?)?)
+            }
+            "unreachable" => Instruction::Plain(UNREACHABLE),
+            "return" => Instruction::Plain(RETURN),
+            "select" => Instruction::Plain(SELECT),
+            "memory.size" => Instruction::MemoryIndex(MEMORY_SIZE),
+            "memory.grow" => Instruction::MemoryIndex(MEMORY_GROW),
+            "memory.copy" => Instruction::Bulk(MEMORY_COPY),
+            "memory.fill" => Instruction::Bulk(MEMORY_FILL),
+            name => {
+                if let Some((_, opcode, _, _)) = NUMERIC.iter().find(|n| n.0 == name) {
+                    Instruction::Plain(*opcode)
+                } else if let Some((_, opcode, _, align)) = MEMORY.iter().find(|m| m.0 == name) {
+                    let (mut align, mut offset) = (*align, 0);
+                    while let Some(Ok(a)) = atoms.peek() {
+                        if let Some(n) = a.strip_prefix("offset=") {
+                            offset = number(n)?;
+                        } else if let Some(n) = a.strip_prefix("align=") {
+                            align = number::<u32>(n)?.trailing_zeros();
+                        } else {
+                            break;
+                        }
+                        atoms.next();
+                    }
+                    Instruction::Memory(*opcode, align, offset)
+                } else {
+                    return Err(WasmError::UnknownInstruction {
+                        name: name.to_owned(),
+                    });
+                }
+            }
+        };
+        instructions.push(i);
+    }
+    if !labels.is_empty() {
+        return unexpected("end");
+    }
+    Ok(instructions)
+}
+
+//
+// The binary format
+//
+
+fn uleb(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    uleb(out, s.len() as u64);
+    out.extend(s.as_bytes());
+}
+
+// Writes the vector `items` with `f`.
+fn vector<T>(out: &mut Vec<u8>, items: &[T], mut f: impl FnMut(&mut Vec<u8>, &T)) {
+    uleb(out, items.len() as u64);
+    for i in items {
+        f(out, i);
+    }
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: Vec<u8>) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend(contents);
+}
+
+fn instruction(out: &mut Vec<u8>, i: &Instruction) {
+    match *i {
+        Instruction::Plain(opcode) => out.push(opcode),
+        Instruction::Block(opcode) => out.extend([opcode, 0x40]),
+        Instruction::Br(opcode, n) | Instruction::Variable(opcode, n) => {
+            out.push(opcode);
+            uleb(out, n as u64);
+        }
+        Instruction::Call(n) => {
+            out.push(CALL);
+            uleb(out, n as u64);
+        }
+        Instruction::Memory(opcode, align, offset) => {
+            out.push(opcode);
+            uleb(out, align as u64);
+            uleb(out, offset as u64);
+        }
+        Instruction::MemoryIndex(opcode) => out.extend([opcode, 0x00]),
+        Instruction::Bulk(n) => {
+            out.push(BULK);
+            uleb(out, n as u64);
+            // The memory indices of the destination (and the source).
+            out.extend(if n == MEMORY_COPY { &[0, 0][..] } else { &[0] });
+        }
+        Instruction::I32Const(n) => {
+            out.push(I32_CONST);
+            sleb(out, n as i64);
+        }
+        Instruction::I64Const(n) => {
+            out.push(I64_CONST);
+            sleb(out, n);
+        }
+    }
+}
+
+impl Module {
+    // Returns the module in the binary format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend(1u32.to_le_bytes());
+
+        let mut s = vec![];
+        vector(&mut s, &self.types, |out, t| {
+            out.push(0x60);
+            vector(out, &t.params, |out, p| out.push(p.code()));
+            vector(out, &t.results, |out, r| out.push(r.code()));
+        });
+        section(&mut out, 1, s);
+
+        let mut s = vec![];
+        vector(&mut s, &self.imports, |out, i| {
+            name(out, &i.module);
+            name(out, &i.name);
+            out.push(0x00);
+            uleb(out, i.ty as u64);
+        });
+        section(&mut out, 2, s);
+
+        let mut s = vec![];
+        vector(&mut s, &self.functions, |out, f| uleb(out, f.ty as u64));
+        section(&mut out, 3, s);
+
+        if let Some(pages) = self.memory {
+            let mut s = vec![];
+            vector(&mut s, &[pages], |out, pages| {
+                out.push(0x00);
+                uleb(out, *pages as u64);
+            });
+            section(&mut out, 5, s);
+        }
+
+        let mut s = vec![];
+        vector(&mut s, &self.globals, |out, g| {
+            out.push(g.ty.code());
+            out.push(g.mutable as u8);
+            instruction(out, &g.init);
+            out.push(END);
+        });
+        section(&mut out, 6, s);
+
+        let mut s = vec![];
+        vector(&mut s, &self.exports, |out, e| {
+            name(out, &e.name);
+            out.push(match e.kind {
+                ExportKind::Function => 0x00,
+                ExportKind::Memory => 0x02,
+            });
+            uleb(out, e.index as u64);
+        });
+        section(&mut out, 7, s);
+
+        let mut s = vec![];
+        vector(&mut s, &self.functions, |out, f| {
+            let mut body = vec![];
+            // The locals are grouped by runs of the same type.
+            let mut groups: Vec<(u32, ValType)> = vec![];
+            for t in &f.locals {
+                match groups.last_mut() {
+                    Some((n, last)) if last == t => *n += 1,
+                    _ => groups.push((1, *t)),
+                }
+            }
+            vector(&mut body, &groups, |out, (n, t)| {
+                uleb(out, *n as u64);
+                out.push(t.code());
+            });
+            for i in &f.body {
+                instruction(&mut body, i);
+            }
+            body.push(END);
+            uleb(out, body.len() as u64);
+            out.extend(body);
+        });
+        section(&mut out, 10, s);
+
+        let mut s = vec![];
+        vector(&mut s, &self.data, |out, d| {
+            out.push(0x00);
+            instruction(out, &Instruction::I32Const(d.offset as i32));
+            out.push(END);
+            uleb(out, d.bytes.len() as u64);
+            out.extend(&d.bytes);
+        });
+        section(&mut out, 11, s);
+
+        out
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn malformed<T>(&self) -> Result<T, WasmError> {
+        Err(WasmError::Malformed { offset: self.pos })
+    }
+
+    fn byte(&mut self) -> Result<u8, WasmError> {
+        match self.bytes.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => self.malformed(),
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), WasmError> {
+        if self.byte()? != byte {
+            self.pos -= 1;
+            return self.malformed();
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], WasmError> {
+        match self.bytes.get(self.pos..self.pos + n) {
+            Some(b) => {
+                self.pos += n;
+                Ok(b)
+            }
+            None => self.malformed(),
+        }
+    }
+
+    // Reads an unsigned LEB128 number of at most `bits` bits.
+    fn uleb(&mut self, bits: u32) -> Result<u64, WasmError> {
+        let (mut n, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits || (shift + 7 > bits && (byte & 0x7f) >> (bits - shift) != 0) {
+                self.pos -= 1;
+                return self.malformed();
+            }
+            n |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, WasmError> {
+        self.uleb(32).map(|n| n as u32)
+    }
+
+    // Reads a signed LEB128 number of at most `bits` bits.
+    fn sleb(&mut self, bits: u32) -> Result<i64, WasmError> {
+        let (mut n, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits {
+                self.pos -= 1;
+                return self.malformed();
+            }
+            n |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    n |= -1 << shift;
+                }
+                // The unused bits of the last byte must be the sign extension.
+                let min = -(1i128 << (bits - 1));
+                let max = (1i128 << (bits - 1)) - 1;
+                if (n as i128) < min || (n as i128) > max {
+                    return self.malformed();
+                }
+                return Ok(n);
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, WasmError> {
+        let n = self.u32()? as usize;
+        let pos = self.pos;
+        let bytes = self.take(n)?;
+        String::from_utf8(bytes.to_vec()).or(Err(WasmError::Malformed { offset: pos }))
+    }
+
+    fn vector<T>(
+        &mut self,
+        mut f: impl FnMut(&mut Self) -> Result<T, WasmError>,
+    ) -> Result<Vec<T>, WasmError> {
+        let n = self.u32()?;
+        (0..n).map(|_| f(self)).collect()
+    }
+
+    fn val_type(&mut self) -> Result<ValType, WasmError> {
+        match self.byte()? {
+            0x7f => Ok(I32),
+            0x7e => Ok(I64),
+            _ => {
+                self.pos -= 1;
+                self.malformed()
+            }
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, WasmError> {
+        let start = self.pos;
+        let opcode = self.byte()?;
+        let i = match opcode {
+            BLOCK | LOOP | IF => {
+                self.expect(0x40)?;
+                Instruction::Block(opcode)
+            }
+            BR | BR_IF => Instruction::Br(opcode, self.u32()?),
+            CALL => Instruction::Call(self.u32()?),
+            LOCAL_GET | LOCAL_SET | LOCAL_TEE | GLOBAL_GET | GLOBAL_SET => {
+                Instruction::Variable(opcode, self.u32()?)
+            }
+            MEMORY_SIZE | MEMORY_GROW => {
+                self.expect(0x00)?;
+                Instruction::MemoryIndex(opcode)
+            }
+            I32_CONST => Instruction::I32Const(self.sleb(32)? as i32),
+            I64_CONST => Instruction::I64Const(self.sleb(64)?),
+            BULK => {
+                let n = self.u32()?;
+                match n {
+                    MEMORY_COPY => {
+                        self.expect(0x00)?;
+                        self.expect(0x00)?;
+                    }
+                    MEMORY_FILL => self.expect(0x00)?,
+                    _ => return Err(WasmError::Malformed { offset: start }),
+                }
+                Instruction::Bulk(n)
+            }
+            UNREACHABLE | END | RETURN | SELECT => Instruction::Plain(opcode),
+            opcode if NUMERIC.iter().any(|n| n.1 == opcode) => Instruction::Plain(opcode),
+            opcode if MEMORY.iter().any(|m| m.1 == opcode) => {
+                Instruction::Memory(opcode, self.u32()?, self.u32()?)
+            }
+            _ => return Err(WasmError::Malformed { offset: start }),
+        };
+        Ok(i)
+    }
+
+    // Reads a constant expression.
+    fn constant(&mut self) -> Result<Instruction, WasmError> {
+        let pos = self.pos;
+        let i = self.instruction()?;
+        self.expect(END)?;
+        match i {
+            Instruction::I32Const(_) | Instruction::I64Const(_) => Ok(i),
+            _ => Err(WasmError::Malformed { offset: pos }),
+        }
+    }
+
+    // Reads a function body without its final 'end'.
+    fn body(&mut self) -> Result<Vec<Instruction>, WasmError> {
+        let mut body = vec![];
+        let mut depth = 0;
+        loop {
+            let i = self.instruction()?;
+            match i {
+                Instruction::Block(_) => depth += 1,
+                Instruction::Plain(END) if depth == 0 => return Ok(body),
+                Instruction::Plain(END) => depth -= 1,
+                _ => {}
+            }
+            body.push(i);
+        }
+    }
+}
+
+impl Module {
+    // Reads a module in the binary format. It isn't validated.
+    pub fn decode(bytes: &[u8]) -> Result<Module, WasmError> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(8)? != b"\0asm\x01\0\0\0" {
+            return Err(WasmError::Malformed { offset: 0 });
+        }
+
+        let mut module = Module::default();
+        let mut function_types = vec![];
+        let mut last_id = 0;
+        while r.pos < bytes.len() {
+            let start = r.pos;
+            let id = r.byte()?;
+            let size = r.u32()? as usize;
+            let mut s = Reader {
+                bytes: r.take(size)?,
+                pos: 0,
+            };
+            if id == 0 {
+                // A custom section.
+                continue;
+            }
+            if id <= last_id {
+                return Err(WasmError::Malformed { offset: start });
+            }
+            last_id = id;
+
+            match id {
+                1 => {
+                    module.types = s.vector(|s| {
+                        s.expect(0x60)?;
+                        Ok(FuncType {
+                            params: s.vector(Reader::val_type)?,
+                            results: s.vector(Reader::val_type)?,
+                        })
+                    })?
+                }
+                2 => {
+                    module.imports = s.vector(|s| {
+                        let module = s.name()?;
+                        let name = s.name()?;
+                        s.expect(0x00)?;
+                        Ok(Import {
+                            module,
+                            name,
+                            ty: s.u32()?,
+                        })
+                    })?
+                }
+                3 => function_types = s.vector(Reader::u32)?,
+                5 => {
+                    let memories = s.vector(|s| {
+                        s.expect(0x00)?;
+                        s.u32()
+                    })?;
+                    match memories.as_slice() {
+                        [pages] => module.memory = Some(*pages),
+                        _ => return Err(WasmError::Malformed { offset: start }),
+                    }
+                }
+                6 => {
+                    module.globals = s.vector(|s| {
+                        let ty = s.val_type()?;
+                        let mutable = match s.byte()? {
+                            0 => false,
+                            1 => true,
+                            _ => return s.malformed(),
+                        };
+                        Ok(Global {
+                            ty,
+                            mutable,
+                            init: s.constant()?,
+                        })
+                    })?
+                }
+                7 => {
+                    module.exports = s.vector(|s| {
+                        let name = s.name()?;
+                        let kind = match s.byte()? {
+                            0x00 => ExportKind::Function,
+                            0x02 => ExportKind::Memory,
+                            _ => return s.malformed(),
+                        };
+                        Ok(Export {
+                            name,
+                            kind,
+                            index: s.u32()?,
+                        })
+                    })?
+                }
+                10 => {
+                    let bodies = s.vector(|s| {
+                        let size = s.u32()? as usize;
+                        let end = s.pos + size;
+                        let mut locals = vec![];
+                        for (n, t) in s.vector(|s| Ok((s.u32()?, s.val_type()?)))? {
+                            if locals.len() + n as usize > 50000 {
+                                return s.malformed();
+                            }
+                            locals.extend(std::iter::repeat_n(t, n as usize));
+                        }
+                        let body = s.body()?;
+                        if s.pos != end {
+                            return s.malformed();
+                        }
+                        Ok((locals, body))
+                    })?;
+                    if bodies.len() != function_types.len() {
+                        return Err(WasmError::Malformed { offset: start });
+                    }
+                    module.functions = function_types
+                        .iter()
+                        .zip(bodies)
+                        .map(|(ty, (locals, body))| Function {
+                            ty: *ty,
+                            locals,
+                            body,
+                        })
+                        .collect();
+                }
+                11 => {
+                    module.data = s.vector(|s| {
+                        s.expect(0x00)?;
+                        let offset = match s.constant()? {
+                            Instruction::I32Const(n) => n as u32,
+                            _ => return s.malformed(),
+                        };
+                        let n = s.u32()? as usize;
+                        Ok(Data {
+                            offset,
+                            bytes: s.take(n)?.to_vec(),
+                        })
+                    })?
+                }
+                _ => return Err(WasmError::Malformed { offset: start }),
+            }
+            if s.pos != size {
+                return Err(WasmError::Malformed {
+                    offset: start + s.pos,
+                });
+            }
+        }
+        if !function_types.is_empty() && module.functions.is_empty() {
+            return Err(WasmError::Malformed { offset: r.pos });
+        }
+        Ok(module)
+    }
+}
+
+//
+// Validation
+//
+
+fn invalid<T>(reason: String) -> Result<T, WasmError> {
+    Err(WasmError::Invalid { reason })
+}
+
+// A block being validated.
+struct Frame {
+    opcode: u8,
+    height: usize,
+    unreachable: bool,
+}
+
+// The operand stack of a function being validated. `None` is an operand of any type, which
+// appears after an unconditional branch.
+struct Validator<'a> {
+    module: &'a Module,
+    locals: Vec<ValType>,
+    results: &'a [ValType],
+    stack: Vec<Option<ValType>>,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Validator<'a> {
+    fn pop(&mut self) -> Result<Option<ValType>, String> {
+        let frame = self.frames.last().unwrap();
+        if self.stack.len() == frame.height {
+            return if frame.unreachable {
+                Ok(None)
+            } else {
+                Err("the operand stack is empty".to_owned())
+            };
+        }
+        Ok(self.stack.pop().unwrap())
+    }
+
+    fn pop_type(&mut self, expected: ValType) -> Result<(), String> {
+        match self.pop()? {
+            Some(t) if t != expected => Err(format!("expected {:?}, found {:?}", expected, t)),
+            _ => Ok(()),
+        }
+    }
+
+    fn push(&mut self, t: ValType) {
+        self.stack.push(Some(t));
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        self.stack.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    // Returns the types a branch to the label `depth` takes. Every block has the empty type, and
+    // the outermost one is the function body.
+    fn label(&self, depth: u32) -> Result<&'a [ValType], String> {
+        match (depth as usize).cmp(&(self.frames.len() - 1)) {
+            std::cmp::Ordering::Less => Ok(&[]),
+            std::cmp::Ordering::Equal => Ok(self.results),
+            std::cmp::Ordering::Greater => Err(format!("unknown label {}", depth)),
+        }
+    }
+
+    fn function_type(&self, index: u32) -> Result<&'a FuncType, String> {
+        let m = self.module;
+        let ty = match m.imports.get(index as usize) {
+            Some(i) => i.ty,
+            None => match m.functions.get(index as usize - m.imports.len()) {
+                Some(f) => f.ty,
+                None => return Err(format!("unknown function {}", index)),
+            },
+        };
+        m.types
+            .get(ty as usize)
+            .ok_or_else(|| format!("unknown type {}", ty))
+    }
+
+    fn memory(&self) -> Result<(), String> {
+        match self.module.memory {
+            Some(_) => Ok(()),
+            None => Err("no memory".to_owned()),
+        }
+    }
+
+    fn instruction(&mut self, i: &Instruction) -> Result<(), String> {
+        match *i {
+            Instruction::Plain(UNREACHABLE) => self.set_unreachable(),
+            Instruction::Plain(RETURN) => {
+                for t in self.results.iter().rev() {
+                    self.pop_type(*t)?;
+                }
+                self.set_unreachable();
+            }
+            Instruction::Plain(SELECT) => {
+                self.pop_type(I32)?;
+                let (a, b) = (self.pop()?, self.pop()?);
+                match (a, b) {
+                    (Some(a), Some(b)) if a != b => {
+                        return Err(format!("select of {:?} and {:?}", b, a))
+                    }
+                    (Some(t), _) | (_, Some(t)) => self.push(t),
+                    (None, None) => self.stack.push(None),
+                }
+            }
+            Instruction::Plain(END) => {
+                let height = self.frames.last().unwrap().height;
+                let results = if self.frames.len() == 1 {
+                    self.results
+                } else {
+                    &[]
+                };
+                for t in results.iter().rev() {
+                    self.pop_type(*t)?;
+                }
+                if self.stack.len() != height {
+                    return Err("values remain at the end of a block".to_owned());
+                }
+                self.frames.pop();
+                for t in results {
+                    self.push(*t);
+                }
+            }
+            Instruction::Plain(opcode) => {
+                let (_, _, params, result) = NUMERIC
+                    .iter()
+                    .find(|n| n.1 == opcode)
+                    .ok_or_else(|| format!("unknown opcode {:#x}", opcode))?;
+                for t in params.iter().rev() {
+                    self.pop_type(*t)?;
+                }
+                if let Some(t) = result {
+                    self.push(*t);
+                }
+            }
+            Instruction::Block(opcode) => {
+                if opcode == IF {
+                    self.pop_type(I32)?;
+                }
+                self.frames.push(Frame {
+                    opcode,
+                    height: self.stack.len(),
+                    unreachable: false,
+                });
+            }
+            Instruction::Br(opcode, depth) => {
+                if opcode == BR_IF {
+                    self.pop_type(I32)?;
+                }
+                // A branch to a loop goes to its start, which takes no values.
+                let frame = &self.frames[self.frames.len().saturating_sub(depth as usize + 1)];
+                let types = if frame.opcode == LOOP {
+                    &[]
+                } else {
+                    self.label(depth)?
+                };
+                for t in types.iter().rev() {
+                    self.pop_type(*t)?;
+                }
+                if opcode == BR {
+                    self.set_unreachable();
+                } else {
+                    for t in types {
+                        self.push(*t);
+                    }
+                }
+            }
+            Instruction::Call(index) => {
+                let ty = self.function_type(index)?;
+                for t in ty.params.iter().rev() {
+                    self.pop_type(*t)?;
+                }
+                for t in &ty.results {
+                    self.push(*t);
+                }
+            }
+            Instruction::Variable(opcode, index) => match opcode {
+                LOCAL_GET | LOCAL_SET | LOCAL_TEE => {
+                    let t = *self
+                        .locals
+                        .get(index as usize)
+                        .ok_or_else(|| format!("unknown local {}", index))?;
+                    if opcode != LOCAL_GET {
+                        self.pop_type(t)?;
+                    }
+                    if opcode != LOCAL_SET {
+                        self.push(t);
+                    }
+                }
+                _ => {
+                    let g = self
+                        .module
+                        .globals
+                        .get(index as usize)
+                        .ok_or_else(|| format!("unknown global {}", index))?;
+                    if opcode == GLOBAL_GET {
+                        self.push(g.ty);
+                    } else if g.mutable {
+                        self.pop_type(g.ty)?;
+                    } else {
+                        return Err(format!("global {} is immutable", index));
+                    }
+                }
+            },
+            Instruction::Memory(opcode, align, _) => {
+                self.memory()?;
+                let (name, _, t, natural) = MEMORY
+                    .iter()
+                    .find(|m| m.1 == opcode)
+                    .ok_or_else(|| format!("unknown opcode {:#x}", opcode))?;
+                if align > *natural {
+                    return Err(format!("the alignment of {} is too large", name));
+                }
+                if is_store(opcode) {
+                    self.pop_type(*t)?;
+                    self.pop_type(I32)?;
+                } else {
+                    self.pop_type(I32)?;
+                    self.push(*t);
+                }
+            }
+            Instruction::MemoryIndex(opcode) => {
+                self.memory()?;
+                if opcode == MEMORY_GROW {
+                    self.pop_type(I32)?;
+                }
+                self.push(I32);
+            }
+            Instruction::Bulk(_) => {
+                self.memory()?;
+                for _ in 0..3 {
+                    self.pop_type(I32)?;
+                }
+            }
+            Instruction::I32Const(_) => self.push(I32),
+            Instruction::I64Const(_) => self.push(I64),
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    // Checks that the indices in the module are in range, and that the functions are well typed.
+    pub fn validate(&self) -> Result<(), WasmError> {
+        for i in &self.imports {
+            if self.types.get(i.ty as usize).is_none() {
+                return invalid(format!(
+                    "import {}.{}: unknown type {}",
+                    i.module, i.name, i.ty
+                ));
+            }
+        }
+        for (n, g) in self.globals.iter().enumerate() {
+            match (g.ty, g.init) {
+                (I32, Instruction::I32Const(_)) | (I64, Instruction::I64Const(_)) => {}
+                _ => return invalid(format!("global {}: mismatched initializer", n)),
+            }
+        }
+        let functions = self.imports.len() + self.functions.len();
+        for (n, e) in self.exports.iter().enumerate() {
+            let valid = match e.kind {
+                ExportKind::Function => (e.index as usize) < functions,
+                ExportKind::Memory => e.index == 0 && self.memory.is_some(),
+            };
+            if !valid {
+                return invalid(format!("export '{}': unknown index {}", e.name, e.index));
+            }
+            if self.exports[..n].iter().any(|other| other.name == e.name) {
+                return invalid(format!("export '{}' is duplicated", e.name));
+            }
+        }
+        let size = self.memory.map_or(0, |pages| pages as u64 * PAGE);
+        for d in &self.data {
+            if d.offset as u64 + d.bytes.len() as u64 > size {
+                return invalid(format!("data at {} is outside the memory", d.offset));
+            }
+        }
+
+        for (n, f) in self.functions.iter().enumerate() {
+            let index = self.imports.len() + n;
+            let ty = match self.types.get(f.ty as usize) {
+                Some(ty) => ty,
+                None => return invalid(format!("function {}: unknown type {}", index, f.ty)),
+            };
+            let mut v = Validator {
+                module: self,
+                locals: ty.params.iter().chain(f.locals.iter()).copied().collect(),
+                results: &ty.results,
+                stack: vec![],
+                frames: vec![Frame {
+                    opcode: BLOCK,
+                    height: 0,
+                    unreachable: false,
+                }],
+            };
+            for (offset, i) in f.body.iter().chain([&Instruction::Plain(END)]).enumerate() {
+                if v.frames.is_empty() {
+                    return invalid(format!("function {}: unexpected end", index));
+                }
+                if let Err(reason) = v.instruction(i) {
+                    return invalid(format!(
+                        "function {}, instruction {}: {}",
+                        index, offset, reason
+                    ));
+                }
+            }
+            if !v.frames.is_empty() {
+                return invalid(format!("function {}: missing end", index));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn compile(src: &str, options: &crate::codegen::options::Options) -> String {
+    crate::codegen::compile(&mut crate::codegen::wasm::Wasm::with_options(options), src)
+}
+
+// Runs `src` with the WASI implementation of Node.js, if it is installed.
+#[cfg(test)]
+pub(super) fn run(
+    src: &str,
+    options: &crate::codegen::options::Options,
+    input: &str,
+) -> Option<(String, String, i32)> {
+    let module = parse(&compile(src, options)).unwrap();
+    module.validate().unwrap();
+    crate::codegen::run_built("wasm", input, |dir| {
+        if !crate::codegen::has_tool("node") {
+            return None;
+        }
+        let (wasm, js) = (dir.join("main.wasm"), dir.join("main.mjs"));
+        std::fs::write(&wasm, module.encode()).unwrap();
+        std::fs::write(
+            &js,
+            "import { readFileSync } from 'node:fs';
+import { WASI } from 'node:wasi';
+const wasi = new WASI({ version: 'preview1', returnOnExit: true });
+const { instance } = await WebAssembly.instantiate(
+  readFileSync(process.argv[2]), wasi.getImportObject());
+process.exitCode = wasi.start(instance);
+",
+        )
+        .unwrap();
+        let mut command = std::process::Command::new("node");
+        command.arg("--no-warnings").arg(js).arg(wasm);
+        Some(command)
+    })
+}
+
+#[test]
+fn test_leb() {
+    let mut out = vec![];
+    uleb(&mut out, 624485);
+    sleb(&mut out, -123456);
+    sleb(&mut out, 64);
+    sleb(&mut out, i64::MIN);
+    assert_eq!(
+        out[..9],
+        [0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78, 0xc0, 0x00, 0x80]
+    );
+
+    let mut r = Reader {
+        bytes: &out,
+        pos: 0,
+    };
+    assert_eq!(r.uleb(32), Ok(624485));
+    assert_eq!(r.sleb(32), Ok(-123456));
+    assert_eq!(r.sleb(32), Ok(64));
+    assert_eq!(r.sleb(64), Ok(i64::MIN));
+    assert_eq!(r.pos, out.len());
+
+    // Too many bytes, and bits beyond 32.
+    let mut r = Reader {
+        bytes: &[
+            0x80, 0x80, 0x80, 0x80, 0x80, 0x00, 0xff, 0xff, 0xff, 0xff, 0x7f,
+        ],
+        pos: 0,
+    };
+    assert_eq!(r.uleb(32), Err(WasmError::Malformed { offset: 5 }));
+    r.pos = 6;
+    assert_eq!(r.uleb(32), Err(WasmError::Malformed { offset: 10 }));
+}
+
+#[test]
+fn test_round_trip() {
+    use crate::codegen::options::{CellWidth, Eof, Options, Overflow, TapeMode};
+
+    let src = "getint();\nwhile *ptr {\n  putint();\n  *ptr -= 1;\n  ptr += 1;\n  getchar();\n  putchar();\n  ptr -= 1;\n}\nexit(*ptr);\n";
+    let mut options = Options::new();
+    for (width, overflow, tape_mode) in [
+        (CellWidth::W8, Overflow::Wrap, TapeMode::Fixed),
+        (CellWidth::W16, Overflow::Saturate, TapeMode::Grow),
+        (CellWidth::W32, Overflow::Trap, TapeMode::Wrap),
+        (CellWidth::W64, Overflow::Saturate, TapeMode::Fixed),
+    ] {
+        options.cell_width = width;
+        options.overflow = overflow;
+        options.tape_mode = tape_mode;
+        options.bounds_check = !options.bounds_check;
+        options.buffered_io = !options.buffered_io;
+        options.eof = Eof::Unchanged;
+
+        let module = parse(&compile(src, &options)).unwrap();
+        assert_eq!(module.validate(), Ok(()));
+        let bytes = module.encode();
+        assert_eq!(&bytes[..8], b"\0asm\x01\0\0\0");
+        let decoded = Module::decode(&bytes).unwrap();
+        assert_eq!(decoded, module);
+        assert_eq!(decoded.validate(), Ok(()));
+        assert_eq!(decoded.encode(), bytes);
+
+        // Any truncation of the module is malformed.
+        for n in [9, bytes.len() / 2, bytes.len() - 1] {
+            assert!(matches!(
+                Module::decode(&bytes[..n]),
+                Err(WasmError::Malformed { .. })
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_validate() {
+    let wat = |body: &str| {
+        parse(&format!(
+            "(module (func $f (param $p i32) (result i32) (local $l i64) {}) (memory 1) \
+             (global $g i32 (i32.const 0)))",
+            body
+        ))
+        .unwrap()
+        .validate()
+    };
+    assert_eq!(wat("local.get $p"), Ok(()));
+    assert_eq!(
+        wat("block $b loop $l local.get $p br_if $l local.get $l i32.wrap_i64 br_if $b end end unreachable"),
+        Ok(())
+    );
+    assert_eq!(wat("i64.const 1 i64.eqz return i32.add"), Ok(()));
+    assert!(wat("local.get $l").is_err());
+    assert!(wat("local.get $p local.get $p").is_err());
+    assert!(wat("i32.const 1 i64.const 1 i64.add").is_err());
+    assert!(wat("block local.get $p end").is_err());
+    assert!(wat("i32.const 0 global.set $g i32.const 0").is_err());
+    assert!(wat("i32.const 0 i64.load align=16").is_err());
+    assert_eq!(
+        parse("(module (func $f br $l))"),
+        Err(WasmError::UnknownIdentifier {
+            name: "$l".to_owned()
+        })
+    );
+    assert_eq!(
+        parse("(module (func $f i32.frobnicate))"),
+        Err(WasmError::UnknownInstruction {
+            name: "i32.frobnicate".to_owned()
+        })
+    );
+    assert!(parse("(module (memory 1) (data (i32.const 65535) \"ab\"))")
+        .unwrap()
+        .validate()
+        .is_err());
+}
+
+#[test]
+fn test_run() {
+    use crate::codegen::options::{CellWidth, Options, Overflow, TapeMode, TapeSize};
+
+    let src = "getint();\n*ptr += 5;\nputint();\nptr += 1;\n*ptr += 10;\nputchar();\ngetint();\nputint();\n";
+    let output = match run(src, &Options::new(), " -37 x") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("-32\n-1".to_owned(), "".to_owned(), 0));
+
+    // Numbers out of range saturate like with scanf.
+    let src = "getint();\nputint();\nptr += 1;\n*ptr += 32;\nputchar();\nptr += 1;\ngetint();\nputint();\n";
+    let input = "99999999999999999999 -99999999999999999999";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W64;
+    assert_eq!(
+        run(src, &options, input).unwrap().0,
+        "9223372036854775807 -9223372036854775808"
+    );
+    let input = "9223372036854775807 -9223372036854775808";
+    assert_eq!(run(src, &options, input).unwrap().0, input);
+
+    let src = "*ptr -= 1;\nputint();\n*ptr += 100;\n*ptr += 100;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1-57");
+    options.overflow = Overflow::Saturate;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1127");
+    options.cell_width = CellWidth::W64;
+    options.buffered_io = true;
+    assert_eq!(run(src, &options, "").unwrap().0, "-1199");
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nptr -= 1;\nputint();\nptr += 2;\nputint();\nexit(*ptr);\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    assert_eq!(
+        run(src, &options, "").unwrap(),
+        ("03".to_owned(), "".to_owned(), 3)
+    );
+
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(2);
+    assert_eq!(run(src, &options, "").unwrap().0, "33");
+
+    options.tape_mode = TapeMode::Fixed;
+    options.tape_size = TapeSize(30000);
+    options.bounds_check = true;
+    assert_eq!(
+        run(src, &options, "").unwrap(),
+        (
+            "".to_owned(),
+            "chiya: 9:1: pointer out of bounds (-1)\n".to_owned(),
+            1
+        )
+    );
+}
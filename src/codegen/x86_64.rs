@@ -0,0 +1,1019 @@
+pub mod assembler;
+
+use std::collections::VecDeque;
+
+use crate::codegen::emitter;
+use crate::codegen::options::{Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::token::Location;
+
+// The size of the address range reserved for a tape with `tape_mode=grow`, unless `tape_size` needs
+// more. Only the pages the program touches are ever allocated, so the tape can grow far in both
+// directions, and leaving the range is an error.
+pub const GROW_BYTES: u64 = 1 << 36;
+
+// The number of cells in the address range reserved for a growing tape.
+pub fn grow_cells(options: &Options) -> u64 {
+    (GROW_BYTES / options.cell_width.bytes() as u64).max(options.tape_size.0 as u64)
+}
+
+// The routines of a standalone program, which does its I/O with raw syscalls. They may clobber
+// the caller-saved registers only, so the tape stays in '%rbx', '%r12' and '%r14'.
+const PEEK: &str = r#"
+# Returns the next byte of the input in %rax without consuming it, or -1 at its end.
+chiya_peek:
+    mov chiya_in_pos(%rip), %rax
+    cmp chiya_in_len(%rip), %rax
+    jb 1f{0}
+    xor %eax, %eax
+    xor %edi, %edi
+    lea chiya_in(%rip), %rsi
+    mov $4096, %edx
+    syscall
+    test %rax, %rax
+    jle 2f
+    mov %rax, chiya_in_len(%rip)
+    xor %eax, %eax
+    mov %rax, chiya_in_pos(%rip)
+1:
+    lea chiya_in(%rip), %rcx
+    movzbl (%rcx,%rax), %eax
+    ret
+2:
+    mov $-1, %rax
+    ret
+
+# Returns the next byte of the input in %rax, or -1 at its end.
+chiya_getchar:
+    call chiya_peek
+    test %rax, %rax
+    js 1f
+    incq chiya_in_pos(%rip)
+1:
+    ret
+
+# Reads a decimal number like scanf("%lld"), saturating at the limits of 64 bits. Returns it in
+# %rax, and 1 in %edx if there was one.
+chiya_getint:
+    call chiya_peek
+    cmp $32, %eax
+    je 1f
+    cmp $9, %eax
+    jl 2f
+    cmp $13, %eax
+    jg 2f
+1:
+    incq chiya_in_pos(%rip)
+    jmp chiya_getint
+2:
+    xor %r8d, %r8d
+    xor %r9d, %r9d
+    xor %r10d, %r10d
+    cmp $45, %eax
+    jne 3f
+    inc %r9d
+    jmp 4f
+3:
+    cmp $43, %eax
+    jne 5f
+4:
+    incq chiya_in_pos(%rip)
+5:
+    call chiya_peek
+    sub $48, %eax
+    cmp $9, %eax
+    ja 7f
+    inc %r10
+    incq chiya_in_pos(%rip)
+    # The number is accumulated with its sign, so that it can reach either limit.
+    imul $10, %r8, %r8
+    jo 6f
+    test %r9d, %r9d
+    jnz 8f
+    add %rax, %r8
+    jno 5b
+    jmp 6f
+8:
+    sub %rax, %r8
+    jno 5b
+6:
+    # The maximum, plus 1 for a negative number, which wraps around to the minimum.
+    movabs $0x7fffffffffffffff, %r8
+    add %r9, %r8
+    jmp 5b
+7:
+    mov %r8, %rax
+    xor %edx, %edx
+    test %r10, %r10
+    setnz %dl
+    ret
+"#;
+
+const PUTCHAR: &str = r#"
+# Writes the byte %dil to the output.
+chiya_putchar:
+    push %rdi
+    mov %rsp, %rsi
+    mov $1, %edx
+    mov $1, %edi
+    call chiya_write
+    pop %rdi
+    ret
+
+# Writes %rdx bytes at %rsi to the output.
+chiya_puts:
+    mov $1, %edi
+    jmp chiya_write
+"#;
+
+const BUFFERED_PUTCHAR: &str = r#"
+# Appends the byte %dil to the output buffer, and writes it when it fills.
+chiya_putchar:
+    mov chiya_out_len(%rip), %rax
+    lea chiya_out(%rip), %rcx
+    mov %dil, (%rcx,%rax)
+    inc %rax
+    mov %rax, chiya_out_len(%rip)
+    cmp $4096, %rax
+    je chiya_flush
+    ret
+
+# Appends %rdx bytes at %rsi to the output buffer.
+chiya_puts:
+    test %rdx, %rdx
+    jz 1f
+    push %rsi
+    push %rdx
+    movzbl (%rsi), %edi
+    call chiya_putchar
+    pop %rdx
+    pop %rsi
+    inc %rsi
+    dec %rdx
+    jmp chiya_puts
+1:
+    ret
+
+# Writes the output buffer.
+chiya_flush:
+    mov $1, %edi
+    lea chiya_out(%rip), %rsi
+    mov chiya_out_len(%rip), %rdx
+    movq $0, chiya_out_len(%rip)
+    jmp chiya_write
+"#;
+
+const PUTINT: &str = r#"
+# Writes %rdi to the output as a signed decimal number.
+chiya_putint:
+    sub $24, %rsp
+    mov %rdi, %rax
+    lea 24(%rsp), %rdi
+    call chiya_format_int
+    call chiya_puts
+    add $24, %rsp
+    ret
+
+# Exits with the status %edi.
+chiya_exit:{0}
+    mov $231, %eax
+    syscall
+"#;
+
+// The routines used by both standalone programs and functions.
+const WRITE: &str = r#"
+# Writes %rdx bytes at %rsi to the file descriptor %edi.
+chiya_write:
+    test %rdx, %rdx
+    jle 1f
+    mov $1, %eax
+    syscall
+    test %rax, %rax
+    jle 1f
+    add %rax, %rsi
+    sub %rax, %rdx
+    jmp chiya_write
+1:
+    ret
+
+# Formats %rax as a signed decimal number ending at %rdi. Returns its start in %rsi and its
+# length in %rdx.
+chiya_format_int:
+    mov %rdi, %rsi
+    mov %rax, %r8
+    test %rax, %rax
+    jns 1f
+    neg %rax
+1:
+    mov $10, %ecx
+2:
+    xor %edx, %edx
+    div %rcx
+    add $48, %dl
+    dec %rsi
+    mov %dl, (%rsi)
+    test %rax, %rax
+    jnz 2b
+    test %r8, %r8
+    jns 3f
+    dec %rsi
+    movb $45, (%rsi)
+3:
+    mov %rdi, %rdx
+    sub %rsi, %rdx
+    ret
+"#;
+
+const OUT_OF_BOUNDS: &str = r#"
+# Reports that `ptr` (%rbx) is outside the tape at the statement described by the string %rdi,
+# and exits with the status 1, or returns it from a function.
+chiya_out_of_bounds:
+    mov %rdi, %rsi
+    xor %edx, %edx
+1:
+    cmpb $0, (%rsi,%rdx)
+    je 2f
+    inc %rdx
+    jmp 1b
+2:
+    mov $2, %edi
+    call chiya_write
+    sub $24, %rsp
+    mov %rbx, %rax
+    lea 24(%rsp), %rdi
+    call chiya_format_int
+    mov $2, %edi
+    call chiya_write
+    add $24, %rsp
+    lea chiya_out_of_bounds_end(%rip), %rsi
+    mov $2, %edx
+    mov $2, %edi
+    call chiya_write
+    {0}
+"#;
+
+const TAPE_LIMIT: &str = r#"
+# Reports that `ptr` left the address range of a growing tape with the string %rdi, and exits
+# with the status 1.
+chiya_tape_limit:
+    mov %rdi, %rsi
+    xor %edx, %edx
+1:
+    cmpb $0, (%rsi,%rdx)
+    je 2f
+    inc %rdx
+    jmp 1b
+2:
+    mov $2, %edi
+    call chiya_write
+    mov $1, %edi
+    jmp chiya_exit
+"#;
+
+// Escapes `s` for a string literal of GNU as.
+fn escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '"' | '\\' => format!("\\{}", c),
+            c if c.is_ascii_control() => format!("\\{:03o}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+// Emits GNU as assembly (AT&T syntax) for x86-64 Linux. The tape is at '%r12' with '%r14'
+// cells, and the pointer is the index '%rbx'. With the `function` option, '%r13' holds the
+// callbacks.
+pub struct X86_64 {
+    label_idx: u32,
+    loop_stack: VecDeque<(u32, Location)>,
+    options: Options,
+    location: Location,
+    // With `debug_info`, the location of the last '.loc' directive.
+    debug_location: Option<Location>,
+    // The locations of the bounds checks, whose error messages are emitted in the footer.
+    checks: Vec<Location>,
+    // The same for the checks of a growing tape.
+    limits: Vec<Location>,
+}
+
+impl X86_64 {
+    pub fn new() -> X86_64 {
+        Default::default()
+    }
+
+    pub fn with_options(options: &Options) -> X86_64 {
+        X86_64 {
+            options: options.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn next_label(&mut self) -> u32 {
+        self.label_idx += 1;
+        self.label_idx - 1
+    }
+
+    // The operand of the current cell.
+    fn cell(&self) -> String {
+        format!("(%r12,%rbx,{})", self.options.cell_width.bytes())
+    }
+
+    // The instruction suffix of a cell.
+    fn suffix(&self) -> char {
+        match self.options.cell_width.bits() {
+            8 => 'b',
+            16 => 'w',
+            32 => 'l',
+            _ => 'q',
+        }
+    }
+
+    // The part of the register `name`, e.g. 'ax' or 'di', that holds a cell.
+    fn register(&self, name: &str) -> String {
+        match self.options.cell_width.bits() {
+            8 if name.ends_with('x') => format!("%{}l", &name[..1]),
+            8 => format!("%{}l", name),
+            16 => format!("%{}", name),
+            32 => format!("%e{}", name),
+            _ => format!("%r{}", name),
+        }
+    }
+
+    // Sign-extends the current cell into the 64-bit (or 32-bit) register `name`, e.g. 'rdi'.
+    fn load_cell(&self, name: &str) -> String {
+        let to = if name.starts_with('r') { 'q' } else { 'l' };
+        match (self.suffix(), to) {
+            ('l', 'l') | ('q', _) => format!("\n    mov {}, %{}", self.cell(), name),
+            ('l', _) => format!("\n    movslq {}, %{}", self.cell(), name),
+            (from, to) => format!("\n    movs{}{} {}, %{}", from, to, self.cell(), name),
+        }
+    }
+
+    // Prepends a '.loc' directive for the current location if debug info is enabled.
+    fn finish(&mut self, s: String) -> String {
+        if !self.options.debug_info || self.debug_location == Some(self.location) {
+            return s;
+        }
+        self.debug_location = Some(self.location);
+        format!(
+            "\n    .loc 1 {} {}{}",
+            self.location.line, self.location.column, s
+        )
+    }
+
+    // Moves the status of the program reaching its end to the register `name`.
+    fn exit_status(&mut self, name: &str) -> String {
+        match self.options.exit_status {
+            ExitStatus::Zero => format!("\n    xor %{0}, %{0}", name),
+            ExitStatus::Cell => self.check() + &self.load_cell(name),
+        }
+    }
+
+    // Checks that the pointer is inside the tape, if requested.
+    fn check(&mut self) -> String {
+        if !self.options.bounds_check || self.options.tape_mode != TapeMode::Fixed {
+            return "".to_owned();
+        }
+
+        let n = match self.checks.iter().position(|l| *l == self.location) {
+            Some(n) => n,
+            None => {
+                self.checks.push(self.location);
+                self.checks.len() - 1
+            }
+        };
+        format!(
+            r#"
+    lea .Lcheck{}(%rip), %rdi
+    cmp %r14, %rbx
+    jae chiya_out_of_bounds"#,
+            n
+        )
+    }
+
+    // Checks that the pointer is inside the address range of a growing tape.
+    fn limit(&mut self) -> String {
+        let n = match self.limits.iter().position(|l| *l == self.location) {
+            Some(n) => n,
+            None => {
+                self.limits.push(self.location);
+                self.limits.len() - 1
+            }
+        };
+        format!(
+            r#"
+    lea .Llimit{}(%rip), %rdi
+    cmp %r14, %rbx
+    jae chiya_tape_limit"#,
+            n
+        )
+    }
+
+    // Replaces the input in %rax with the value for the end of input if the condition `cc` holds
+    // for the comparison made by `cmp`, and stores it to the cell.
+    fn store_input(&self, cmp: &str, cc: &str) -> String {
+        let eof = match self.options.eof {
+            Eof::MinusOne => "\n    mov $-1, %rcx".to_owned(),
+            Eof::Zero => "\n    xor %ecx, %ecx".to_owned(),
+            Eof::Unchanged => self.load_cell("rcx"),
+        };
+        format!(
+            "{}\n    {}\n    cmov{} %rcx, %rax\n    mov {}, {}",
+            eof,
+            cmp,
+            cc,
+            self.register("ax"),
+            self.cell()
+        )
+    }
+
+    // Adds `n` to the cell with the configured overflow behavior.
+    fn add(&mut self, n: i32) -> String {
+        let width = self.options.cell_width;
+        let (suffix, cell) = (self.suffix(), self.cell());
+        match self.options.overflow {
+            Overflow::Wrap => format!("\n    add{} ${}, {}", suffix, width.wrap(n as i64), cell),
+            Overflow::Trap => width
+                .split_addend(n as i64)
+                .iter()
+                .map(|a| format!("\n    add{} ${}, {}\n    jo chiya_trap", suffix, a, cell))
+                .collect(),
+            Overflow::Saturate => {
+                let a = self.register("ax");
+                let adds: String = width
+                    .split_addend(n as i64)
+                    .iter()
+                    .map(|n| {
+                        let l = self.next_label();
+                        let limit = if *n > 0 { width.max() } else { width.min() };
+                        format!(
+                            "\n    add ${1}, {0}\n    jno .Ladd{2}_ok\n    mov ${3}, {0}\n.Ladd{2}_ok:",
+                            a, n, l, limit
+                        )
+                    })
+                    .collect();
+                format!("\n    mov {1}, {0}{2}\n    mov {0}, {1}", a, cell, adds)
+            }
+        }
+    }
+
+    // The routines and the data the emitted code refers to.
+    fn runtime(&self) -> String {
+        let function = self.options.function.is_some();
+        let mut s = String::new();
+        if !function {
+            let flush = if self.options.buffered_io {
+                // The output so far, e.g. a prompt, must be visible before waiting for the input.
+                "\n    call chiya_flush"
+            } else {
+                ""
+            };
+            s += &PEEK.replace("{0}", flush);
+            s += if self.options.buffered_io {
+                BUFFERED_PUTCHAR
+            } else {
+                PUTCHAR
+            };
+            s += &PUTINT.replace(
+                "{0}",
+                if self.options.buffered_io {
+                    "\n    push %rdi\n    call chiya_flush\n    pop %rdi"
+                } else {
+                    ""
+                },
+            );
+        }
+        if !function || self.options.bounds_check {
+            s += WRITE;
+        }
+        if self.options.bounds_check {
+            s += &OUT_OF_BOUNDS.replace(
+                "{0}",
+                if function {
+                    "mov $1, %eax\n    jmp .Lexit"
+                } else {
+                    "mov $1, %edi\n    jmp chiya_exit"
+                },
+            );
+        }
+        if self.options.tape_mode == TapeMode::Grow {
+            s += TAPE_LIMIT;
+        }
+        if self.options.overflow == Overflow::Trap || self.options.tape_mode == TapeMode::Grow {
+            s += "\nchiya_trap:\n    ud2\n";
+        }
+
+        if self.options.bounds_check {
+            s += "\n    .section .rodata\nchiya_out_of_bounds_end:\n    .ascii \")\\n\"\n";
+            for (i, l) in self.checks.iter().enumerate() {
+                s += &format!(
+                    ".Lcheck{}:\n    .asciz \"chiya: {}:{}: pointer out of bounds (\"\n",
+                    i, l.line, l.column
+                );
+            }
+        }
+        if self.options.tape_mode == TapeMode::Grow {
+            s += "\n    .section .rodata\n";
+            for (i, l) in self.limits.iter().enumerate() {
+                s += &format!(
+                    ".Llimit{}:\n    .asciz \"chiya: {}:{}: tape limit of {} cells exceeded\\n\"\n",
+                    i,
+                    l.line,
+                    l.column,
+                    grow_cells(&self.options)
+                );
+            }
+        }
+
+        if !function {
+            s += r#"
+    .bss
+    .p2align 4
+chiya_in_pos:
+    .zero 8
+chiya_in_len:
+    .zero 8
+chiya_in:
+    .zero 4096
+"#;
+            if self.options.buffered_io {
+                s += "chiya_out_len:\n    .zero 8\nchiya_out:\n    .zero 4096\n";
+            }
+            if self.options.tape_mode != TapeMode::Grow {
+                s += &format!(
+                    "    .p2align 4\nchiya_tape:\n    .zero {}\n",
+                    self.options.tape_size.0 as u64 * self.options.cell_width.bytes() as u64
+                );
+            }
+        }
+        s
+    }
+}
+
+impl emitter::Emitter for X86_64 {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        let s = match self.options.tape_mode {
+            TapeMode::Wrap if self.options.function.is_some() => format!(
+                r#"
+    lea {}(%rbx), %rax
+    cqo
+    idiv %r14
+    lea (%rdx,%r14), %rax
+    test %rdx, %rdx
+    cmovs %rax, %rdx
+    mov %rdx, %rbx"#,
+                offset
+            ),
+            TapeMode::Wrap => match (offset as i64).rem_euclid(self.options.tape_size.0 as i64) {
+                0 => "".to_owned(),
+                offset => format!(
+                    r#"{}
+    mov %rbx, %rax
+    sub %r14, %rax
+    cmp %r14, %rbx
+    cmovae %rax, %rbx"#,
+                    // An immediate operand has 32 bits.
+                    if offset > i32::MAX as i64 {
+                        format!("\n    movabs ${}, %rax\n    add %rax, %rbx", offset)
+                    } else {
+                        format!("\n    add ${}, %rbx", offset)
+                    }
+                ),
+            },
+            _ if offset < 0 => format!("\n    sub ${}, %rbx", -(offset as i64)),
+            _ if offset > 0 => format!("\n    add ${}, %rbx", offset),
+            _ => "".to_owned(),
+        };
+        let s = match self.options.tape_mode {
+            TapeMode::Grow if offset != 0 => s + &self.limit(),
+            _ => s,
+        };
+        let s = format!("\n    # emit_move_ptr({}){}", offset, s);
+        self.finish(s)
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        let s = format!("\n    # emit_add({}){}{}", n, self.check(), self.add(n));
+        self.finish(s)
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        let call = match self.options.function {
+            Some(_) => format!(
+                "\n    mov (%r13), %rdi{}\n    call *16(%r13)",
+                self.load_cell("esi")
+            ),
+            None => format!("\n    movzbl {}, %edi\n    call chiya_putchar", self.cell()),
+        };
+        let s = format!("\n    # emit_call_putchar(){}{}", self.check(), call);
+        self.finish(s)
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        let call = match self.options.function {
+            Some(_) => "\n    mov (%r13), %rdi\n    call *8(%r13)\n    movslq %eax, %rax",
+            None => "\n    call chiya_getchar",
+        };
+        let s = format!(
+            "\n    # emit_call_getchar(){}{}{}",
+            self.check(),
+            call,
+            self.store_input("cmp $-1, %rax", "e")
+        );
+        self.finish(s)
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        let call = match self.options.function {
+            Some(_) => format!(
+                "\n    mov (%r13), %rdi{}\n    call *32(%r13)",
+                self.load_cell("rsi")
+            ),
+            None => format!("{}\n    call chiya_putint", self.load_cell("rdi")),
+        };
+        let s = format!("\n    # emit_call_putint(){}{}", self.check(), call);
+        self.finish(s)
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        let call = match self.options.function {
+            Some(_) => {
+                "\n    mov (%r13), %rdi\n    mov %rsp, %rsi\n    call *24(%r13)\n    mov %eax, %edx\n    mov (%rsp), %rax"
+            }
+            None => "\n    call chiya_getint",
+        };
+        let s = format!(
+            "\n    # emit_call_getint(){}{}{}",
+            self.check(),
+            call,
+            self.store_input("cmp $1, %edx", "ne")
+        );
+        self.finish(s)
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        let l = self.next_label();
+        self.loop_stack.push_back((l, self.location));
+        let s = format!(
+            "\n    # emit_loop_begin()\n.Lloop{0}_cond:{1}\n    cmp{2} $0, {3}\n    je .Lloop{0}_end",
+            l,
+            self.check(),
+            self.suffix(),
+            self.cell()
+        );
+        self.finish(s)
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        let l = match self.loop_stack.pop_back() {
+            Some((l, location)) => {
+                // The code closing the loop belongs to the loop statement.
+                self.location = location;
+                l
+            }
+            None => return "".to_owned(),
+        };
+        let s = format!(
+            "\n    # emit_loop_end()\n    jmp .Lloop{0}_cond\n.Lloop{0}_end:",
+            l
+        );
+        self.finish(s)
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        let exit = match (&self.options.function, status) {
+            (Some(_), Some(n)) => format!("\n    mov ${}, %eax\n    jmp .Lexit", n),
+            (Some(_), None) => format!("{}{}\n    jmp .Lexit", self.check(), self.load_cell("eax")),
+            (None, Some(n)) => format!("\n    mov ${}, %edi\n    jmp chiya_exit", n),
+            (None, None) => format!(
+                "{}{}\n    jmp chiya_exit",
+                self.check(),
+                self.load_cell("edi")
+            ),
+        };
+        let s = format!(
+            "\n    # emit_exit({}){}",
+            status.map_or("*ptr".to_owned(), |n| n.to_string()),
+            exit
+        );
+        self.finish(s)
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        let file = if self.options.debug_info {
+            format!(
+                "    .file 1 \"{}\"\n",
+                escape(self.options.source.as_deref().unwrap_or("<stdin>"))
+            )
+        } else {
+            "".to_owned()
+        };
+
+        let body = match &self.options.function {
+            Some(name) => format!(
+                r#"    .globl {0}
+    .type {0}, @function
+{0}:
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    sub $8, %rsp
+    mov %rdi, %r12
+    mov %rsi, %r14
+    mov %rdx, %r13
+    {1}{2}"#,
+                name,
+                if self.options.bidirectional {
+                    "mov %rsi, %rbx\n    shr %rbx"
+                } else {
+                    "xor %ebx, %ebx"
+                },
+                if self.options.tape_mode == TapeMode::Wrap {
+                    // An empty circular tape has no cell to wrap around to.
+                    "\n    mov $1, %eax\n    test %r14, %r14\n    jz .Lexit"
+                } else {
+                    ""
+                }
+            ),
+            None => {
+                let size = self.options.tape_size.0 as u64;
+                let (tape, size) = match self.options.tape_mode {
+                    TapeMode::Grow => {
+                        let size = grow_cells(&self.options);
+                        (
+                            format!(
+                                r#"mov $9, %eax
+    xor %edi, %edi
+    mov ${}, %rsi
+    mov $3, %edx
+    mov $0x4022, %r10d
+    mov $-1, %r8
+    xor %r9d, %r9d
+    syscall
+    test %rax, %rax
+    js chiya_trap
+    mov %rax, %r12"#,
+                                size.saturating_mul(self.options.cell_width.bytes() as u64)
+                            ),
+                            size,
+                        )
+                    }
+                    _ => ("lea chiya_tape(%rip), %r12".to_owned(), size),
+                };
+                let start =
+                    if self.options.bidirectional || self.options.tape_mode == TapeMode::Grow {
+                        size / 2
+                    } else {
+                        0
+                    };
+                format!(
+                    "    .globl _start\n_start:\n    {}\n    mov ${}, %r14\n    mov ${}, %rbx",
+                    tape, size, start
+                )
+            }
+        };
+
+        format!("# emit_header()\n{}    .text\n{}", file, body)
+    }
+
+    fn emit_footer(&mut self) -> String {
+        let end = match self.options.function.clone() {
+            Some(name) => format!(
+                r#"{1}
+.Lexit:
+    add $8, %rsp
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    ret
+    .size {0}, .-{0}"#,
+                name,
+                self.exit_status("eax")
+            ),
+            None => format!("{}\n    jmp chiya_exit", self.exit_status("edi")),
+        };
+
+        format!(
+            "\n    # emit_footer(){}\n{}\n    .section .note.GNU-stack,\"\",@progbits\n",
+            end,
+            self.runtime()
+        )
+    }
+}
+
+impl Default for X86_64 {
+    fn default() -> Self {
+        X86_64 {
+            label_idx: 1,
+            loop_stack: VecDeque::new(),
+            options: Options::new(),
+            location: Default::default(),
+            debug_location: None,
+            checks: Vec::new(),
+            limits: Vec::new(),
+        }
+    }
+}
+
+// Compiles `src` to assembly, builds it with the system `as` and `ld`, or with `cc` together with
+// the C source `host` that calls `function`, and runs it on `input`. Returns the output, the error
+// output and the exit status, or None if there is no `as`, `ld` or `cc`.
+#[cfg(test)]
+fn run(src: &str, options: &Options, host: &str, input: &str) -> Option<(String, String, i32)> {
+    use crate::codegen::{build_step, has_tool};
+    use std::process::Command;
+
+    let code = crate::codegen::compile(&mut X86_64::with_options(options), src);
+    crate::codegen::run_built("x86_64", input, |dir| {
+        if !cfg!(all(target_arch = "x86_64", target_os = "linux"))
+            || !has_tool("as")
+            || !has_tool("ld")
+        {
+            return None;
+        }
+        let (s, o, exe) = (dir.join("main.s"), dir.join("main.o"), dir.join("main"));
+        std::fs::write(&s, code).unwrap();
+        if let (Some(name), Some(header)) = (&options.function, crate::codegen::c::header(options))
+        {
+            if !has_tool("cc") {
+                return None;
+            }
+            let c = dir.join("host.c");
+            std::fs::write(dir.join(format!("{}.h", name)), header).unwrap();
+            std::fs::write(&c, host).unwrap();
+            build_step(Command::new("cc").arg("-o").arg(&exe).arg(&s).arg(&c));
+            return Some(Command::new(exe));
+        }
+        build_step(Command::new("as").arg("-o").arg(&o).arg(&s));
+        build_step(Command::new("ld").arg("-o").arg(&exe).arg(&o));
+        Some(Command::new(exe))
+    })
+}
+
+#[test]
+fn test_emit() {
+    use crate::codegen::emitter::Emitter;
+    use crate::codegen::options::CellWidth;
+
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let mut e = X86_64::with_options(&options);
+    assert!(e.emit_add(300).ends_with("\n    addb $44, (%r12,%rbx,1)"));
+    assert!(e.emit_move_ptr(-2).ends_with("\n    sub $2, %rbx"));
+    assert!(e.emit_loop_begin().contains("\n.Lloop1_cond:"));
+    assert!(e.emit_loop_begin().contains("\n.Lloop2_cond:"));
+    assert!(e
+        .emit_loop_end()
+        .ends_with("jmp .Lloop2_cond\n.Lloop2_end:"));
+    assert!(e
+        .emit_loop_end()
+        .ends_with("jmp .Lloop1_cond\n.Lloop1_end:"));
+
+    options.overflow = Overflow::Saturate;
+    let mut e = X86_64::with_options(&options);
+    let s = e.emit_add(200);
+    assert!(s.contains("add $127, %al\n    jno .Ladd1_ok\n    mov $127, %al"));
+    assert!(s.contains("add $73, %al\n    jno .Ladd2_ok\n    mov $127, %al"));
+
+    let mut options = Options::new();
+    options.bounds_check = true;
+    let mut e = X86_64::with_options(&options);
+    e.set_location(Location { line: 2, column: 5 });
+    assert!(e.emit_call_putchar().contains("lea .Lcheck0(%rip), %rdi"));
+    assert!(e
+        .emit_footer()
+        .contains(".Lcheck0:\n    .asciz \"chiya: 2:5: pointer out of bounds (\""));
+}
+
+#[test]
+fn test_run() {
+    use crate::codegen::options::{CellWidth, TapeSize};
+
+    let src = "getint();\n*ptr += 5;\nputint();\nptr += 1;\n*ptr += 10;\nputchar();\ngetint();\nputint();\n";
+    let output = match run(src, &Options::new(), "", " -37 x") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("-32\n-1".to_owned(), "".to_owned(), 0));
+
+    let src = "*ptr -= 1;\nputint();\n*ptr += 100;\n*ptr += 100;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    assert_eq!(run(src, &options, "", "").unwrap().0, "-1-57");
+    options.overflow = Overflow::Saturate;
+    assert_eq!(run(src, &options, "", "").unwrap().0, "-1127");
+    options.cell_width = CellWidth::W64;
+    options.buffered_io = true;
+    assert_eq!(run(src, &options, "", "").unwrap().0, "-1199");
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nptr -= 1;\nputint();\nptr += 2;\nputint();\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    assert_eq!(run(src, &options, "", "").unwrap().0, "03");
+
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(2);
+    assert_eq!(run(src, &options, "", "").unwrap().0, "33");
+
+    options.tape_mode = TapeMode::Fixed;
+    options.tape_size = TapeSize(30000);
+    options.bounds_check = true;
+    assert_eq!(
+        run(src, &options, "", "").unwrap(),
+        (
+            "".to_owned(),
+            "chiya: 9:1: pointer out of bounds (-1)\n".to_owned(),
+            1
+        )
+    );
+
+    let src = "*ptr += 7;\nwhile *ptr {\n  exit(*ptr);\n}\nexit(3);\n";
+    assert_eq!(run(src, &Options::new(), "", "").unwrap().2, 7);
+
+    // Numbers out of range saturate like with scanf.
+    let src = "getint();\nputint();\nptr += 1;\n*ptr += 32;\nputchar();\nptr += 1;\ngetint();\nputint();\n";
+    let input = "99999999999999999999 -99999999999999999999";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W64;
+    assert_eq!(
+        run(src, &options, "", input).unwrap().0,
+        "9223372036854775807 -9223372036854775808"
+    );
+
+    // A growing tape fails when it leaves the address range reserved for it.
+    let src = "ptr += 2000000000;\nptr += 2000000000;\nptr += 2000000000;\nptr += 2000000000;\nptr += 2000000000;\n*ptr += 1;\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    assert_eq!(
+        run(src, &options, "", "").unwrap(),
+        (
+            "".to_owned(),
+            "chiya: 5:1: tape limit of 17179869184 cells exceeded\n".to_owned(),
+            1
+        )
+    );
+
+    // An offset beyond 32 bits is moved to a register.
+    let src = "ptr -= 1;\n*ptr += 7;\nputint();\nptr += 1;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(3000000000);
+    assert_eq!(run(src, &options, "", "").unwrap().0, "70");
+}
+
+#[test]
+fn test_function() {
+    let host = r#"#include <stdio.h>
+#include "run.h"
+
+static void put(void *data, int32_t c) {
+  (void)data;
+  putchar(c);
+}
+
+int main(void) {
+  int32_t tape[4] = {0};
+  struct chiya_callbacks callbacks = {0};
+  callbacks.putchar = put;
+  int32_t status = run(tape, 4, &callbacks);
+  tape[0] = 0;
+  printf(" %d %d", status, run(tape, 0, &callbacks));
+  return 0;
+}
+"#;
+    let mut options = Options::new();
+    options.function = Some("run".to_owned());
+    let src = "*ptr += 65;\nputchar();\nexit(*ptr);\n";
+    let output = match run(src, &options, host, "") {
+        Some(output) => output,
+        None => return,
+    };
+    assert_eq!(output, ("AA 65 65".to_owned(), "".to_owned(), 0));
+
+    // Errors are returned to the caller instead of exiting.
+    options.bounds_check = true;
+    let src = "*ptr += 1;\nptr -= 1;\n*ptr += 1;\n";
+    assert_eq!(
+        run(src, &options, host, "").unwrap(),
+        (
+            " 1 1".to_owned(),
+            "chiya: 3:1: pointer out of bounds (-1)\nchiya: 1:1: pointer out of bounds (0)\n"
+                .to_owned(),
+            0
+        )
+    );
+
+    // An empty circular tape has no cell to wrap around to.
+    options.bounds_check = false;
+    options.tape_mode = TapeMode::Wrap;
+    options.exit_status = ExitStatus::Cell;
+    let src = "ptr -= 1;\n*ptr += 2;\n";
+    assert_eq!(run(src, &options, host, "").unwrap().0, " 2 1");
+}
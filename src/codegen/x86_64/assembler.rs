@@ -0,0 +1,316 @@
+// An assembler for the x86-64 instructions used by `Elf` and the JIT, which encodes them into
+// machine code with labels.
+
+pub const RAX: u8 = 0;
+pub const RCX: u8 = 1;
+pub const RDX: u8 = 2;
+pub const RBX: u8 = 3;
+pub const RSP: u8 = 4;
+pub const RBP: u8 = 5;
+pub const RSI: u8 = 6;
+pub const RDI: u8 = 7;
+pub const R8: u8 = 8;
+pub const R9: u8 = 9;
+pub const R10: u8 = 10;
+pub const R12: u8 = 12;
+pub const R13: u8 = 13;
+pub const R14: u8 = 14;
+pub const R15: u8 = 15;
+
+// The condition codes of 'jcc', 'cmovcc' and 'setcc'.
+pub const O: u8 = 0x0;
+pub const NO: u8 = 0x1;
+pub const B: u8 = 0x2;
+pub const AE: u8 = 0x3;
+pub const E: u8 = 0x4;
+pub const NE: u8 = 0x5;
+pub const A: u8 = 0x7;
+pub const S: u8 = 0x8;
+pub const NS: u8 = 0x9;
+pub const L: u8 = 0xc;
+pub const LE: u8 = 0xe;
+pub const G: u8 = 0xf;
+
+// The opcodes of the 'op r/m64, r64' forms and the extensions of the 'op r/m64, imm32' forms.
+pub const ADD: (u8, u8) = (0x01, 0);
+pub const SUB: (u8, u8) = (0x29, 5);
+pub const CMP: (u8, u8) = (0x39, 7);
+pub const TEST: u8 = 0x85;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Label(usize);
+
+// A memory operand '[base + index * scale + disp]', where the displacement may be the address of
+// a label.
+#[derive(Debug, Clone, Copy)]
+pub struct Mem {
+    pub base: u8,
+    pub index: Option<(u8, u32)>,
+    pub disp: i32,
+    pub label: Option<Label>,
+}
+
+impl Mem {
+    pub fn new(base: u8, disp: i32) -> Mem {
+        Mem {
+            base,
+            index: None,
+            disp,
+            label: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    Reg(u8),
+    Mem(Mem),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Code(usize),
+    Address(u64),
+}
+
+// Machine code referring to labels, which are resolved once the layout of the executable is known.
+#[derive(Debug, Default, Clone)]
+pub struct Code {
+    pub bytes: Vec<u8>,
+    labels: Vec<Option<Target>>,
+    // The positions of the 32-bit fields holding the addresses of labels, and whether they are
+    // relative to the end of the field.
+    fixups: Vec<(usize, Label, bool)>,
+}
+
+impl Code {
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, l: Label) {
+        self.labels[l.0] = Some(Target::Code(self.bytes.len()));
+    }
+
+    // Binds `l` to an absolute address outside the code.
+    pub fn place(&mut self, l: Label, address: u64) {
+        self.labels[l.0] = Some(Target::Address(address));
+    }
+
+    pub fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Emits the lowest `size` bytes of `n`.
+    pub fn imm(&mut self, n: i64, size: u32) {
+        self.emit(&n.to_le_bytes()[..size as usize]);
+    }
+
+    pub fn fixup(&mut self, l: Label, relative: bool) {
+        self.fixups.push((self.bytes.len(), l, relative));
+        self.imm(0, 4);
+    }
+
+    // Emits an instruction with the operand size `size` in bytes, the opcode `op`, the register
+    // (or the opcode extension) `reg` and the operand `rm`.
+    pub fn insn(&mut self, size: u32, op: &[u8], reg: u8, rm: Operand) {
+        if size == 2 {
+            self.emit(&[0x66]);
+        }
+        let (x, b) = match rm {
+            Operand::Reg(r) => (0, r >> 3),
+            Operand::Mem(m) => (m.index.map_or(0, |(i, _)| i >> 3), m.base >> 3),
+        };
+        let rex = (if size == 8 { 8 } else { 0 }) | (reg >> 3) << 2 | x << 1 | b;
+        // Without a REX prefix, the byte registers 4-7 are 'ah'-'bh' instead of 'spl'-'dil'.
+        let high = |r: u8| (4..8).contains(&r);
+        let byte_regs = size == 1 && (high(reg) || matches!(rm, Operand::Reg(r) if high(r)));
+        if rex != 0 || byte_regs {
+            self.emit(&[0x40 | rex]);
+        }
+        self.emit(op);
+
+        let reg = (reg & 7) << 3;
+        match rm {
+            Operand::Reg(r) => self.emit(&[0xc0 | reg | r & 7]),
+            Operand::Mem(m) => {
+                // Always with a 32-bit displacement.
+                match m.index {
+                    None if m.base & 7 != 4 => self.emit(&[0x80 | reg | m.base & 7]),
+                    index => {
+                        let (i, scale) =
+                            index.map_or((4, 0), |(i, s)| (i & 7, s.trailing_zeros() as u8));
+                        self.emit(&[0x84 | reg, scale << 6 | i << 3 | m.base & 7]);
+                    }
+                }
+                match m.label {
+                    Some(l) => self.fixup(l, false),
+                    None => self.imm(m.disp as i64, 4),
+                }
+            }
+        }
+    }
+
+    pub fn mov(&mut self, dst: u8, src: u8) {
+        self.insn(8, &[0x89], src, Operand::Reg(dst));
+    }
+
+    pub fn mov_imm(&mut self, dst: u8, n: i64) {
+        if (0..=u32::MAX as i64).contains(&n) {
+            // The 32-bit form zero-extends the immediate.
+            if dst >= 8 {
+                self.emit(&[0x41]);
+            }
+            self.emit(&[0xb8 | dst & 7]);
+            self.imm(n, 4);
+        } else if (i32::MIN as i64..0).contains(&n) {
+            self.insn(8, &[0xc7], 0, Operand::Reg(dst));
+            self.imm(n, 4);
+        } else {
+            self.emit(&[0x48 | dst >> 3, 0xb8 | dst & 7]);
+            self.imm(n, 8);
+        }
+    }
+
+    // Loads the address of `l`, which is below 4 GiB.
+    pub fn mov_address(&mut self, dst: u8, l: Label) {
+        if dst >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[0xb8 | dst & 7]);
+        self.fixup(l, false);
+    }
+
+    pub fn lea(&mut self, dst: u8, m: Mem) {
+        self.insn(8, &[0x8d], dst, Operand::Mem(m));
+    }
+
+    pub fn alu(&mut self, (op, _): (u8, u8), dst: u8, src: u8) {
+        self.insn(8, &[op], src, Operand::Reg(dst));
+    }
+
+    pub fn alu_imm(&mut self, (_, ext): (u8, u8), dst: u8, n: i32) {
+        self.insn(8, &[0x81], ext, Operand::Reg(dst));
+        self.imm(n as i64, 4);
+    }
+
+    pub fn test(&mut self, r: u8) {
+        self.insn(8, &[TEST], r, Operand::Reg(r));
+    }
+
+    pub fn xor32(&mut self, r: u8) {
+        self.insn(4, &[0x31], r, Operand::Reg(r));
+    }
+
+    pub fn inc(&mut self, r: u8) {
+        self.insn(8, &[0xff], 0, Operand::Reg(r));
+    }
+
+    pub fn dec(&mut self, r: u8) {
+        self.insn(8, &[0xff], 1, Operand::Reg(r));
+    }
+
+    pub fn neg(&mut self, r: u8) {
+        self.insn(8, &[0xf7], 3, Operand::Reg(r));
+    }
+
+    pub fn cmov(&mut self, cc: u8, dst: u8, src: u8) {
+        self.insn(8, &[0x0f, 0x40 | cc], dst, Operand::Reg(src));
+    }
+
+    pub fn push(&mut self, r: u8) {
+        if r >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[0x50 | r & 7]);
+    }
+
+    pub fn pop(&mut self, r: u8) {
+        if r >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[0x58 | r & 7]);
+    }
+
+    pub fn jcc(&mut self, cc: u8, l: Label) {
+        self.emit(&[0x0f, 0x80 | cc]);
+        self.fixup(l, true);
+    }
+
+    pub fn jmp(&mut self, l: Label) {
+        self.emit(&[0xe9]);
+        self.fixup(l, true);
+    }
+
+    pub fn call(&mut self, l: Label) {
+        self.emit(&[0xe8]);
+        self.fixup(l, true);
+    }
+
+    pub fn ret(&mut self) {
+        self.emit(&[0xc3]);
+    }
+
+    pub fn syscall(&mut self) {
+        self.emit(&[0x0f, 0x05]);
+    }
+
+    // Resolves the labels, with the code placed at `address`.
+    pub fn link(&mut self, address: u64) {
+        for &(pos, l, relative) in &self.fixups {
+            let target = match self.labels[l.0] {
+                Some(Target::Code(offset)) => address + offset as u64,
+                Some(Target::Address(a)) => a,
+                None => panic!("unbound label {:?}", l),
+            };
+            let value = if relative {
+                target.wrapping_sub(address + pos as u64 + 4) as u32
+            } else {
+                target as u32
+            };
+            self.bytes[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+#[test]
+fn test_encode() {
+    let mut c = Code::default();
+    c.mov(R12, RAX);
+    c.mov_imm(RSI, 1 << 36);
+    c.mov_imm(R8, -1);
+    c.alu(CMP, RBX, R14);
+    c.insn(
+        1,
+        &[0x88],
+        RDI,
+        Operand::Mem(Mem {
+            index: Some((RBX, 1)),
+            ..Mem::new(R12, 0)
+        }),
+    );
+    c.lea(RDI, Mem::new(RSP, 24));
+    assert_eq!(
+        c.bytes,
+        [
+            0x49, 0x89, 0xc4, // mov %rax, %r12
+            0x48, 0xbe, 0, 0, 0, 0, 0x10, 0, 0, 0, // movabs $0x1000000000, %rsi
+            0x49, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, // mov $-1, %r8
+            0x4c, 0x39, 0xf3, // cmp %r14, %rbx
+            0x41, 0x88, 0xbc, 0x1c, 0, 0, 0, 0, // mov %dil, 0(%r12,%rbx,1)
+            0x48, 0x8d, 0xbc, 0x24, 24, 0, 0, 0, // lea 24(%rsp), %rdi
+        ]
+    );
+
+    let mut c = Code::default();
+    let l = c.label();
+    c.jmp(l);
+    c.bind(l);
+    c.jcc(E, l);
+    c.link(0x400000);
+    assert_eq!(
+        c.bytes,
+        [0xe9, 0, 0, 0, 0, 0x0f, 0x84, 0xfa, 0xff, 0xff, 0xff]
+    );
+}
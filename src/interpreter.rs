@@ -0,0 +1,897 @@
+pub mod bytecode;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::codegen::emitter;
+use crate::codegen::options::{Eof, ExitStatus, Options, Overflow, TapeMode};
+use crate::token::Location;
+
+#[derive(Debug, failure::Fail)]
+pub enum InterpreterError {
+    #[fail(display = "{}:{}: pointer out of bounds ({})", line, column, index)]
+    OutOfBounds {
+        line: usize,
+        column: usize,
+        index: i64,
+    },
+
+    #[fail(display = "{}:{}: overflow", line, column)]
+    Overflow { line: usize, column: usize },
+
+    #[fail(display = "{}:{}: out of fuel", line, column)]
+    OutOfFuel {
+        line: usize,
+        column: usize,
+        snapshot: Snapshot,
+    },
+
+    #[fail(
+        display = "{}:{}: tape limit of {} cells exceeded",
+        line, column, limit
+    )]
+    TapeLimit {
+        line: usize,
+        column: usize,
+        limit: usize,
+        snapshot: Snapshot,
+    },
+
+    #[fail(
+        display = "{}:{}: output limit of {} bytes exceeded",
+        line, column, limit
+    )]
+    OutputLimit {
+        line: usize,
+        column: usize,
+        limit: u64,
+        snapshot: Snapshot,
+    },
+
+    #[fail(display = "cannot allocate a tape of {} cells", size)]
+    TapeAllocation { size: usize },
+
+    #[fail(display = "{}", _0)]
+    Io(#[fail(cause)] io::Error),
+}
+
+impl InterpreterError {
+    // Returns the state of the program when it hit a limit.
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        match self {
+            InterpreterError::OutOfFuel { snapshot, .. }
+            | InterpreterError::TapeLimit { snapshot, .. }
+            | InterpreterError::OutputLimit { snapshot, .. } => Some(snapshot),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for InterpreterError {
+    fn from(e: io::Error) -> Self {
+        InterpreterError::Io(e)
+    }
+}
+
+// The pointer and the tape of a program stopped by a limit, before the instruction that hit it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub ptr: i64,
+    pub tape: Vec<i64>,
+}
+
+// Shows the cells around the pointer, with the current one in brackets.
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.tape.len() as i64;
+        let start = (self.ptr - 8).clamp(0, len);
+        let end = (self.ptr + 9).clamp(start, len);
+        write!(f, "ptr = {}, tape[{}..{}] =", self.ptr, start, end)?;
+        for i in start..end {
+            match self.tape[i as usize] {
+                v if i == self.ptr => write!(f, " [{}]", v)?,
+                v => write!(f, " {}", v)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// The limits of the options.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Limit {
+    Fuel,
+    Tape,
+    Output,
+}
+
+impl Limit {
+    // Returns the error for hitting the limit at `location`.
+    fn error(
+        self,
+        options: &Options,
+        location: Location,
+        ptr: i64,
+        tape: &[i64],
+    ) -> InterpreterError {
+        let (line, column) = (location.line, location.column);
+        let snapshot = Snapshot {
+            ptr,
+            tape: tape.to_vec(),
+        };
+        match self {
+            Limit::Fuel => InterpreterError::OutOfFuel {
+                line,
+                column,
+                snapshot,
+            },
+            Limit::Tape => InterpreterError::TapeLimit {
+                line,
+                column,
+                limit: options.max_tape.unwrap_or_default(),
+                snapshot,
+            },
+            Limit::Output => InterpreterError::OutputLimit {
+                line,
+                column,
+                limit: options.max_output.unwrap_or_default(),
+                snapshot,
+            },
+        }
+    }
+}
+
+// An instruction, which corresponds to a call to an emitter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Move(i32),
+    Add(i32),
+    PutChar,
+    GetChar,
+    PutInt,
+    GetInt,
+    // Jumps past the matching `LoopEnd` at the index if the cell is zero.
+    LoopBegin(usize),
+    // Jumps back past the matching `LoopBegin` at the index if the cell is not zero.
+    LoopEnd(usize),
+    Exit(Option<i32>),
+}
+
+// The instructions of a program, and the location of the statement each comes from.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Code {
+    pub instructions: Vec<Instruction>,
+    pub locations: Vec<Location>,
+}
+
+// Collects the instructions of a program. It is an emitter, so the code is built from a syntax
+// tree or from Brainfuck the same way as by the compiled backends.
+#[derive(Default)]
+pub struct Compiler {
+    code: Code,
+    location: Location,
+    // The indices of the `LoopBegin`s of the enclosing loops, and their locations.
+    loop_stack: Vec<(usize, Location)>,
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Default::default()
+    }
+
+    // Returns the code. `emit_footer` must have been called.
+    pub fn code(self) -> Code {
+        self.code
+    }
+
+    fn push(&mut self, instruction: Instruction) -> String {
+        self.code.instructions.push(instruction);
+        self.code.locations.push(self.location);
+        "".to_owned()
+    }
+}
+
+impl emitter::Emitter for Compiler {
+    fn emit_move_ptr(&mut self, offset: i32) -> String {
+        self.push(Instruction::Move(offset))
+    }
+
+    fn emit_add(&mut self, n: i32) -> String {
+        self.push(Instruction::Add(n))
+    }
+
+    fn emit_call_putchar(&mut self) -> String {
+        self.push(Instruction::PutChar)
+    }
+
+    fn emit_call_getchar(&mut self) -> String {
+        self.push(Instruction::GetChar)
+    }
+
+    fn emit_call_putint(&mut self) -> String {
+        self.push(Instruction::PutInt)
+    }
+
+    fn emit_call_getint(&mut self) -> String {
+        self.push(Instruction::GetInt)
+    }
+
+    fn emit_loop_begin(&mut self) -> String {
+        // The end is filled in by `emit_loop_end`.
+        self.loop_stack
+            .push((self.code.instructions.len(), self.location));
+        self.push(Instruction::LoopBegin(0))
+    }
+
+    fn emit_loop_end(&mut self) -> String {
+        // The code closing the loop belongs to the loop statement.
+        let (begin, location) = match self.loop_stack.pop() {
+            Some(l) => l,
+            None => return "".to_owned(),
+        };
+        self.location = location;
+        self.code.instructions[begin] = Instruction::LoopBegin(self.code.instructions.len());
+        self.push(Instruction::LoopEnd(begin))
+    }
+
+    fn emit_exit(&mut self, status: Option<i32>) -> String {
+        self.push(Instruction::Exit(status))
+    }
+
+    fn set_location(&mut self, location: Location) {
+        self.location = location;
+    }
+
+    fn emit_header(&self) -> String {
+        "".to_owned()
+    }
+
+    fn emit_footer(&mut self) -> String {
+        // A loop left open runs to the end of the program.
+        while let Some((begin, _)) = self.loop_stack.pop() {
+            self.code.instructions[begin] = Instruction::LoopBegin(self.code.instructions.len());
+        }
+        "".to_owned()
+    }
+}
+
+// The input, whose next byte can be looked at without reading it like with scanf.
+struct Input<R> {
+    inner: R,
+    peeked: Option<Option<u8>>,
+}
+
+impl<R: Read> Input<R> {
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        while self.peeked.is_none() {
+            let mut byte = [0];
+            match self.inner.read(&mut byte) {
+                Ok(0) => self.peeked = Some(None),
+                Ok(_) => self.peeked = Some(Some(byte[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.peeked.unwrap())
+    }
+
+    fn next(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.peek()?;
+        self.peeked = None;
+        Ok(byte)
+    }
+
+    // Reads a decimal integer like scanf("%lld"), which saturates.
+    fn integer(&mut self) -> io::Result<Option<i64>> {
+        while let Some(b'\t' | b'\n' | 0x0b | 0x0c | b'\r' | b' ') = self.peek()? {
+            self.next()?;
+        }
+        let negative = match self.peek()? {
+            Some(c @ (b'+' | b'-')) => {
+                self.next()?;
+                c == b'-'
+            }
+            _ => false,
+        };
+        let mut value = None;
+        while let Some(c @ b'0'..=b'9') = self.peek()? {
+            self.next()?;
+            let digit = (c - b'0') as i64;
+            let v: &mut i64 = value.get_or_insert(0);
+            *v = if negative {
+                v.saturating_mul(10).saturating_sub(digit)
+            } else {
+                v.saturating_mul(10).saturating_add(digit)
+            };
+        }
+        Ok(value)
+    }
+}
+
+// Runs code with the semantics of the compiled backends, so that it serves as their reference.
+// Unlike them, it reports an access outside a fixed tape even without `bounds_check`.
+pub struct Interpreter {
+    options: Options,
+}
+
+impl Interpreter {
+    pub fn with_options(options: &Options) -> Interpreter {
+        Interpreter {
+            options: options.clone(),
+        }
+    }
+
+    // Runs `code` and returns its exit status. The output is flushed before reading the input.
+    pub fn run(
+        &self,
+        code: &Code,
+        input: &mut (impl Read + ?Sized),
+        output: &mut (impl Write + ?Sized),
+    ) -> Result<i32, InterpreterError> {
+        Machine::new(code.clone(), &self.options, input, output)?.run()
+    }
+}
+
+// A program being run by the interpreter, which can be stopped after any instruction to look at
+// or change its tape and pointer.
+pub struct Machine<R, W> {
+    code: Code,
+    options: Options,
+    input: Input<R>,
+    output: W,
+    tape: Vec<i64>,
+    ptr: i64,
+    // The index of the next instruction.
+    pc: usize,
+    // The exit status, once the program has ended.
+    status: Option<i32>,
+    // The instructions left to run with the `fuel` option, and the number of bytes written.
+    fuel: Option<u64>,
+    written: u64,
+}
+
+impl<R: Read, W: Write> Machine<R, W> {
+    // Fails if the tape of the options cannot be allocated.
+    pub fn new(
+        code: Code,
+        options: &Options,
+        input: R,
+        output: W,
+    ) -> Result<Machine<R, W>, InterpreterError> {
+        let size = options.tape_size.0;
+        Ok(Machine {
+            code,
+            options: options.clone(),
+            input: Input {
+                inner: input,
+                peeked: None,
+            },
+            output,
+            tape: new_tape(size)?,
+            ptr: if options.bidirectional { size / 2 } else { 0 } as i64,
+            pc: 0,
+            status: None,
+            fuel: options.fuel,
+            written: 0,
+        })
+    }
+
+    // Runs the program to its end and returns its exit status.
+    pub fn run(&mut self) -> Result<i32, InterpreterError> {
+        loop {
+            if let Some(status) = self.step()? {
+                return Ok(status);
+            }
+        }
+    }
+
+    // Runs at most `fuel` instructions, and returns the exit status if the program has ended.
+    pub fn run_until(&mut self, fuel: u64) -> Result<Option<i32>, InterpreterError> {
+        for _ in 0..fuel {
+            if let Some(status) = self.step()? {
+                return Ok(Some(status));
+            }
+        }
+        Ok(self.status)
+    }
+
+    // Runs the next instruction, and returns the exit status if the program has ended. After an
+    // error, the machine stays before the instruction that failed.
+    pub fn step(&mut self) -> Result<Option<i32>, InterpreterError> {
+        if self.status.is_some() {
+            return Ok(self.status);
+        }
+        if let Some(&instruction) = self.code.instructions.get(self.pc) {
+            if self.fuel == Some(0) {
+                return Err(self.limit(Limit::Fuel));
+            }
+            self.execute(instruction)?;
+            self.fuel = self.fuel.map(|fuel| fuel - 1);
+        }
+        if self.status.is_none() && self.pc >= self.code.instructions.len() {
+            self.status = Some(match self.options.exit_status {
+                ExitStatus::Zero => 0,
+                ExitStatus::Cell => {
+                    // The end of the program belongs to its last statement.
+                    let last = self.code.instructions.len().saturating_sub(1);
+                    self.tape[self.cell(last)?] as i32
+                }
+            });
+        }
+        Ok(self.status)
+    }
+
+    // Returns the exit status if the program has ended.
+    pub fn status(&self) -> Option<i32> {
+        self.status
+    }
+
+    // Returns the number of instructions left to run with the `fuel` option.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Returns the index of the next instruction in the code.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    // Returns the location of the statement the next instruction comes from.
+    pub fn location(&self) -> Option<Location> {
+        self.code.locations.get(self.pc).copied()
+    }
+
+    // Returns the tape. When a growing tape grows to the left, its cells and the pointer shift.
+    pub fn tape(&self) -> &[i64] {
+        &self.tape
+    }
+
+    // Sets the cell at `index` of the tape, truncating the value to the cell width like the
+    // input does. Panics if the index is outside the tape.
+    pub fn set_cell(&mut self, index: usize, value: i64) {
+        self.tape[index] = self.options.cell_width.wrap(value);
+    }
+
+    // Returns the index of the current cell in the tape, which may be outside a fixed tape until
+    // the cell is accessed.
+    pub fn ptr(&self) -> i64 {
+        self.ptr
+    }
+
+    pub fn set_ptr(&mut self, ptr: i64) {
+        self.ptr = ptr;
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), InterpreterError> {
+        let width = self.options.cell_width;
+        match instruction {
+            Instruction::Move(offset) => {
+                self.ptr = move_ptr(&self.options, &mut self.tape, self.ptr, offset)?
+                    .ok_or_else(|| self.limit(Limit::Tape))?;
+            }
+            Instruction::Add(n) => {
+                let i = self.cell(self.pc)?;
+                self.tape[i] = add(&self.options, self.tape[i], n).ok_or_else(|| {
+                    let location = self.code.locations[self.pc];
+                    InterpreterError::Overflow {
+                        line: location.line,
+                        column: location.column,
+                    }
+                })?;
+            }
+            Instruction::PutChar => {
+                let i = self.cell(self.pc)?;
+                self.write(&[self.tape[i] as u8])?;
+            }
+            Instruction::GetChar => {
+                let i = self.cell(self.pc)?;
+                self.output.flush()?;
+                match self.input.next()? {
+                    Some(c) => self.tape[i] = width.wrap(c as i64),
+                    None => eof(&self.options, &mut self.tape[i]),
+                }
+            }
+            Instruction::PutInt => {
+                let i = self.cell(self.pc)?;
+                self.write(self.tape[i].to_string().as_bytes())?;
+            }
+            Instruction::GetInt => {
+                let i = self.cell(self.pc)?;
+                self.output.flush()?;
+                match self.input.integer()? {
+                    Some(n) => self.tape[i] = width.wrap(n),
+                    None => eof(&self.options, &mut self.tape[i]),
+                }
+            }
+            Instruction::LoopBegin(end) => {
+                if self.tape[self.cell(self.pc)?] == 0 {
+                    self.pc = end;
+                }
+            }
+            Instruction::LoopEnd(begin) => {
+                if self.tape[self.cell(self.pc)?] != 0 {
+                    self.pc = begin;
+                }
+            }
+            Instruction::Exit(Some(status)) => self.status = Some(status),
+            Instruction::Exit(None) => self.status = Some(self.tape[self.cell(self.pc)?] as i32),
+        }
+        self.pc += 1;
+        Ok(())
+    }
+
+    // Writes `bytes` unless they would exceed the output limit.
+    fn write(&mut self, bytes: &[u8]) -> Result<(), InterpreterError> {
+        if !count_output(&self.options, &mut self.written, bytes.len()) {
+            return Err(self.limit(Limit::Output));
+        }
+        Ok(self.output.write_all(bytes)?)
+    }
+
+    // Returns the error for hitting `limit` at the next instruction.
+    fn limit(&self, limit: Limit) -> InterpreterError {
+        let location = self.location().unwrap_or_default();
+        limit.error(&self.options, location, self.ptr, &self.tape)
+    }
+
+    // Returns the index of the current cell in the tape, or an error at the location of the
+    // instruction at `pc` if the pointer is outside the tape.
+    fn cell(&self, pc: usize) -> Result<usize, InterpreterError> {
+        if self.ptr < 0 || self.ptr >= self.tape.len() as i64 {
+            let location = self.code.locations.get(pc).copied().unwrap_or_default();
+            return Err(InterpreterError::OutOfBounds {
+                line: location.line,
+                column: location.column,
+                index: self.ptr,
+            });
+        }
+        Ok(self.ptr as usize)
+    }
+}
+
+// Allocates a tape of `size` cells, whose size may come from an untrusted program.
+fn new_tape(size: usize) -> Result<Vec<i64>, InterpreterError> {
+    let mut tape = vec![];
+    tape.try_reserve_exact(size)
+        .map_err(|_| InterpreterError::TapeAllocation { size })?;
+    tape.resize(size, 0);
+    Ok(tape)
+}
+
+// Returns `ptr` moved by `offset` with the tape mode of the options, or `None` if a growing tape
+// would exceed the tape limit.
+fn move_ptr(
+    options: &Options,
+    tape: &mut Vec<i64>,
+    ptr: i64,
+    offset: i32,
+) -> Result<Option<i64>, InterpreterError> {
+    let moved = ptr + offset as i64;
+    match options.tape_mode {
+        TapeMode::Fixed => Ok(Some(moved)),
+        TapeMode::Grow => grow(tape, moved, options.max_tape),
+        TapeMode::Wrap => Ok(Some(moved.rem_euclid(tape.len() as i64))),
+    }
+}
+
+// Returns `value + n` with the overflow behavior of the options, or `None` if it traps.
+fn add(options: &Options, value: i64, n: i32) -> Option<i64> {
+    let width = options.cell_width;
+    match options.overflow {
+        Overflow::Wrap => Some(width.wrap(value.wrapping_add(n as i64))),
+        overflow => {
+            let (min, max) = (width.min() as i128, width.max() as i128);
+            let mut value = value;
+            for a in width.split_addend(n as i64) {
+                let sum = value as i128 + a as i128;
+                value = match sum.clamp(min, max) {
+                    v if v == sum => v as i64,
+                    _ if overflow == Overflow::Trap => return None,
+                    v => v as i64,
+                };
+            }
+            Some(value)
+        }
+    }
+}
+
+// Stores what the input helpers store at the end of the input to `cell`.
+fn eof(options: &Options, cell: &mut i64) {
+    match options.eof {
+        Eof::Zero => *cell = 0,
+        Eof::MinusOne => *cell = -1,
+        Eof::Unchanged => {}
+    }
+}
+
+// Grows the tape so that it covers `index`, which may be negative, and returns the index of the
+// same cell in the new tape. The tape doubles, but not beyond `max` cells, and `None` is returned
+// if `index` cannot be covered within them. Without a limit, the size comes from the program, so
+// failing to allocate it is an error.
+fn grow(
+    tape: &mut Vec<i64>,
+    index: i64,
+    max: Option<usize>,
+) -> Result<Option<i64>, InterpreterError> {
+    let len = tape.len() as i64;
+    let max = max.map_or(i64::MAX, |max| i64::try_from(max).unwrap_or(i64::MAX));
+    let reserve = |tape: &mut Vec<i64>, size: i64| {
+        tape.try_reserve_exact((size - len) as usize).map_err(|_| {
+            InterpreterError::TapeAllocation {
+                size: size as usize,
+            }
+        })
+    };
+    if index >= len {
+        if index >= max {
+            return Ok(None);
+        }
+        let size = (index + 1).max(len.saturating_mul(2)).min(max);
+        reserve(tape, size)?;
+        tape.resize(size as usize, 0);
+    }
+    if index >= 0 {
+        return Ok(Some(index));
+    }
+
+    if -index > max - len {
+        return Ok(None);
+    }
+    let shift = (-index).max(len).min(max - len);
+    reserve(tape, len + shift)?;
+    tape.splice(0..0, std::iter::repeat_n(0, shift as usize));
+    Ok(Some(index + shift))
+}
+
+// Counts `n` bytes about to be written in `written`, or returns false if they would exceed the
+// output limit.
+fn count_output(options: &Options, written: &mut u64, n: usize) -> bool {
+    let total = *written + n as u64;
+    if options.max_output.is_some_and(|max| total > max) {
+        return false;
+    }
+    *written = total;
+    true
+}
+
+#[cfg(test)]
+fn compile(src: &str) -> Code {
+    let (tokens, locations) = crate::token::tokenize_with_locations(src).unwrap();
+    let tree = crate::parser::program(&tokens).unwrap().1;
+    let mut compiler = Compiler::new();
+    crate::codegen::gen_with_locations(&mut compiler, &tree, &locations).unwrap();
+    compiler.code()
+}
+
+#[cfg(test)]
+fn run(src: &str, options: &Options, input: &str) -> (String, Result<i32, InterpreterError>) {
+    let mut output = vec![];
+    let status =
+        Interpreter::with_options(options).run(&compile(src), &mut input.as_bytes(), &mut output);
+    (String::from_utf8(output).unwrap(), status)
+}
+
+#[test]
+fn test_compile() {
+    let code = compile("*ptr += 2;\nwhile *ptr {\n  *ptr -= 1;\n}\nexit(*ptr);\n");
+    assert_eq!(
+        code.instructions,
+        vec![
+            Instruction::Add(2),
+            Instruction::LoopBegin(3),
+            Instruction::Add(-1),
+            Instruction::LoopEnd(1),
+            Instruction::Exit(None),
+        ]
+    );
+    // The end of the loop belongs to the loop statement.
+    assert_eq!(code.locations[3], Location { line: 2, column: 1 });
+    assert_eq!(code.locations[4], Location { line: 5, column: 1 });
+}
+
+#[test]
+fn test_run() {
+    use crate::codegen::options::{CellWidth, TapeSize};
+
+    let src = "getint();\n*ptr += 5;\nputint();\nptr += 1;\n*ptr += 10;\nputchar();\ngetchar();\nputchar();\n";
+    let (output, status) = run(src, &Options::new(), " -37x");
+    assert_eq!((output.as_str(), status.unwrap()), ("-32\nx", 0));
+
+    let src = "*ptr -= 1;\nputint();\n*ptr += 100;\n*ptr += 100;\nputint();\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    assert_eq!(run(src, &options, "").0, "-1-57");
+    options.overflow = Overflow::Saturate;
+    assert_eq!(run(src, &options, "").0, "-1127");
+    options.overflow = Overflow::Trap;
+    assert!(matches!(
+        run(src, &options, ""),
+        (_, Err(InterpreterError::Overflow { line: 4, column: 1 }))
+    ));
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nptr -= 1;\nputint();\nptr += 2;\nputint();\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    options.tape_size = TapeSize(1);
+    assert_eq!(run(src, &options, "").0, "03");
+
+    options.tape_mode = TapeMode::Wrap;
+    options.tape_size = TapeSize(2);
+    assert_eq!(run(src, &options, "").0, "33");
+
+    options.tape_mode = TapeMode::Fixed;
+    options.tape_size = TapeSize(30000);
+    let (_, status) = run(src, &options, "");
+    assert_eq!(
+        status.unwrap_err().to_string(),
+        "9:1: pointer out of bounds (-1)"
+    );
+
+    let src = "*ptr += 7;\nwhile *ptr {\n  exit(*ptr);\n}\nexit(3);\n";
+    assert_eq!(run(src, &Options::new(), "").1.unwrap(), 7);
+
+    let src = "getchar();\n*ptr -= 2;\n";
+    let mut options = Options::new();
+    options.eof = Eof::Unchanged;
+    options.exit_status = ExitStatus::Cell;
+    assert_eq!(run(src, &options, "").1.unwrap(), -2);
+}
+
+#[test]
+fn test_machine() {
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  getchar();\n  putchar();\n  ptr -= 1;\n  *ptr -= 1;\n}\n";
+    let mut machine = Machine::new(compile(src), &Options::new(), &b"abc"[..], vec![]).unwrap();
+    assert_eq!(machine.step().unwrap(), None);
+    assert_eq!(machine.tape()[0], 3);
+    assert_eq!(machine.location(), Some(Location { line: 2, column: 1 }));
+
+    // The first iteration of the loop, with its begin and end, is 7 instructions.
+    assert_eq!(machine.run_until(7).unwrap(), None);
+    assert_eq!(
+        (machine.ptr(), machine.tape()[..2].to_vec()),
+        (0, vec![2, 97])
+    );
+    assert_eq!(machine.output(), b"a");
+
+    machine.set_cell(0, (1 << 32) + 1);
+    machine.set_ptr(0);
+    assert_eq!(machine.tape()[0], 1);
+    assert_eq!(machine.run_until(100).unwrap(), Some(0));
+    assert_eq!(machine.step().unwrap(), Some(0));
+    assert_eq!(machine.into_output(), b"ab");
+
+    let mut options = Options::new();
+    options.tape_size = crate::codegen::options::TapeSize(1);
+    let mut machine = Machine::new(
+        compile("ptr += 1;\nputint();\n"),
+        &options,
+        io::empty(),
+        vec![],
+    )
+    .unwrap();
+    assert!(machine.step().is_ok());
+    assert!(matches!(
+        machine.step(),
+        Err(InterpreterError::OutOfBounds {
+            line: 2,
+            column: 1,
+            index: 1
+        })
+    ));
+    machine.set_ptr(0);
+    assert_eq!(machine.run().unwrap(), 0);
+    assert_eq!(machine.output(), b"0");
+}
+
+#[test]
+fn test_limits() {
+    use crate::codegen::options::TapeSize;
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  putint();\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\n";
+    let mut options = Options::new();
+    options.fuel = Some(8);
+    let mut machine = Machine::new(compile(src), &options, io::empty(), vec![]).unwrap();
+    assert_eq!(machine.run_until(7).unwrap(), None);
+    assert_eq!(machine.fuel(), Some(1));
+    match machine.run() {
+        Err(InterpreterError::OutOfFuel {
+            line: 3,
+            column: 3,
+            snapshot,
+        }) => {
+            assert_eq!((snapshot.ptr, &snapshot.tape[..2]), (0, &[2, 1][..]));
+            assert_eq!(
+                snapshot.to_string(),
+                "ptr = 0, tape[0..9] = [2] 1 0 0 0 0 0 0 0"
+            );
+        }
+        status => panic!("{:?}", status),
+    }
+    // The machine stays before the instruction out of fuel.
+    assert_eq!(machine.location(), Some(Location { line: 3, column: 3 }));
+    assert_eq!(machine.output(), b"3");
+
+    options.fuel = None;
+    options.max_output = Some(2);
+    let (output, status) = run(src, &options, "");
+    assert_eq!(output, "32");
+    assert_eq!(
+        status.unwrap_err().to_string(),
+        "3:3: output limit of 2 bytes exceeded"
+    );
+
+    // A growing tape doubles up to the limit.
+    let src =
+        "while *ptr {\n  ptr += 1;\n}\n*ptr += 1;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n}\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    options.tape_size = TapeSize(2);
+    options.max_tape = Some(5);
+    match run(src, &options, "").1 {
+        Err(e @ InterpreterError::TapeLimit { .. }) => {
+            assert_eq!(e.to_string(), "6:3: tape limit of 5 cells exceeded");
+            assert_eq!(e.snapshot().unwrap().tape, vec![1; 5]);
+        }
+        status => panic!("{:?}", status),
+    }
+    options.bidirectional = true;
+    let src = "ptr -= 3;\nputint();\nptr -= 1;\n";
+    let (output, status) = run(src, &options, "");
+    assert_eq!((output, status.unwrap()), ("0".to_owned(), 0));
+    options.max_tape = Some(3);
+    assert_eq!(
+        run(src, &options, "").1.unwrap_err().to_string(),
+        "1:1: tape limit of 3 cells exceeded"
+    );
+
+    // A tape too large to allocate is an error rather than an abort.
+    let mut options = Options::new();
+    options.tape_size = TapeSize(1 << 60);
+    assert_eq!(
+        run("putint();\n", &options, "").1.unwrap_err().to_string(),
+        "cannot allocate a tape of 1152921504606846976 cells"
+    );
+    let program = bytecode::Program::new(&compile("putint();\n"), &options);
+    assert!(matches!(
+        program.run(&mut io::empty(), &mut io::sink()),
+        Err(InterpreterError::TapeAllocation { .. })
+    ));
+    // So is growing a tape without a limit beyond the memory.
+    let mut tape = vec![0; 2];
+    assert!(matches!(
+        grow(&mut tape, 1 << 61, None),
+        Err(InterpreterError::TapeAllocation { .. })
+    ));
+    assert!(matches!(
+        grow(&mut tape, -(1 << 61), None),
+        Err(InterpreterError::TapeAllocation { .. })
+    ));
+    assert_eq!(
+        (grow(&mut tape, -1, None).unwrap(), tape.len()),
+        (Some(1), 4)
+    );
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        let (output, status) = run(src, options, input);
+        assert_eq!(status.unwrap(), 0);
+        Some(output)
+    });
+}
@@ -0,0 +1,831 @@
+// A compact bytecode for the interpreter, with runs of moves and additions fused and the jumps of
+// loops precomputed. `Program::encode` writes it as a '.chb' file, and `Program::decode` reads one
+// back and verifies it, so that malformed bytecode is rejected before it runs.
+//
+// A file is the magic, the version, the options it was compiled with, and the ops, each an opcode
+// followed by its operand and the line and column of its statement. Numbers are LEB128.
+
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+use super::{
+    add, count_output, eof, move_ptr, new_tape, Code, Input, Instruction, InterpreterError, Limit,
+};
+use crate::codegen::options::{CellWidth, Eof, ExitStatus, Options, Overflow, TapeMode, TapeSize};
+use crate::token::Location;
+
+pub const MAGIC: &[u8; 4] = b"\0chb";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq, failure::Fail)]
+pub enum BytecodeError {
+    #[fail(display = "malformed bytecode at offset {}", offset)]
+    Malformed { offset: usize },
+
+    #[fail(display = "unsupported bytecode version {}", version)]
+    UnsupportedVersion { version: u8 },
+
+    #[fail(display = "invalid bytecode: {}", reason)]
+    Invalid { reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Move(i32),
+    Add(i32),
+    // Sets the cell to zero, for a loop adding an odd number to a wrapping cell.
+    Clear,
+    PutChar,
+    GetChar,
+    PutInt,
+    GetInt,
+    // Jumps to the index if the cell is zero, which is just after the matching `JumpIfNotZero`.
+    JumpIfZero(usize),
+    // Jumps to the index if the cell is not zero, which is just after the matching `JumpIfZero`.
+    JumpIfNotZero(usize),
+    Exit(i32),
+    // Exits with the cell as the status.
+    ExitCell,
+}
+
+const MOVE: u8 = 0x00;
+const ADD: u8 = 0x01;
+const CLEAR: u8 = 0x02;
+const PUTCHAR: u8 = 0x03;
+const GETCHAR: u8 = 0x04;
+const PUTINT: u8 = 0x05;
+const GETINT: u8 = 0x06;
+const JUMP_IF_ZERO: u8 = 0x07;
+const JUMP_IF_NOT_ZERO: u8 = 0x08;
+const EXIT: u8 = 0x09;
+const EXIT_CELL: u8 = 0x0a;
+
+// The flags of the options.
+const BIDIRECTIONAL: u8 = 0x01;
+const BOUNDS_CHECK: u8 = 0x02;
+const BUFFERED_IO: u8 = 0x04;
+const EXIT_STATUS_CELL: u8 = 0x08;
+// The ops are not fused. Files without it are fused, as every file was before it.
+const UNFUSED: u8 = 0x10;
+
+// The ops of a program, the location of the statement each comes from, and the options that
+// change how they run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub options: Options,
+    pub ops: Vec<Op>,
+    pub locations: Vec<Location>,
+    // Whether runs of ops were fused, in which case an op may run several instructions and the
+    // `fuel` option does not count them.
+    pub fused: bool,
+}
+
+impl Program {
+    // Compiles `code`. Additions are fused only with wrapping cells, where they do not depend on
+    // the order. With the `fuel` option nothing is fused, so that the ops are the instructions
+    // counted by the interpreter, and the program can be run with fuel. Neither is it with the
+    // `max-tape` option on a growing tape, where each move must be checked against the limit.
+    pub fn new(code: &Code, options: &Options) -> Program {
+        let fuse = options.fuel.is_none() && !limits_tape(options);
+        let wrap = fuse && options.overflow == Overflow::Wrap;
+        let mut program = Program {
+            options: Options {
+                eof: options.eof,
+                cell_width: options.cell_width,
+                overflow: options.overflow,
+                tape_size: options.tape_size,
+                tape_mode: options.tape_mode,
+                bidirectional: options.bidirectional,
+                bounds_check: options.bounds_check,
+                buffered_io: options.buffered_io,
+                exit_status: options.exit_status,
+                fuel: options.fuel,
+                max_tape: options.max_tape,
+                max_output: options.max_output,
+                ..Options::new()
+            },
+            ops: vec![],
+            locations: vec![],
+            fused: fuse,
+        };
+        // The indices of the `JumpIfZero`s of the enclosing loops.
+        let mut loop_stack = vec![];
+
+        for (instruction, location) in code.instructions.iter().zip(&code.locations) {
+            let op = match *instruction {
+                Instruction::Move(offset) => {
+                    if let (true, Some(Op::Move(m))) = (fuse, program.ops.last_mut()) {
+                        if let Some(sum) = m.checked_add(offset) {
+                            *m = sum;
+                            continue;
+                        }
+                    }
+                    Op::Move(offset)
+                }
+                Instruction::Add(n) => {
+                    if let (true, Some(Op::Add(m))) = (wrap, program.ops.last_mut()) {
+                        if let Some(sum) = m.checked_add(n) {
+                            *m = sum;
+                            continue;
+                        }
+                    }
+                    Op::Add(n)
+                }
+                Instruction::PutChar => Op::PutChar,
+                Instruction::GetChar => Op::GetChar,
+                Instruction::PutInt => Op::PutInt,
+                Instruction::GetInt => Op::GetInt,
+                Instruction::LoopBegin(_) => {
+                    loop_stack.push(program.ops.len());
+                    Op::JumpIfZero(0)
+                }
+                Instruction::LoopEnd(_) => {
+                    let begin = match loop_stack.pop() {
+                        Some(begin) => begin,
+                        None => continue,
+                    };
+                    // Adding an odd number to a wrapping cell reaches zero from any value.
+                    if let (true, [Op::JumpIfZero(_), Op::Add(n)]) = (wrap, &program.ops[begin..]) {
+                        if n % 2 != 0 {
+                            program.ops.truncate(begin + 1);
+                            program.locations.truncate(begin + 1);
+                            program.ops[begin] = Op::Clear;
+                            continue;
+                        }
+                    }
+                    program.ops[begin] = Op::JumpIfZero(program.ops.len() + 1);
+                    Op::JumpIfNotZero(begin + 1)
+                }
+                Instruction::Exit(Some(status)) => Op::Exit(status),
+                Instruction::Exit(None) => Op::ExitCell,
+            };
+            program.ops.push(op);
+            program.locations.push(*location);
+        }
+
+        // A loop left open runs to the end of the program.
+        for begin in loop_stack {
+            program.ops[begin] = Op::JumpIfZero(program.ops.len());
+        }
+
+        program
+    }
+
+    // Runs the program and returns its exit status, like `Interpreter::run`. The limits are
+    // those of `options`, which are not part of the encoding, and `verify` checks that they can
+    // be applied.
+    pub fn run(
+        &self,
+        input: &mut (impl Read + ?Sized),
+        output: &mut (impl Write + ?Sized),
+    ) -> Result<i32, InterpreterError> {
+        let options = &self.options;
+        let width = options.cell_width;
+        let size = options.tape_size.0;
+        let mut input = Input {
+            inner: input,
+            peeked: None,
+        };
+        let mut tape = new_tape(size)?;
+        let mut ptr = if options.bidirectional { size / 2 } else { 0 } as i64;
+        let (mut fuel, mut written) = (options.fuel, 0);
+
+        // Returns the index of the current cell in the tape.
+        let cell = |tape: &[i64], ptr: i64, pc: usize| {
+            if ptr < 0 || ptr >= tape.len() as i64 {
+                let location = self.locations.get(pc).copied().unwrap_or_default();
+                return Err(InterpreterError::OutOfBounds {
+                    line: location.line,
+                    column: location.column,
+                    index: ptr,
+                });
+            }
+            Ok(ptr as usize)
+        };
+
+        // Returns the error for hitting `limit` at the op at `pc`.
+        let limit = |limit: Limit, tape: &[i64], ptr: i64, pc: usize| {
+            limit.error(options, self.locations[pc], ptr, tape)
+        };
+
+        let mut pc = 0;
+        while let Some(op) = self.ops.get(pc) {
+            match fuel {
+                Some(0) => return Err(limit(Limit::Fuel, &tape, ptr, pc)),
+                Some(ref mut fuel) => *fuel -= 1,
+                None => {}
+            }
+            match *op {
+                Op::Move(offset) => {
+                    ptr = move_ptr(options, &mut tape, ptr, offset)?
+                        .ok_or_else(|| limit(Limit::Tape, &tape, ptr, pc))?;
+                }
+                Op::Add(n) => {
+                    let i = cell(&tape, ptr, pc)?;
+                    tape[i] = add(options, tape[i], n).ok_or_else(|| {
+                        let location = self.locations[pc];
+                        InterpreterError::Overflow {
+                            line: location.line,
+                            column: location.column,
+                        }
+                    })?;
+                }
+                Op::Clear => {
+                    let i = cell(&tape, ptr, pc)?;
+                    tape[i] = 0;
+                }
+                Op::PutChar => {
+                    let i = cell(&tape, ptr, pc)?;
+                    if !count_output(options, &mut written, 1) {
+                        return Err(limit(Limit::Output, &tape, ptr, pc));
+                    }
+                    output.write_all(&[tape[i] as u8])?;
+                }
+                Op::GetChar => {
+                    let i = cell(&tape, ptr, pc)?;
+                    output.flush()?;
+                    match input.next()? {
+                        Some(c) => tape[i] = width.wrap(c as i64),
+                        None => eof(options, &mut tape[i]),
+                    }
+                }
+                Op::PutInt => {
+                    let i = cell(&tape, ptr, pc)?;
+                    let s = tape[i].to_string();
+                    if !count_output(options, &mut written, s.len()) {
+                        return Err(limit(Limit::Output, &tape, ptr, pc));
+                    }
+                    output.write_all(s.as_bytes())?;
+                }
+                Op::GetInt => {
+                    let i = cell(&tape, ptr, pc)?;
+                    output.flush()?;
+                    match input.integer()? {
+                        Some(n) => tape[i] = width.wrap(n),
+                        None => eof(options, &mut tape[i]),
+                    }
+                }
+                Op::JumpIfZero(target) => {
+                    if tape[cell(&tape, ptr, pc)?] == 0 {
+                        pc = target;
+                        continue;
+                    }
+                }
+                Op::JumpIfNotZero(target) => {
+                    if tape[cell(&tape, ptr, pc)?] != 0 {
+                        pc = target;
+                        continue;
+                    }
+                }
+                Op::Exit(status) => return Ok(status),
+                Op::ExitCell => return Ok(tape[cell(&tape, ptr, pc)?] as i32),
+            }
+            pc += 1;
+        }
+
+        match options.exit_status {
+            ExitStatus::Zero => Ok(0),
+            ExitStatus::Cell => {
+                // The end of the program belongs to its last statement.
+                let last = self.ops.len().saturating_sub(1);
+                Ok(tape[cell(&tape, ptr, last)?] as i32)
+            }
+        }
+    }
+
+    // Checks that every jump goes to just after its matching jump, or to the end of the program
+    // for a loop left open, that there is a location for every op, and that a fused program is
+    // not given fuel or a limit on a growing tape.
+    pub fn verify(&self) -> Result<(), BytecodeError> {
+        let invalid = |index: usize, reason: &str| {
+            Err(BytecodeError::Invalid {
+                reason: format!("op {}: {}", index, reason),
+            })
+        };
+
+        if self.locations.len() != self.ops.len() {
+            return Err(BytecodeError::Invalid {
+                reason: "the numbers of ops and locations differ".to_string(),
+            });
+        }
+
+        // The fused ops would use less fuel than the statements they come from.
+        if self.fused && self.options.fuel.is_some() {
+            return Err(BytecodeError::Invalid {
+                reason: "fuel requires bytecode compiled with fuel".to_string(),
+            });
+        }
+        // A fused move could pass the limit and come back without growing the tape.
+        if self.fused && limits_tape(&self.options) {
+            return Err(BytecodeError::Invalid {
+                reason: "max-tape requires bytecode compiled with max-tape".to_string(),
+            });
+        }
+
+        let mut loop_stack = vec![];
+        for (i, op) in self.ops.iter().enumerate() {
+            match *op {
+                Op::JumpIfZero(target) if target <= i || target > self.ops.len() => {
+                    return invalid(i, "jump target out of range");
+                }
+                Op::JumpIfZero(_) => loop_stack.push(i),
+                Op::Clear if !self.fused => return invalid(i, "clear in a program not fused"),
+                Op::JumpIfNotZero(target) => match loop_stack.pop() {
+                    Some(begin)
+                        if target == begin + 1 && self.ops[begin] == Op::JumpIfZero(i + 1) => {}
+                    Some(_) => return invalid(i, "jump does not match its loop"),
+                    None => return invalid(i, "jump outside a loop"),
+                },
+                _ => {}
+            }
+        }
+        for begin in loop_stack {
+            if self.ops[begin] != Op::JumpIfZero(self.ops.len()) {
+                return invalid(begin, "jump does not match its loop");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let options = &self.options;
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+
+        out.push(match options.eof {
+            Eof::MinusOne => 0,
+            Eof::Zero => 1,
+            Eof::Unchanged => 2,
+        });
+        out.push(options.cell_width.bits() as u8);
+        out.push(match options.overflow {
+            Overflow::Wrap => 0,
+            Overflow::Saturate => 1,
+            Overflow::Trap => 2,
+        });
+        out.push(match options.tape_mode {
+            TapeMode::Fixed => 0,
+            TapeMode::Grow => 1,
+            TapeMode::Wrap => 2,
+        });
+        let flags = [
+            (options.bidirectional, BIDIRECTIONAL),
+            (options.bounds_check, BOUNDS_CHECK),
+            (options.buffered_io, BUFFERED_IO),
+            (options.exit_status == ExitStatus::Cell, EXIT_STATUS_CELL),
+            (!self.fused, UNFUSED),
+        ];
+        out.push(flags.iter().filter(|(set, _)| *set).map(|(_, f)| f).sum());
+        uleb(&mut out, options.tape_size.0 as u64);
+
+        uleb(&mut out, self.ops.len() as u64);
+        for (op, location) in self.ops.iter().zip(&self.locations) {
+            match *op {
+                Op::Move(offset) => {
+                    out.push(MOVE);
+                    sleb(&mut out, offset as i64);
+                }
+                Op::Add(n) => {
+                    out.push(ADD);
+                    sleb(&mut out, n as i64);
+                }
+                Op::Clear => out.push(CLEAR),
+                Op::PutChar => out.push(PUTCHAR),
+                Op::GetChar => out.push(GETCHAR),
+                Op::PutInt => out.push(PUTINT),
+                Op::GetInt => out.push(GETINT),
+                Op::JumpIfZero(target) => {
+                    out.push(JUMP_IF_ZERO);
+                    uleb(&mut out, target as u64);
+                }
+                Op::JumpIfNotZero(target) => {
+                    out.push(JUMP_IF_NOT_ZERO);
+                    uleb(&mut out, target as u64);
+                }
+                Op::Exit(status) => {
+                    out.push(EXIT);
+                    sleb(&mut out, status as i64);
+                }
+                Op::ExitCell => out.push(EXIT_CELL),
+            }
+            uleb(&mut out, location.line as u64);
+            uleb(&mut out, location.column as u64);
+        }
+
+        out
+    }
+
+    // Reads a program written by `encode` and verifies it.
+    pub fn decode(bytes: &[u8]) -> Result<Program, BytecodeError> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(BytecodeError::Malformed { offset: 0 });
+        }
+        match r.byte()? {
+            VERSION => {}
+            version => return Err(BytecodeError::UnsupportedVersion { version }),
+        }
+
+        let mut options = Options::new();
+        options.eof = match r.byte()? {
+            0 => Eof::MinusOne,
+            1 => Eof::Zero,
+            2 => Eof::Unchanged,
+            _ => return r.malformed_before(),
+        };
+        options.cell_width = match r.byte()? {
+            8 => CellWidth::W8,
+            16 => CellWidth::W16,
+            32 => CellWidth::W32,
+            64 => CellWidth::W64,
+            _ => return r.malformed_before(),
+        };
+        options.overflow = match r.byte()? {
+            0 => Overflow::Wrap,
+            1 => Overflow::Saturate,
+            2 => Overflow::Trap,
+            _ => return r.malformed_before(),
+        };
+        options.tape_mode = match r.byte()? {
+            0 => TapeMode::Fixed,
+            1 => TapeMode::Grow,
+            2 => TapeMode::Wrap,
+            _ => return r.malformed_before(),
+        };
+        let flags = r.byte()?;
+        if flags & !(BIDIRECTIONAL | BOUNDS_CHECK | BUFFERED_IO | EXIT_STATUS_CELL | UNFUSED) != 0 {
+            return r.malformed_before();
+        }
+        options.bidirectional = flags & BIDIRECTIONAL != 0;
+        options.bounds_check = flags & BOUNDS_CHECK != 0;
+        options.buffered_io = flags & BUFFERED_IO != 0;
+        if flags & EXIT_STATUS_CELL != 0 {
+            options.exit_status = ExitStatus::Cell;
+        }
+        let pos = r.pos;
+        options.tape_size = match usize::try_from(r.uleb(64)?) {
+            Ok(0) | Err(_) => return Err(BytecodeError::Malformed { offset: pos }),
+            Ok(n) => TapeSize(n),
+        };
+
+        let count = r.uleb(32)? as usize;
+        // Every op takes at least 3 bytes, so a count beyond that is not allocated for.
+        let capacity = count.min(bytes.len() / 3);
+        let mut program = Program {
+            options,
+            ops: Vec::with_capacity(capacity),
+            locations: Vec::with_capacity(capacity),
+            fused: flags & UNFUSED == 0,
+        };
+        for _ in 0..count {
+            let op = match r.byte()? {
+                MOVE => Op::Move(r.sleb(32)? as i32),
+                ADD => Op::Add(r.sleb(32)? as i32),
+                CLEAR => Op::Clear,
+                PUTCHAR => Op::PutChar,
+                GETCHAR => Op::GetChar,
+                PUTINT => Op::PutInt,
+                GETINT => Op::GetInt,
+                JUMP_IF_ZERO => Op::JumpIfZero(r.uleb(32)? as usize),
+                JUMP_IF_NOT_ZERO => Op::JumpIfNotZero(r.uleb(32)? as usize),
+                EXIT => Op::Exit(r.sleb(32)? as i32),
+                EXIT_CELL => Op::ExitCell,
+                _ => return r.malformed_before(),
+            };
+            program.ops.push(op);
+            program.locations.push(Location {
+                line: r.uleb(32)? as usize,
+                column: r.uleb(32)? as usize,
+            });
+        }
+        if r.pos != bytes.len() {
+            return r.malformed();
+        }
+
+        program.verify()?;
+        Ok(program)
+    }
+}
+
+// Returns whether the options limit a growing tape.
+fn limits_tape(options: &Options) -> bool {
+    options.tape_mode == TapeMode::Grow && options.max_tape.is_some()
+}
+
+fn uleb(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn malformed<T>(&self) -> Result<T, BytecodeError> {
+        Err(BytecodeError::Malformed { offset: self.pos })
+    }
+
+    // Reports the byte just read as malformed.
+    fn malformed_before<T>(&self) -> Result<T, BytecodeError> {
+        Err(BytecodeError::Malformed {
+            offset: self.pos - 1,
+        })
+    }
+
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        match self.bytes.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => self.malformed(),
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        match self.bytes.get(self.pos..self.pos + n) {
+            Some(b) => {
+                self.pos += n;
+                Ok(b)
+            }
+            None => self.malformed(),
+        }
+    }
+
+    // Reads an unsigned LEB128 number of at most `bits` bits.
+    fn uleb(&mut self, bits: u32) -> Result<u64, BytecodeError> {
+        let (mut n, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits || (shift + 7 > bits && (byte & 0x7f) >> (bits - shift) != 0) {
+                return self.malformed_before();
+            }
+            n |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    // Reads a signed LEB128 number of at most `bits` bits.
+    fn sleb(&mut self, bits: u32) -> Result<i64, BytecodeError> {
+        let (mut n, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.byte()?;
+            if shift >= bits {
+                return self.malformed_before();
+            }
+            n |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    n |= -1 << shift;
+                }
+                // The unused bits of the last byte must be the sign extension.
+                let min = -(1i128 << (bits - 1));
+                let max = (1i128 << (bits - 1)) - 1;
+                if (n as i128) < min || (n as i128) > max {
+                    return self.malformed_before();
+                }
+                return Ok(n);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn program(src: &str, options: &Options) -> Program {
+    Program::new(&super::compile(src), options)
+}
+
+#[test]
+fn test_compile() {
+    let src = "ptr += 1;\nptr += 2;\n*ptr += 3;\n*ptr -= 1;\nwhile *ptr {\n  *ptr -= 1;\n}\nwhile *ptr {\n  putchar();\n  ptr -= 1;\n}\nexit(*ptr);\n";
+    let p = program(src, &Options::new());
+    assert_eq!(
+        p.ops,
+        vec![
+            Op::Move(3),
+            Op::Add(2),
+            Op::Clear,
+            Op::JumpIfZero(7),
+            Op::PutChar,
+            Op::Move(-1),
+            Op::JumpIfNotZero(4),
+            Op::ExitCell,
+        ]
+    );
+    assert_eq!(p.locations[2], Location { line: 5, column: 1 });
+    assert_eq!(p.locations[6], Location { line: 8, column: 1 });
+    assert_eq!(p.verify(), Ok(()));
+
+    // Additions that saturate are neither fused nor turned into a clear.
+    let mut options = Options::new();
+    options.overflow = Overflow::Saturate;
+    let p = program(src, &options);
+    assert_eq!(p.ops[1..3], [Op::Add(3), Op::Add(-1)]);
+    assert_eq!(p.ops[3], Op::JumpIfZero(6));
+
+    // With fuel, every instruction is an op, so that the same number of them runs.
+    options.overflow = Overflow::Wrap;
+    options.fuel = Some(100);
+    let p = program(src, &options);
+    assert_eq!(p.ops.len(), super::compile(src).instructions.len());
+    assert_eq!(p.options.fuel, Some(100));
+    assert!(!p.fused);
+
+    // A loop left open jumps to the end.
+    let code = Code {
+        instructions: vec![Instruction::LoopBegin(2), Instruction::PutChar],
+        locations: vec![Location::default(); 2],
+    };
+    let p = Program::new(&code, &Options::new());
+    assert_eq!(p.ops, vec![Op::JumpIfZero(2), Op::PutChar]);
+    assert_eq!(p.verify(), Ok(()));
+}
+
+#[test]
+fn test_decode() {
+    let src = "// chiya: cell-width=8 tape-mode=grow eof=unchanged exit-status=cell\ngetint();\nwhile *ptr {\n  ptr -= 70000;\n  *ptr += 1;\n  ptr += 70000;\n  *ptr -= 1;\n}\nexit(-3);\n";
+    let mut options = Options::new();
+    options.apply_pragmas(src).unwrap();
+    options.bounds_check = true;
+    let p = program(src, &options);
+    let bytes = p.encode();
+    assert_eq!(&bytes[..5], b"\0chb\x01");
+    assert_eq!(Program::decode(&bytes), Ok(p));
+
+    // No truncation or change of a byte makes it panic.
+    for i in 0..bytes.len() {
+        assert!(Program::decode(&bytes[..i]).is_err());
+        for b in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+            let mut bytes = bytes.clone();
+            bytes[i] = b;
+            let _ = Program::decode(&bytes);
+        }
+    }
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(
+        Program::decode(&trailing),
+        Err(BytecodeError::Malformed {
+            offset: bytes.len()
+        })
+    );
+    let mut version = bytes.clone();
+    version[4] = 2;
+    assert_eq!(
+        Program::decode(&version),
+        Err(BytecodeError::UnsupportedVersion { version: 2 })
+    );
+    assert_eq!(
+        Program::decode(b"// chiya"),
+        Err(BytecodeError::Malformed { offset: 0 })
+    );
+
+    let mut p = program("while *ptr {\n  putchar();\n}\n", &Options::new());
+    p.ops[2] = Op::JumpIfNotZero(0);
+    assert_eq!(
+        Program::decode(&p.encode()),
+        Err(BytecodeError::Invalid {
+            reason: "op 2: jump does not match its loop".to_string()
+        })
+    );
+    p.ops[0] = Op::JumpIfZero(9);
+    assert_eq!(
+        p.verify(),
+        Err(BytecodeError::Invalid {
+            reason: "op 0: jump target out of range".to_string()
+        })
+    );
+
+    // A fused program cannot be given fuel once it is loaded, while one compiled with fuel can.
+    let src = "*ptr += 5;\nwhile *ptr {\n  *ptr -= 1;\n}\n*ptr += 65;\nputchar();\n";
+    let mut p = Program::decode(&program(src, &Options::new()).encode()).unwrap();
+    assert!(p.fused);
+    p.options.fuel = Some(7);
+    assert_eq!(
+        p.verify(),
+        Err(BytecodeError::Invalid {
+            reason: "fuel requires bytecode compiled with fuel".to_string()
+        })
+    );
+    let mut options = Options::new();
+    options.fuel = Some(1);
+    let mut p = Program::decode(&program(src, &options).encode()).unwrap();
+    assert!(!p.fused);
+    p.options.fuel = Some(7);
+    assert_eq!(p.verify(), Ok(()));
+    let mut output = vec![];
+    let error = p.run(&mut "".as_bytes(), &mut output).unwrap_err();
+    assert_eq!(error.to_string(), "2:1: out of fuel");
+
+    p.ops[1] = Op::Clear;
+    p.fused = false;
+    assert_eq!(
+        Program::decode(&p.encode()),
+        Err(BytecodeError::Invalid {
+            reason: "op 1: clear in a program not fused".to_string()
+        })
+    );
+
+    // Neither can it be given a limit on a growing tape, which a fused move could pass unchecked.
+    let src = "ptr += 3;\nptr -= 3;\nputint();\n";
+    let mut options = Options::new();
+    options.tape_mode = TapeMode::Grow;
+    options.tape_size = TapeSize(1);
+    let mut p = Program::decode(&program(src, &options).encode()).unwrap();
+    assert!(p.fused);
+    p.options.max_tape = Some(2);
+    assert_eq!(
+        p.verify(),
+        Err(BytecodeError::Invalid {
+            reason: "max-tape requires bytecode compiled with max-tape".to_string()
+        })
+    );
+    options.max_tape = Some(2);
+    let mut p = Program::decode(&program(src, &options).encode()).unwrap();
+    assert!(!p.fused);
+    p.options.max_tape = Some(2);
+    assert_eq!(p.verify(), Ok(()));
+    let error = p.run(&mut "".as_bytes(), &mut vec![]).unwrap_err();
+    assert_eq!(error.to_string(), "1:1: tape limit of 2 cells exceeded");
+}
+
+#[test]
+fn test_run() {
+    let run = |src: &str, options: &Options| {
+        let mut output = vec![];
+        let status = program(src, options).run(&mut "7 x".as_bytes(), &mut output);
+        (String::from_utf8(output).unwrap(), status)
+    };
+
+    let src = "getint();\nwhile *ptr {\n  putint();\n  *ptr -= 3;\n}\n";
+    let mut options = Options::new();
+    options.cell_width = CellWidth::W8;
+    let (output, status) = run(src, &options);
+    assert_eq!(output, super::run(src, &options, "7 x").0);
+    assert_eq!((&output[..9], status.unwrap()), ("741-2-5-8", 0));
+    options.overflow = Overflow::Trap;
+    assert!(matches!(
+        run(src, &options),
+        (_, Err(InterpreterError::Overflow { line: 4, column: 3 }))
+    ));
+
+    let src = "ptr -= 1;\nwhile *ptr {\n  *ptr -= 1;\n}\n";
+    assert_eq!(
+        run(src, &Options::new()).1.unwrap_err().to_string(),
+        "2:1: pointer out of bounds (-1)"
+    );
+
+    let src = "getchar();\ngetchar();\nexit(*ptr);\n";
+    assert_eq!(run(src, &Options::new()).1.unwrap(), 32);
+
+    let src = "getint();\nwhile *ptr {\n  putint();\n  *ptr -= 1;\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n}\n";
+    let mut options = Options::new();
+    for (fuel, max_output) in [(Some(20), None), (None, Some(4))] {
+        options.fuel = fuel;
+        options.max_output = max_output;
+        let (output, status) = run(src, &options);
+        let (expected, error) = super::run(src, &options, "7 x");
+        assert_eq!(output, expected);
+        let (status, error) = (status.unwrap_err(), error.unwrap_err());
+        assert_eq!(status.to_string(), error.to_string());
+        assert_eq!(status.snapshot(), error.snapshot());
+    }
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        let p = Program::decode(&program(src, options).encode()).unwrap();
+        let mut output = vec![];
+        let status = p.run(&mut input.as_bytes(), &mut output);
+        assert_eq!(status.unwrap(), 0);
+        Some(String::from_utf8(output).unwrap())
+    });
+}
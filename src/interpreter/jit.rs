@@ -0,0 +1,592 @@
+// Compiles bytecode to x86-64 machine code in executable memory and runs it in the process, with
+// the same results as the interpreter.
+//
+// The machine code keeps the tape at 'r12' with 'r14' cells, the pointer as the index 'rbx', the
+// context at 'r13' and the fuel left at 'r15', all callee-saved, and calls back into Rust for the
+// I/O and to grow the tape. It returns one of the results below, and leaves the pointer, the index
+// of the op that failed and the exit status in the context.
+
+use std::io::{self, Read, Write};
+
+use super::bytecode::{Op, Program};
+use super::{count_output, eof, grow, new_tape, Input, InterpreterError, Limit};
+use crate::codegen::options::{CellWidth, ExitStatus, Options, Overflow, TapeMode};
+use crate::codegen::x86_64::assembler::*;
+use crate::token::Location;
+
+const EXITED: i64 = 0;
+const OUT_OF_BOUNDS: i64 = 1;
+const OVERFLOW: i64 = 2;
+const IO_ERROR: i64 = 3;
+const OUT_OF_FUEL: i64 = 4;
+const TAPE_LIMIT: i64 = 5;
+const OUTPUT_LIMIT: i64 = 6;
+const TAPE_ALLOCATION: i64 = 7;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+type Callback<'a> = extern "sysv64" fn(*mut Context<'a>, i64) -> i64;
+
+// The state shared by the machine code and the callbacks. The machine code reads and writes the
+// fields before `tape`.
+#[repr(C)]
+struct Context<'a> {
+    base: *mut i64,
+    len: i64,
+    ptr: i64,
+    pc: i64,
+    status: i64,
+    fuel: i64,
+    // Called with the index of the current cell. They return 0, or the result of a failure.
+    putchar: Callback<'a>,
+    getchar: Callback<'a>,
+    putint: Callback<'a>,
+    getint: Callback<'a>,
+    // Called with a pointer outside the tape. It returns the index of the same cell after growing
+    // the tape, and updates `base` and `len`, or returns the negated result of a failure.
+    grow: Callback<'a>,
+    tape: Vec<i64>,
+    options: &'a Options,
+    input: Input<&'a mut dyn Read>,
+    output: &'a mut dyn Write,
+    // The error of an `IO_ERROR` or `TAPE_ALLOCATION` result.
+    error: Option<InterpreterError>,
+    // The number of bytes written.
+    written: u64,
+}
+
+// Returns the operand of the field of the context at `offset`.
+fn field(offset: usize) -> Operand {
+    Operand::Mem(Mem::new(R13, offset as i32))
+}
+
+// The operand of the current cell.
+const CELL: Operand = Operand::Mem(Mem {
+    base: R12,
+    index: Some((RBX, 8)),
+    disp: 0,
+    label: None,
+});
+
+// Runs the I/O of a callback, and returns 0, or `IO_ERROR` after storing its error.
+fn io(ctx: *mut Context, f: impl FnOnce(&mut Context) -> io::Result<()>) -> i64 {
+    let ctx = unsafe { &mut *ctx };
+    match f(ctx) {
+        Ok(()) => 0,
+        Err(e) => {
+            ctx.error = Some(InterpreterError::Io(e));
+            IO_ERROR
+        }
+    }
+}
+
+// Writes `bytes` like `io`, or returns `OUTPUT_LIMIT` if they would exceed the output limit.
+fn write(ctx: *mut Context, bytes: &[u8]) -> i64 {
+    let c = unsafe { &mut *ctx };
+    if !count_output(c.options, &mut c.written, bytes.len()) {
+        return OUTPUT_LIMIT;
+    }
+    io(ctx, |ctx| ctx.output.write_all(bytes))
+}
+
+extern "sysv64" fn putchar(ctx: *mut Context, index: i64) -> i64 {
+    let c = unsafe { &*ctx };
+    write(ctx, &[c.tape[index as usize] as u8])
+}
+
+extern "sysv64" fn getchar(ctx: *mut Context, index: i64) -> i64 {
+    io(ctx, |ctx| {
+        ctx.output.flush()?;
+        let i = index as usize;
+        match ctx.input.next()? {
+            Some(c) => ctx.tape[i] = ctx.options.cell_width.wrap(c as i64),
+            None => eof(ctx.options, &mut ctx.tape[i]),
+        }
+        Ok(())
+    })
+}
+
+extern "sysv64" fn putint(ctx: *mut Context, index: i64) -> i64 {
+    let c = unsafe { &*ctx };
+    write(ctx, c.tape[index as usize].to_string().as_bytes())
+}
+
+extern "sysv64" fn getint(ctx: *mut Context, index: i64) -> i64 {
+    io(ctx, |ctx| {
+        ctx.output.flush()?;
+        let i = index as usize;
+        match ctx.input.integer()? {
+            Some(n) => ctx.tape[i] = ctx.options.cell_width.wrap(n),
+            None => eof(ctx.options, &mut ctx.tape[i]),
+        }
+        Ok(())
+    })
+}
+
+extern "sysv64" fn grow_tape(ctx: *mut Context, index: i64) -> i64 {
+    let ctx = unsafe { &mut *ctx };
+    let index = grow(&mut ctx.tape, index, ctx.options.max_tape);
+    ctx.base = ctx.tape.as_mut_ptr();
+    ctx.len = ctx.tape.len() as i64;
+    match index {
+        Ok(index) => index.unwrap_or(-TAPE_LIMIT),
+        Err(e) => {
+            ctx.error = Some(e);
+            -TAPE_ALLOCATION
+        }
+    }
+}
+
+// Compiled machine code, which is unmapped when dropped.
+pub struct Jit {
+    options: Options,
+    locations: Vec<Location>,
+    memory: *mut u8,
+    size: usize,
+}
+
+impl Jit {
+    pub fn new(program: &Program) -> io::Result<Jit> {
+        let mut code = compile(program);
+        let size = code.bytes.len();
+        let memory = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if memory as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let jit = Jit {
+            options: program.options.clone(),
+            locations: program.locations.clone(),
+            memory,
+            size,
+        };
+
+        // The memory is never writable and executable at once.
+        code.link(memory as u64);
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.bytes.as_ptr(), memory, size);
+            if mprotect(memory, size, PROT_READ | PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(jit)
+    }
+
+    // Runs the code and returns its exit status, like `Interpreter::run`.
+    pub fn run(
+        &self,
+        input: &mut (impl Read + ?Sized),
+        output: &mut (impl Write + ?Sized),
+    ) -> Result<i32, InterpreterError> {
+        let size = self.options.tape_size.0;
+        let (mut input, mut output) = (input, output);
+        let mut ctx = Context {
+            base: std::ptr::null_mut(),
+            len: size as i64,
+            ptr: if self.options.bidirectional {
+                size / 2
+            } else {
+                0
+            } as i64,
+            pc: 0,
+            status: 0,
+            fuel: self.options.fuel.unwrap_or_default() as i64,
+            putchar,
+            getchar,
+            putint,
+            getint,
+            grow: grow_tape,
+            tape: new_tape(size)?,
+            options: &self.options,
+            input: Input {
+                inner: &mut input,
+                peeked: None,
+            },
+            output: &mut output,
+            error: None,
+            written: 0,
+        };
+        ctx.base = ctx.tape.as_mut_ptr();
+
+        let f: extern "sysv64" fn(*mut Context) -> i64 =
+            unsafe { std::mem::transmute(self.memory) };
+        let result = f(&mut ctx);
+
+        let location = self
+            .locations
+            .get(ctx.pc as usize)
+            .copied()
+            .unwrap_or_default();
+        match result {
+            EXITED => Ok(ctx.status as i32),
+            OUT_OF_BOUNDS => Err(InterpreterError::OutOfBounds {
+                line: location.line,
+                column: location.column,
+                index: ctx.ptr,
+            }),
+            OVERFLOW => Err(InterpreterError::Overflow {
+                line: location.line,
+                column: location.column,
+            }),
+            OUT_OF_FUEL => Err(Limit::Fuel.error(&self.options, location, ctx.ptr, &ctx.tape)),
+            TAPE_LIMIT => Err(Limit::Tape.error(&self.options, location, ctx.ptr, &ctx.tape)),
+            OUTPUT_LIMIT => Err(Limit::Output.error(&self.options, location, ctx.ptr, &ctx.tape)),
+            _ => Err(ctx
+                .error
+                .take()
+                .unwrap_or_else(|| InterpreterError::Io(io::Error::other("lost I/O error")))),
+        }
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.memory, self.size);
+        }
+    }
+}
+
+// Compiles the program to a function taking the context in 'rdi' and returning the result.
+fn compile(program: &Program) -> Code {
+    let options = &program.options;
+    let width = options.cell_width;
+    let mut c = Code::default();
+    // The labels of the ops and of the end of the program.
+    let ops: Vec<_> = (0..=program.ops.len()).map(|_| c.label()).collect();
+    let leave = c.label();
+    // The labels reporting a failure, with the result, or `None` for the one in 'rax', and the
+    // index of the op.
+    let mut failures = vec![];
+    let mut fail = |c: &mut Code, result: Option<i64>, pc: usize| {
+        let l = c.label();
+        failures.push((l, result, pc));
+        l
+    };
+    // Checks that the pointer is inside a fixed tape. The other tapes always contain it.
+    let check = |c: &mut Code, fail: &mut dyn FnMut(&mut Code, Option<i64>, usize) -> Label, pc| {
+        if options.tape_mode == TapeMode::Fixed {
+            c.alu(CMP, RBX, R14);
+            let l = fail(c, Some(OUT_OF_BOUNDS), pc);
+            c.jcc(AE, l);
+        }
+    };
+    // Calls the callback at `offset` in the context with the pointer.
+    let call = |c: &mut Code, offset: usize| {
+        c.mov(RDI, R13);
+        c.mov(RSI, RBX);
+        c.insn(4, &[0xff], 2, field(offset));
+    };
+
+    // Pushing 'r15' also keeps the stack aligned for the calls.
+    for r in [RBX, R12, R13, R14, R15] {
+        c.push(r);
+    }
+    c.mov(R13, RDI);
+    c.insn(8, &[0x8b], R12, field(std::mem::offset_of!(Context, base)));
+    c.insn(8, &[0x8b], R14, field(std::mem::offset_of!(Context, len)));
+    c.insn(8, &[0x8b], RBX, field(std::mem::offset_of!(Context, ptr)));
+    c.insn(8, &[0x8b], R15, field(std::mem::offset_of!(Context, fuel)));
+
+    for (pc, op) in program.ops.iter().enumerate() {
+        c.bind(ops[pc]);
+        if options.fuel.is_some() {
+            c.alu_imm(SUB, R15, 1);
+            let l = fail(&mut c, Some(OUT_OF_FUEL), pc);
+            c.jcc(B, l);
+        }
+        match *op {
+            Op::Move(offset) => match options.tape_mode {
+                TapeMode::Fixed => c.alu_imm(ADD, RBX, offset),
+                TapeMode::Grow => {
+                    let inside = c.label();
+                    c.alu_imm(ADD, RBX, offset);
+                    c.alu(CMP, RBX, R14);
+                    c.jcc(B, inside);
+                    call(&mut c, std::mem::offset_of!(Context, grow));
+                    // The pointer stays before the move when the tape cannot grow.
+                    let grown = c.label();
+                    c.test(RAX);
+                    c.jcc(NS, grown);
+                    c.alu_imm(SUB, RBX, offset);
+                    c.neg(RAX);
+                    let l = fail(&mut c, None, pc);
+                    c.jmp(l);
+                    c.bind(grown);
+                    c.mov(RBX, RAX);
+                    c.insn(8, &[0x8b], R12, field(std::mem::offset_of!(Context, base)));
+                    c.insn(8, &[0x8b], R14, field(std::mem::offset_of!(Context, len)));
+                    c.bind(inside);
+                }
+                TapeMode::Wrap => {
+                    c.mov_imm(RAX, (offset as i64).rem_euclid(options.tape_size.0 as i64));
+                    c.alu(ADD, RBX, RAX);
+                    c.mov(RAX, RBX);
+                    c.alu(SUB, RAX, R14);
+                    c.alu(CMP, RBX, R14);
+                    c.cmov(AE, RBX, RAX);
+                }
+            },
+            Op::Add(n) => {
+                check(&mut c, &mut fail, pc);
+                if options.overflow == Overflow::Wrap && width == CellWidth::W64 {
+                    c.insn(8, &[0x81], 0, CELL);
+                    c.imm(n as i64, 4);
+                    continue;
+                }
+                c.insn(8, &[0x8b], RAX, CELL);
+                c.alu_imm(ADD, RAX, n);
+                match (options.overflow, width) {
+                    // Sign-extends the low bits.
+                    (Overflow::Wrap, CellWidth::W8) => {
+                        c.insn(8, &[0x0f, 0xbe], RAX, Operand::Reg(RAX))
+                    }
+                    (Overflow::Wrap, CellWidth::W16) => {
+                        c.insn(8, &[0x0f, 0xbf], RAX, Operand::Reg(RAX))
+                    }
+                    (Overflow::Wrap, _) => c.insn(8, &[0x63], RAX, Operand::Reg(RAX)),
+                    (Overflow::Trap, CellWidth::W64) => {
+                        let l = fail(&mut c, Some(OVERFLOW), pc);
+                        c.jcc(O, l);
+                    }
+                    (Overflow::Saturate, CellWidth::W64) => {
+                        let ok = c.label();
+                        c.jcc(NO, ok);
+                        c.mov_imm(RAX, if n > 0 { width.max() } else { width.min() });
+                        c.bind(ok);
+                    }
+                    // The sum of a narrower cell and an addend fits in 64 bits, so it only has
+                    // to be compared with the limit.
+                    (overflow, _) if n != 0 => {
+                        let (limit, cc) = if n > 0 {
+                            (width.max(), G)
+                        } else {
+                            (width.min(), L)
+                        };
+                        c.mov_imm(RCX, limit);
+                        c.alu(CMP, RAX, RCX);
+                        if overflow == Overflow::Trap {
+                            let l = fail(&mut c, Some(OVERFLOW), pc);
+                            c.jcc(cc, l);
+                        } else {
+                            c.cmov(cc, RAX, RCX);
+                        }
+                    }
+                    _ => {}
+                }
+                c.insn(8, &[0x89], RAX, CELL);
+            }
+            Op::Clear => {
+                check(&mut c, &mut fail, pc);
+                c.insn(8, &[0xc7], 0, CELL);
+                c.imm(0, 4);
+            }
+            Op::PutChar | Op::GetChar | Op::PutInt | Op::GetInt => {
+                check(&mut c, &mut fail, pc);
+                let offset = match *op {
+                    Op::PutChar => std::mem::offset_of!(Context, putchar),
+                    Op::GetChar => std::mem::offset_of!(Context, getchar),
+                    Op::PutInt => std::mem::offset_of!(Context, putint),
+                    _ => std::mem::offset_of!(Context, getint),
+                };
+                call(&mut c, offset);
+                c.test(RAX);
+                let l = fail(&mut c, None, pc);
+                c.jcc(NE, l);
+            }
+            Op::JumpIfZero(target) | Op::JumpIfNotZero(target) => {
+                check(&mut c, &mut fail, pc);
+                c.insn(8, &[0x83], 7, CELL);
+                c.imm(0, 1);
+                let cc = if matches!(op, Op::JumpIfZero(_)) {
+                    E
+                } else {
+                    NE
+                };
+                c.jcc(cc, ops[target]);
+            }
+            Op::Exit(status) => {
+                c.mov_imm(RAX, status as i64);
+                c.insn(
+                    8,
+                    &[0x89],
+                    RAX,
+                    field(std::mem::offset_of!(Context, status)),
+                );
+                c.xor32(RAX);
+                c.jmp(leave);
+            }
+            Op::ExitCell => {
+                check(&mut c, &mut fail, pc);
+                c.insn(8, &[0x8b], RAX, CELL);
+                c.insn(
+                    8,
+                    &[0x89],
+                    RAX,
+                    field(std::mem::offset_of!(Context, status)),
+                );
+                c.xor32(RAX);
+                c.jmp(leave);
+            }
+        }
+    }
+
+    c.bind(ops[program.ops.len()]);
+    match options.exit_status {
+        ExitStatus::Zero => c.xor32(RAX),
+        ExitStatus::Cell => {
+            // The end of the program belongs to its last statement.
+            check(&mut c, &mut fail, program.ops.len().saturating_sub(1));
+            c.insn(8, &[0x8b], RAX, CELL);
+        }
+    }
+    c.insn(
+        8,
+        &[0x89],
+        RAX,
+        field(std::mem::offset_of!(Context, status)),
+    );
+    c.xor32(RAX);
+
+    c.bind(leave);
+    c.insn(8, &[0x89], RBX, field(std::mem::offset_of!(Context, ptr)));
+    for r in [R15, R14, R13, R12, RBX] {
+        c.pop(r);
+    }
+    c.ret();
+
+    for (l, result, pc) in failures {
+        c.bind(l);
+        c.mov_imm(RCX, pc as i64);
+        c.insn(8, &[0x89], RCX, field(std::mem::offset_of!(Context, pc)));
+        if let Some(result) = result {
+            c.mov_imm(RAX, result);
+        }
+        c.jmp(leave);
+    }
+
+    c
+}
+
+// Runs `src` with the JIT and with the interpreter, and checks that the results are the same.
+// Returns the output, and the exit status or the error with the snapshot of a limit.
+#[cfg(test)]
+fn check(src: &str, options: &Options, input: &str) -> (String, String) {
+    let describe = |status: Result<i32, InterpreterError>| match status {
+        Ok(status) => status.to_string(),
+        Err(e) => match e.snapshot() {
+            Some(snapshot) => format!("{}: {}", e, snapshot),
+            None => e.to_string(),
+        },
+    };
+    let jit = Jit::new(&Program::new(&super::compile(src), options)).unwrap();
+    let mut output = vec![];
+    let status = jit.run(&mut input.as_bytes(), &mut output);
+    let result = (String::from_utf8(output).unwrap(), describe(status));
+    let (output, status) = super::run(src, options, input);
+    assert_eq!(result, (output, describe(status)), "{:?}", options);
+    result
+}
+
+#[test]
+fn test_run() {
+    use crate::codegen::options::{Eof, TapeSize};
+
+    let src = "getint();\n*ptr += 5;\nputint();\nptr += 1;\n*ptr += 10;\nputchar();\ngetchar();\nputchar();\n";
+    assert_eq!(
+        check(src, &Options::new(), " -37x"),
+        ("-32\nx".to_owned(), "0".to_owned())
+    );
+
+    let src = "getint();\n*ptr += 100;\nputint();\n*ptr += 100;\nputint();\n*ptr -= 30000;\nputint();\nptr += 1;\n*ptr += 5;\nwhile *ptr {\n  *ptr -= 1;\n}\nputint();\n";
+    for width in [
+        CellWidth::W8,
+        CellWidth::W16,
+        CellWidth::W32,
+        CellWidth::W64,
+    ] {
+        for overflow in [Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+            let mut options = Options::new();
+            options.cell_width = width;
+            options.overflow = overflow;
+            check(src, &options, "9223372036854775707");
+            check(src, &options, "-20");
+        }
+    }
+
+    let src = "*ptr += 3;\nwhile *ptr {\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nptr -= 1;\nputint();\nptr += 2;\nputint();\nptr += 40000;\nputint();\n";
+    for mode in [TapeMode::Fixed, TapeMode::Grow, TapeMode::Wrap] {
+        for size in [2, 3, 30000] {
+            let mut options = Options::new();
+            options.tape_mode = mode;
+            options.tape_size = TapeSize(size);
+            check(src, &options, "");
+            options.bidirectional = true;
+            check(src, &options, "");
+        }
+    }
+    let mut options = Options::new();
+    options.tape_size = TapeSize(1 << 60);
+    check("putint();\n", &options, "");
+
+    let src = "*ptr += 12;\nwhile *ptr {\n  putint();\n  ptr += 1;\n  *ptr += 1;\n  ptr -= 1;\n  *ptr -= 1;\n}\nwhile *ptr {\n  ptr -= 1;\n}\n";
+    for fuel in [0, 1, 17, 50, 100] {
+        let mut options = Options::new();
+        options.fuel = Some(fuel);
+        check(src, &options, "");
+    }
+    let mut options = Options::new();
+    options.max_output = Some(13);
+    assert_eq!(
+        check(src, &options, ""),
+        (
+            "1211109876543".to_owned(),
+            "3:3: output limit of 13 bytes exceeded: ptr = 0, tape[0..9] = [2] 10 0 0 0 0 0 0 0"
+                .to_owned()
+        )
+    );
+    options.tape_mode = TapeMode::Grow;
+    options.tape_size = TapeSize(2);
+    options.bidirectional = true;
+    for max in [2, 3, 4, 100] {
+        options.max_tape = Some(max);
+        check(src, &options, "");
+    }
+
+    let src = "getchar();\nputint();\ngetchar();\nputint();\nexit(*ptr);\n";
+    let mut options = Options::new();
+    options.eof = Eof::Unchanged;
+    assert_eq!(check(src, &options, " ").1, "32");
+    options.eof = Eof::Zero;
+    options.exit_status = ExitStatus::Cell;
+    assert_eq!(check("getchar();\n*ptr -= 2;\n", &options, "").1, "-2");
+    let src = "*ptr += 7;\nwhile *ptr {\n  exit(-3);\n}\n";
+    assert_eq!(check(src, &options, "").1, "-3");
+}
+
+#[test]
+fn test_golden() {
+    crate::codegen::golden(|src, options, input| {
+        let (output, status) = check(src, options, input);
+        assert_eq!(status, "0");
+        Some(output)
+    });
+}
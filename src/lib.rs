@@ -1,3 +1,7 @@
+// `failure_derive` expands `#[derive(Fail)]` into impls nested in an anonymous const.
+#![allow(non_local_definitions)]
+
 pub mod codegen;
+pub mod interpreter;
 pub mod parser;
 pub mod token;
@@ -14,15 +14,25 @@ pub enum Lhs {
     Dereference(String),
 }
 
+// argument -> rhs
+//           | lhs
+#[derive(Debug, PartialEq)]
+pub enum Argument {
+    Rhs(Rhs),
+    Lhs(Lhs),
+}
+
 // expression -> lhs '+=' rhs
 //             | lhs '-=' rhs
 //             | lhs '(' ')'
+//             | lhs '(' argument ')'
 //             | lhs
 #[derive(Debug, PartialEq)]
 pub enum Expression {
     AssignAdd(Lhs, Rhs),
     AssignSub(Lhs, Rhs),
     FunctionCall(Lhs),
+    FunctionCallWith(Lhs, Argument),
     Lhs(Lhs),
 }
 
@@ -56,6 +66,82 @@ pub enum Program {
     Statements(Statements),
 }
 
+// The number of tokens each node was parsed from, used to find the location of a node in the
+// token stream.
+impl Rhs {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Rhs::Number(_) => 1,
+        }
+    }
+}
+
+impl Lhs {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Lhs::Pointer(_) => 1,
+            Lhs::Dereference(_) => 2,
+        }
+    }
+}
+
+impl Argument {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Argument::Rhs(r) => r.token_count(),
+            Argument::Lhs(l) => l.token_count(),
+        }
+    }
+}
+
+impl Expression {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Expression::AssignAdd(l, r) | Expression::AssignSub(l, r) => {
+                l.token_count() + 1 + r.token_count()
+            }
+            Expression::FunctionCall(l) => l.token_count() + 2,
+            Expression::FunctionCallWith(l, a) => l.token_count() + 1 + a.token_count() + 1,
+            Expression::Lhs(l) => l.token_count(),
+        }
+    }
+}
+
+impl Statement {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Statement::Expression(e) => e.token_count() + 1,
+            Statement::Block(b) => b.token_count(),
+            Statement::While(e, b) => 1 + e.token_count() + b.token_count(),
+        }
+    }
+}
+
+impl Statements {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Statements::Statements(ss, s) => ss.token_count() + s.token_count(),
+            Statements::Statement(s) => s.token_count(),
+        }
+    }
+}
+
+impl Block {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Block::Statements(ss) => 1 + ss.token_count() + 1,
+        }
+    }
+}
+
+impl Program {
+    pub fn token_count(&self) -> usize {
+        match self {
+            Program::Statements(ss) => ss.token_count(),
+        }
+    }
+}
+
 pub fn rhs(tokens: &[Token]) -> Option<(&[Token], Rhs)> {
     tokens.first().and_then(|t| match t {
         Token::Integer(i) => Some((&tokens[1..], Rhs::Number(*i))),
@@ -75,7 +161,7 @@ fn test_rhs() {
 }
 
 pub fn lhs(tokens: &[Token]) -> Option<(&[Token], Lhs)> {
-    match (tokens.get(0), tokens.get(1)) {
+    match (tokens.first(), tokens.get(1)) {
         (Some(Token::Star), Some(Token::Identifier(s))) => {
             Some((&tokens[2..], Lhs::Dereference(s.to_string())))
         }
@@ -101,8 +187,14 @@ fn test_lhs() {
     );
 }
 
+pub fn argument(tokens: &[Token]) -> Option<(&[Token], Argument)> {
+    rhs(tokens)
+        .map(|(tokens, r)| (tokens, Argument::Rhs(r)))
+        .or_else(|| lhs(tokens).map(|(tokens, l)| (tokens, Argument::Lhs(l))))
+}
+
 pub fn expression(tokens: &[Token]) -> Option<(&[Token], Expression)> {
-    lhs(tokens).and_then(|(tokens, l)| match (tokens.get(0), tokens.get(1)) {
+    lhs(tokens).and_then(|(tokens, l)| match (tokens.first(), tokens.get(1)) {
         (Some(Token::PlusEq), _) => {
             rhs(&tokens[1..]).map(|(t, r)| (t, Expression::AssignAdd(l, r)))
         }
@@ -115,6 +207,13 @@ pub fn expression(tokens: &[Token]) -> Option<(&[Token], Expression)> {
             Some((&tokens[2..], Expression::FunctionCall(l)))
         }
 
+        (Some(Token::ParenOpen), _) => {
+            argument(&tokens[1..]).and_then(|(tokens, a)| match tokens.first() {
+                Some(Token::ParenClose) => Some((&tokens[1..], Expression::FunctionCallWith(l, a))),
+                _ => None,
+            })
+        }
+
         _ => Some((tokens, Expression::Lhs(l))),
     })
 }
@@ -208,6 +307,46 @@ fn test_expression() {
         ))
     );
 
+    assert_eq!(
+        expression(&[
+            Token::Identifier("hoge".to_owned()),
+            Token::ParenOpen,
+            Token::Integer(123),
+            Token::ParenClose
+        ]),
+        Some((
+            &[] as &[Token],
+            Expression::FunctionCallWith(
+                Lhs::Pointer("hoge".to_owned()),
+                Argument::Rhs(Rhs::Number(123))
+            )
+        ))
+    );
+    assert_eq!(
+        expression(&[
+            Token::Identifier("hoge".to_owned()),
+            Token::ParenOpen,
+            Token::Star,
+            Token::Identifier("ptr".to_owned()),
+            Token::ParenClose
+        ]),
+        Some((
+            &[] as &[Token],
+            Expression::FunctionCallWith(
+                Lhs::Pointer("hoge".to_owned()),
+                Argument::Lhs(Lhs::Dereference("ptr".to_owned()))
+            )
+        ))
+    );
+    assert_eq!(
+        expression(&[
+            Token::Identifier("hoge".to_owned()),
+            Token::ParenOpen,
+            Token::Integer(123),
+        ]),
+        None
+    );
+
     assert_eq!(
         expression(&[
             Token::Identifier("hoge".to_owned()),
@@ -533,3 +672,27 @@ fn test_program() {
         ))
     );
 }
+
+#[test]
+fn test_token_count() {
+    let tokens = crate::token::tokenize(
+        r#"
+ptr += 1;
+*ptr -= 1;
+putchar();
+{
+    *ptr += 2;
+    while *ptr {
+        *ptr -= 1;
+        { ptr += 1; }
+    }
+    exit(*ptr);
+}
+exit(3);
+"#,
+    )
+    .unwrap();
+    let (rest, tree) = program(&tokens).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(tree.token_count(), tokens.len());
+}
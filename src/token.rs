@@ -20,6 +20,29 @@ pub enum Token {
     CurlyClose, // '}'
 }
 
+// 1-based position of a token in the source.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    // Returns the location just after `s`, which starts at this location.
+    pub fn advance(self, s: &str) -> Location {
+        s.chars().fold(self, |l, c| match c {
+            '\n' => Location {
+                line: l.line + 1,
+                column: 1,
+            },
+            _ => Location {
+                column: l.column + 1,
+                ..l
+            },
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, failure::Fail)]
 pub enum TokenizerError {
     #[fail(display = "unexpected character: '{}'", character)]
@@ -30,15 +53,26 @@ pub enum TokenizerError {
 }
 
 pub fn tokenize(src: &str) -> Result<Vec<Token>, TokenizerError> {
+    tokenize_with_locations(src).map(|(tokens, _)| tokens)
+}
+
+// Same as `tokenize`, but also returns the location of each token.
+pub fn tokenize_with_locations(src: &str) -> Result<(Vec<Token>, Vec<Location>), TokenizerError> {
     let mut tokens = Vec::new();
+    let mut locations = Vec::new();
+    let mut location = Location { line: 1, column: 1 };
     let mut cur = 0;
     while cur < src.len() {
         if let Some(s) = src.get(cur..) {
+            let start = location;
+
             macro_rules! match_re {
                 ($re:expr, $closure:expr) => {
                     if let Some(m) = $re.find(s) {
                         $closure(m);
+                        locations.resize(tokens.len(), start);
                         cur += m.end();
+                        location = location.advance(m.as_str());
                         continue;
                     }
                 };
@@ -48,7 +82,9 @@ pub fn tokenize(src: &str) -> Result<Vec<Token>, TokenizerError> {
                 ($pat:expr, $e:expr) => {
                     if s.starts_with($pat) {
                         $e;
+                        locations.resize(tokens.len(), start);
                         cur += $pat.len();
+                        location = location.advance($pat);
                         continue;
                     }
                 };
@@ -94,7 +130,7 @@ pub fn tokenize(src: &str) -> Result<Vec<Token>, TokenizerError> {
         }
     }
 
-    Ok(tokens)
+    Ok((tokens, locations))
 }
 
 #[test]
@@ -177,3 +213,48 @@ fn test_tokenize() {
         ])
     );
 }
+
+#[test]
+fn test_tokenize_with_locations() {
+    assert_eq!(
+        tokenize_with_locations("// comment\n  *ptr += 1;\nwhile *ptr {}"),
+        Ok((
+            vec![
+                Token::Star,
+                Token::Identifier("ptr".to_owned()),
+                Token::PlusEq,
+                Token::Integer(1),
+                Token::Semi,
+                Token::While,
+                Token::Star,
+                Token::Identifier("ptr".to_owned()),
+                Token::CurlyOpen,
+                Token::CurlyClose,
+            ],
+            vec![
+                Location { line: 2, column: 3 },
+                Location { line: 2, column: 4 },
+                Location { line: 2, column: 8 },
+                Location {
+                    line: 2,
+                    column: 11
+                },
+                Location {
+                    line: 2,
+                    column: 12
+                },
+                Location { line: 3, column: 1 },
+                Location { line: 3, column: 7 },
+                Location { line: 3, column: 8 },
+                Location {
+                    line: 3,
+                    column: 12
+                },
+                Location {
+                    line: 3,
+                    column: 13
+                },
+            ]
+        ))
+    );
+}